@@ -2,7 +2,7 @@ use bevy::{math::VectorSpace, prelude::*, utils::hashbrown::HashMap};
 use bevy_replicon_lockstep::prelude::SimulationId;
 use avian3d::prelude::*;
 
-#[derive(Eq, PartialEq, Hash, Reflect)]
+#[derive(Eq, PartialEq, Hash, Reflect, Clone, Copy)]
 pub enum Unit {
     Capsule,
 }
@@ -12,7 +12,7 @@ pub fn spawn_unit(
     transform: Transform,
     id: SimulationId,
     commands: &mut Commands,
-    assets: &Res<UnitAssets>,
+    assets: &UnitAssets,
 ) -> Entity {
     match unit {
         Unit::Capsule => {
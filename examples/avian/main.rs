@@ -3,7 +3,7 @@ use bevy_replicon::prelude::*;
 use bevy_replicon_lockstep::prelude::*;
 use bevy_replicon_renet::RepliconRenetPlugins;
 use game_assets::{spawn_unit, Unit, UnitAssets};
-use std::{env, time::Duration};
+use std::{env, net::SocketAddr, time::Duration};
 use avian3d::prelude::*;
 
 mod connection;
@@ -12,6 +12,8 @@ mod game_assets;
 const SIM_TICK_INTERVAL: Duration = Duration::from_millis(33);
 
 /// Command types for the simulation.  Must derive Reflect and be registered
+/// with `register_game_command`, which applies them on every peer in the
+/// same order, right before physics is stepped for the tick
  
 /// This is a command it will be broadcast from the server
 #[derive(Reflect)]
@@ -21,6 +23,27 @@ struct SpawnUnit {
     pub position: Vec3,
 }
 
+impl GameCommand for SpawnUnit {
+    fn apply(&self, _ctx: &CommandContext, world: &mut World) {
+        // Always allocate new SimulationIds while applying commands
+        // Also make sure the order of spawning is identical for determinism
+        let sim_id = world.resource_mut::<SimulationIdAllocator>().allocate();
+        world.resource_scope(|world, assets: Mut<UnitAssets>| {
+            spawn_unit(
+                self.unit_type,
+                Transform::default().with_translation(self.position),
+                sim_id,
+                &mut world.commands(),
+                &assets,
+            );
+        });
+        let mut selected = world.query::<&mut Selected>();
+        if let Ok(mut selected) = selected.get_single_mut(world) {
+            selected.0 = sim_id;
+        }
+    }
+}
+
 /// This is a command to move a unit by applying a fore to its rigidbody
 #[derive(Reflect)]
 struct ApplyForce {
@@ -28,30 +51,30 @@ struct ApplyForce {
     target: SimulationId,
 }
 
+impl GameCommand for ApplyForce {
+    fn apply(&self, ctx: &CommandContext, world: &mut World) {
+        // Resolving the target can fail, e.g. if the unit was never spawned
+        let Some(&unit) = world.resource::<SimulationIdEntityMap>().get(&self.target) else {
+            warn!("{}", SimRefError::UnknownId { context: *ctx, id: self.target });
+            return;
+        };
+        if let Some(mut force) = world.get_mut::<ExternalForce>(unit) {
+            force.apply_force(self.force);
+        }
+    }
+}
+
 // Simple component to hold a reference to the actively selected unit
 // In this simple example it will just be the last one spawned in
 #[derive(Component, Deref, Copy, Clone, Debug)]
 struct Selected(SimulationId);
 
-// Store the last Simulation tick we processed
-#[derive(Resource, Deref, DerefMut, Default)]
-struct LastProcessedTick(u32);
-
-// condition to check if we have a tick ready for processing
-fn new_tick_ready(
-    cache: Res<LockstepGameCommandBuffer>,
-    last_tick: Res<LastProcessedTick>
-) -> bool {
-    cache.get(last_tick.0 + 1).is_some()
-}
-
-
 fn main() {
     let mut app = App::new();
 
     // Register our reflected command types
-    app.register_type::<SpawnUnit>();
-    app.register_type::<ApplyForce>();
+    app.register_game_command::<SpawnUnit>();
+    app.register_game_command::<ApplyForce>();
 
     app.add_plugins((
         DefaultPlugins
@@ -72,78 +95,47 @@ fn main() {
             ..default()
         }),
         RepliconRenetPlugins,
-        RepliconLockstepPlugin {
-            simulation: SimulationSettings {
+        RepliconLockstepPlugins::default()
+            .with_simulation(SimulationSettings {
                 // ~30 ticks per second
                 tick_timestep: SIM_TICK_INTERVAL,
                 num_players: 2,
                 ..default()
-            },
-            server: ConnectionSettings {
+            })
+            .with_connections(ConnectionSettings {
                 server_mode: ServerMode::Host,
                 ..default()
-            }
-        }
+            }),
     ));
     app.init_resource::<Gravity>();
 
-    // Convenience triggers for connection management
-    app.add_observer(connection::start_server.map(|_res| {}));
-    app.add_observer(connection::connect_client.map(|_res| {}));
-    app.add_observer(connection::stop_server);
-    app.add_observer(connection::disconnect_client);
+    // Connection events
     app.add_observer(connection::on_client_disconnect);
     app.add_observer(connection::on_client_reconnect);
+    app.add_observer(connection::on_transport_error);
 
     // parse cli commands to choose host or client mode
     // Run `cargo run server` to start a host server
     if env::args().collect::<Vec<String>>().iter().any(|arg| {arg == "server"}) {
-        app.add_systems(Startup, |
-            mut commands: Commands,
-            mut state: ResMut<NextState<SimulationState>>
-            | {
-                commands.trigger(connection::TriggerStartServer);
-                state.set(SimulationState::Connecting);
-            });
+        app.add_systems(Startup, |mut commands: Commands| {
+            commands.lockstep_host();
+        });
     } else { // else it's a client
-        app.add_systems(Startup, |
-                mut commands: Commands,
-                mut state: ResMut<NextState<SimulationState>>
-            | {
-                commands.trigger(connection::TriggerConnectClient);
-                state.set(SimulationState::Connecting);
-            });
+        app.add_systems(Startup, |mut commands: Commands, settings: Res<ConnectionSettings>| {
+            commands.lockstep_connect(SocketAddr::new(settings.server_address.into(), settings.server_port));
+        });
     }
 
-    // Game related systems
+    // Game related systems.  Commands and physics are applied by the lockstep
+    // plugins, the game only sends input
     app
-        .init_resource::<LastProcessedTick>()
-        .add_systems(
-            Startup,
-            |mut time: ResMut<Time<Physics>>| {
-                // Start with physics paused
-                // We have to step it manually for determinism
-                // Ticks are not triggered at a regular rate across clients
-                time.pause();
-            })
         .add_systems(Update, (
 
             // Initialization logic
             setup_game.run_if(in_state(SimulationState::Setup)),
 
-            // in-game logic
-            (   
-                // send input commands to server
-                send_commands,
-
-                // handle commands from server for all clients
-                (
-                    process_tick_commands,
-                    step_physics,
-                    update_last_tick,
-                ).run_if(new_tick_ready).chain(),
-
-            ).run_if(in_state(SimulationState::Running))
+            // send input commands to server
+            send_commands.run_if(in_state(SimulationState::Running)),
         ))
         .run();
 }
@@ -176,7 +168,7 @@ fn setup_game (
 fn send_commands(
     mut commands: Commands,
     kb: Res<ButtonInput<KeyCode>>,
-    sim_tick: Res<SimulationTick>,
+    queue: Res<LockstepCommandQueue>,
     mut count: Local<u16>,
     selected: Query<&Selected>,
 ) {
@@ -185,9 +177,7 @@ fn send_commands(
         commands.spawn(Selected(SimulationId::PLACEHOLDER));
     }
 
-    // vec of Commands to be send this tick, if any
-    let mut client_commands: Vec<Box<dyn PartialReflect>> = vec![];
-
+    // Queued commands are coalesced and sent once per frame
     // Spawn a new unit with space bar
     if kb.just_pressed(KeyCode::Space) {
         let x: f32 = (*count % 10) as f32 - 5.0;
@@ -195,12 +185,12 @@ fn send_commands(
         *count += 1;
         let position = Vec3::new(x, 1., z);
 
-        client_commands.push(Box::new(SpawnUnit {
+        queue.push(SpawnUnit {
             // Always use PLACEHOLDER when sending SimulationIds to the server
             id: SimulationId::PLACEHOLDER,
             unit_type: Unit::Capsule,
             position,
-        }));
+        });
     }
 
     // Move the selected unit (last spawned) around with WASD
@@ -220,75 +210,10 @@ fn send_commands(
         }
         if force != Vec3::ZERO {
             force *= 5.0;
-            client_commands.push(Box::new(ApplyForce {
+            queue.push(ApplyForce {
                 force,
                 target: **selected,
-            }));
-        }
-    }
-
-    if client_commands.iter().len() > 0 {
-        trace!("SENDING COMMANDS on tick {}", **sim_tick);
-        commands.client_trigger(ClientSendCommands {
-            commands: client_commands,
-            issued_tick: **sim_tick,
-        });
-    }
-}
-
-// Receiving commands from server: 
-// This is where you should implement the logic for applying commands for all clients
-// The next 3 systems will all be run in the order they are defined below.
-// They will be delayed by a few ticks to account for client ping.  This is the downside
-// of the lockstep architecture.  The upside is it scales better than other architectures
-// when you have massive numbers of entities that need to stay in sync.
-
-fn process_tick_commands(
-    mut commands: Commands,
-    command_history: Res<LockstepGameCommandBuffer>,
-    assets: Res<UnitAssets>,
-    mut selected: Query<&mut Selected>,
-    ids: Res<SimulationIdEntityMap>,
-    mut forces: Query<&mut ExternalForce>,
-    last_tick: Res<LastProcessedTick>,
-) {
-    let Some(tick_commands) = command_history.get(last_tick.0 + 1) else { return };
-    for (_client, commands_for_client) in tick_commands.iter() {
-        for cmd in commands_for_client.iter() {
-            // Is it a SpawnUnit command ?
-            if let Some(spawn_cmd) = SpawnUnit::from_reflect(cmd.as_partial_reflect()) {
-                // Always use new when spawning a new SimulationId to the server
-                // Also make sure the order of spawning is identical for determinism
-                let sim_id = SimulationId::new();
-                spawn_unit(
-                    spawn_cmd.unit_type,
-                    Transform::default().with_translation(spawn_cmd.position),
-                    sim_id,
-                    &mut commands,
-                    &assets,
-                );
-                selected.single_mut().0 = sim_id;
-            // Is it an ApplyForce command?
-            } else if let Some(force_cmd) = ApplyForce::from_reflect(cmd.as_partial_reflect()) {
-                if let Some(unit) = ids.get(&force_cmd.target) {
-                    if let Ok(mut unit) = forces.get_mut(*unit) {
-                        unit.apply_force(force_cmd.force);
-                    }
-                }
-            }
+            });
         }
     }
 }
-
-fn step_physics(
-    world: &mut World
-) {
-    world
-        .resource_mut::<Time<Physics>>()
-        .advance_by(SIM_TICK_INTERVAL);
-    world.run_schedule(PhysicsSchedule);
-}
-
-fn update_last_tick(mut last_tick: ResMut<LastProcessedTick>) {
-    last_tick.0 += 1;
-}
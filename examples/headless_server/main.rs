@@ -0,0 +1,86 @@
+use bevy::{log::LogPlugin, prelude::*};
+use bevy_replicon::prelude::*;
+use bevy_replicon_lockstep::prelude::*;
+use bevy_replicon_renet::RepliconRenetPlugins;
+use std::{env, net::SocketAddr, time::Duration};
+
+// A dedicated server without a window or renderer, and headless bot
+// clients to play against it.
+// Run `cargo run server` for the server, then `cargo run` twice for the clients.
+
+const SIM_TICK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Commands must be registered on the server and every client
+#[derive(Reflect)]
+struct Say(String);
+
+impl GameCommand for Say {
+    fn apply(&self, ctx: &CommandContext, _world: &mut World) {
+        info!("tick {}: client {} says {}", ctx.tick, ctx.client, self.0);
+    }
+}
+
+fn main() {
+    let server = env::args().any(|arg| arg == "server");
+    let settings = SimulationSettings {
+        tick_timestep: SIM_TICK_INTERVAL,
+        num_players: 2,
+        // The dedicated clock thread keeps ticks on time regardless of frame work
+        tick_clock: if server { TickClock::Dedicated } else { TickClock::Fixed },
+        ..default()
+    };
+
+    let mut app = App::new();
+    app.register_game_command::<Say>();
+    app.add_plugins((
+        HeadlessServerPlugins::for_settings(&settings),
+        LogPlugin::default(),
+        RepliconPlugins.set(ServerPlugin {
+            tick_policy: TickPolicy::EveryFrame,
+            ..default()
+        }),
+        RepliconRenetPlugins,
+        RepliconLockstepPlugins::default()
+            .with_simulation(settings)
+            .with_connections(ConnectionSettings {
+                // The server doesn't take a seat, it waits for num_players
+                // remote clients and is never counted as ready or missing
+                server_mode: ServerMode::Dedicated,
+                ..default()
+            }),
+    ));
+
+    if server {
+        app.add_systems(Startup, |mut commands: Commands| {
+            commands.lockstep_host();
+        });
+    } else {
+        app
+            .add_systems(Startup, |mut commands: Commands, settings: Res<ConnectionSettings>| {
+                commands.lockstep_connect(SocketAddr::new(settings.server_address.into(), settings.server_port));
+            })
+            .add_systems(Update, (
+                mark_ready.run_if(in_state(SimulationState::Setup)),
+                say_hello.run_if(in_state(SimulationState::Running)),
+            ));
+    }
+    app.run();
+}
+
+fn mark_ready(
+    mut commands: Commands,
+    local_client: Query<&LocalClient>,
+    mut ready: Local<bool>,
+) {
+    if *ready || local_client.is_empty() { return }
+    commands.client_trigger(ClientReadyEvent);
+    *ready = true;
+}
+
+/// Sends a command every second
+fn say_hello(queue: Res<LockstepCommandQueue>, sim_tick: Res<SimulationTick>, mut last: Local<SimTick>) {
+    let ticks_per_second = (1.0 / SIM_TICK_INTERVAL.as_secs_f32()) as SimTick;
+    if **sim_tick < *last + ticks_per_second { return }
+    *last = **sim_tick;
+    queue.push(Say("hello".into()));
+}
@@ -0,0 +1,54 @@
+//! Feeds arbitrary bytes to every network-facing decoder.  The first byte
+//! selects the message format.  Decoding must never panic, and whatever
+//! decodes must encode canonically.
+
+#![no_main]
+
+use std::sync::OnceLock;
+use bevy::prelude::*;
+use bevy_replicon_lockstep::{fuzzing::{WireMessage, reencode}, prelude::*};
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Reflect)]
+struct MoveTo {
+    unit: u32,
+    x: f32,
+    y: f32,
+}
+
+#[derive(Reflect)]
+struct Chat(String);
+
+#[derive(Reflect)]
+enum Order {
+    Stop,
+    Attack { target: u64 },
+    Build(u16, i32),
+}
+
+#[derive(Reflect)]
+struct Select {
+    units: Vec<u32>,
+}
+
+fn registry() -> &'static AppTypeRegistry {
+    static REGISTRY: OnceLock<AppTypeRegistry> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let mut app = App::new();
+        app
+            .register_lockstep_command::<MoveTo>()
+            .register_lockstep_command::<Chat>()
+            .register_lockstep_command::<Order>()
+            .register_lockstep_command::<Select>();
+        app.world().resource::<AppTypeRegistry>().clone()
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&selector, message)) = data.split_first() else { return };
+    let kind = WireMessage::ALL[selector as usize % WireMessage::ALL.len()];
+    let registry = registry().read();
+    if let Ok(encoded) = reencode(kind, message, &registry) {
+        assert_eq!(reencode(kind, &encoded, &registry).unwrap(), encoded);
+    }
+});
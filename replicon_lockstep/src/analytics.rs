@@ -0,0 +1,143 @@
+//! Match health analytics.  Every peer records how its match went, and on
+//! entering [`SimulationState::Ending`] hands a [`MatchAnalytics`] report
+//! to an [`AnalyticsSink`], so studios can send it to their telemetry
+//! without scraping logs.  Command latencies are measured by the server,
+//! reports of clients leave them empty.
+
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use bevy::prelude::*;
+use bevy_replicon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::prelude::*;
+
+/// Exports a [`MatchAnalytics`] report at the end of every match
+pub struct MatchAnalyticsPlugin {
+    pub sink: Arc<dyn AnalyticsSink>,
+}
+
+impl MatchAnalyticsPlugin {
+    pub fn new(sink: impl AnalyticsSink) -> Self {
+        Self { sink: Arc::new(sink) }
+    }
+}
+
+impl Plugin for MatchAnalyticsPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(MatchAnalyticsSink(self.sink.clone()))
+            .init_resource::<AnalyticsRecorder>()
+            .add_observer(record_stall)
+            .add_observer(record_disconnect)
+            .add_systems(OnEnter(SimulationState::Setup), reset_analytics)
+            .add_systems(OnEnter(SimulationState::Starting), start_analytics)
+            .add_systems(OnEnter(SimulationState::Ending), export_analytics);
+    }
+}
+
+/// Receives the [`MatchAnalytics`] of every match that ended, e.g. to
+/// serialize and upload it
+pub trait AnalyticsSink: Send + Sync + 'static {
+    fn export(&self, analytics: &MatchAnalytics);
+}
+
+impl<F: Fn(&MatchAnalytics) + Send + Sync + 'static> AnalyticsSink for F {
+    fn export(&self, analytics: &MatchAnalytics) {
+        self(analytics)
+    }
+}
+
+/// How a match went, as seen by one peer
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct MatchAnalytics {
+    /// The [`SessionIdentity`] seed, the same on every peer of the match
+    pub session_seed: u64,
+    pub is_server: bool,
+    /// The last simulated tick
+    pub ticks: SimTick,
+    /// Wall clock time from Starting to Ending
+    pub duration: Duration,
+    /// Times no tick arrived for longer than
+    /// `SimulationSettings::stall_notify_threshold`
+    pub stalls: u32,
+    /// Wall clock time spent in those stalls
+    pub stalled_for: Duration,
+    /// Mean time between clients issuing commands and them executing,
+    /// measured by the server
+    pub average_latency: BTreeMap<ClientId, Duration>,
+    /// Commands applied per command type, by type path
+    pub commands: BTreeMap<String, u64>,
+    /// Clients that disconnected during the match, in order, repeated if
+    /// they disconnected more than once
+    pub disconnects: Vec<ClientId>,
+}
+
+#[derive(Resource, Clone)]
+struct MatchAnalyticsSink(Arc<dyn AnalyticsSink>);
+
+/// What the current match recorded so far
+#[derive(Resource, Default)]
+struct AnalyticsRecorder {
+    started_at: Option<Duration>,
+    stalls: u32,
+    stalled_for: Duration,
+    disconnects: Vec<ClientId>,
+}
+
+fn reset_analytics(mut recorder: ResMut<AnalyticsRecorder>) {
+    *recorder = AnalyticsRecorder::default();
+}
+
+fn start_analytics(mut recorder: ResMut<AnalyticsRecorder>, time: Res<Time<Real>>) {
+    recorder.started_at = Some(time.elapsed());
+}
+
+fn record_stall(trigger: Trigger<PresentationResume>, mut recorder: ResMut<AnalyticsRecorder>) {
+    recorder.stalls += 1;
+    recorder.stalled_for += trigger.stalled_for;
+}
+
+fn record_disconnect(
+    trigger: Trigger<ClientDisconnect>,
+    mut recorder: ResMut<AnalyticsRecorder>,
+    state: Res<State<SimulationState>>,
+) {
+    if matches!(state.get(), SimulationState::Running | SimulationState::Paused) {
+        recorder.disconnects.push(trigger.0);
+    }
+}
+
+fn export_analytics(
+    sink: Res<MatchAnalyticsSink>,
+    recorder: Res<AnalyticsRecorder>,
+    sim_tick: Res<SimulationTick>,
+    history: Res<LockstepGameCommandBuffer>,
+    diagnostics: Res<LockstepDiagnostics>,
+    identity: Res<SessionIdentity>,
+    settings: Res<SimulationSettings>,
+    server: Res<RepliconServer>,
+    time: Res<Time<Real>>,
+) {
+    let mut commands = BTreeMap::<String, u64>::new();
+    for tick_commands in history.iter() {
+        for command in tick_commands.values().flatten() {
+            *commands.entry(command.reflect_type_path().to_string()).or_default() += 1;
+        }
+    }
+    let average_latency = diagnostics.command_latency
+        .iter()
+        .filter_map(|(&client, latency)| Some((client, settings.tick_timestep.mul_f64(latency.mean()?))))
+        .collect();
+    let analytics = MatchAnalytics {
+        session_seed: identity.seed,
+        is_server: server.is_running(),
+        ticks: **sim_tick,
+        duration: recorder.started_at.map_or(Duration::ZERO, |started_at| time.elapsed().saturating_sub(started_at)),
+        stalls: recorder.stalls,
+        stalled_for: recorder.stalled_for,
+        average_latency,
+        commands,
+        disconnects: recorder.disconnects.clone(),
+    };
+    info!("Exporting analytics of a match of {} ticks", analytics.ticks);
+    sink.0.export(&analytics);
+}
@@ -0,0 +1,139 @@
+//! Player chat.  Messages travel through the server like any other client
+//! trigger, which attributes them to the sender's [`ClientId`] and routes
+//! them to everyone or to the sender's team.  Chat is not part of the
+//! simulation, messages are neither scheduled on ticks nor recorded in
+//! replays.
+
+use std::time::Duration;
+use bevy::{prelude::*, utils::hashbrown::HashMap};
+use bevy_replicon::{prelude::*, shared::backend::connected_client::NetworkId};
+use serde::{Deserialize, Serialize};
+use crate::prelude::*;
+
+/// Reliable chat between the peers of a match
+#[derive(Default)]
+pub struct LockstepChatPlugin {
+    pub settings: ChatSettings,
+}
+
+impl Plugin for LockstepChatPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(self.settings.clone())
+            .init_resource::<ChatTeams>()
+            .init_resource::<LastChatMessage>()
+            .add_client_trigger::<SendChatMessage>(Channel::Ordered)
+            .add_server_trigger::<ChatMessageReceived>(Channel::Ordered)
+            .add_observer(route_chat_message);
+    }
+}
+
+#[derive(Resource, Debug, Clone)]
+pub struct ChatSettings {
+    /// Longer messages are cut to this many characters
+    pub max_length: usize,
+    /// The server drops messages a client sends sooner than this after its
+    /// previous one
+    pub min_interval: Duration,
+}
+
+impl Default for ChatSettings {
+    fn default() -> Self {
+        Self {
+            max_length: 256,
+            min_interval: Duration::from_millis(250),
+        }
+    }
+}
+
+/// Who a chat message is for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ChatScope {
+    /// Every connected peer, including spectators
+    All,
+    /// Peers on the sender's team in [`ChatTeams`]
+    Team,
+}
+
+/// Trigger this with `client_trigger` on any peer to send a chat message
+#[derive(Event, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SendChatMessage {
+    pub text: String,
+    pub scope: ChatScope,
+}
+
+/// Sent by the server to every recipient of a chat message, including the
+/// sender
+#[derive(Event, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatMessageReceived {
+    pub sender: ClientId,
+    pub scope: ChatScope,
+    pub text: String,
+}
+
+/// The team of each client, used by the server to route
+/// [`ChatScope::Team`] messages.  Set by the game on the server, e.g. on
+/// entering Setup.  Team messages of a client without a team only reach
+/// the sender.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct ChatTeams(HashMap<ClientId, u8>);
+
+impl ChatTeams {
+    pub fn set_team(&mut self, client: ClientId, team: u8) {
+        self.0.insert(client, team);
+    }
+
+    pub fn remove(&mut self, client: ClientId) {
+        self.0.remove(&client);
+    }
+
+    pub fn team(&self, client: ClientId) -> Option<u8> {
+        self.0.get(&client).copied()
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// Server-only time of each client's last accepted message
+#[derive(Resource, Default, Deref, DerefMut)]
+struct LastChatMessage(HashMap<ClientId, Duration>);
+
+fn route_chat_message(
+    trigger: Trigger<FromClient<SendChatMessage>>,
+    mut commands: Commands,
+    clients: LockstepClients,
+    recipients: Query<(Entity, &NetworkId)>,
+    host: Option<Res<HostSeat>>,
+    teams: Res<ChatTeams>,
+    settings: Res<ChatSettings>,
+    mut last_message: ResMut<LastChatMessage>,
+    time: Res<Time<Real>>,
+) {
+    let Some(sender) = clients.client_id(trigger.client_entity) else { return };
+    let message = &trigger.event;
+    let text: String = message.text.trim().chars().take(settings.max_length).collect();
+    if text.is_empty() { return }
+    let now = time.elapsed();
+    if last_message.get(&sender).is_some_and(|&last| now.saturating_sub(last) < settings.min_interval) {
+        debug!("dropping chat message of client {}, sent too soon", sender);
+        return;
+    }
+    last_message.insert(sender, now);
+
+    let event = ChatMessageReceived { sender, scope: message.scope, text };
+    if message.scope == ChatScope::All {
+        commands.server_trigger(ToClients { mode: SendMode::Broadcast, event });
+        return;
+    }
+    let team = teams.team(sender);
+    for (entity, id) in recipients.iter() {
+        let recipient = id.get();
+        let on_team = recipient == sender || team.is_some_and(|team| teams.team(recipient) == Some(team));
+        if !on_team { continue }
+        // The host seat receives through the server's local delivery
+        let entity = if host.as_ref().is_some_and(|host| host.entity == entity) { SERVER } else { entity };
+        commands.server_trigger(ToClients { mode: SendMode::Direct(entity), event: event.clone() });
+    }
+}
@@ -0,0 +1,336 @@
+//! Checkpoints for joining a match late.  Replaying the whole history takes
+//! longer the longer a match runs, so the server periodically asks a
+//! trusted peer for a snapshot of its world after a tick.  A client that
+//! joins, or rejoins without any history, receives the latest checkpoint
+//! through [`SnapshotStreamingPlugin`] and only the ticks after it.
+//!
+//! The game provides the snapshot format with [`CheckpointAppExt`]: a
+//! [`SnapshotProvider`] serializing its simulated world, and a
+//! [`SnapshotApplier`] rebuilding it.  The [`SimulationIdAllocator`] and the
+//! [`ChildSimulationIdAllocator`] are captured and restored by the crate.
+//! A restored client has no commands for the ticks up to the checkpoint,
+//! see [`FirstAvailableTick`], and keeps the checkpoint to serve in their
+//! place should it become the host.
+
+use std::sync::Arc;
+use bevy::prelude::*;
+use bevy_replicon::{
+    postcard::{self, Deserializer, Serializer},
+    prelude::*,
+    shared::{backend::connected_client::NetworkId, postcard_utils::ExtendMutFlavor},
+};
+use serde::{Deserialize, Serialize};
+use crate::{prelude::*, commands::catch_up};
+
+/// Periodic checkpoints on the server, restored by late joiners.  Requires
+/// the [`SnapshotStreamingPlugin`].
+#[derive(Default)]
+pub struct CheckpointPlugin {
+    pub settings: CheckpointSettings,
+}
+
+impl Plugin for CheckpointPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(self.settings.clone())
+            .init_resource::<LatestCheckpoint>()
+            .init_resource::<CheckpointRequest>()
+            .init_resource::<PendingCapture>()
+            .add_server_trigger::<RequestCheckpoint>(Channel::Ordered)
+            .add_client_trigger::<CheckpointUpload>(Channel::Ordered)
+            .add_observer(receive_checkpoint_request)
+            .add_observer(capture_checkpoint)
+            .add_observer(receive_checkpoint_upload)
+            .add_observer(restore_checkpoint)
+            .add_systems(OnEnter(SimulationState::Setup), clear_checkpoints)
+            .add_systems(Update, request_checkpoint
+                .run_if(server_running.and(in_state(SimulationState::Running))));
+    }
+}
+
+#[derive(Resource, Debug, Clone)]
+pub struct CheckpointSettings {
+    /// Ticks between checkpoints
+    pub interval: SimTick,
+    /// The client asked for checkpoints when the server doesn't simulate
+    /// itself.  None picks the seated player with the lowest id.
+    pub trusted_client: Option<ClientId>,
+}
+
+impl Default for CheckpointSettings {
+    fn default() -> Self {
+        Self {
+            interval: 600,
+            trusted_client: None,
+        }
+    }
+}
+
+/// Serializes the simulated world after a tick was applied
+pub trait SnapshotProvider: Send + Sync + 'static {
+    fn snapshot(&self, world: &World, tick: SimTick) -> Vec<u8>;
+}
+
+impl<F: Fn(&World, SimTick) -> Vec<u8> + Send + Sync + 'static> SnapshotProvider for F {
+    fn snapshot(&self, world: &World, tick: SimTick) -> Vec<u8> {
+        self(world, tick)
+    }
+}
+
+/// Rebuilds the simulated world from a [`SnapshotProvider`] snapshot.  It
+/// runs after Setup, once the history after the checkpoint is requested,
+/// and should replace whatever Setup spawned.
+pub trait SnapshotApplier: Send + Sync + 'static {
+    fn apply(&self, world: &mut World, tick: SimTick, data: &[u8]);
+}
+
+impl<F: Fn(&mut World, SimTick, &[u8]) + Send + Sync + 'static> SnapshotApplier for F {
+    fn apply(&self, world: &mut World, tick: SimTick, data: &[u8]) {
+        self(world, tick, data)
+    }
+}
+
+pub trait CheckpointAppExt {
+    fn set_snapshot_provider(&mut self, provider: impl SnapshotProvider) -> &mut Self;
+    fn set_snapshot_applier(&mut self, applier: impl SnapshotApplier) -> &mut Self;
+}
+
+impl CheckpointAppExt for App {
+    fn set_snapshot_provider(&mut self, provider: impl SnapshotProvider) -> &mut Self {
+        self.insert_resource(CheckpointProvider(Arc::new(provider)))
+    }
+
+    fn set_snapshot_applier(&mut self, applier: impl SnapshotApplier) -> &mut Self {
+        self.insert_resource(CheckpointApplier(Arc::new(applier)))
+    }
+}
+
+#[derive(Resource, Clone)]
+struct CheckpointProvider(Arc<dyn SnapshotProvider>);
+
+#[derive(Resource, Clone)]
+struct CheckpointApplier(Arc<dyn SnapshotApplier>);
+
+/// The world after `tick`, as sent to late joiners
+#[derive(Debug, Clone)]
+pub struct MatchCheckpoint {
+    pub tick: SimTick,
+    pub data: Arc<Vec<u8>>,
+}
+
+/// The latest checkpoint of the match on the server, or the one a client
+/// restored
+#[derive(Resource, Default, Debug, Clone, Deref)]
+pub struct LatestCheckpoint(pub Option<MatchCheckpoint>);
+
+/// A trigger that fires on the server when a new checkpoint was taken
+#[derive(Event, Debug, Clone, Copy)]
+pub struct CheckpointStored {
+    pub tick: SimTick,
+    pub source: ClientId,
+    pub bytes: usize,
+}
+
+/// A trigger that fires on a client after it restored a checkpoint
+#[derive(Event, Debug, Clone, Copy)]
+pub struct CheckpointRestored {
+    pub tick: SimTick,
+}
+
+/// Sent by the server to the trusted client: capture the world once `tick`
+/// is applied
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+struct RequestCheckpoint {
+    tick: SimTick,
+}
+
+/// A captured checkpoint sent to the server
+#[derive(Event, Serialize, Deserialize, Debug, Clone)]
+struct CheckpointUpload {
+    tick: SimTick,
+    data: Vec<u8>,
+}
+
+/// The tick a checkpoint was asked for and who was asked, on the server
+#[derive(Resource, Default)]
+struct CheckpointRequest(Option<(SimTick, ClientId)>);
+
+/// The tick the local peer captures a checkpoint after
+#[derive(Resource, Default)]
+struct PendingCapture(Option<SimTick>);
+
+/// Present on a client that resyncs from a checkpoint.  The resync finishes
+/// once it is restored and the history after it arrived.
+#[derive(Resource, Debug, Clone, Copy)]
+pub(crate) struct RestoringCheckpoint {
+    pub(crate) tick: SimTick,
+    pub(crate) restored: bool,
+}
+
+/// The crate state a checkpoint carries along with the game's snapshot
+#[derive(Serialize, Deserialize)]
+struct CheckpointData {
+    next_simulation_id: u32,
+    child_allocations: Vec<(SimulationId, u32)>,
+    game: Vec<u8>,
+}
+
+fn encode_checkpoint(world: &World, game: Vec<u8>) -> postcard::Result<Vec<u8>> {
+    let data = CheckpointData {
+        next_simulation_id: world.resource::<SimulationIdAllocator>().next_id(),
+        child_allocations: world.resource::<ChildSimulationIdAllocator>().allocations(),
+        game,
+    };
+    let mut bytes = Vec::new();
+    data.serialize(&mut Serializer { output: ExtendMutFlavor::new(&mut bytes) })?;
+    Ok(bytes)
+}
+
+fn decode_checkpoint(bytes: &[u8]) -> postcard::Result<CheckpointData> {
+    CheckpointData::deserialize(&mut Deserializer::from_bytes(bytes))
+}
+
+/// Asks for a checkpoint every `CheckpointSettings::interval` ticks, again
+/// if the last request went unanswered for that long
+fn request_checkpoint(
+    mut commands: Commands,
+    mut request: ResMut<CheckpointRequest>,
+    mut capture: ResMut<PendingCapture>,
+    latest: Res<LatestCheckpoint>,
+    sim_tick: Res<SimulationTick>,
+    settings: Res<CheckpointSettings>,
+    layout: Res<SeatLayout>,
+    host: Query<&NetworkId, With<LocalClient>>,
+    clients: Query<(Entity, &NetworkId)>,
+    provider: Option<Res<CheckpointProvider>>,
+) {
+    let last = request.0.map(|(tick, _)| tick)
+        .or(latest.as_ref().map(|checkpoint| checkpoint.tick))
+        .unwrap_or(0);
+    if **sim_tick < last + settings.interval.max(1) { return }
+    let tick = **sim_tick;
+    // A host simulates itself and captures its own checkpoints
+    if let (Ok(host), Some(_)) = (host.get_single(), provider) {
+        capture.0 = Some(tick);
+        request.0 = Some((tick, host.get()));
+        return;
+    }
+    let Some(trusted) = settings.trusted_client.or_else(|| layout.players().min()) else { return };
+    let Some((entity, _)) = clients.iter().find(|(_, id)| id.get() == trusted) else {
+        debug!("trusted client {} for checkpoints is not connected", trusted);
+        return;
+    };
+    debug!("asking client {} for a checkpoint of tick {}", trusted, tick);
+    request.0 = Some((tick, trusted));
+    commands.server_trigger(ToClients {
+        mode: SendMode::Direct(entity),
+        event: RequestCheckpoint { tick },
+    });
+}
+
+fn receive_checkpoint_request(trigger: Trigger<RequestCheckpoint>, mut capture: ResMut<PendingCapture>) {
+    capture.0 = Some(trigger.tick);
+}
+
+fn capture_checkpoint(
+    trigger: Trigger<GameCommandsApplied>,
+    world: &World,
+    mut commands: Commands,
+) {
+    let tick = trigger.event().0;
+    if world.resource::<PendingCapture>().0.is_none_or(|requested| tick < requested) { return }
+    let Some(provider) = world.get_resource::<CheckpointProvider>() else {
+        warn!("a checkpoint was requested without a snapshot provider");
+        return;
+    };
+    let data = match encode_checkpoint(world, provider.0.snapshot(world, tick)) {
+        Ok(data) => data,
+        Err(err) => {
+            error!("Failed to encode the checkpoint of tick {}: {}", tick, err);
+            return;
+        }
+    };
+    commands.queue(move |world: &mut World| {
+        world.resource_mut::<PendingCapture>().0 = None;
+        if world.resource::<RepliconServer>().is_running() {
+            let Some(source) = world.resource::<CheckpointRequest>().0.map(|(_, source)| source) else { return };
+            store_checkpoint(world, tick, source, data);
+        } else {
+            trace!("uploading the checkpoint of tick {}", tick);
+            world.commands().client_trigger(CheckpointUpload { tick, data });
+        }
+    });
+}
+
+fn receive_checkpoint_upload(
+    trigger: Trigger<FromClient<CheckpointUpload>>,
+    mut commands: Commands,
+    clients: LockstepClients,
+    request: Res<CheckpointRequest>,
+) {
+    let Some(client) = clients.client_id(trigger.client_entity) else { return };
+    let Some((tick, source)) = request.0 else { return };
+    let upload = &trigger.event;
+    if client != source || upload.tick < tick {
+        warn!("ignoring checkpoint of tick {} from client {}", upload.tick, client);
+        return;
+    }
+    let (tick, data) = (upload.tick, upload.data.clone());
+    commands.queue(move |world: &mut World| store_checkpoint(world, tick, source, data));
+}
+
+fn store_checkpoint(world: &mut World, tick: SimTick, source: ClientId, data: Vec<u8>) {
+    info!("Stored checkpoint of tick {} from client {}, {} bytes", tick, source, data.len());
+    let bytes = data.len();
+    world.resource_mut::<CheckpointRequest>().0 = None;
+    world.resource_mut::<LatestCheckpoint>().0 = Some(MatchCheckpoint { tick, data: Arc::new(data) });
+    world.trigger(CheckpointStored { tick, source, bytes });
+}
+
+fn restore_checkpoint(
+    trigger: Trigger<SnapshotReceived>,
+    mut commands: Commands,
+    restoring: Option<ResMut<RestoringCheckpoint>>,
+) {
+    let Some(mut restoring) = restoring else { return };
+    let received = trigger.event();
+    if restoring.restored || received.tick != restoring.tick { return }
+    restoring.restored = true;
+    let (tick, data) = (received.tick, received.data.clone());
+    commands.queue(move |world: &mut World| {
+        let checkpoint = match decode_checkpoint(&data) {
+            Ok(checkpoint) => checkpoint,
+            Err(err) => {
+                error!("Failed to decode the checkpoint of tick {}: {}", tick, err);
+                return;
+            }
+        };
+        let Some(applier) = world.get_resource::<CheckpointApplier>().cloned() else {
+            error!("Received a checkpoint without a snapshot applier");
+            return;
+        };
+        info!("Restoring checkpoint of tick {}", tick);
+        if world.resource_mut::<SimulationIdAllocator>().set_next_id(checkpoint.next_simulation_id).is_err() {
+            warn!("checkpoint of tick {} has no valid simulation id counter", tick);
+        }
+        world.resource_mut::<ChildSimulationIdAllocator>().restore(checkpoint.child_allocations);
+        applier.0.apply(world, tick, &checkpoint.game);
+        **world.resource_mut::<LastAppliedTick>() = tick;
+        **world.resource_mut::<FirstAvailableTick>() = tick + 1;
+        world.resource_mut::<LatestCheckpoint>().0 = Some(MatchCheckpoint { tick, data });
+        world.trigger(CheckpointRestored { tick });
+        catch_up::finish_checkpoint_resync(world);
+    });
+}
+
+fn clear_checkpoints(
+    mut commands: Commands,
+    mut latest: ResMut<LatestCheckpoint>,
+    mut request: ResMut<CheckpointRequest>,
+    mut capture: ResMut<PendingCapture>,
+) {
+    latest.0 = None;
+    request.0 = None;
+    capture.0 = None;
+    commands.remove_resource::<RestoringCheckpoint>();
+}
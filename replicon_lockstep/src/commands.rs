@@ -1,39 +1,194 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, time::Duration};
 
-use bevy::prelude::*;
-use bevy_replicon::{prelude::*, shared::backend::connected_client::NetworkId};
-use crate::prelude::*;
+use bevy::{ecs::system::SystemParam, prelude::*, utils::hashbrown::HashMap};
+use bevy_replicon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::{prelude::*, replay, lockstep_core::{TickBuffer, schedule}, simulation::{HeldCommands, LastBroadcastTick, rtt_to_ticks}};
 
-mod serialization;
+pub(crate) mod serialization;
+mod sanitization;
+mod registry;
+pub(crate) mod codec;
+mod versions;
+mod streams;
+mod queue;
+mod issue;
+mod game_command;
+mod parallel;
+mod physics;
+mod typed;
+mod diagnostics;
+mod ticks_behind;
+mod registry_check;
+mod injection;
+mod resend;
+mod input_delay;
+mod input;
+#[cfg(feature = "strict-determinism")]
+mod frozen;
+pub(crate) mod catch_up;
 
-pub(crate) struct LockstepCommandsPlugin;
+pub use sanitization::{FloatSanitization, NonFinitePolicy};
+pub use streams::{CommandStreamId, GAMEPLAY_STREAM, StreamDelay, CommandStreamSettings, LockstepStreamBuffers};
+pub use queue::LockstepCommandQueue;
+pub use issue::{LockstepCommands, PendingOrderId, PendingOrder, PendingOrders, OrderConfirmed};
+pub use catch_up::{ReflectCatchUpCommand, RequestCatchUp, CatchUpHistory, CatchUpProgress, ResyncStarted, ResyncFinished, ClientResynced, DroppedClients};
+pub use injection::{InjectedInputSource, InjectedInputs, InjectedInputAppExt, ScriptedInput};
+pub use diagnostics::{LockstepDiagnostics, CommandLatency, LockstepNetworkDiagnostics, ClientNetworkStats, LockstepBandwidth, CommandBandwidth};
+pub use typed::{LockstepCommand, ReflectCommandTrigger, LockstepCommandHandlers, LockstepCommandItems, ReflectCommandHandler};
+pub use ticks_behind::{CatchUpPolicy, TicksBehind};
+pub use input_delay::{InputDelayAdjustment, SetInputDelay, ServerSetInputDelay, ClientInputDelay, InputDelays};
+pub use input::{InputDevice, DeadZone, InputDeadZones, QuantizedAxis, QuantizedStick, ButtonBits};
+pub use registry_check::{CommandRegistryVerified, CommandRegistryMismatch};
+pub use versions::{UpgradeCommand, ReflectUpgradeCommand, CommandVersions};
+pub use codec::TickCompression;
+pub use physics::{BeforePhysicsStep, PhysicsStep, AfterPhysicsStep};
+#[cfg(feature = "avian")]
+pub use physics::LockstepAvianPlugin;
+pub(crate) use resend::{send_client_commands, UnackedCommands, ClientSequences};
+pub use game_command::{GameCommand, ReflectGameCommand, LastAppliedTick, GameCommandsApplied};
+pub(crate) use game_command::apply_tick;
+pub use parallel::{ParallelGameCommand, ReflectParallelGameCommand};
+pub use registry::{LockstepCommandAppExt, LockstepCommandId, ReflectLockstepCommand, lockstep_command_id};
+
+/// Sending, scheduling and broadcasting of client commands
+pub struct LockstepCommandsPlugin;
 
 impl Plugin for LockstepCommandsPlugin {
     fn build(&self, app: &mut App) {
         app
             .init_resource::<LockstepGameCommandBuffer>()
+            .init_resource::<FirstAvailableTick>()
             .init_resource::<LockstepGameCommandsReceived>()
+            .init_resource::<ClientExecutionSchedule>()
+            .init_resource::<LockstepStreamBuffers>()
+            .init_resource::<TickDigests>()
+            .init_resource::<LockstepCommandQueue>()
+            .init_resource::<PendingOrders>()
+            .add_observer(issue::confirm_pending_orders)
+            .init_resource::<LastAppliedTick>()
+            .init_resource::<LockstepDiagnostics>()
+            .init_resource::<LockstepNetworkDiagnostics>()
+            .init_resource::<InputDeadZones>()
+            .register_type::<QuantizedAxis>()
+            .register_type::<QuantizedStick>()
+            .register_type::<ButtonBits>()
+            .add_server_trigger::<diagnostics::NetworkDiagnosticsReport>(Channel::Unreliable)
+            .add_observer(diagnostics::receive_network_diagnostics)
+            .init_resource::<LockstepBandwidth>()
+            .add_observer(diagnostics::measure_received_tick)
+            .add_systems(FixedPostUpdate, (
+                diagnostics::update_network_diagnostics_server.run_if(server_running),
+                diagnostics::update_network_diagnostics_client.run_if(not(server_running)),
+            ).run_if(in_state(SimulationState::Running).or(in_state(SimulationState::Paused))))
+            .init_resource::<InjectedInputs>()
             .add_server_trigger_with::<ServerSendCommands>(
                 Channel::Ordered, 
                 serialization::serialize_server_send_commands,
                 serialization::deserialize_server_send_commands,
             )
+            .add_server_trigger_with::<CatchUpHistory>(
+                Channel::Ordered,
+                serialization::serialize_catch_up_history,
+                serialization::deserialize_catch_up_history,
+            )
+            .add_client_trigger::<RequestCatchUp>(Channel::Ordered)
+            .init_resource::<catch_up::PendingCatchUp>()
+            .add_observer(catch_up::send_catch_up_history)
+            .add_observer(catch_up::receive_catch_up_history)
+            .init_resource::<catch_up::PendingResync>()
+            .init_resource::<DroppedClients>()
+            .add_server_trigger::<ResyncStarted>(Channel::Ordered)
+            .add_client_trigger::<ResyncFinished>(Channel::Ordered)
+            .add_observer(catch_up::receive_resync_started)
+            .add_observer(catch_up::record_dropped_client)
+            .add_observer(catch_up::receive_resync_finished)
+            .add_client_trigger_with::<catch_up::MigrationHistory>(
+                Channel::Ordered,
+                serialization::serialize_migration_history,
+                serialization::deserialize_migration_history,
+            )
+            .add_observer(catch_up::receive_migration_history)
+            .add_systems(FixedPreUpdate, catch_up::request_resync
+                .run_if(in_state(SimulationState::Reconnecting).and(client_connected)))
             .add_client_trigger_with::<ClientSendCommands>(
                 Channel::Ordered, 
                 serialization::serialize_client_send_commands,
                 serialization::deserialize_client_send_commands,
             )
+            .add_server_trigger::<CommandsRescheduled>(Channel::Unordered)
+            .add_server_trigger::<CommandsDeferred>(Channel::Unordered)
+            .add_server_trigger::<CommandsScheduled>(Channel::Unreliable)
+            .init_resource::<UnackedCommands>()
+            .init_resource::<ClientSequences>()
+            .add_client_trigger_with::<resend::ResentCommands>(
+                Channel::Unreliable,
+                serialization::serialize_resent_commands,
+                serialization::deserialize_resent_commands,
+            )
+            .add_server_trigger::<resend::CommandsAcked>(Channel::Unreliable)
+            .add_observer(resend::receive_resent_commands)
+            .add_observer(resend::receive_commands_acked)
+            .add_systems(PostUpdate, resend::send_commands_acked
+                .run_if(server_running)
+                .before(ServerSet::Send))
+            .add_client_trigger::<registry_check::CommandRegistryReport>(Channel::Ordered)
+            .add_server_trigger::<CommandRegistryMismatch>(Channel::Ordered)
+            .add_observer(registry_check::receive_command_registry)
+            .add_systems(OnEnter(SimulationState::Setup), registry_check::send_command_registry
+                .run_if(server_running.or(client_connected)))
+            .init_resource::<CommandVersions>()
+            .add_server_trigger::<versions::SessionCommandVersions>(Channel::Ordered)
+            .add_observer(versions::receive_command_versions)
+            .register_type::<codec::CommandWireCodec>()
+            .init_resource::<TickCompression>()
+            .add_systems(OnEnter(SimulationState::Setup), (versions::reset_command_versions, codec::assign_compact_command_ids).chain())
+            .add_systems(OnEnter(SimulationState::Setup), injection::reset_injected_inputs)
             .add_observer(receive_commands_server)
             .add_observer(send_empty_commands_to_server_on_tick)
-            .add_systems(OnEnter(SimulationState::Running), send_initial_commands_to_server);
+            .add_systems(OnEnter(SimulationState::Running), send_initial_commands_to_server)
+            .init_resource::<TicksBehind>()
+            .init_resource::<InputDelays>()
+            .add_server_trigger::<ServerSetInputDelay>(Channel::Ordered)
+            .add_observer(input_delay::set_input_delay)
+            .add_observer(input_delay::receive_input_delay)
+            .add_systems(Update, input_delay::adjust_input_delays
+                .run_if(server_running.and(in_state(SimulationState::Running))))
+            .init_schedule(typed::LockstepCommandHandlers)
+            .init_schedule(physics::BeforePhysicsStep)
+            .init_schedule(physics::PhysicsStep)
+            .init_schedule(physics::AfterPhysicsStep)
+            // Freeze locally as soon as the connection drops, before the
+            // state changes to Reconnecting
+            .add_systems(Update, (
+                game_command::apply_game_commands
+                    .run_if(server_running.or(client_connected).or(replay::playing_back)),
+                ticks_behind::update_ticks_behind,
+            ).chain().run_if(in_state(SimulationState::Running)))
+            .add_systems(OnExit(SimulationState::Running), ticks_behind::reset_time_dilation)
+            .add_systems(PostUpdate, (
+                injection::poll_injected_inputs,
+                queue::drain_command_queue,
+                resend::resend_unacked_commands.run_if(client_connected.and(not(server_running))),
+            ).chain()
+                .run_if(in_state(SimulationState::Running))
+                .before(ClientSet::Send));
     }
 }
 
-/// An event type for clients to send their commands for their current tick to the server
+/// An event type for clients to send their commands for their current tick to the server.
+/// Each trigger is a separate network message, prefer [`LockstepCommands`]
+/// or [`LockstepCommandQueue`] to coalesce commands issued from several
+/// systems.
 #[derive(Event, Default, TypePath)]
 pub struct ClientSendCommands {
     pub issued_tick: SimTick,
     pub commands: Vec<Box<dyn PartialReflect>>,
+    /// The command stream these commands belong to
+    pub stream: CommandStreamId,
+    /// Identifies the message for acknowledgement and resending, assigned
+    /// when the crate sends it.  Zero messages are never resent.
+    pub sequence: u32,
 }
 
 impl Clone for ClientSendCommands {
@@ -41,6 +196,8 @@ impl Clone for ClientSendCommands {
         Self {
             issued_tick: self.issued_tick.clone(),
             commands: self.commands.iter().map(|x| x.clone_value()).collect(),
+            stream: self.stream,
+            sequence: self.sequence,
         }
     }
 }
@@ -48,10 +205,19 @@ impl Clone for ClientSendCommands {
 /// An event type for the server to broadcast client commands with delayed tick
 #[derive(Event, Default)]
 pub(crate) struct ServerSendCommands {
+    pub(crate) session: SessionHash,
     pub(crate) tick: SimTick,
+    /// Hash of `commands`, see [`TickDigests`]
+    pub(crate) digest: u64,
     pub(crate) commands: LockstepClientCommands,
+    /// Commands from additional command streams
+    pub(crate) streams: BTreeMap<CommandStreamId, LockstepClientCommands>,
 }
 
+/// Commands issued by the server itself are stored under this id.
+/// It sorts before all clients, so server commands apply first on a tick.
+pub const SERVER_CLIENT_ID: ClientId = 0;
+
 /// A type for storing per-client commands for one tick, sorted by ClientId for determinism
 #[derive(Default, Deref, DerefMut)]
 pub struct LockstepClientCommands(BTreeMap<ClientId, Vec<Box<dyn PartialReflect>>>);
@@ -93,9 +259,162 @@ impl LockstepGameCommandsReceived {
 #[derive(Resource, Default, Deref, DerefMut)]
 pub struct LockstepGameCommandBuffer(Vec<LockstepClientCommands>);
 
+/// The first tick whose commands are in the [`LockstepGameCommandBuffer`].
+/// A client that restored a checkpoint only has the ticks after it, the
+/// default ticks before are placeholders that are never served to other
+/// peers or recorded.
+#[derive(Resource, Default, Debug, Clone, Copy, Deref, DerefMut)]
+pub struct FirstAvailableTick(pub SimTick);
+
 impl LockstepGameCommandBuffer {
     pub fn get(&self, tick: SimTick) -> Option<&LockstepClientCommands> { self.0.get(tick as usize) }
     pub fn resize(&mut self, size: u32, value: LockstepClientCommands ) { self.0.resize(size as usize, value) }
+
+    /// Schedule a command issued by the server for execution on the given tick.
+    /// Only meaningful on the server, and only for ticks not yet broadcast.
+    pub fn insert_server_command(&mut self, tick: SimTick, command: Box<dyn PartialReflect>) {
+        if tick >= self.0.len() as u32 {
+            self.resize(tick + 1, LockstepClientCommands::default());
+        }
+        self.0[tick as usize].entry(SERVER_CLIENT_ID).or_default().push(command);
+    }
+
+    /// Schedule a client's commands on the given tick, after any commands it
+    /// already has there.  With `max_per_tick`, commands past the cap spill
+    /// over into the following ticks in order.  Only the server schedules,
+    /// so every peer executes the same split.  Returns the last tick used.
+    pub(crate) fn schedule_client_commands(
+        &mut self,
+        tick: SimTick,
+        client_id: ClientId,
+        commands: Vec<Box<dyn PartialReflect>>,
+        max_per_tick: Option<usize>,
+    ) -> SimTick {
+        let max_per_tick = max_per_tick.unwrap_or(usize::MAX).max(1);
+        let mut commands = commands.into_iter().peekable();
+        let mut tick = tick;
+        loop {
+            if tick >= self.0.len() as u32 {
+                self.resize(tick + 1, LockstepClientCommands::default());
+            }
+            let scheduled = self.0[tick as usize].entry(client_id).or_default();
+            let room = max_per_tick.saturating_sub(scheduled.len());
+            scheduled.extend(commands.by_ref().take(room));
+            if commands.peek().is_none() { return tick }
+            tick += 1;
+        }
+    }
+}
+
+/// The most recent execution tick the server assigned to a client's commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScheduledExecution {
+    /// The tick the client issued the commands on
+    pub issued_tick: SimTick,
+    /// The tick the commands were scheduled to execute on
+    pub execution_tick: SimTick,
+    /// Total input delay in ticks applied to the commands
+    pub delay: SimTick,
+}
+
+/// Server-only resource tracking how far in the future each client's
+/// inputs are being scheduled.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct ClientExecutionSchedule(HashMap<ClientId, ScheduledExecution>);
+
+/// A trigger that fires on the server when a client's input delay changes by at
+/// least `SimulationSettings::input_delay_jump_threshold` ticks.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct InputDelayJump {
+    pub client: ClientId,
+    pub previous_delay: SimTick,
+    pub new_delay: SimTick,
+}
+
+/// Sent by the server to a client in strict scheduling mode when the client's
+/// commands would have executed on a tick that was already broadcast, and
+/// were moved to the next tick instead.
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct CommandsRescheduled {
+    pub issued_tick: SimTick,
+    pub requested_tick: SimTick,
+    pub execution_tick: SimTick,
+}
+
+/// Sent unreliably by the server to a client as soon as its commands were
+/// scheduled, ahead of the ordered tick broadcast that carries them.  Use it
+/// for order confirmation markers, e.g. RTS move pings, shown until the
+/// commands execute.  Acks can be lost, the commands still execute.
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct CommandsScheduled {
+    pub issued_tick: SimTick,
+    pub stream: CommandStreamId,
+    pub execution_tick: SimTick,
+    /// Number of commands scheduled, after validation
+    pub count: u16,
+}
+
+impl CommandsScheduled {
+    /// The predicted time until the commands execute, given the local
+    /// simulation tick
+    pub fn time_until_execution(&self, current_tick: SimTick, tick_timestep: Duration) -> Duration {
+        tick_timestep * self.execution_tick.saturating_sub(current_tick)
+    }
+}
+
+/// Sent by the server to a client whose commands exceeded
+/// `SimulationSettings::max_commands_per_tick`.  The commands were spread
+/// over `execution_tick..=last_tick`, keeping their order.
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct CommandsDeferred {
+    pub issued_tick: SimTick,
+    pub execution_tick: SimTick,
+    pub last_tick: SimTick,
+    /// Number of commands moved past `execution_tick`
+    pub deferred: u32,
+}
+
+/// What the server does with gameplay commands past
+/// `SimulationSettings::max_commands_per_tick`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CommandLimitPolicy {
+    /// Spread them over the following ticks, see [`CommandsDeferred`]
+    #[default]
+    Defer,
+    /// Drop the commands past the cap, keeping the first ones
+    Truncate,
+    /// Drop the whole message
+    Reject,
+}
+
+/// A trigger that fires on the server when a client sent more gameplay
+/// commands than fit on their tick, with [`CommandLimitPolicy::Truncate`]
+/// or [`CommandLimitPolicy::Reject`]
+#[derive(Event, Debug, Clone, Copy)]
+pub struct CommandRateLimitExceeded {
+    pub client: ClientId,
+    pub issued_tick: SimTick,
+    /// Commands in the message
+    pub sent: u32,
+    /// Commands that were scheduled
+    pub allowed: u32,
+}
+
+/// Digests of the gameplay commands of each tick, stored on clients as ticks
+/// arrive.  The server computes the digest before broadcasting and the client
+/// recomputes it after deserializing, so a stored digest means the tick was
+/// received intact.  Reconnect and resync flows can compare digests instead
+/// of downloading ticks again.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct TickDigests(TickBuffer<Option<u64>>);
+
+/// A trigger that fires on a client when a received tick doesn't match the
+/// digest computed by the server
+#[derive(Event, Debug, Clone, Copy)]
+pub struct TickDigestMismatch {
+    pub tick: SimTick,
+    pub expected: u64,
+    pub computed: u64,
 }
 
 /// The server ticks only if it gets commands from all clients,
@@ -104,9 +423,12 @@ impl LockstepGameCommandBuffer {
 /// just to get the party started
 fn send_initial_commands_to_server(
     mut commands: Commands,
+    local_client: Query<&LocalClient>,
 ) {
+    // A dedicated server has no seat to send commands from
+    if local_client.is_empty() { return }
     trace!("Sending intitial commands");
-    commands.client_trigger(ClientSendCommands::default());
+    send_client_commands(&mut commands, ClientSendCommands::default());
 }
 
 /// Commands won't be sent for every player on every tick.
@@ -117,17 +439,41 @@ fn send_empty_commands_to_server_on_tick(
     mut commands: Commands,
     sim_tick: Res<SimulationTick>,
     local_client: Query<&LocalClient>,
+    settings: Res<SimulationSettings>,
+    server: Res<RepliconServer>,
 ) {
     // Dont send commands if in dedicated server mode
     if local_client.get_single().is_err() { return }
+    // The server infers empty commands of remote clients from liveness
+    // heartbeats instead.  The host's own seat never leaves the process, so
+    // it keeps sending them and is never waited on.
+    if settings.empty_command_suppression.is_some() && !server.is_running() { return }
 
     trace!("tick changed to {}, sending empty commands", **sim_tick);
-    commands.client_trigger(ClientSendCommands {
+    send_client_commands(&mut commands, ClientSendCommands {
         issued_tick: tick.tick,
         ..default()
     });
 }
 
+#[derive(SystemParam)]
+struct ReceiveDiagnostics<'w> {
+    latency: ResMut<'w, LockstepDiagnostics>,
+    network: ResMut<'w, LockstepNetworkDiagnostics>,
+}
+
+/// Server state that decides whether received commands are scheduled at all
+#[derive(SystemParam)]
+struct SchedulingGates<'w, 's> {
+    state: Res<'w, State<SimulationState>>,
+    spectators: Res<'w, LockstepSpectators>,
+    versions: Res<'w, CommandVersions>,
+    final_tick: Res<'w, FinalTick>,
+    roles: Query<'w, 's, &'static ClientRole>,
+    input_delays: Res<'w, InputDelays>,
+    last_broadcast: Res<'w, LastBroadcastTick>,
+}
+
 /// When the server receives commmands from a client it should
 ///  store the commands in the command history
 fn receive_commands_server(
@@ -135,16 +481,28 @@ fn receive_commands_server(
     mut received: ResMut<LockstepGameCommandsReceived>,
     mut history: ResMut<LockstepGameCommandBuffer>,
     current_tick: Res<SimulationTick>,
-    clients: Query<&NetworkId>,
+    clients: LockstepClients,
     settings: Res<SimulationSettings>,
     stats: Query<&NetworkStats>,
+    mut schedule: ResMut<ClientExecutionSchedule>,
+    mut sequences: ResMut<ClientSequences>,
+    mut commands: Commands,
+    mut held: ResMut<HeldCommands>,
+    mut stream_buffers: ResMut<LockstepStreamBuffers>,
+    registry: Res<AppTypeRegistry>,
+    mut diagnostics: ReceiveDiagnostics,
+    correlation: Res<MatchCorrelation>,
+    gates: SchedulingGates,
 ) { 
-    // In host server mode, the server can send events to itself
-    // Server sent events use Entity::PLACEHOLDER
-    // Instead I have set Host to have its own entity which has NetworkId=1
-    let client_id: u64 = clients.get(trigger.client_entity).map_or(1, |id: &NetworkId| id.get());
+    let _span = correlation.span().entered();
+    // A dedicated server has no seat to send commands from
+    let Some(client_id) = clients.client_id(trigger.client_entity) else { return };
+    // The original and a resent copy can both arrive, only handle the first
+    if !sequences.receive(client_id, trigger.client_entity, trigger.event().sequence) {
+        trace!("dropping duplicate commands from client {}", client_id);
+        return;
+    }
     let client_commands: &Vec<Box<dyn PartialReflect>> = &trigger.event().commands;
-    let num_commands = client_commands.iter().len();
     trace!("server received commands from client {} issued on client tick {}", client_id, trigger.event().issued_tick);
 
     // Track received commands always, even when empty, for managing connections
@@ -154,19 +512,145 @@ fn receive_commands_server(
     }
     received[tick as usize].insert(client_id,
         client_commands.iter().map(|x| x.clone_value()).collect());
+    diagnostics.network.record_received(client_id, tick);
+
+    // Spectators no longer play, drop whatever they send
+    if gates.spectators.contains_key(&client_id) { return }
+    if gates.roles.get(trigger.client_entity).is_ok_and(|&role| role == ClientRole::Spectator) { return }
+    // Commands can't be scheduled past the final tick of an ending match
+    if gates.final_tick.is_some() {
+        if !client_commands.is_empty() {
+            debug!("dropping commands from client {}, the match is ending", client_id);
+        }
+        return;
+    }
 
     // But only send valid commands back to clients
-    if num_commands > 0 {
+    let mut client_commands: Vec<Box<dyn PartialReflect>> =
+        client_commands.iter().map(|x| x.clone_value()).collect();
+    if let Some(sanitization) = &settings.command_sanitization {
+        let rejected = sanitization::sanitize_commands(&mut client_commands, sanitization);
+        if rejected > 0 {
+            warn!("rejected {} command(s) from client {} with non-finite values", rejected, client_id);
+        }
+    }
+    let rejected = versions::upgrade_commands(&mut client_commands, &gates.versions, &registry.read());
+    if rejected > 0 {
+        warn!("rejected {} command(s) from client {} without a version every peer has", rejected, client_id);
+    }
+    let rejected = game_command::validate_commands(&mut client_commands, tick, client_id, &registry.read());
+    if rejected > 0 {
+        warn!("rejected {} invalid command(s) from client {}", rejected, client_id);
+    }
+    let stream = trigger.event().stream;
+    if stream != GAMEPLAY_STREAM {
+        let Some(stream_settings) = settings.stream_settings(stream) else {
+            warn!("client {} sent commands for unknown stream {}", client_id, stream);
+            return;
+        };
+        if client_commands.is_empty() { return }
+        let delay = match stream_settings.delay {
+            StreamDelay::Fixed(delay) => delay.max(1),
+            StreamDelay::Standard => schedule::input_delay(
+                stats
+                    .get(trigger.client_entity)
+                    .map_or(1, |s: &NetworkStats| rtt_to_ticks(s.rtt, &settings))
+                    .max(1),
+                settings.base_input_tick_delay as SimTick,
+            ),
+        };
+        let execution_tick = schedule::execution_tick(**current_tick, delay);
+        trace!("storing {} stream commands for tick {} for client {}", stream_settings.name, execution_tick, client_id);
+        commands.server_trigger(ToClients {
+            mode: SendMode::Direct(trigger.client_entity),
+            event: CommandsScheduled {
+                issued_tick: tick,
+                stream,
+                execution_tick,
+                count: client_commands.len() as u16,
+            },
+        });
+        stream_buffers.insert(stream, execution_tick, client_id, client_commands);
+        return;
+    }
+    if !client_commands.is_empty() && *gates.state.get() == SimulationState::Paused {
+        trace!("holding commands from client {} until resume", client_id);
+        held.push((client_id, client_commands));
+    } else if !client_commands.is_empty() {
         // Input tick delay depends on ping, for host server default to 1 tick for now
-        let tick_delay: u32 = stats
-            .get(trigger.client_entity)
-            .map_or(1, |s: &NetworkStats| ((s.rtt / 2.0) / settings.tick_timestep.as_secs_f64()).ceil() as SimTick);
-        let execution_tick = **current_tick + tick_delay + settings.base_input_tick_delay as SimTick;
+        let mut delay = gates.input_delays.delay_at(client_id, **current_tick).map_or_else(|| {
+            let latency_ticks = stats
+                .get(trigger.client_entity)
+                .map_or(1, |s: &NetworkStats| rtt_to_ticks(s.rtt, &settings));
+            schedule::input_delay(latency_ticks, settings.base_input_tick_delay as SimTick)
+        }, |delay| delay.max(1));
+        let mut execution_tick = schedule::execution_tick(**current_tick, delay);
+        // The client expects its commands on the tick it issued them plus
+        // its delay, which a shrinking delay or a lagging client can put on
+        // a tick whose history is already final
+        let requested_tick = schedule::execution_tick(tick, delay);
+        let last_broadcast = **gates.last_broadcast;
+        if settings.strict_scheduling && !schedule::is_schedulable(requested_tick, last_broadcast) {
+            execution_tick = last_broadcast + 1;
+            delay = execution_tick.saturating_sub(**current_tick).max(1);
+            trace!("rescheduling commands from client {} to tick {}", client_id, execution_tick);
+            commands.server_trigger(ToClients {
+                mode: SendMode::Direct(trigger.client_entity),
+                event: CommandsRescheduled {
+                    issued_tick: tick,
+                    requested_tick,
+                    execution_tick,
+                },
+            });
+        }
         trace!("storing commands for execution tick {} for client {}", execution_tick, client_id);
-        if execution_tick >= history.len() as u32 {
-            history.resize(execution_tick + 1, LockstepClientCommands::default());
+        diagnostics.latency.record_latency(client_id, tick, execution_tick);
+        let previous = schedule.insert(client_id, ScheduledExecution { issued_tick: tick, execution_tick, delay });
+        if let Some(previous) = previous {
+            if previous.delay.abs_diff(delay) >= settings.input_delay_jump_threshold {
+                commands.trigger(InputDelayJump { client: client_id, previous_delay: previous.delay, new_delay: delay });
+            }
+        }
+        // Commands already scheduled on the tick, e.g. deferred ones, count
+        // towards the cap
+        let queued = history.get(execution_tick).and_then(|tick| tick.get(&client_id)).map_or(0, Vec::len);
+        let room = settings.max_commands_per_tick.map_or(usize::MAX, |max| max.max(1).saturating_sub(queued));
+        let mut deferred = client_commands.len().saturating_sub(room);
+        if deferred > 0 && settings.command_limit_policy != CommandLimitPolicy::Defer {
+            let allowed = if settings.command_limit_policy == CommandLimitPolicy::Truncate { room } else { 0 };
+            warn!("client {} sent {} command(s) for tick {} with room for {}, keeping {}",
+                client_id, client_commands.len(), execution_tick, room, allowed);
+            commands.trigger(CommandRateLimitExceeded {
+                client: client_id,
+                issued_tick: tick,
+                sent: client_commands.len() as u32,
+                allowed: allowed as u32,
+            });
+            client_commands.truncate(allowed);
+            deferred = 0;
+            if client_commands.is_empty() { return }
+        }
+        commands.server_trigger(ToClients {
+            mode: SendMode::Direct(trigger.client_entity),
+            event: CommandsScheduled {
+                issued_tick: tick,
+                stream,
+                execution_tick,
+                count: client_commands.len() as u16,
+            },
+        });
+        let last_tick = history.schedule_client_commands(execution_tick, client_id, client_commands, settings.max_commands_per_tick);
+        if deferred > 0 {
+            debug!("deferred {} command(s) from client {} up to tick {}", deferred, client_id, last_tick);
+            commands.server_trigger(ToClients {
+                mode: SendMode::Direct(trigger.client_entity),
+                event: CommandsDeferred {
+                    issued_tick: tick,
+                    execution_tick,
+                    last_tick,
+                    deferred: deferred as u32,
+                },
+            });
         }
-        history[execution_tick as usize].insert(client_id,
-            client_commands.iter().map(|x| x.clone_value()).collect());
     }
 }
\ No newline at end of file
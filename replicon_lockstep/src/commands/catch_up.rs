@@ -0,0 +1,466 @@
+use std::any::TypeId;
+use bevy::{prelude::*, reflect::TypeRegistry, utils::hashbrown::HashMap};
+use bevy_replicon::{prelude::*, shared::backend::connected_client::NetworkId};
+use serde::{Deserialize, Serialize};
+use crate::{prelude::*, checkpoint::RestoringCheckpoint, connections::PendingHostMigration, simulation::{LastBroadcastTick, SettingsTimeline}};
+use super::ServerSendCommands;
+
+/// Ticks of history per catch-up message
+pub(super) const CATCH_UP_CHUNK_TICKS: SimTick = 128;
+
+/// Type data marking commands required to catch up, see
+/// [`LockstepCommandAppExt::register_catch_up_command`]
+#[derive(Clone, Copy, Debug)]
+pub struct ReflectCatchUpCommand;
+
+/// Sent by a client that joined or rejoined a running match and is missing
+/// the history before its first received tick
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct RequestCatchUp {
+    pub from_tick: SimTick,
+    pub to_tick: SimTick,
+    /// Send every command instead of only the catch-up commands.  Used by
+    /// reconnecting clients, which fast-forward their simulation through
+    /// the history.
+    pub full: bool,
+}
+
+/// Sent by the server to a reconnected client before its missed history
+#[derive(Event, Serialize, Deserialize, Debug, Clone)]
+pub struct ResyncStarted {
+    /// The first tick of the history being sent
+    pub from_tick: SimTick,
+    /// The last tick of the history being sent, before `from_tick` if the
+    /// client missed nothing
+    pub to_tick: SimTick,
+    /// The state the client enters once it received the history
+    pub state: SimulationState,
+    /// The checkpoint sent as a snapshot ahead of the history, which then
+    /// starts right after it
+    pub checkpoint: Option<SimTick>,
+    /// The value of every setting that can change mid-match when the match
+    /// started
+    pub initial_settings: Vec<SettingsChange>,
+    /// Every passed settings change with its effective tick, including the
+    /// ones not effective yet.  The client simulates each tick with the
+    /// settings of that tick and adopts the changes up to `to_tick` right
+    /// away.
+    pub settings_changes: Vec<(SimTick, SettingsChange)>,
+}
+
+/// Sent by a reconnected client once it received all missed history.  The
+/// server triggers [`ClientResynced`].
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ResyncFinished {
+    pub tick: SimTick,
+}
+
+/// A trigger that fires on the server when a reconnected client is back in
+/// sync.  If `ConnectionSettings::resume_after_resync` is set and no other
+/// client is missing, a paused simulation resumes.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct ClientResynced {
+    pub client: ClientId,
+    /// The tick the client was last heard from before it dropped, if the
+    /// server noticed the drop
+    pub dropped_at: Option<SimTick>,
+}
+
+/// Server-only record of clients that dropped mid-match and the tick they
+/// dropped at.  The server keeps the whole command history of the match,
+/// so a dropped client can resync from any tick.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct DroppedClients(HashMap<ClientId, SimTick>);
+
+/// The state a reconnecting client returns to once its history is complete
+#[derive(Resource, Default)]
+pub(crate) struct PendingResync(Option<SimulationState>);
+
+/// A chunk of command history sent by the server to a catching up client.
+/// If any command types were registered as catch-up commands, only those are
+/// included.
+#[derive(Event, Default)]
+pub struct CatchUpHistory {
+    pub(crate) from_tick: SimTick,
+    /// The last tick the client asked for
+    pub(crate) to_tick: SimTick,
+    pub(crate) ticks: Vec<LockstepClientCommands>,
+}
+
+/// Sent by a reconnected client that is further ahead than the server,
+/// which only happens after host migration, with the ticks the server is
+/// missing.  It finishes the client's resync like [`ResyncFinished`].
+#[derive(Event, Default)]
+pub(crate) struct MigrationHistory {
+    pub(crate) from_tick: SimTick,
+    pub(crate) ticks: Vec<LockstepClientCommands>,
+}
+
+/// A trigger that fires on a client for every received chunk of history
+#[derive(Event, Debug, Clone, Copy)]
+pub struct CatchUpProgress {
+    pub received_up_to: SimTick,
+    pub target: SimTick,
+}
+
+/// The history a client has requested and not fully received yet.  A
+/// second gap may open while the first is still being filled, so every
+/// outstanding request is tracked.
+#[derive(Resource, Default)]
+pub(crate) struct PendingCatchUp(Vec<RequestCatchUp>);
+
+impl PendingCatchUp {
+    /// The first tick of history still missing
+    pub(crate) fn first_missing_tick(&self) -> Option<SimTick> {
+        self.0.iter().map(|request| request.from_tick).min()
+    }
+
+    pub(crate) fn is_catching_up(&self) -> bool {
+        !self.0.is_empty()
+    }
+
+    /// Sends the request unless an outstanding one already covers its ticks
+    fn request(&mut self, commands: &mut Commands, request: RequestCatchUp) {
+        let covered = self.0.iter().any(|pending| pending.from_tick <= request.from_tick
+            && pending.to_tick >= request.to_tick
+            && (pending.full || !request.full));
+        if covered { return }
+        self.0.push(request);
+        commands.client_trigger(request);
+    }
+
+    /// Marks the request a chunk answers as complete, the server answers
+    /// every request up to the chunk's last tick
+    fn complete(&mut self, to_tick: SimTick) {
+        self.0.retain(|request| request.from_tick > to_tick || request.to_tick < to_tick);
+    }
+}
+
+/// Keep only the commands needed to catch up
+fn filter_catch_up(commands: &LockstepClientCommands, registry: &TypeRegistry) -> LockstepClientCommands {
+    if registry.iter_with_data::<ReflectCatchUpCommand>().next().is_none() {
+        return commands.clone();
+    }
+    let mut filtered = LockstepClientCommands::default();
+    for (&client, client_commands) in commands.iter() {
+        let kept: Vec<_> = client_commands
+            .iter()
+            .filter(|command| {
+                let type_id = command.get_represented_type_info().map_or(TypeId::of::<()>(), |info| info.type_id());
+                registry.get_type_data::<ReflectCatchUpCommand>(type_id).is_some()
+            })
+            .map(|command| command.clone_value())
+            .collect();
+        if !kept.is_empty() {
+            filtered.insert(client, kept);
+        }
+    }
+    filtered
+}
+
+/// Requests the missing history when the first received tick leaves a gap
+pub(crate) fn request_missing_history(
+    commands: &mut Commands,
+    pending: &mut PendingCatchUp,
+    buffered_ticks: usize,
+    received_tick: SimTick,
+    full: bool,
+) {
+    if buffered_ticks as SimTick >= received_tick { return }
+    let request = RequestCatchUp {
+        from_tick: (buffered_ticks as SimTick).max(1),
+        to_tick: received_tick - 1,
+        full,
+    };
+    if request.from_tick > request.to_tick { return }
+    info!("Requesting history for ticks {} to {}", request.from_tick, request.to_tick);
+    pending.request(commands, request);
+}
+
+/// Requests the ticks between the last tick before a gap and the first
+/// tick after it, with every command
+pub(crate) fn request_gap(commands: &mut Commands, pending: &mut PendingCatchUp, from_tick: SimTick, to_tick: SimTick) {
+    if from_tick > to_tick { return }
+    info!("Requesting missing ticks {} to {}", from_tick, to_tick);
+    pending.request(commands, RequestCatchUp { from_tick, to_tick, full: true });
+}
+
+/// Sends the pending requests again, e.g. when their history didn't arrive
+pub(crate) fn request_again(commands: &mut Commands, pending: &PendingCatchUp) {
+    for &request in pending.0.iter() {
+        commands.client_trigger(request);
+    }
+}
+
+pub(super) fn send_catch_up_history(
+    trigger: Trigger<FromClient<RequestCatchUp>>,
+    mut commands: Commands,
+    history: Res<LockstepGameCommandBuffer>,
+    sim_tick: Res<SimulationTick>,
+    registry: Res<AppTypeRegistry>,
+    state: Res<State<SimulationState>>,
+    clients: LockstepClients,
+    latest_checkpoint: Option<Res<LatestCheckpoint>>,
+    first_available: Res<FirstAvailableTick>,
+    settings_timeline: Res<SettingsTimeline>,
+) {
+    let request = trigger.event;
+    // Only broadcast ticks are final
+    let to_tick = request.to_tick.min(**sim_tick);
+    let mut from_tick = request.from_tick.max(1);
+    // A client starting from nothing starts from the latest checkpoint
+    let checkpoint = latest_checkpoint.as_ref()
+        .and_then(|latest| latest.0.clone())
+        .filter(|checkpoint| request.full && request.from_tick <= 1 && checkpoint.tick <= to_tick);
+    let client = clients.client_id(trigger.client_entity);
+    let checkpoint = checkpoint.zip(client).map(|(checkpoint, client)| {
+        info!("Sending checkpoint of tick {} to client {}", checkpoint.tick, client);
+        commands.trigger(SendSnapshot { client, tick: checkpoint.tick, data: checkpoint.data });
+        from_tick = checkpoint.tick + 1;
+        checkpoint.tick
+    });
+    // A host that restored a checkpoint itself has nothing before it
+    if from_tick < **first_available {
+        warn!("Ticks {} to {} are not available, sending history from tick {}",
+            from_tick, **first_available - 1, **first_available);
+        from_tick = **first_available;
+    }
+    if request.full {
+        commands.server_trigger(ToClients {
+            mode: SendMode::Direct(trigger.client_entity),
+            event: ResyncStarted {
+                from_tick,
+                to_tick,
+                state: *state.get(),
+                checkpoint,
+                initial_settings: settings_timeline.initial.clone(),
+                settings_changes: settings_timeline.changes.clone(),
+            },
+        });
+    }
+    let registry = registry.read();
+    while from_tick <= to_tick {
+        let last = (from_tick + CATCH_UP_CHUNK_TICKS - 1).min(to_tick);
+        let ticks = (from_tick..=last)
+            .map(|tick| match history.get(tick) {
+                Some(commands) if request.full => commands.clone(),
+                Some(commands) => filter_catch_up(commands, &registry),
+                None => LockstepClientCommands::default(),
+            })
+            .collect();
+        commands.server_trigger(ToClients {
+            mode: SendMode::Direct(trigger.client_entity),
+            event: CatchUpHistory { from_tick, to_tick, ticks },
+        });
+        from_tick = last + 1;
+    }
+}
+
+pub(super) fn receive_catch_up_history(
+    trigger: Trigger<CatchUpHistory>,
+    mut commands: Commands,
+    server: Res<RepliconServer>,
+    mut history: ResMut<LockstepGameCommandBuffer>,
+    mut pending: ResMut<PendingCatchUp>,
+    mut resync: ResMut<PendingResync>,
+    mut next_state: ResMut<NextState<SimulationState>>,
+    restoring: Option<Res<RestoringCheckpoint>>,
+) {
+    if server.is_running() { return }
+    let chunk = trigger.event();
+    if chunk.ticks.is_empty() { return }
+    let last = chunk.from_tick + chunk.ticks.len() as SimTick - 1;
+    if history.len() <= last as usize {
+        history.resize(last + 1, LockstepClientCommands::default());
+    }
+    for (offset, tick_commands) in chunk.ticks.iter().enumerate() {
+        history[chunk.from_tick as usize + offset] = tick_commands.clone();
+    }
+    trace!("received history up to tick {} of {}", last, chunk.to_tick);
+    commands.trigger(CatchUpProgress { received_up_to: last, target: chunk.to_tick });
+    if last >= chunk.to_tick {
+        pending.complete(chunk.to_tick);
+        if pending.is_catching_up() { return }
+        // Finished once the checkpoint is restored as well
+        if restoring.is_some_and(|restoring| !restoring.restored) { return }
+        if let Some(state) = resync.0.take() {
+            info!("Resynced up to tick {}", last);
+            next_state.set(state);
+            commands.client_trigger(ResyncFinished { tick: last });
+        }
+    }
+}
+
+pub(super) fn receive_resync_started(
+    trigger: Trigger<ResyncStarted>,
+    mut commands: Commands,
+    mut pending: ResMut<PendingCatchUp>,
+    mut resync: ResMut<PendingResync>,
+    mut next_state: ResMut<NextState<SimulationState>>,
+    history: Res<LockstepGameCommandBuffer>,
+) {
+    let started = trigger.event();
+    if let Some(tick) = started.checkpoint {
+        debug!("resyncing from the checkpoint of tick {}", tick);
+        commands.insert_resource(RestoringCheckpoint { tick, restored: false });
+    }
+    if started.from_tick <= started.to_tick {
+        debug!("resyncing ticks {} to {}", started.from_tick, started.to_tick);
+        resync.0 = Some(started.state);
+        return;
+    }
+    if started.checkpoint.is_some() {
+        // Nothing happened after the checkpoint
+        pending.0.clear();
+        resync.0 = Some(started.state);
+        return;
+    }
+    pending.0.clear();
+    next_state.set(started.state);
+    let from_tick = started.to_tick + 1;
+    if (history.len() as SimTick) > from_tick {
+        // A new host after migration may not have received every tick we did
+        info!("Server is behind, sending ticks {} to {}", from_tick, history.len() - 1);
+        let ticks = history[from_tick as usize..].to_vec();
+        commands.client_trigger(MigrationHistory { from_tick, ticks });
+        return;
+    }
+    info!("Nothing missed while disconnected");
+    commands.client_trigger(ResyncFinished { tick: started.to_tick });
+}
+
+/// Finishes a resync from a checkpoint restored after its history arrived
+pub(crate) fn finish_checkpoint_resync(world: &mut World) {
+    if world.resource::<PendingCatchUp>().is_catching_up() { return }
+    let Some(state) = world.resource_mut::<PendingResync>().0.take() else { return };
+    let tick = world.resource::<LockstepGameCommandBuffer>().len().saturating_sub(1) as SimTick;
+    info!("Resynced up to tick {}", tick);
+    world.resource_mut::<NextState<SimulationState>>().set(state);
+    world.commands().client_trigger(ResyncFinished { tick });
+}
+
+/// Requests everything missed while disconnected once the connection is back
+pub(super) fn request_resync(
+    mut commands: Commands,
+    history: Res<LockstepGameCommandBuffer>,
+    mut pending: ResMut<PendingCatchUp>,
+) {
+    // A live tick that arrived first may already have requested the gap
+    if pending.is_catching_up() { return }
+    let request = RequestCatchUp {
+        from_tick: (history.len() as SimTick).max(1),
+        to_tick: SimTick::MAX,
+        full: true,
+    };
+    info!("Requesting history from tick {} to resync", request.from_tick);
+    pending.request(&mut commands, request);
+}
+
+pub(super) fn record_dropped_client(
+    trigger: Trigger<OnRemove, NetworkId>,
+    clients: Query<&NetworkId>,
+    server: Res<RepliconServer>,
+    state: Res<State<SimulationState>>,
+    sim_tick: Option<Res<SimulationTick>>,
+    mut dropped: ResMut<DroppedClients>,
+) {
+    if !server.is_running() { return }
+    if !matches!(state.get(), SimulationState::Running | SimulationState::Paused) { return }
+    let (Ok(id), Some(sim_tick)) = (clients.get(trigger.entity()), sim_tick) else { return };
+    debug!("client {} dropped at tick {}", id.get(), **sim_tick);
+    dropped.insert(id.get(), **sim_tick);
+}
+
+pub(super) fn receive_resync_finished(
+    trigger: Trigger<FromClient<ResyncFinished>>,
+    mut commands: Commands,
+    clients: LockstepClients,
+    settings: Res<ConnectionSettings>,
+    state: Res<State<SimulationState>>,
+    mut dropped: ResMut<DroppedClients>,
+    migration: Option<Res<PendingHostMigration>>,
+    pause: Res<PauseStatus>,
+) {
+    let Some(client) = clients.client_id(trigger.client_entity) else { return };
+    info!("client {} resynced up to tick {}", client, trigger.event.tick);
+    let hold = migration.is_some() || pause.reason.is_some_and(|reason| reason != PauseReason::ConnectionLoss);
+    finish_resync(&mut commands, &clients, &settings, *state.get(), &mut dropped, hold, client);
+}
+
+/// Adopts the ticks a client received from the previous host but we didn't
+pub(super) fn receive_migration_history(
+    trigger: Trigger<FromClient<MigrationHistory>>,
+    mut commands: Commands,
+    clients: LockstepClients,
+    settings: Res<ConnectionSettings>,
+    state: Res<State<SimulationState>>,
+    mut dropped: ResMut<DroppedClients>,
+    migration: Option<Res<PendingHostMigration>>,
+    pause: Res<PauseStatus>,
+    mut sim_tick: ResMut<SimulationTick>,
+    mut last_broadcast: ResMut<LastBroadcastTick>,
+    mut history: ResMut<LockstepGameCommandBuffer>,
+    session: Res<SessionHash>,
+    registry: Res<AppTypeRegistry>,
+) {
+    let Some(client) = clients.client_id(trigger.client_entity) else { return };
+    let chunk = &trigger.event;
+    // The chunk comes off the network, an empty or out of range one adopts nothing
+    let last = (chunk.ticks.len() as SimTick)
+        .checked_sub(1)
+        .and_then(|len| chunk.from_tick.checked_add(len));
+    let adoptable = migration.is_some() && *state.get() == SimulationState::Paused && chunk.from_tick <= **sim_tick + 1;
+    match last {
+        Some(last) if !adoptable => {
+            warn!("can't adopt ticks {} to {} from client {} at tick {}", chunk.from_tick, last, client, **sim_tick);
+        }
+        Some(last) if last > **sim_tick => {
+            info!("adopting ticks {} to {} from client {}", **sim_tick + 1, last, client);
+            if history.len() <= last as usize {
+                history.resize(last + 1, LockstepClientCommands::default());
+            }
+            let registry = registry.read();
+            for tick in **sim_tick + 1..=last {
+                let tick_commands = chunk.ticks[(tick - chunk.from_tick) as usize].clone();
+                history[tick as usize] = tick_commands.clone();
+                // The sender already has them, everyone else gets them as usual
+                commands.server_trigger(ToClients {
+                    mode: SendMode::BroadcastExcept(trigger.client_entity),
+                    event: ServerSendCommands {
+                        session: *session,
+                        tick,
+                        digest: hash_tick_commands(&tick_commands, &registry),
+                        streams: default(),
+                        commands: tick_commands,
+                    },
+                });
+            }
+            **sim_tick = last;
+            **last_broadcast = last;
+        }
+        _ => {}
+    }
+    let hold = migration.is_some() || pause.reason.is_some_and(|reason| reason != PauseReason::ConnectionLoss);
+    finish_resync(&mut commands, &clients, &settings, *state.get(), &mut dropped, hold, client);
+}
+
+fn finish_resync(
+    commands: &mut Commands,
+    clients: &LockstepClients,
+    settings: &ConnectionSettings,
+    state: SimulationState,
+    dropped: &mut DroppedClients,
+    hold: bool,
+    client: ClientId,
+) {
+    let dropped_at = dropped.remove(&client);
+    commands.trigger(ClientResynced { client, dropped_at });
+    // Clients still away don't hold the others back, the tick gating pauses
+    // again if they stay missing.  After host migration the new host
+    // resumes once everyone is back, and pauses for any other reason than
+    // the connection loss are held until resumed explicitly.
+    let resyncing = clients.ids().any(|id| dropped.contains_key(&id));
+    if settings.resume_after_resync && state == SimulationState::Paused && !resyncing && !hold {
+        commands.trigger(ResumeSimulation);
+    }
+}
@@ -0,0 +1,118 @@
+//! Compact encoding of the tick broadcast.  Once every peer verified it
+//! registered the same command types, the registered ids are numbered in
+//! order, so a command's id fits a single byte for up to 127 types instead
+//! of the up to five bytes of its stable id.  With the `compression`
+//! feature, broadcasts above `SimulationSettings::compress_ticks_above`
+//! are additionally compressed with lz4, if every peer reported it was
+//! built with the feature as well.
+//!
+//! The codec lives in the type registry, the only app state the replicon
+//! serialization functions can reach.  Replays keep the stable ids, so
+//! they stay readable by apps registering more command types.
+
+use std::any::TypeId;
+use bevy::{prelude::*, reflect::{GetTypeRegistration, TypeRegistry}, utils::hashbrown::HashMap};
+use bevy_replicon::prelude::*;
+use crate::prelude::*;
+
+/// Carries the [`CompactCommandIds`] of the current match as type data
+#[derive(Reflect)]
+pub(crate) struct CommandWireCodec;
+
+/// Per match numbering of the registered command types
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CompactCommandIds {
+    ids: HashMap<TypeId, LockstepCommandId>,
+    /// Command types by compact id minus one
+    types: Vec<TypeId>,
+    /// Encoded tick broadcasts above this many bytes are compressed
+    pub(crate) compress_above: Option<usize>,
+}
+
+impl CompactCommandIds {
+    /// The compact id of a command type, ids start at 1 like stable ids
+    pub(crate) fn id(&self, type_id: TypeId) -> Option<LockstepCommandId> {
+        self.ids.get(&type_id).copied()
+    }
+
+    pub(crate) fn type_id(&self, id: LockstepCommandId) -> Option<TypeId> {
+        self.types.get((id as usize).checked_sub(1)?).copied()
+    }
+}
+
+/// Whether every peer of the match can read lz4 compressed ticks.  Starts
+/// out as whether this build can, the server clears it once a client
+/// reports it can't, including a client joining the match in progress.
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct TickCompression(pub(crate) bool);
+
+impl Default for TickCompression {
+    fn default() -> Self {
+        Self(cfg!(feature = "compression"))
+    }
+}
+
+impl TickCompression {
+    /// Whether ticks of this match may be compressed
+    pub fn enabled(&self) -> bool {
+        self.0
+    }
+
+    /// The size above which ticks are compressed, if they are
+    pub(crate) fn threshold(&self, settings: &SimulationSettings) -> Option<usize> {
+        settings.compress_ticks_above.filter(|_| self.0)
+    }
+}
+
+/// The compact ids of the current match, if they were assigned
+pub(crate) fn compact_ids(registry: &TypeRegistry) -> Option<&CompactCommandIds> {
+    registry.get_type_data::<CompactCommandIds>(TypeId::of::<CommandWireCodec>())
+}
+
+/// Numbers the registered command types by stable id on the server.  Runs
+/// on entering Setup, where the registry check rejects any client whose
+/// registered types differ from the server's.  Versioned types may differ,
+/// so clients drop the ids of a previous match and only number the types
+/// once they receive the [`CommandVersions`] of this one.  Until then they
+/// neither encode nor decode compact ids.
+pub(super) fn assign_compact_command_ids(
+    registry: Res<AppTypeRegistry>,
+    versions: Res<CommandVersions>,
+    settings: Res<SimulationSettings>,
+    mut compression: ResMut<TickCompression>,
+    server: Res<RepliconServer>,
+) {
+    *compression = TickCompression::default();
+    let mut registry = registry.write();
+    if server.is_running() {
+        number_command_types(&mut registry, &versions, compression.threshold(&settings));
+    } else {
+        clear_command_types(&mut registry);
+    }
+}
+
+/// Drops the compact ids, so commands use their stable ids
+fn clear_command_types(registry: &mut TypeRegistry) {
+    registry.overwrite_registration(CommandWireCodec::get_type_registration());
+}
+
+/// Numbers the registered command types every peer has
+pub(crate) fn number_command_types(registry: &mut TypeRegistry, versions: &CommandVersions, compress_above: Option<usize>) {
+    let mut registered: Vec<_> = registry
+        .iter_with_data::<ReflectLockstepCommand>()
+        .filter(|(_, data)| versions.contains(data.id))
+        .map(|(registration, data)| (data.id, registration.type_id()))
+        .collect();
+    registered.sort_unstable_by_key(|(id, _)| *id);
+    let types: Vec<TypeId> = registered.into_iter().map(|(_, type_id)| type_id).collect();
+    let ids = types.iter()
+        .enumerate()
+        .map(|(index, &type_id)| (type_id, index as LockstepCommandId + 1))
+        .collect();
+    trace!("assigned compact ids to {} command types", types.len());
+    let codec = CompactCommandIds { ids, types, compress_above };
+    registry
+        .get_mut(TypeId::of::<CommandWireCodec>())
+        .expect("the codec type is registered by the commands plugin")
+        .insert(codec);
+}
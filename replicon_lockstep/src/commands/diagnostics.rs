@@ -0,0 +1,284 @@
+use std::{collections::{BTreeMap, VecDeque}, time::Duration};
+use bevy::{prelude::*, reflect::TypeRegistry, utils::hashbrown::HashMap};
+use bevy_replicon::{prelude::*, shared::backend::connected_client::NetworkId};
+use serde::{Deserialize, Serialize};
+use crate::prelude::*;
+use super::{ClientExecutionSchedule, ClientSendCommands, ServerSendCommands, codec, serialization};
+
+/// Number of recent commands kept per client for percentiles
+const LATENCY_WINDOW: usize = 256;
+
+/// Weight of the latest tick in the per tick averages of [`LockstepBandwidth`]
+const BANDWIDTH_SMOOTHING: f64 = 0.05;
+
+/// Rolling command latency of one client: the ticks between the client
+/// issuing commands and the tick they execute on
+#[derive(Debug, Clone, Default)]
+pub struct CommandLatency {
+    samples: VecDeque<SimTick>,
+    /// Total command messages recorded, including those no longer in the window
+    pub total: u64,
+}
+
+impl CommandLatency {
+    fn record(&mut self, ticks: SimTick) {
+        if self.samples.len() == LATENCY_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(ticks);
+        self.total += 1;
+    }
+
+    /// The most recent latency in ticks
+    pub fn last(&self) -> Option<SimTick> {
+        self.samples.back().copied()
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        if self.samples.is_empty() { return None }
+        Some(self.samples.iter().map(|&ticks| ticks as f64).sum::<f64>() / self.samples.len() as f64)
+    }
+
+    /// Latency in ticks at a percentile between 0 and 100, over the window
+    pub fn percentile(&self, percentile: f64) -> Option<SimTick> {
+        if self.samples.is_empty() { return None }
+        let mut sorted: Vec<SimTick> = self.samples.iter().copied().collect();
+        sorted.sort_unstable();
+        let rank = (percentile.clamp(0.0, 100.0) / 100.0 * (sorted.len() - 1) as f64).round() as usize;
+        Some(sorted[rank])
+    }
+
+    /// Like [`CommandLatency::percentile`], converted to time
+    pub fn percentile_duration(&self, percentile: f64, tick_timestep: Duration) -> Option<Duration> {
+        self.percentile(percentile).map(|ticks| tick_timestep * ticks)
+    }
+
+    pub fn p50(&self) -> Option<SimTick> { self.percentile(50.0) }
+    pub fn p95(&self) -> Option<SimTick> { self.percentile(95.0) }
+    pub fn p99(&self) -> Option<SimTick> { self.percentile(99.0) }
+}
+
+/// Server-only diagnostics of the input lag each player experiences, for
+/// tuning `SimulationSettings::base_input_tick_delay`
+#[derive(Resource, Debug, Clone, Default)]
+pub struct LockstepDiagnostics {
+    pub command_latency: HashMap<ClientId, CommandLatency>,
+}
+
+impl LockstepDiagnostics {
+    pub(super) fn record_latency(&mut self, client: ClientId, issued_tick: SimTick, execution_tick: SimTick) {
+        self.command_latency
+            .entry(client)
+            .or_default()
+            .record(execution_tick.saturating_sub(issued_tick));
+    }
+
+    pub fn latency(&self, client: ClientId) -> Option<&CommandLatency> {
+        self.command_latency.get(&client)
+    }
+}
+
+/// Network conditions of one client, as seen by the server
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct ClientNetworkStats {
+    pub rtt: Duration,
+    /// Ticks between the client issuing commands and them executing
+    pub input_delay: SimTick,
+    /// The latest client tick the server received commands for
+    pub last_received_tick: SimTick,
+    /// How far `last_received_tick` trails the server's tick
+    pub ticks_behind: SimTick,
+}
+
+/// Per-client network conditions for HUDs, e.g. "player X is lagging".
+/// The server updates it every tick and broadcasts it every
+/// `SimulationSettings::network_diagnostics_interval` ticks, clients keep
+/// the last broadcast.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct LockstepNetworkDiagnostics {
+    /// The latest server tick known locally
+    pub server_tick: SimTick,
+    /// The local simulation tick
+    pub local_tick: SimTick,
+    pub clients: BTreeMap<ClientId, ClientNetworkStats>,
+}
+
+impl LockstepNetworkDiagnostics {
+    pub fn client(&self, client: ClientId) -> Option<&ClientNetworkStats> {
+        self.clients.get(&client)
+    }
+
+    /// Clients trailing the server by more than `max_ticks_behind`
+    pub fn lagging(&self, max_ticks_behind: SimTick) -> impl Iterator<Item = ClientId> + '_ {
+        self.clients
+            .iter()
+            .filter(move |(_, stats)| stats.ticks_behind > max_ticks_behind)
+            .map(|(&client, _)| client)
+    }
+
+    pub(super) fn record_received(&mut self, client: ClientId, issued_tick: SimTick) {
+        let stats = self.clients.entry(client).or_default();
+        stats.last_received_tick = stats.last_received_tick.max(issued_tick);
+    }
+}
+
+/// Broadcast by the server with its [`LockstepNetworkDiagnostics`]
+#[derive(Event, Serialize, Deserialize, Debug, Clone)]
+pub(super) struct NetworkDiagnosticsReport {
+    server_tick: SimTick,
+    clients: Vec<(ClientId, ClientNetworkStats)>,
+}
+
+pub(super) fn update_network_diagnostics_server(
+    mut commands: Commands,
+    mut diagnostics: ResMut<LockstepNetworkDiagnostics>,
+    clients: Query<(&NetworkId, Option<&NetworkStats>)>,
+    schedule: Res<ClientExecutionSchedule>,
+    sim_tick: Res<SimulationTick>,
+    settings: Res<SimulationSettings>,
+) {
+    let tick = **sim_tick;
+    let previous_tick = diagnostics.server_tick;
+    diagnostics.server_tick = tick;
+    diagnostics.local_tick = tick;
+    let connected: Vec<ClientId> = clients.iter().map(|(id, _)| id.get()).collect();
+    diagnostics.clients.retain(|client, _| connected.contains(client));
+    for (id, stats) in clients.iter() {
+        let client = id.get();
+        let entry = diagnostics.clients.entry(client).or_default();
+        // The host has no network stats
+        entry.rtt = stats.map_or(Duration::ZERO, |stats| Duration::from_secs_f64(stats.rtt.max(0.0)));
+        if let Some(scheduled) = schedule.get(&client) {
+            entry.input_delay = scheduled.delay;
+        }
+        entry.ticks_behind = tick.saturating_sub(entry.last_received_tick);
+    }
+
+    let interval = settings.network_diagnostics_interval;
+    if interval == 0 || tick == previous_tick || tick % interval != 0 { return }
+    commands.server_trigger(ToClients {
+        mode: SendMode::Broadcast,
+        event: NetworkDiagnosticsReport {
+            server_tick: tick,
+            clients: diagnostics.clients.iter().map(|(&client, &stats)| (client, stats)).collect(),
+        },
+    });
+}
+
+pub(super) fn update_network_diagnostics_client(
+    mut diagnostics: ResMut<LockstepNetworkDiagnostics>,
+    sim_tick: Res<SimulationTick>,
+) {
+    diagnostics.local_tick = **sim_tick;
+}
+
+pub(super) fn receive_network_diagnostics(
+    trigger: Trigger<NetworkDiagnosticsReport>,
+    mut diagnostics: ResMut<LockstepNetworkDiagnostics>,
+) {
+    let report = trigger.event();
+    // Reports are unreliable and may arrive out of order
+    if report.server_tick < diagnostics.server_tick { return }
+    diagnostics.server_tick = report.server_tick;
+    diagnostics.clients = report.clients.iter().copied().collect();
+}
+
+/// Bytes of one command type in the lockstep messages of a client
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommandBandwidth {
+    pub sent: u64,
+    pub sent_bytes: u64,
+    pub received: u64,
+    pub received_bytes: u64,
+}
+
+/// Client-only size of the lockstep command messages, sent commands and
+/// received ticks, without the rest of the replicon traffic.  Measured when
+/// `SimulationSettings::bandwidth_diagnostics` is set, by serializing the
+/// messages again, e.g. to find the command types that dominate bandwidth.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct LockstepBandwidth {
+    /// Bytes sent per tick, averaged over recent ticks
+    pub sent_per_tick: f64,
+    /// Bytes received per tick, averaged over recent ticks
+    pub received_per_tick: f64,
+    pub total_sent: u64,
+    pub total_received: u64,
+    /// Bytes of each command type, by type path.  Sizes are of the
+    /// serialized command alone, without the framing of the message.
+    pub commands: BTreeMap<String, CommandBandwidth>,
+    /// Bytes sent since the last received tick
+    sent_this_tick: u64,
+    ticks: u32,
+}
+
+impl LockstepBandwidth {
+    /// Average bytes sent per second at the given tick timestep
+    pub fn sent_per_second(&self, tick_timestep: Duration) -> f64 {
+        self.sent_per_tick / tick_timestep.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Average bytes received per second at the given tick timestep
+    pub fn received_per_second(&self, tick_timestep: Duration) -> f64 {
+        self.received_per_tick / tick_timestep.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Command types by total bytes, largest first
+    pub fn heaviest_commands(&self) -> Vec<(&str, &CommandBandwidth)> {
+        let mut commands: Vec<(&str, &CommandBandwidth)> = self.commands
+            .iter()
+            .map(|(type_path, bandwidth)| (type_path.as_str(), bandwidth))
+            .collect();
+        commands.sort_by_key(|(_, bandwidth)| std::cmp::Reverse(bandwidth.sent_bytes + bandwidth.received_bytes));
+        commands
+    }
+
+    pub(crate) fn record_sent(&mut self, event: &ClientSendCommands, registry: &TypeRegistry) {
+        let mut message = Vec::new();
+        if serialization::write_client_send_commands(event, registry, &mut message).is_err() { return }
+        self.sent_this_tick += message.len() as u64;
+        self.total_sent += message.len() as u64;
+        for command in &event.commands {
+            let bytes = serialization::command_size(command.as_ref(), registry, None);
+            let bandwidth = self.commands.entry(command.reflect_type_path().to_string()).or_default();
+            bandwidth.sent += 1;
+            bandwidth.sent_bytes += bytes as u64;
+        }
+    }
+
+    fn record_tick(&mut self, tick: &ServerSendCommands, registry: &TypeRegistry) {
+        let mut message = Vec::new();
+        if serialization::write_server_send_commands(tick, registry, &mut message).is_err() { return }
+        let received = message.len() as u64;
+        self.total_received += received;
+        let compact = codec::compact_ids(registry);
+        let streams = tick.streams.values().flat_map(|commands| commands.values());
+        for command in tick.commands.values().chain(streams).flatten() {
+            let bytes = serialization::command_size(command.as_ref(), registry, compact);
+            let bandwidth = self.commands.entry(command.reflect_type_path().to_string()).or_default();
+            bandwidth.received += 1;
+            bandwidth.received_bytes += bytes as u64;
+        }
+        let sent = std::mem::take(&mut self.sent_this_tick);
+        if self.ticks == 0 {
+            self.sent_per_tick = sent as f64;
+            self.received_per_tick = received as f64;
+        } else {
+            self.sent_per_tick += (sent as f64 - self.sent_per_tick) * BANDWIDTH_SMOOTHING;
+            self.received_per_tick += (received as f64 - self.received_per_tick) * BANDWIDTH_SMOOTHING;
+        }
+        self.ticks += 1;
+    }
+}
+
+pub(super) fn measure_received_tick(
+    tick: Trigger<ServerSendCommands>,
+    server: Res<RepliconServer>,
+    settings: Res<SimulationSettings>,
+    registry: Res<AppTypeRegistry>,
+    mut bandwidth: ResMut<LockstepBandwidth>,
+) {
+    // The server's own broadcast never crosses the network
+    if server.is_running() || !settings.bandwidth_diagnostics { return }
+    bandwidth.record_tick(tick.event(), &registry.read());
+}
@@ -0,0 +1,16 @@
+use bevy::prelude::*;
+use crate::prelude::*;
+
+/// Panics if the commands read for `tick` differ from the ones recorded in
+/// [`TickDigests`] when the tick arrived, so a system mutating boxed
+/// commands in [`LockstepGameCommandBuffer`] in place is caught instead of
+/// silently desyncing the peers that read the buffer at a different time
+pub(crate) fn verify_frozen_commands(world: &World, tick: SimTick, tick_commands: &LockstepClientCommands) {
+    let Some(&Some(expected)) = world.get_resource::<TickDigests>().and_then(|digests| digests.get(tick)) else { return };
+    let computed = hash_tick_commands(tick_commands, &world.resource::<AppTypeRegistry>().read());
+    assert_eq!(
+        expected, computed,
+        "commands of tick {} were mutated after they were scheduled, expected {:016x} got {:016x}",
+        tick, expected, computed,
+    );
+}
@@ -0,0 +1,178 @@
+use std::any::TypeId;
+use bevy::{prelude::*, reflect::{GetTypeRegistration, TypeRegistry}};
+use crate::{prelude::*, lockstep_core::schedule, simulation::{InLockstepSchedule, apply_simulation_reset}};
+use super::{catch_up::PendingCatchUp, physics, parallel::{ParallelBatch, ReflectParallelGameCommand}, typed::{ReflectCommandTrigger, ReflectCommandHandler, LockstepCommandHandlers}};
+
+/// A command that knows how to validate and apply itself.  Register with
+/// [`LockstepCommandAppExt::register_game_command`] and the crate validates
+/// it on the server and applies it on every peer, in tick, client and
+/// command order, so no hand written `process_tick_commands` is needed.
+pub trait GameCommand: Reflect + FromReflect + TypePath + GetTypeRegistration {
+    /// Checked on the server before the command is scheduled.  Rejected
+    /// commands never reach any peer.  `ctx.tick` is the tick the client
+    /// issued the command on.
+    fn validate(&self, _ctx: &CommandContext) -> bool {
+        true
+    }
+
+    /// Apply the command to the simulation on the tick it executes on
+    fn apply(&self, ctx: &CommandContext, world: &mut World);
+}
+
+/// Type data attached to types registered with
+/// [`LockstepCommandAppExt::register_game_command`]
+#[derive(Clone, Copy)]
+pub struct ReflectGameCommand {
+    validate: fn(&dyn PartialReflect, &CommandContext) -> bool,
+    apply: fn(&dyn PartialReflect, &CommandContext, &mut World),
+}
+
+impl ReflectGameCommand {
+    pub(super) fn of<T: GameCommand>() -> Self {
+        Self {
+            validate: |command, ctx| T::from_reflect(command).is_some_and(|command| command.validate(ctx)),
+            apply: |command, ctx, world| {
+                match T::from_reflect(command) {
+                    Some(command) => command.apply(ctx, world),
+                    None => warn!("could not convert command to {}", T::type_path()),
+                }
+            },
+        }
+    }
+
+    pub fn validate(&self, command: &dyn PartialReflect, ctx: &CommandContext) -> bool {
+        (self.validate)(command, ctx)
+    }
+
+    pub fn apply(&self, command: &dyn PartialReflect, ctx: &CommandContext, world: &mut World) {
+        (self.apply)(command, ctx, world)
+    }
+}
+
+/// The last tick whose game commands were applied
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct LastAppliedTick(SimTick);
+
+/// A trigger that fires after the game commands of a tick were applied and
+/// physics was stepped.  Hash or record the state of the tick here.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct GameCommandsApplied(pub SimTick);
+
+fn game_command_data(command: &dyn PartialReflect, registry: &TypeRegistry) -> Option<ReflectGameCommand> {
+    command_data(command, registry).0
+}
+
+fn type_id(command: &dyn PartialReflect) -> TypeId {
+    command.get_represented_type_info().map_or(TypeId::of::<()>(), |info| info.type_id())
+}
+
+/// How a command is applied and whether it has typed observers
+fn command_data(
+    command: &dyn PartialReflect,
+    registry: &TypeRegistry,
+) -> (Option<ReflectGameCommand>, Option<ReflectCommandTrigger>, Option<ReflectCommandHandler>) {
+    let type_id = type_id(command);
+    (
+        registry.get_type_data::<ReflectGameCommand>(type_id).copied(),
+        registry.get_type_data::<ReflectCommandTrigger>(type_id).copied(),
+        registry.get_type_data::<ReflectCommandHandler>(type_id).copied(),
+    )
+}
+
+/// Validate game commands in place, removing any that were rejected.
+/// Commands that aren't game commands are kept.  Returns the number of
+/// rejected commands.
+pub(super) fn validate_commands(
+    commands: &mut Vec<Box<dyn PartialReflect>>,
+    issued_tick: SimTick,
+    client: ClientId,
+    registry: &TypeRegistry,
+) -> usize {
+    let before = commands.len();
+    let mut index = 0;
+    commands.retain(|command| {
+        let ctx = CommandContext::new(issued_tick, client, index);
+        index += 1;
+        game_command_data(command.as_ref(), registry).is_none_or(|data| data.validate(command.as_ref(), &ctx))
+    });
+    before - commands.len()
+}
+
+/// Applies the game commands of every confirmed tick up to the current
+/// simulation tick.  A tick is confirmed once the client holds the complete
+/// history up to it, so ticks after a gap waiting for catch-up history are
+/// held back.  Each tick is applied as a whole: commands deferred by
+/// [`GameCommand::apply`] are flushed and physics is stepped before the tick
+/// is marked applied, so [`LastAppliedTick`] never points into a partially
+/// applied tick.  At most
+/// `CatchUpPolicy::max_ticks_per_frame` ticks are applied per run, or the
+/// ticks the frame took plus one if more, so slow and fast rendering peers
+/// apply the same ticks at the same pace.
+pub(super) fn apply_game_commands(world: &mut World) {
+    let mut confirmed_tick = **world.resource::<SimulationTick>();
+    if let Some(missing) = world.resource::<PendingCatchUp>().first_missing_tick() {
+        confirmed_tick = confirmed_tick.min(missing.saturating_sub(1));
+    }
+    let settings = world.resource::<SimulationSettings>();
+    let max_ticks = schedule::ticks_per_frame(
+        world.resource::<Time<Real>>().delta_secs_f64(),
+        settings.tick_timestep.as_secs_f64(),
+        settings.catch_up.max_ticks_per_frame,
+    );
+    confirmed_tick = confirmed_tick.min(**world.resource::<LastAppliedTick>() + max_ticks);
+    world.resource_mut::<InLockstepSchedule>().0 = true;
+    while **world.resource::<LastAppliedTick>() < confirmed_tick {
+        let tick = **world.resource::<LastAppliedTick>() + 1;
+        let Some(tick_commands) = world.resource::<LockstepGameCommandBuffer>().get(tick).cloned() else { break };
+        apply_tick(world, tick, &tick_commands);
+    }
+    world.resource_mut::<InLockstepSchedule>().0 = false;
+}
+
+/// Applies the commands of one tick, steps physics and marks the tick applied
+pub(crate) fn apply_tick(world: &mut World, tick: SimTick, tick_commands: &LockstepClientCommands) {
+    #[cfg(feature = "strict-determinism")]
+    super::frozen::verify_frozen_commands(world, tick, tick_commands);
+    let salt = TickSalt::new(world.resource::<LockstepRng>(), tick);
+    *world.resource_mut::<TickSalt>() = salt;
+    apply_simulation_reset(world, tick, tick_commands);
+    let registry = world.resource::<AppTypeRegistry>().clone();
+    let threshold = world.resource::<SimulationSettings>().parallel_apply_threshold;
+    let mut batch = ParallelBatch::default();
+    for (&client, client_commands) in tick_commands.iter() {
+        for (index, command) in client_commands.iter().enumerate() {
+            let ctx = CommandContext::new(tick, client, index);
+            let (apply, trigger, handler) = command_data(command.as_ref(), &registry.read());
+            let parallel = threshold.and(registry.read().get_type_data::<ReflectParallelGameCommand>(type_id(command.as_ref())).copied());
+            match (apply, parallel) {
+                // Observers may touch anything, so only commands without
+                // them can be deferred into the batch
+                (Some(data), Some(parallel)) if trigger.is_none() => {
+                    batch.push(ctx, command.as_ref(), data, parallel);
+                }
+                (apply, _) => {
+                    if let Some(threshold) = threshold {
+                        batch.apply(world, threshold);
+                    }
+                    if let Some(data) = apply {
+                        data.apply(command.as_ref(), &ctx, world);
+                    }
+                }
+            }
+            if let Some(data) = handler {
+                data.collect(command.as_ref(), &ctx, world);
+            }
+            if let Some(data) = trigger {
+                data.trigger(command.as_ref(), ctx, world);
+            }
+        }
+    }
+    if let Some(threshold) = threshold {
+        batch.apply(world, threshold);
+    }
+    world.flush();
+    world.run_schedule(LockstepCommandHandlers);
+    physics::run_physics_step(world, tick);
+    **world.resource_mut::<LastAppliedTick>() = tick;
+    world.trigger(GameCommandsApplied(tick));
+}
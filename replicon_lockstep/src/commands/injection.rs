@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use bevy::prelude::*;
+use crate::prelude::*;
+use super::queue::LockstepCommandQueue;
+
+/// A programmatic source of client commands, e.g. a test script, an AI demo
+/// or a tutorial ghost player.  Register with
+/// [`InjectedInputAppExt::add_injected_input`].
+pub trait InjectedInputSource: Send + Sync + 'static {
+    /// The commands to issue on this tick, and the stream to send them on.
+    /// Called exactly once per tick, in tick order.
+    fn commands_for_tick(&mut self, tick: SimTick) -> Vec<(CommandStreamId, Box<dyn PartialReflect>)>;
+
+    /// Finished sources are dropped
+    fn is_finished(&self) -> bool {
+        false
+    }
+}
+
+/// Registered input sources, polled in registration order.  On each tick
+/// their commands are sent ahead of any commands the game queued, so mixed
+/// injected and real input is always ordered the same way.
+#[derive(Resource, Default)]
+pub struct InjectedInputs {
+    sources: Vec<Box<dyn InjectedInputSource>>,
+    last_polled: Option<SimTick>,
+}
+
+impl InjectedInputs {
+    pub fn add(&mut self, source: impl InjectedInputSource) {
+        self.sources.push(Box::new(source));
+    }
+
+    pub fn len(&self) -> usize {
+        self.sources.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+}
+
+pub trait InjectedInputAppExt {
+    /// Drive the local client's commands from an input source
+    fn add_injected_input(&mut self, source: impl InjectedInputSource) -> &mut Self;
+}
+
+impl InjectedInputAppExt for App {
+    fn add_injected_input(&mut self, source: impl InjectedInputSource) -> &mut Self {
+        self.world_mut().get_resource_or_insert_with(InjectedInputs::default).add(source);
+        self
+    }
+}
+
+/// An input source playing back a fixed script of commands by tick
+#[derive(Default)]
+pub struct ScriptedInput {
+    script: BTreeMap<SimTick, Vec<(CommandStreamId, Box<dyn PartialReflect>)>>,
+}
+
+impl ScriptedInput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Issue a gameplay command on the given tick
+    pub fn at(mut self, tick: SimTick, command: impl PartialReflect) -> Self {
+        self.script.entry(tick).or_default().push((GAMEPLAY_STREAM, Box::new(command)));
+        self
+    }
+
+    /// Play back the commands one client executed in a recorded match.  The
+    /// commands are issued on the ticks they executed on, so they execute
+    /// later by the input delay.
+    pub fn from_replay(replay: &MatchReplay, client: ClientId) -> Self {
+        let script = replay.ticks
+            .iter()
+            .enumerate()
+            .filter_map(|(tick, tick_commands)| {
+                let commands = tick_commands.get(&client)?;
+                Some((tick as SimTick, commands
+                    .iter()
+                    .map(|command| (GAMEPLAY_STREAM, command.clone_value()))
+                    .collect()))
+            })
+            .collect();
+        Self { script }
+    }
+}
+
+impl InjectedInputSource for ScriptedInput {
+    fn commands_for_tick(&mut self, tick: SimTick) -> Vec<(CommandStreamId, Box<dyn PartialReflect>)> {
+        self.script.remove(&tick).unwrap_or_default()
+    }
+
+    fn is_finished(&self) -> bool {
+        self.script.is_empty()
+    }
+}
+
+/// Every match starts polling from its first tick again, e.g. a rematch
+pub(super) fn reset_injected_inputs(mut inputs: ResMut<InjectedInputs>) {
+    inputs.last_polled = None;
+}
+
+pub(super) fn poll_injected_inputs(
+    mut inputs: ResMut<InjectedInputs>,
+    queue: Res<LockstepCommandQueue>,
+    sim_tick: Res<SimulationTick>,
+    local_client: Query<&LocalClient>,
+) {
+    if inputs.sources.is_empty() || local_client.is_empty() { return }
+    let first = inputs.last_polled.map_or(**sim_tick, |last| last + 1);
+    let mut injected = Vec::new();
+    for tick in first..=**sim_tick {
+        for source in inputs.sources.iter_mut() {
+            injected.extend(source.commands_for_tick(tick));
+        }
+    }
+    inputs.last_polled = Some(**sim_tick);
+    inputs.sources.retain(|source| !source.is_finished());
+    if !injected.is_empty() {
+        queue.push_front(injected);
+    }
+}
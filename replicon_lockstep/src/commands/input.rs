@@ -0,0 +1,210 @@
+//! Quantized input for command payloads.  Analog input differs by device,
+//! a mouse aims with exact directions while a gamepad stick rests slightly
+//! off center, and floats in a payload round differently across platforms.
+//! Normalizing the input on the sending peer, applying its device's
+//! [`DeadZone`] and quantizing it into integers, makes every peer decode the
+//! same command whatever the player held.
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// The device an input came from, selecting its dead zones in
+/// [`InputDeadZones`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InputDevice {
+    /// Exact input that needs no dead zone, e.g. mouse aim or keys
+    Pointer,
+    Gamepad,
+}
+
+/// Rescales the magnitude of an analog input so values within `inner` read
+/// as zero and values beyond `outer` as full deflection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeadZone {
+    pub inner: f32,
+    pub outer: f32,
+}
+
+impl DeadZone {
+    pub const NONE: Self = Self { inner: 0.0, outer: 1.0 };
+
+    pub const fn new(inner: f32, outer: f32) -> Self {
+        Self { inner, outer }
+    }
+
+    /// A single axis, e.g. a trigger, in `[-1, 1]`
+    pub fn apply(&self, value: f32) -> f32 {
+        if !value.is_finite() { return 0.0 }
+        self.rescale(value.abs()).copysign(value)
+    }
+
+    /// A stick, by the length of its deflection so the dead zone is round
+    /// and the direction is kept
+    pub fn apply_radial(&self, value: Vec2) -> Vec2 {
+        if !value.is_finite() { return Vec2::ZERO }
+        let length = value.length();
+        if length == 0.0 { return Vec2::ZERO }
+        value * (self.rescale(length) / length)
+    }
+
+    fn rescale(&self, magnitude: f32) -> f32 {
+        let span = self.outer - self.inner;
+        if span <= 0.0 { return if magnitude > self.inner { 1.0 } else { 0.0 } }
+        ((magnitude - self.inner) / span).clamp(0.0, 1.0)
+    }
+}
+
+/// The dead zones of each [`InputDevice`], applied before quantizing
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+pub struct InputDeadZones {
+    pub pointer: DeadZone,
+    pub gamepad_stick: DeadZone,
+    pub gamepad_trigger: DeadZone,
+}
+
+impl Default for InputDeadZones {
+    fn default() -> Self {
+        Self {
+            pointer: DeadZone::NONE,
+            gamepad_stick: DeadZone::new(0.15, 0.95),
+            gamepad_trigger: DeadZone::new(0.05, 0.95),
+        }
+    }
+}
+
+impl InputDeadZones {
+    /// A stick or aim direction from `device`
+    pub fn stick(&self, device: InputDevice, value: Vec2) -> QuantizedStick {
+        let dead_zone = match device {
+            InputDevice::Pointer => self.pointer,
+            InputDevice::Gamepad => self.gamepad_stick,
+        };
+        QuantizedStick::from_vec2(dead_zone.apply_radial(value))
+    }
+
+    /// A single axis, e.g. a trigger or a key pair, from `device`
+    pub fn axis(&self, device: InputDevice, value: f32) -> QuantizedAxis {
+        let dead_zone = match device {
+            InputDevice::Pointer => self.pointer,
+            InputDevice::Gamepad => self.gamepad_trigger,
+        };
+        QuantizedAxis::from_f32(dead_zone.apply(value))
+    }
+}
+
+/// An axis in `[-1, 1]` as a signed 1.15 fixed-point number
+#[derive(Serialize, Deserialize, Reflect, Clone, Copy, Default, Debug, PartialEq, Eq, Hash)]
+#[reflect(Default, PartialEq, Hash)]
+pub struct QuantizedAxis(i16);
+
+impl QuantizedAxis {
+    pub const ZERO: Self = Self(0);
+    pub const MAX: Self = Self(i16::MAX);
+    pub const MIN: Self = Self(-i16::MAX);
+
+    pub const fn from_raw(raw: i16) -> Self {
+        Self(if raw == i16::MIN { -i16::MAX } else { raw })
+    }
+
+    pub const fn raw(self) -> i16 {
+        self.0
+    }
+
+    /// Clamps to `[-1, 1]` and rounds to the nearest step, non-finite
+    /// values read as zero
+    pub fn from_f32(value: f32) -> Self {
+        if !value.is_finite() { return Self::ZERO }
+        Self((value.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16)
+    }
+
+    /// Decodes the axis.  The division is exact on every platform, so it
+    /// may feed the simulation.
+    pub fn to_f32(self) -> f32 {
+        self.0 as f32 / i16::MAX as f32
+    }
+
+    #[cfg(feature = "fixed-point")]
+    pub fn to_fixed(self) -> crate::prelude::Fixed {
+        crate::prelude::Fixed::from_ratio(self.0 as i32, i16::MAX as i32)
+    }
+}
+
+/// Two [`QuantizedAxis`], e.g. a movement stick or an aim direction
+#[derive(Serialize, Deserialize, Reflect, Clone, Copy, Default, Debug, PartialEq, Eq, Hash)]
+#[reflect(Default, PartialEq, Hash)]
+pub struct QuantizedStick {
+    pub x: QuantizedAxis,
+    pub y: QuantizedAxis,
+}
+
+impl QuantizedStick {
+    pub const ZERO: Self = Self { x: QuantizedAxis::ZERO, y: QuantizedAxis::ZERO };
+
+    /// Deflections longer than one are shortened to one first, so diagonals
+    /// of keys or mice aren't faster than a stick
+    pub fn from_vec2(value: Vec2) -> Self {
+        if !value.is_finite() { return Self::ZERO }
+        let value = value.clamp_length_max(1.0);
+        Self { x: QuantizedAxis::from_f32(value.x), y: QuantizedAxis::from_f32(value.y) }
+    }
+
+    /// A unit direction, e.g. from the player to the mouse cursor
+    pub fn from_direction(direction: Vec2) -> Self {
+        Self::from_vec2(direction.normalize_or_zero())
+    }
+
+    pub fn to_vec2(self) -> Vec2 {
+        Vec2::new(self.x.to_f32(), self.y.to_f32())
+    }
+
+    pub fn is_zero(self) -> bool {
+        self == Self::ZERO
+    }
+
+    #[cfg(feature = "fixed-point")]
+    pub fn to_fixed(self) -> (crate::prelude::Fixed, crate::prelude::Fixed) {
+        (self.x.to_fixed(), self.y.to_fixed())
+    }
+}
+
+/// Up to 32 buttons held on a tick, by index.  Map keys and gamepad buttons
+/// that do the same thing onto the same index.
+#[derive(Serialize, Deserialize, Reflect, Clone, Copy, Default, Debug, PartialEq, Eq, Hash)]
+#[reflect(Default, PartialEq, Hash)]
+pub struct ButtonBits(u32);
+
+impl ButtonBits {
+    pub const NONE: Self = Self(0);
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Indices of 32 and above are ignored
+    pub fn set(&mut self, index: u8, pressed: bool) {
+        let Some(mask) = 1u32.checked_shl(index as u32) else { return };
+        if pressed { self.0 |= mask } else { self.0 &= !mask }
+    }
+
+    pub fn with(mut self, index: u8, pressed: bool) -> Self {
+        self.set(index, pressed);
+        self
+    }
+
+    pub fn pressed(self, index: u8) -> bool {
+        1u32.checked_shl(index as u32).is_some_and(|mask| self.0 & mask != 0)
+    }
+
+    /// Buttons held now that weren't in `previous`
+    pub fn just_pressed(self, previous: Self) -> Self {
+        Self(self.0 & !previous.0)
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
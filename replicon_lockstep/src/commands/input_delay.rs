@@ -0,0 +1,156 @@
+use bevy::{prelude::*, utils::hashbrown::HashMap};
+use bevy_replicon::{prelude::*, shared::backend::connected_client::{NetworkId, NetworkStats}};
+use serde::{Deserialize, Serialize};
+use crate::{prelude::*, simulation::rtt_to_ticks};
+
+/// How the server adjusts each client's input delay to its measured round
+/// trip time, like the latency meter of classic RTS games.  Without it the
+/// delay follows the round trip time of every received message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InputDelayAdjustment {
+    /// Ticks between checks of every client's round trip time
+    pub interval: SimTick,
+    /// A client's delay only changes once its round trip time calls for a
+    /// delay at least this many ticks away, so jitter doesn't flip it back
+    /// and forth
+    pub drift_threshold: SimTick,
+    /// Ticks between deciding on a change and it taking effect, so every
+    /// peer learns about it first
+    pub lead_ticks: SimTick,
+}
+
+impl Default for InputDelayAdjustment {
+    fn default() -> Self {
+        Self {
+            interval: 30,
+            drift_threshold: 2,
+            lead_ticks: 10,
+        }
+    }
+}
+
+/// Trigger this on the server to pin a client's total input delay, or to
+/// return it to automatic with None.  The change takes effect
+/// `InputDelayAdjustment::lead_ticks` ticks later, or on the next tick
+/// without an adjustment.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct SetInputDelay {
+    pub client: ClientId,
+    pub delay: Option<SimTick>,
+}
+
+/// Broadcast by the server when a client's input delay changes
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerSetInputDelay {
+    pub client: ClientId,
+    /// The total input delay in ticks, None follows the round trip time of
+    /// every message again
+    pub delay: Option<SimTick>,
+    /// Commands the server receives from this tick on are delayed by `delay`
+    pub effective_tick: SimTick,
+    /// Set with [`SetInputDelay`] instead of adjusted to the round trip time
+    pub pinned: bool,
+}
+
+/// A client's input delay as changed by the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientInputDelay {
+    pub delay: Option<SimTick>,
+    pub effective_tick: SimTick,
+    /// The delay before `effective_tick`
+    pub previous: Option<SimTick>,
+    pub pinned: bool,
+}
+
+impl ClientInputDelay {
+    /// The delay commands received on `tick` are scheduled with
+    pub fn delay_at(&self, tick: SimTick) -> Option<SimTick> {
+        if tick >= self.effective_tick { self.delay } else { self.previous }
+    }
+}
+
+/// Input delays the server set per client, the same on every peer.  Clients
+/// without an entry follow their round trip time.
+#[derive(Resource, Debug, Clone, Default, Deref)]
+pub struct InputDelays(HashMap<ClientId, ClientInputDelay>);
+
+impl InputDelays {
+    /// The delay a client's commands received on `tick` are scheduled with,
+    /// if the server set one
+    pub fn delay_at(&self, client: ClientId, tick: SimTick) -> Option<SimTick> {
+        self.get(&client).and_then(|delay| delay.delay_at(tick))
+    }
+}
+
+fn broadcast_input_delay(
+    commands: &mut Commands,
+    delays: &InputDelays,
+    client: ClientId,
+    delay: Option<SimTick>,
+    effective_tick: SimTick,
+    pinned: bool,
+) {
+    if delays.get(&client).is_some_and(|current| current.delay == delay && current.pinned == pinned) { return }
+    debug!("input delay of client {} changes to {:?} on tick {}", client, delay, effective_tick);
+    commands.server_trigger(ToClients {
+        mode: SendMode::Broadcast,
+        event: ServerSetInputDelay { client, delay, effective_tick, pinned },
+    });
+}
+
+pub(super) fn set_input_delay(
+    trigger: Trigger<SetInputDelay>,
+    mut commands: Commands,
+    server: Res<RepliconServer>,
+    delays: Res<InputDelays>,
+    sim_tick: Res<SimulationTick>,
+    settings: Res<SimulationSettings>,
+) {
+    if !server.is_running() { return }
+    let lead = settings.input_delay_adjustment.map_or(1, |adjustment| adjustment.lead_ticks.max(1));
+    let pinned = trigger.delay.is_some();
+    broadcast_input_delay(&mut commands, &delays, trigger.client, trigger.delay, **sim_tick + lead, pinned);
+}
+
+/// The server also receives its own broadcast here
+pub(super) fn receive_input_delay(
+    trigger: Trigger<ServerSetInputDelay>,
+    mut delays: ResMut<InputDelays>,
+    sim_tick: Res<SimulationTick>,
+) {
+    let change = trigger.event();
+    let previous = delays.delay_at(change.client, **sim_tick);
+    delays.0.insert(change.client, ClientInputDelay {
+        delay: change.delay,
+        effective_tick: change.effective_tick,
+        previous,
+        pinned: change.pinned,
+    });
+}
+
+/// Moves the delay of every client not pinned to what its round trip time
+/// calls for, once it drifted far enough
+pub(super) fn adjust_input_delays(
+    mut commands: Commands,
+    mut last_check: Local<SimTick>,
+    delays: Res<InputDelays>,
+    clients: Query<(&NetworkId, &NetworkStats)>,
+    sim_tick: Res<SimulationTick>,
+    settings: Res<SimulationSettings>,
+) {
+    let Some(adjustment) = settings.input_delay_adjustment else { return };
+    if **sim_tick < *last_check + adjustment.interval.max(1) && **sim_tick >= *last_check { return }
+    *last_check = **sim_tick;
+    for (id, stats) in clients.iter() {
+        let client = id.get();
+        let current = delays.get(&client);
+        if current.is_some_and(|current| current.pinned) { continue }
+        let wanted = rtt_to_ticks(stats.rtt, &settings).max(1) + settings.base_input_tick_delay as SimTick;
+        let drifted = current
+            .and_then(|current| current.delay)
+            .is_none_or(|delay| delay.abs_diff(wanted) >= adjustment.drift_threshold.max(1));
+        if drifted {
+            broadcast_input_delay(&mut commands, &delays, client, Some(wanted), **sim_tick + adjustment.lead_ticks.max(1), false);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy_replicon::shared::backend::connected_client::NetworkId;
+use crate::prelude::*;
+use super::ServerSendCommands;
+
+/// Tracked orders the server hasn't echoed this many ticks after they were
+/// issued are given up on, e.g. rejected by `GameCommand::validate`
+const MAX_PENDING_TICKS: SimTick = 300;
+
+/// Issues commands from systems.  Commands are queued on the
+/// [`LockstepCommandQueue`], which batches everything queued in a frame and
+/// stamps it with the current simulation tick.  Tracked commands are also
+/// kept in [`PendingOrders`] until the server echoes them back, e.g. to
+/// show an order as pending in the UI.
+#[derive(SystemParam)]
+pub struct LockstepCommands<'w> {
+    queue: Res<'w, LockstepCommandQueue>,
+    pending: ResMut<'w, PendingOrders>,
+    sim_tick: Res<'w, SimulationTick>,
+}
+
+impl LockstepCommands<'_> {
+    /// Queue a command on the gameplay stream
+    pub fn queue(&mut self, command: impl PartialReflect) {
+        self.queue.push(command);
+    }
+
+    /// Queue a command on an additional command stream
+    pub fn queue_to_stream(&mut self, stream: CommandStreamId, command: impl PartialReflect) {
+        self.queue.push_to_stream(stream, command);
+    }
+
+    /// Queue a command on the gameplay stream and keep a copy in
+    /// [`PendingOrders`] until the server echoes it back
+    pub fn queue_tracked(&mut self, command: impl PartialReflect) -> PendingOrderId {
+        let id = self.pending.track(&command, **self.sim_tick);
+        self.queue.push(command);
+        id
+    }
+
+    pub fn pending(&self) -> &PendingOrders {
+        &self.pending
+    }
+}
+
+/// Identifies a command queued with [`LockstepCommands::queue_tracked`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PendingOrderId(u64);
+
+pub struct PendingOrder {
+    pub id: PendingOrderId,
+    pub issued_tick: SimTick,
+    pub command: Box<dyn PartialReflect>,
+}
+
+/// Tracked commands of the local client the server hasn't echoed yet, in
+/// the order they were queued
+#[derive(Resource, Default)]
+pub struct PendingOrders {
+    next_id: u64,
+    orders: Vec<PendingOrder>,
+}
+
+impl PendingOrders {
+    fn track(&mut self, command: &dyn PartialReflect, issued_tick: SimTick) -> PendingOrderId {
+        self.next_id += 1;
+        let id = PendingOrderId(self.next_id);
+        self.orders.push(PendingOrder { id, issued_tick, command: command.clone_value() });
+        id
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PendingOrder> {
+        self.orders.iter()
+    }
+
+    pub fn contains(&self, id: PendingOrderId) -> bool {
+        self.orders.iter().any(|order| order.id == id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.orders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orders.is_empty()
+    }
+}
+
+/// A trigger that fires on the local client when a tracked command arrived
+/// back from the server
+#[derive(Event, Debug, Clone, Copy)]
+pub struct OrderConfirmed {
+    pub id: PendingOrderId,
+    /// The tick the command executes on
+    pub tick: SimTick,
+}
+
+/// Matches the local client's echoed commands to the oldest equal pending
+/// order
+pub(super) fn confirm_pending_orders(
+    tick: Trigger<ServerSendCommands>,
+    mut commands: Commands,
+    mut pending: ResMut<PendingOrders>,
+    local_client: Query<&NetworkId, With<LocalClient>>,
+) {
+    if pending.is_empty() { return }
+    let Ok(local_id) = local_client.get_single() else { return };
+    if let Some(echoed) = tick.commands.get(&local_id.get()) {
+        for command in echoed {
+            let Some(index) = pending.orders.iter().position(|order| {
+                order.command.reflect_type_path() == command.reflect_type_path()
+                    && order.command.reflect_partial_eq(command.as_ref()) == Some(true)
+            }) else { continue };
+            let order = pending.orders.remove(index);
+            commands.trigger(OrderConfirmed { id: order.id, tick: tick.tick });
+        }
+    }
+    pending.orders.retain(|order| {
+        let expired = order.issued_tick + MAX_PENDING_TICKS < tick.tick;
+        if expired {
+            debug!("order {:?} issued on tick {} was never echoed", order.id, order.issued_tick);
+        }
+        !expired
+    });
+}
@@ -0,0 +1,141 @@
+use bevy::{
+    ecs::world::EntityMut,
+    prelude::*,
+    tasks::{ComputeTaskPool, TaskPool},
+    utils::hashbrown::HashMap,
+};
+use crate::prelude::*;
+use super::game_command::{GameCommand, ReflectGameCommand};
+
+/// A game command that only changes the entity it targets.  With
+/// `SimulationSettings::parallel_apply_threshold` set, runs of such commands
+/// in a tick are grouped by target and the groups are applied in parallel.
+/// Commands sharing a target conflict and run in tick order within their
+/// group, so the result is the same as applying every command in order.
+/// [`GameCommand::apply`] is still used for small batches and targets
+/// without an entity, and should have the same effect as `apply_to`.
+pub trait ParallelGameCommand: GameCommand {
+    /// The simulation entity the command changes
+    fn target(&self) -> SimulationId;
+
+    /// Apply the command to its target only.  Runs concurrently with
+    /// commands on other targets.
+    fn apply_to(&self, ctx: &CommandContext, entity: &mut EntityMut);
+}
+
+/// Type data attached to types registered with
+/// [`LockstepCommandAppExt::register_parallel_game_command`]
+#[derive(Clone, Copy)]
+pub struct ReflectParallelGameCommand {
+    target: fn(&dyn PartialReflect) -> Option<SimulationId>,
+    apply_to: fn(&dyn PartialReflect, &CommandContext, &mut EntityMut),
+}
+
+impl ReflectParallelGameCommand {
+    pub(super) fn of<T: ParallelGameCommand>() -> Self {
+        Self {
+            target: |command| T::from_reflect(command).map(|command| command.target()),
+            apply_to: |command, ctx, entity| {
+                match T::from_reflect(command) {
+                    Some(command) => command.apply_to(ctx, entity),
+                    None => warn!("could not convert command to {}", T::type_path()),
+                }
+            },
+        }
+    }
+
+    pub fn target(&self, command: &dyn PartialReflect) -> Option<SimulationId> {
+        (self.target)(command)
+    }
+
+    pub fn apply_to(&self, command: &dyn PartialReflect, ctx: &CommandContext, entity: &mut EntityMut) {
+        (self.apply_to)(command, ctx, entity)
+    }
+}
+
+struct PendingCommand<'a> {
+    ctx: CommandContext,
+    command: &'a dyn PartialReflect,
+    apply: ReflectGameCommand,
+    parallel: ReflectParallelGameCommand,
+}
+
+/// Consecutive parallel commands of a tick, applied together once a command
+/// that needs the whole world comes up
+#[derive(Default)]
+pub(super) struct ParallelBatch<'a> {
+    commands: Vec<PendingCommand<'a>>,
+}
+
+impl<'a> ParallelBatch<'a> {
+    pub(super) fn push(
+        &mut self,
+        ctx: CommandContext,
+        command: &'a dyn PartialReflect,
+        apply: ReflectGameCommand,
+        parallel: ReflectParallelGameCommand,
+    ) {
+        self.commands.push(PendingCommand { ctx, command, apply, parallel });
+    }
+
+    /// Applies the batch, in parallel if it has at least `threshold`
+    /// commands
+    pub(super) fn apply(&mut self, world: &mut World, threshold: usize) {
+        if self.commands.is_empty() { return }
+        let commands = std::mem::take(&mut self.commands);
+        if commands.len() < threshold {
+            for pending in commands {
+                pending.apply.apply(pending.command, &pending.ctx, world);
+            }
+            return;
+        }
+
+        // Group by target entity, keeping tick order within each group
+        let mut groups: Vec<(Entity, Vec<&PendingCommand>)> = Vec::new();
+        let mut group_of = HashMap::<Entity, usize>::new();
+        let mut unresolved = Vec::new();
+        {
+            let ids = world.resource::<SimulationIdEntityMap>();
+            for pending in commands.iter() {
+                let entity = pending.parallel.target(pending.command).and_then(|id| ids.get(&id).copied());
+                let Some(entity) = entity else {
+                    unresolved.push(pending);
+                    continue;
+                };
+                let group = *group_of.entry(entity).or_insert_with(|| {
+                    groups.push((entity, Vec::new()));
+                    groups.len() - 1
+                });
+                groups[group].1.push(pending);
+            }
+        }
+        // Targets without an entity, e.g. spawned earlier in this tick, can't
+        // be touched by the others
+        for pending in unresolved {
+            pending.apply.apply(pending.command, &pending.ctx, world);
+        }
+        groups.retain(|(entity, _)| world.get_entity(*entity).is_ok());
+        let entities: Vec<Entity> = groups.iter().map(|(entity, _)| *entity).collect();
+        let Ok(entity_muts) = world.get_entity_mut(entities.as_slice()) else {
+            warn!("could not fetch the targets of {} parallel commands", commands.len());
+            return;
+        };
+        let mut work: Vec<(EntityMut, Vec<&PendingCommand>)> = entity_muts
+            .into_iter()
+            .zip(groups.into_iter().map(|(_, group)| group))
+            .collect();
+        let pool = ComputeTaskPool::get_or_init(TaskPool::default);
+        let chunk_size = work.len().div_ceil(pool.thread_num().max(1)).max(1);
+        pool.scope(|scope| {
+            for chunk in work.chunks_mut(chunk_size) {
+                scope.spawn(async move {
+                    for (entity, group) in chunk.iter_mut() {
+                        for pending in group.iter() {
+                            pending.parallel.apply_to(pending.command, &pending.ctx, entity);
+                        }
+                    }
+                });
+            }
+        });
+    }
+}
@@ -0,0 +1,70 @@
+use bevy::{ecs::schedule::ScheduleLabel, prelude::*};
+use crate::{prelude::*, simulation::SettingsTimeline};
+
+/// Runs once per applied tick, after the tick's game commands were applied
+/// and before [`PhysicsStep`].  Systems here see the commands' effects, e.g.
+/// forces they added, and can prepare the physics state for the step.
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BeforePhysicsStep;
+
+/// Steps physics by one tick.  With the `avian` feature the step is added
+/// by [`LockstepAvianPlugin`], otherwise add the physics engine's stepping
+/// here.  The generic [`Time`] advances by the tick timestep while it runs.
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PhysicsStep;
+
+/// Runs once per applied tick, after [`PhysicsStep`] and before
+/// [`GameCommandsApplied`] fires
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AfterPhysicsStep;
+
+/// Runs the physics step and its hooks for one tick.  The generic clock is
+/// swapped for one that advanced by exactly the timestep of `tick`, so
+/// systems reading `Res<Time>` don't depend on the frame rate of the peer,
+/// nor on when a tick rate change reached it.
+pub(super) fn run_physics_step(world: &mut World, tick: SimTick) {
+    let timestep = world
+        .resource::<SettingsTimeline>()
+        .tick_timestep(tick)
+        .unwrap_or(world.resource::<SimulationSettings>().tick_timestep);
+    let mut tick_time = world.resource::<Time>().clone();
+    tick_time.advance_by(timestep);
+    let frame_time = std::mem::replace(&mut *world.resource_mut::<Time>(), tick_time);
+    world.run_schedule(BeforePhysicsStep);
+    world.run_schedule(PhysicsStep);
+    world.run_schedule(AfterPhysicsStep);
+    *world.resource_mut::<Time>() = frame_time;
+}
+
+/// Steps avian physics in [`PhysicsStep`], so it only advances with the
+/// lockstep ticks.  Add `PhysicsPlugins` as usual, its clock is paused here
+/// and advanced by the tick timestep on every step.
+#[cfg(feature = "avian")]
+pub struct LockstepAvianPlugin;
+
+#[cfg(feature = "avian")]
+impl Plugin for LockstepAvianPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .add_systems(Startup, pause_avian_time)
+            .add_systems(PhysicsStep, step_avian_physics);
+    }
+}
+
+#[cfg(feature = "avian")]
+fn pause_avian_time(time: Option<ResMut<Time<avian3d::prelude::Physics>>>) {
+    match time {
+        Some(mut time) => time.pause(),
+        None => warn!("LockstepAvianPlugin needs PhysicsPlugins"),
+    }
+}
+
+/// Advances by the delta of the tick clock set up by `run_physics_step`
+#[cfg(feature = "avian")]
+fn step_avian_physics(world: &mut World) {
+    let timestep = world.resource::<Time>().delta();
+    world
+        .resource_mut::<Time<avian3d::prelude::Physics>>()
+        .advance_by(timestep);
+    world.run_schedule(avian3d::prelude::PhysicsSchedule);
+}
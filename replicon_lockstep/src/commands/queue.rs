@@ -0,0 +1,145 @@
+use std::{collections::{BTreeMap, VecDeque}, sync::{Arc, Mutex, MutexGuard}};
+use bevy::prelude::*;
+use bevy_replicon::prelude::*;
+use crate::{prelude::*, commands::send_client_commands};
+
+/// A thread safe handle for issuing commands, also from outside of ECS
+/// systems, e.g. pathfinding jobs or async UI flows.  Clone it out of the
+/// resource and move it into the task.  Queued commands are coalesced
+/// according to `SimulationSettings::command_coalescing_window` and sent to
+/// the server as one [`ClientSendCommands`] per stream, stamped with the
+/// current simulation tick.
+#[derive(Resource, Clone, Default)]
+pub struct LockstepCommandQueue(Arc<Mutex<QueueState>>);
+
+#[derive(Default)]
+struct QueueState {
+    pending: Vec<(CommandStreamId, Box<dyn PartialReflect>)>,
+    spread: VecDeque<SpreadBatch>,
+}
+
+/// A large batch released a chunk per tick
+struct SpreadBatch {
+    stream: CommandStreamId,
+    commands: VecDeque<Box<dyn PartialReflect>>,
+    max_per_tick: usize,
+}
+
+impl LockstepCommandQueue {
+    /// Queue a command on the gameplay stream
+    pub fn push(&self, command: impl PartialReflect) {
+        self.push_to_stream(GAMEPLAY_STREAM, command);
+    }
+
+    /// Queue a command on an additional command stream
+    pub fn push_to_stream(&self, stream: CommandStreamId, command: impl PartialReflect) {
+        self.push_boxed(stream, Box::new(command));
+    }
+
+    /// Queue an already boxed command
+    pub fn push_boxed(&self, stream: CommandStreamId, command: Box<dyn PartialReflect>) {
+        self.lock().pending.push((stream, command));
+    }
+
+    /// Queue a large batch of gameplay commands, e.g. scripted scenario
+    /// setup, to be sent at most `max_per_tick` at a time on consecutive
+    /// ticks.  Commands keep their order, and batches queued one after the
+    /// other are sent one after the other.
+    pub fn send_commands_spread<C: PartialReflect>(&self, commands: impl IntoIterator<Item = C>, max_per_tick: usize) {
+        let boxed = commands.into_iter().map(|command| Box::new(command) as Box<dyn PartialReflect>);
+        self.send_boxed_spread(GAMEPLAY_STREAM, boxed, max_per_tick);
+    }
+
+    /// Like [`LockstepCommandQueue::send_commands_spread`] for already boxed
+    /// commands on any stream
+    pub fn send_boxed_spread(
+        &self,
+        stream: CommandStreamId,
+        commands: impl IntoIterator<Item = Box<dyn PartialReflect>>,
+        max_per_tick: usize,
+    ) {
+        let commands: VecDeque<_> = commands.into_iter().collect();
+        if commands.is_empty() { return }
+        self.lock().spread.push_back(SpreadBatch { stream, commands, max_per_tick: max_per_tick.max(1) });
+    }
+
+    /// Queue commands ahead of everything already queued, keeping their order
+    pub(super) fn push_front(&self, commands: impl IntoIterator<Item = (CommandStreamId, Box<dyn PartialReflect>)>) {
+        let mut queue = self.lock();
+        let rest = core::mem::take(&mut queue.pending);
+        queue.pending.extend(commands);
+        queue.pending.extend(rest);
+    }
+
+    /// Number of commands waiting to be sent, including spread batches
+    pub fn len(&self) -> usize {
+        let queue = self.lock();
+        queue.pending.len() + queue.spread.iter().map(|batch| batch.commands.len()).sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Moves the next chunk of the oldest spread batch to the pending commands
+    fn release_spread_chunk(&self) -> bool {
+        let mut queue = self.lock();
+        let Some(batch) = queue.spread.front_mut() else { return false };
+        let count = batch.max_per_tick.min(batch.commands.len());
+        let stream = batch.stream;
+        let chunk: Vec<_> = batch.commands.drain(..count).map(|command| (stream, command)).collect();
+        if batch.commands.is_empty() {
+            queue.spread.pop_front();
+        }
+        queue.pending.extend(chunk);
+        true
+    }
+
+    fn has_pending(&self) -> bool {
+        !self.lock().pending.is_empty()
+    }
+
+    fn drain(&self) -> Vec<(CommandStreamId, Box<dyn PartialReflect>)> {
+        core::mem::take(&mut self.lock().pending)
+    }
+}
+
+/// Sends queued commands to the server, one message per stream, once the
+/// coalescing window has passed or the tick has changed
+pub(super) fn drain_command_queue(
+    mut commands: Commands,
+    queue: Res<LockstepCommandQueue>,
+    sim_tick: Res<SimulationTick>,
+    settings: Res<SimulationSettings>,
+    time: Res<Time<Real>>,
+    mut window_start: Local<Option<(f64, SimTick)>>,
+    mut spread_tick: Local<Option<SimTick>>,
+) {
+    // At most one chunk of spread batches per tick
+    if *spread_tick != Some(**sim_tick) && queue.release_spread_chunk() {
+        *spread_tick = Some(**sim_tick);
+    }
+    if !queue.has_pending() { return }
+    let now = time.elapsed_secs_f64();
+    let (started, tick) = *window_start.get_or_insert((now, **sim_tick));
+    let window = settings.command_coalescing_window.as_secs_f64();
+    if tick == **sim_tick && now - started < window { return }
+    *window_start = None;
+
+    let mut streams: BTreeMap<CommandStreamId, Vec<Box<dyn PartialReflect>>> = BTreeMap::new();
+    for (stream, command) in queue.drain() {
+        streams.entry(stream).or_default().push(command);
+    }
+    for (stream, stream_commands) in streams {
+        send_client_commands(&mut commands, ClientSendCommands {
+            issued_tick: **sim_tick,
+            commands: stream_commands,
+            stream,
+            ..default()
+        });
+    }
+}
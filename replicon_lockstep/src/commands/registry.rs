@@ -0,0 +1,308 @@
+use std::{any::TypeId, fmt};
+use bevy::{
+    ecs::system::IntoObserverSystem,
+    prelude::*,
+    reflect::{
+        serde::{ReflectDeserializer, ReflectSerializer, TypedReflectDeserializer, TypedReflectSerializer},
+        GetTypeRegistration, TypeRegistry,
+    },
+};
+use serde::{
+    de::{self, DeserializeSeed, SeqAccess, Visitor},
+    ser::SerializeTuple,
+    Deserializer, Serialize, Serializer,
+};
+use crate::{prelude::*, hashing::stable_hash};
+use super::{game_command::{GameCommand, ReflectGameCommand}, parallel::{ParallelGameCommand, ReflectParallelGameCommand}, catch_up::ReflectCatchUpCommand, codec::CompactCommandIds, versions::{UpgradeCommand, ReflectUpgradeCommand}, typed::{self, LockstepCommand, ReflectCommandTrigger, ReflectCommandHandler, LockstepCommandItems, LockstepCommandHandlers, CommandHandlerSet, CommandHandlerCount}};
+
+/// Stable identifier of a registered command type on the wire
+pub type LockstepCommandId = u32;
+
+/// Type data attached to command types registered with
+/// [`LockstepCommandAppExt::register_lockstep_command`]
+#[derive(Clone, Copy, Debug)]
+pub struct ReflectLockstepCommand {
+    pub id: LockstepCommandId,
+}
+
+/// Derive the stable id for a command type from its type path.  Every
+/// monomorphized instance of a generic command has its own type path, and so
+/// its own id.  Id 0 is reserved for unregistered types.
+pub fn lockstep_command_id(type_path: &str) -> LockstepCommandId {
+    (stable_hash(type_path.as_bytes()) as LockstepCommandId).max(1)
+}
+
+pub trait LockstepCommandAppExt {
+    /// Register a command type.  Registered commands are sent with a compact
+    /// id instead of their full type path.  Generic commands must be
+    /// registered once per concrete type, e.g. `TargetedCommand<Attack>`.
+    fn register_lockstep_command<T: Reflect + TypePath + GetTypeRegistration>(&mut self) -> &mut Self;
+
+    /// Register a command type that validates and applies itself, see [`GameCommand`]
+    fn register_game_command<T: GameCommand>(&mut self) -> &mut Self;
+
+    /// Register a game command that only changes its target, so it can be
+    /// applied in parallel, see [`ParallelGameCommand`]
+    fn register_parallel_game_command<T: ParallelGameCommand>(&mut self) -> &mut Self;
+
+    /// Mark a registered command type as required for late-join catch-up.
+    /// Once any type is marked, clients catching up only receive the history
+    /// of marked types, so the rest of the game state must be derivable
+    /// from them.
+    fn register_catch_up_command<T: Reflect + TypePath + GetTypeRegistration>(&mut self) -> &mut Self;
+
+    /// Register a previous version of a command type and its upgrade to the
+    /// next version, registering either if it isn't yet.  Peers may then
+    /// register different versions and still play together, see
+    /// [`CommandVersions`].
+    fn register_command_upgrade<T: UpgradeCommand>(&mut self) -> &mut Self;
+
+    /// Register a command type, if it isn't yet, and an observer of
+    /// [`LockstepCommand<T>`] that runs for each such command when its tick
+    /// is applied
+    fn add_lockstep_command_observer<T, B, M>(
+        &mut self,
+        observer: impl IntoObserverSystem<LockstepCommand<T>, B, M>,
+    ) -> &mut Self
+    where
+        T: Reflect + FromReflect + TypePath + GetTypeRegistration,
+        B: Bundle;
+
+    /// Register a command type, if it isn't yet, and a system that handles
+    /// the commands of that type in each applied tick.  The handler reads
+    /// them from [`LockstepCommandItems<T>`] in [`LockstepCommandHandlers`],
+    /// in client and command order, e.g. one handler per command type
+    /// instead of one system matching every command.  Handlers run one
+    /// after the other in the order they were added, the systems of one
+    /// call are only ordered if chained.
+    fn on_lockstep_command<T, M>(&mut self, handler: impl IntoSystemConfigs<M>) -> &mut Self
+    where
+        T: Reflect + FromReflect + TypePath + GetTypeRegistration;
+}
+
+impl LockstepCommandAppExt for App {
+    fn register_lockstep_command<T: Reflect + TypePath + GetTypeRegistration>(&mut self) -> &mut Self {
+        self.register_type::<T>();
+        let id = lockstep_command_id(T::type_path());
+        let registry = self.world().resource::<AppTypeRegistry>().clone();
+        {
+            let mut registry = registry.write();
+            if let Some((other, _)) = registry
+                .iter_with_data::<ReflectLockstepCommand>()
+                .find(|(registration, data)| data.id == id && registration.type_id() != TypeId::of::<T>())
+            {
+                panic!("Lockstep command id collision between {} and {}",
+                    T::type_path(), other.type_info().type_path());
+            }
+            registry
+                .get_mut(TypeId::of::<T>())
+                .expect("type was just registered")
+                .insert(ReflectLockstepCommand { id });
+        }
+        self.world_mut()
+            .get_resource_or_insert_with(SessionIdentity::default)
+            .command_types
+            .insert(T::type_path().to_string());
+        self
+    }
+
+    fn register_game_command<T: GameCommand>(&mut self) -> &mut Self {
+        self.register_lockstep_command::<T>();
+        self.world()
+            .resource::<AppTypeRegistry>()
+            .write()
+            .get_mut(TypeId::of::<T>())
+            .expect("type was just registered")
+            .insert(ReflectGameCommand::of::<T>());
+        self
+    }
+
+    fn register_parallel_game_command<T: ParallelGameCommand>(&mut self) -> &mut Self {
+        self.register_game_command::<T>();
+        self.world()
+            .resource::<AppTypeRegistry>()
+            .write()
+            .get_mut(TypeId::of::<T>())
+            .expect("type was just registered")
+            .insert(ReflectParallelGameCommand::of::<T>());
+        self
+    }
+
+    fn register_catch_up_command<T: Reflect + TypePath + GetTypeRegistration>(&mut self) -> &mut Self {
+        self.register_lockstep_command::<T>();
+        self.world()
+            .resource::<AppTypeRegistry>()
+            .write()
+            .get_mut(TypeId::of::<T>())
+            .expect("type was just registered")
+            .insert(ReflectCatchUpCommand);
+        self
+    }
+
+    fn register_command_upgrade<T: UpgradeCommand>(&mut self) -> &mut Self {
+        if !is_lockstep_command(self, TypeId::of::<T>()) {
+            self.register_lockstep_command::<T>();
+        }
+        if !is_lockstep_command(self, TypeId::of::<T::Next>()) {
+            self.register_lockstep_command::<T::Next>();
+        }
+        self.world()
+            .resource::<AppTypeRegistry>()
+            .write()
+            .get_mut(TypeId::of::<T>())
+            .expect("type is registered")
+            .insert(ReflectUpgradeCommand::of::<T>());
+        self
+    }
+
+    fn add_lockstep_command_observer<T, B, M>(
+        &mut self,
+        observer: impl IntoObserverSystem<LockstepCommand<T>, B, M>,
+    ) -> &mut Self
+    where
+        T: Reflect + FromReflect + TypePath + GetTypeRegistration,
+        B: Bundle,
+    {
+        let registered = self.world()
+            .resource::<AppTypeRegistry>()
+            .read()
+            .get_type_data::<ReflectLockstepCommand>(TypeId::of::<T>())
+            .is_some();
+        if !registered {
+            self.register_lockstep_command::<T>();
+        }
+        self.world()
+            .resource::<AppTypeRegistry>()
+            .write()
+            .get_mut(TypeId::of::<T>())
+            .expect("type is registered")
+            .insert(ReflectCommandTrigger::of::<T>());
+        self.add_observer(observer)
+    }
+
+    fn on_lockstep_command<T, M>(&mut self, handler: impl IntoSystemConfigs<M>) -> &mut Self
+    where
+        T: Reflect + FromReflect + TypePath + GetTypeRegistration,
+    {
+        let registered = self.world()
+            .resource::<AppTypeRegistry>()
+            .read()
+            .get_type_data::<ReflectLockstepCommand>(TypeId::of::<T>())
+            .is_some();
+        if !registered {
+            self.register_lockstep_command::<T>();
+        }
+        // The items of a type are collected and cleared once, however many
+        // handlers it has
+        if !self.world().contains_resource::<LockstepCommandItems<T>>() {
+            self.world()
+                .resource::<AppTypeRegistry>()
+                .write()
+                .get_mut(TypeId::of::<T>())
+                .expect("type is registered")
+                .insert(ReflectCommandHandler::of::<T>());
+            self.init_resource::<LockstepCommandItems<T>>()
+                .configure_sets(LockstepCommandHandlers, (CommandHandlerSet::Handle, CommandHandlerSet::Clear).chain())
+                .add_systems(LockstepCommandHandlers, typed::clear_command_items::<T>.in_set(CommandHandlerSet::Clear));
+        }
+        // Handlers run one after the other in registration order, so they
+        // apply commands the same way on every peer
+        let index = {
+            let mut count = self.world_mut().get_resource_or_insert_with(CommandHandlerCount::default);
+            count.0 += 1;
+            count.0
+        };
+        self.configure_sets(LockstepCommandHandlers, CommandHandlerSet::Handler(index).in_set(CommandHandlerSet::Handle));
+        if index > 1 {
+            self.configure_sets(LockstepCommandHandlers, CommandHandlerSet::Handler(index)
+                .after(CommandHandlerSet::Handler(index - 1)));
+        }
+        self.add_systems(LockstepCommandHandlers, handler.in_set(CommandHandlerSet::Handler(index)))
+    }
+}
+
+fn is_lockstep_command(app: &App, type_id: TypeId) -> bool {
+    app.world()
+        .resource::<AppTypeRegistry>()
+        .read()
+        .get_type_data::<ReflectLockstepCommand>(type_id)
+        .is_some()
+}
+
+/// Serializes one command as `(id, payload)`.  Registered commands use their
+/// stable id, or their compact id if given, and a typed payload, others use
+/// id 0 and the full type path.
+pub(crate) struct CommandSerializer<'a> {
+    pub(crate) command: &'a dyn PartialReflect,
+    pub(crate) registry: &'a TypeRegistry,
+    pub(crate) compact: Option<&'a CompactCommandIds>,
+}
+
+impl Serialize for CommandSerializer<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let id = self.command
+            .get_represented_type_info()
+            .and_then(|info| match self.compact {
+                Some(compact) => compact.id(info.type_id()),
+                None => self.registry.get_type_data::<ReflectLockstepCommand>(info.type_id()).map(|data| data.id),
+            });
+        let mut tuple = serializer.serialize_tuple(2)?;
+        match id {
+            Some(id) => {
+                tuple.serialize_element(&id)?;
+                tuple.serialize_element(&TypedReflectSerializer::new(self.command, self.registry))?;
+            }
+            None => {
+                tuple.serialize_element(&(0 as LockstepCommandId))?;
+                tuple.serialize_element(&ReflectSerializer::new(self.command, self.registry))?;
+            }
+        }
+        tuple.end()
+    }
+}
+
+/// Counterpart of [`CommandSerializer`]
+pub(crate) struct CommandDeserializer<'a> {
+    pub(crate) registry: &'a TypeRegistry,
+    pub(crate) compact: Option<&'a CompactCommandIds>,
+}
+
+impl<'de> DeserializeSeed<'de> for CommandDeserializer<'_> {
+    type Value = Box<dyn PartialReflect>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_tuple(2, CommandVisitor { registry: self.registry, compact: self.compact })
+    }
+}
+
+struct CommandVisitor<'a> {
+    registry: &'a TypeRegistry,
+    compact: Option<&'a CompactCommandIds>,
+}
+
+impl<'de> Visitor<'de> for CommandVisitor<'_> {
+    type Value = Box<dyn PartialReflect>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a lockstep command id followed by its payload")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let id: LockstepCommandId = seq.next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let command = if id == 0 {
+            seq.next_element_seed(ReflectDeserializer::new(self.registry))?
+        } else {
+            let registration = match self.compact {
+                Some(compact) => compact.type_id(id).and_then(|type_id| self.registry.get(type_id)),
+                None => self.registry
+                    .iter_with_data::<ReflectLockstepCommand>()
+                    .find(|(_, data)| data.id == id)
+                    .map(|(registration, _)| registration),
+            }
+                .ok_or_else(|| de::Error::custom(format!("unknown lockstep command id {}", id)))?;
+            seq.next_element_seed(TypedReflectDeserializer::new(registration, self.registry))?
+        };
+        command.ok_or_else(|| de::Error::invalid_length(1, &self))
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::{BTreeMap, BTreeSet};
+use bevy::{prelude::*, reflect::TypeRegistry};
+use bevy_replicon::{prelude::*, shared::backend::connected_client::NetworkId};
+use serde::{Deserialize, Serialize};
+use crate::prelude::*;
+use super::{codec::TickCompression, versions::{self, SessionCommandVersions}};
+
+/// Sent by every client on entering Setup with the command types it
+/// registered, so the server can compare them against its own
+#[derive(Event, Serialize, Deserialize, Debug, Clone)]
+pub(super) struct CommandRegistryReport {
+    types: Vec<(LockstepCommandId, String)>,
+    /// Registered command upgrades as `(previous, next)`
+    upgrades: Vec<(LockstepCommandId, LockstepCommandId)>,
+    /// Whether the client can read compressed ticks
+    decompresses: bool,
+}
+
+/// Marker on the server's client entities whose registered command types
+/// match the server's.  Setup only finishes once every client has it.
+#[derive(Component, Default, Debug)]
+pub struct CommandRegistryVerified;
+
+/// Fires on the server, and on the client concerned, when a client's
+/// registered command types differ from the server's.  Usually a
+/// `register_lockstep_command` missing on one side.  Versioned types may
+/// differ as long as every peer shares a version, see [`CommandVersions`].
+/// The client never becomes verified, so the match doesn't start.
+#[derive(Event, Serialize, Deserialize, Debug, Clone)]
+pub struct CommandRegistryMismatch {
+    pub client: ClientId,
+    /// Type paths the client registered but the server didn't
+    pub missing_on_server: Vec<String>,
+    /// Type paths the server registered but the client didn't
+    pub missing_on_client: Vec<String>,
+}
+
+/// The registered command types by id
+fn registered_commands(registry: &TypeRegistry) -> BTreeMap<LockstepCommandId, String> {
+    registry
+        .iter_with_data::<ReflectLockstepCommand>()
+        .map(|(registration, data)| (data.id, registration.type_info().type_path().to_string()))
+        .collect()
+}
+
+/// The versions of each versioned command type, keyed by the newest one
+fn version_chains(upgrades: &[(LockstepCommandId, LockstepCommandId)]) -> BTreeMap<LockstepCommandId, BTreeSet<LockstepCommandId>> {
+    let next: BTreeMap<_, _> = upgrades.iter().copied().collect();
+    let mut chains = BTreeMap::<_, BTreeSet<_>>::new();
+    for &(previous, _) in upgrades {
+        let mut newest = previous;
+        // Bounded in case of a cycle
+        for _ in 0..=upgrades.len() {
+            let Some(&id) = next.get(&newest) else { break };
+            newest = id;
+        }
+        let chain = chains.entry(newest).or_default();
+        chain.insert(previous);
+        chain.insert(newest);
+    }
+    chains
+}
+
+pub(super) fn send_command_registry(mut commands: Commands, registry: Res<AppTypeRegistry>) {
+    let registry = registry.read();
+    let types: Vec<_> = registered_commands(&registry).into_iter().collect();
+    let upgrades = versions::registered_upgrades(&registry);
+    trace!("sending {} registered command types", types.len());
+    commands.client_trigger(CommandRegistryReport { types, upgrades, decompresses: cfg!(feature = "compression") });
+}
+
+pub(super) fn receive_command_registry(
+    trigger: Trigger<FromClient<CommandRegistryReport>>,
+    mut commands: Commands,
+    clients: LockstepClients,
+    registry: Res<AppTypeRegistry>,
+    state: Res<State<SimulationState>>,
+    ids: Query<&NetworkId>,
+    mut command_versions: ResMut<CommandVersions>,
+    settings: Res<SimulationSettings>,
+    mut compression: ResMut<TickCompression>,
+    // The host is the server and has the types already
+    verified: Query<Entity, (With<CommandRegistryVerified>, Without<HostingSeat>)>,
+) {
+    let joining = match *state.get() {
+        SimulationState::Setup => false,
+        SimulationState::None | SimulationState::Lobby | SimulationState::Connecting => return,
+        // A client joining the match in progress, which can't change the
+        // command types of the match any more
+        _ => true,
+    };
+    let Some(client) = clients.entity(trigger.client_entity) else { return };
+    let Ok(client_id) = ids.get(client).map(|id| id.get()) else { return };
+    let mut registry = registry.write();
+    let server_types = registered_commands(&registry);
+    let server_upgrades = versions::registered_upgrades(&registry);
+    let client_types: BTreeMap<_, _> = trigger.event.types.iter().cloned().collect();
+    // Versions known to only one side are fine, as long as a common one is left
+    let versioned: BTreeSet<LockstepCommandId> = server_upgrades
+        .iter()
+        .chain(trigger.event.upgrades.iter())
+        .flat_map(|&(previous, next)| [previous, next])
+        .collect();
+
+    let missing_on_server: Vec<String> = client_types
+        .iter()
+        .filter(|(id, _)| !server_types.contains_key(id) && !versioned.contains(id))
+        .map(|(_, path)| path.clone())
+        .collect();
+    let mut missing_on_client: Vec<String> = server_types
+        .iter()
+        .filter(|(id, _)| !client_types.contains_key(id) && !versioned.contains(id))
+        .map(|(_, path)| path.clone())
+        .collect();
+    let client_ids: BTreeSet<LockstepCommandId> = client_types.keys().copied().collect();
+    let narrowed = !joining && command_versions.retain(&client_ids);
+    // Ticks carry whether they are compressed, so compression can stop for
+    // a client joining the match in progress too
+    let uncompressed = !trigger.event.decompresses && compression.enabled();
+    if uncompressed {
+        debug!("client {} can't read compressed ticks, sending them uncompressed", client_id);
+        compression.0 = false;
+    }
+    if narrowed || uncompressed {
+        super::codec::number_command_types(&mut registry, &command_versions, compression.threshold(&settings));
+    }
+    if narrowed {
+        debug!("client {} narrowed the command types of the match", client_id);
+        for client in verified.iter() {
+            send_command_versions(&mut commands, client, &command_versions);
+        }
+    }
+    // Agreed versions the client lacks, which only a client joining the
+    // match in progress can have
+    missing_on_client.extend(command_versions
+        .ids()
+        .into_iter()
+        .flatten()
+        .filter(|id| versioned.contains(id) && !client_ids.contains(id))
+        .filter_map(|id| server_types.get(id))
+        .cloned());
+    for chain in version_chains(&server_upgrades).into_values() {
+        if !chain.iter().any(|&id| command_versions.contains(id)) {
+            missing_on_client.extend(chain.iter().filter_map(|id| server_types.get(id)).cloned());
+        }
+    }
+    if missing_on_server.is_empty() && missing_on_client.is_empty() {
+        trace!("client {} command registry verified", client_id);
+        commands.entity(client).insert(CommandRegistryVerified);
+        send_command_versions(&mut commands, trigger.client_entity, &command_versions);
+        return;
+    }
+
+    error!(
+        "client {} command types don't match the server, missing on server: {:?}, missing on client: {:?}",
+        client_id, missing_on_server, missing_on_client,
+    );
+    let mismatch = CommandRegistryMismatch { client: client_id, missing_on_server, missing_on_client };
+    // Sending to the server's own seat already fires it here
+    if !clients.is_local(trigger.client_entity) {
+        commands.trigger(mismatch.clone());
+    }
+    commands.server_trigger(ToClients {
+        mode: SendMode::Direct(trigger.client_entity),
+        event: mismatch,
+    });
+}
+
+/// Clients number the command types once they know which ones every peer
+/// registered, so they are sent to each client directly as it's verified
+/// and again whenever they narrow
+fn send_command_versions(commands: &mut Commands, client: Entity, versions: &CommandVersions) {
+    let Some(common) = versions.ids() else { return };
+    commands.server_trigger(ToClients {
+        mode: SendMode::Direct(client),
+        event: SessionCommandVersions { common: common.clone() },
+    });
+}
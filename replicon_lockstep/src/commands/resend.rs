@@ -0,0 +1,183 @@
+use std::collections::{BTreeMap, BTreeSet};
+use bevy::{prelude::*, utils::hashbrown::HashMap};
+use bevy_replicon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::prelude::*;
+use super::ClientSendCommands;
+
+/// Unacknowledged messages kept for resending.  When the server stops
+/// acknowledging, e.g. it was replaced, the oldest are dropped.
+const MAX_UNACKED_MESSAGES: usize = 256;
+
+/// Sequences received out of order the server remembers per client.  Past
+/// this the gap is given up on, so a lost message can't grow the set forever.
+const MAX_OUT_OF_ORDER_SEQUENCES: usize = 1024;
+
+/// Round trips a message waits for its acknowledgement before it's resent
+const RESEND_RTT_FACTOR: f64 = 1.5;
+
+/// Client-only copies of sent commands the server hasn't acknowledged yet,
+/// by sequence
+#[derive(Resource, Default)]
+pub(crate) struct UnackedCommands {
+    last_sequence: u32,
+    /// Time the message was last sent, and the message
+    pending: BTreeMap<u32, (f64, ClientSendCommands)>,
+}
+
+/// The sequences a client sent that reached the server
+pub(crate) struct ReceivedSequences {
+    client_entity: Entity,
+    /// Every sequence before this one was received
+    next: u32,
+    /// Received sequences past a gap
+    ahead: BTreeSet<u32>,
+    /// The client should be told about `next`
+    ack_pending: bool,
+}
+
+impl ReceivedSequences {
+    /// Records a received sequence, false if it was received before
+    fn receive(&mut self, sequence: u32) -> bool {
+        if sequence < self.next || !self.ahead.insert(sequence) { return false }
+        while self.ahead.remove(&self.next) {
+            self.next += 1;
+        }
+        if self.ahead.len() > MAX_OUT_OF_ORDER_SEQUENCES {
+            self.next = self.ahead.pop_first().unwrap_or(self.next) + 1;
+            while self.ahead.remove(&self.next) {
+                self.next += 1;
+            }
+        }
+        self.ack_pending = true;
+        true
+    }
+}
+
+/// Server-only sequences received from each client
+#[derive(Resource, Default, Deref, DerefMut)]
+pub(crate) struct ClientSequences(HashMap<ClientId, ReceivedSequences>);
+
+impl ClientSequences {
+    /// Records a message from a client, false if it is a resent duplicate
+    /// that was already handled.  Unsequenced messages are always new.
+    pub(crate) fn receive(&mut self, client_id: ClientId, client_entity: Entity, sequence: u32) -> bool {
+        if sequence == 0 { return true }
+        // The first message seen starts the window, the client may have
+        // sent to a previous server
+        let received = self.entry(client_id).or_insert(ReceivedSequences {
+            client_entity,
+            next: sequence,
+            ahead: BTreeSet::new(),
+            ack_pending: false,
+        });
+        received.client_entity = client_entity;
+        if received.receive(sequence) { return true }
+        // The client resent, so it missed the last acknowledgement
+        received.ack_pending = true;
+        false
+    }
+}
+
+/// Sent unreliably by the server to a client: every command message the
+/// client sent before `next_sequence` arrived
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub(super) struct CommandsAcked {
+    next_sequence: u32,
+}
+
+/// A copy of an unacknowledged [`ClientSendCommands`], sent unreliably so
+/// it doesn't queue behind the original
+#[derive(Event, Default, Clone)]
+pub(super) struct ResentCommands(pub(super) ClientSendCommands);
+
+/// Sends commands to the server.  On a remote client with
+/// `SimulationSettings::command_resend_interval` set, the message gets the
+/// next sequence and a copy is kept until the server acknowledges it.
+pub(crate) fn send_client_commands(commands: &mut Commands, event: ClientSendCommands) {
+    commands.queue(move |world: &mut World| {
+        let mut event = event;
+        let resend = world.resource::<SimulationSettings>().command_resend_interval.is_some();
+        // The host's own commands never leave the process
+        let remote = !world.resource::<RepliconServer>().is_running();
+        if resend && remote {
+            let now = world.resource::<Time<Real>>().elapsed_secs_f64();
+            let mut unacked = world.resource_mut::<UnackedCommands>();
+            unacked.last_sequence += 1;
+            event.sequence = unacked.last_sequence;
+            unacked.pending.insert(event.sequence, (now, event.clone()));
+            if unacked.pending.len() > MAX_UNACKED_MESSAGES {
+                if let Some((sequence, _)) = unacked.pending.pop_first() {
+                    debug!("giving up on resending command message {}", sequence);
+                }
+            }
+        }
+        if remote && world.resource::<SimulationSettings>().bandwidth_diagnostics {
+            let registry = world.resource::<AppTypeRegistry>().clone();
+            world.resource_mut::<LockstepBandwidth>().record_sent(&event, &registry.read());
+        }
+        world.commands().client_trigger(event);
+    });
+}
+
+/// Resends the messages the server didn't acknowledge within the resend
+/// interval, or one and a half round trips if that's longer
+pub(super) fn resend_unacked_commands(
+    mut commands: Commands,
+    mut unacked: ResMut<UnackedCommands>,
+    settings: Res<SimulationSettings>,
+    client: Res<RepliconClient>,
+    time: Res<Time<Real>>,
+    registry: Res<AppTypeRegistry>,
+    mut bandwidth: ResMut<LockstepBandwidth>,
+) {
+    let Some(interval) = settings.command_resend_interval else { return };
+    // An acknowledgement takes a round trip, resending sooner only doubles
+    // the traffic
+    let interval = interval.as_secs_f64().max(client.stats().rtt * RESEND_RTT_FACTOR);
+    let now = time.elapsed_secs_f64();
+    for (sequence, (sent, event)) in unacked.pending.iter_mut() {
+        if now - *sent < interval { continue }
+        trace!("resending command message {} issued on tick {}", sequence, event.issued_tick);
+        *sent = now;
+        if settings.bandwidth_diagnostics {
+            bandwidth.record_sent(event, &registry.read());
+        }
+        commands.client_trigger(ResentCommands(event.clone()));
+    }
+}
+
+/// Drops the copies of acknowledged messages
+pub(super) fn receive_commands_acked(
+    trigger: Trigger<CommandsAcked>,
+    mut unacked: ResMut<UnackedCommands>,
+) {
+    let next_sequence = trigger.next_sequence;
+    unacked.pending = unacked.pending.split_off(&next_sequence);
+}
+
+/// Handles a resent message like the original, duplicates are dropped in
+/// `receive_commands_server`
+pub(super) fn receive_resent_commands(
+    trigger: Trigger<FromClient<ResentCommands>>,
+    mut commands: Commands,
+) {
+    commands.trigger(FromClient {
+        client_entity: trigger.client_entity,
+        event: trigger.event().0.clone(),
+    });
+}
+
+/// Acknowledges received messages, at most once per frame and client
+pub(super) fn send_commands_acked(
+    mut commands: Commands,
+    mut sequences: ResMut<ClientSequences>,
+) {
+    for received in sequences.values_mut().filter(|received| received.ack_pending) {
+        received.ack_pending = false;
+        commands.server_trigger(ToClients {
+            mode: SendMode::Direct(received.client_entity),
+            event: CommandsAcked { next_sequence: received.next },
+        });
+    }
+}
@@ -0,0 +1,103 @@
+use bevy::{prelude::*, reflect::ReflectMut};
+
+/// What to do with NaN and infinite values found in command payloads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NonFinitePolicy {
+    /// Drop the whole command
+    #[default]
+    Reject,
+    /// Replace NaN with zero and infinities with the largest allowed magnitude
+    Clamp,
+}
+
+/// Server-side sanitization of floating point fields in command payloads.
+/// Applied before commands are stored for broadcast, so every client receives
+/// the same sanitized values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FloatSanitization {
+    pub non_finite: NonFinitePolicy,
+    /// Finite values are clamped to +/- this magnitude, if set
+    pub max_magnitude: Option<f64>,
+    /// Finite values are rounded to the nearest multiple of this value, if set
+    pub quantization: Option<f64>,
+}
+
+impl Default for FloatSanitization {
+    fn default() -> Self {
+        Self {
+            non_finite: NonFinitePolicy::Reject,
+            max_magnitude: None,
+            quantization: None,
+        }
+    }
+}
+
+impl FloatSanitization {
+    /// `max` is the largest finite value of the field's type, which a larger
+    /// `max_magnitude` must not push the value past
+    fn sanitize_f64(&self, value: f64, max: f64) -> Option<f64> {
+        let max = self.max_magnitude.unwrap_or(max).min(max);
+        let mut value = if value.is_finite() {
+            value
+        } else {
+            match self.non_finite {
+                NonFinitePolicy::Reject => return None,
+                NonFinitePolicy::Clamp if value.is_nan() => 0.0,
+                NonFinitePolicy::Clamp => value.signum() * max,
+            }
+        };
+        if let Some(step) = self.quantization {
+            if step > 0.0 {
+                value = (value / step).round() * step;
+            }
+        }
+        Some(value.clamp(-max, max))
+    }
+}
+
+/// Sanitize all commands in place, removing any that were rejected.
+/// Returns the number of rejected commands.
+pub(super) fn sanitize_commands(
+    commands: &mut Vec<Box<dyn PartialReflect>>,
+    settings: &FloatSanitization,
+) -> usize {
+    let before = commands.len();
+    commands.retain_mut(|command| sanitize_value(command.as_mut(), settings));
+    before - commands.len()
+}
+
+/// Recursively walks a reflected value. Returns false if the value must be rejected.
+fn sanitize_value(value: &mut dyn PartialReflect, settings: &FloatSanitization) -> bool {
+    match value.reflect_mut() {
+        ReflectMut::Struct(s) => (0..s.field_len())
+            .all(|i| s.field_at_mut(i).is_none_or(|f| sanitize_value(f, settings))),
+        ReflectMut::TupleStruct(s) => (0..s.field_len())
+            .all(|i| s.field_mut(i).is_none_or(|f| sanitize_value(f, settings))),
+        ReflectMut::Tuple(t) => (0..t.field_len())
+            .all(|i| t.field_mut(i).is_none_or(|f| sanitize_value(f, settings))),
+        ReflectMut::List(l) => (0..l.len())
+            .all(|i| l.get_mut(i).is_none_or(|f| sanitize_value(f, settings))),
+        ReflectMut::Array(a) => (0..a.len())
+            .all(|i| a.get_mut(i).is_none_or(|f| sanitize_value(f, settings))),
+        ReflectMut::Map(m) => (0..m.len())
+            .all(|i| m.get_at_mut(i).is_none_or(|(_, f)| sanitize_value(f, settings))),
+        ReflectMut::Enum(e) => (0..e.field_len())
+            .all(|i| e.field_at_mut(i).is_none_or(|f| sanitize_value(f, settings))),
+        ReflectMut::Opaque(v) => {
+            if let Some(x) = v.try_downcast_mut::<f32>() {
+                match settings.sanitize_f64(*x as f64, f32::MAX as f64) {
+                    Some(sanitized) => { *x = sanitized as f32; true }
+                    None => false,
+                }
+            } else if let Some(x) = v.try_downcast_mut::<f64>() {
+                match settings.sanitize_f64(*x, f64::MAX) {
+                    Some(sanitized) => { *x = sanitized; true }
+                    None => false,
+                }
+            } else {
+                true
+            }
+        }
+        _ => true,
+    }
+}
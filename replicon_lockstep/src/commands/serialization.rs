@@ -1,9 +1,9 @@
 use std::collections::BTreeMap;
-use bevy::{prelude::*, reflect::serde::{ReflectDeserializer, ReflectSerializer}};
+use bevy::{prelude::*, reflect::TypeRegistry};
 use bevy_replicon::{
     bytes::Bytes,
     postcard::{
-        self, Deserializer, Serializer
+        self, de_flavors, ser_flavors, Deserializer, Serializer
     },
     shared::{
         event::ctx::{ClientReceiveCtx, ClientSendCtx, ServerReceiveCtx, ServerSendCtx},
@@ -12,47 +12,106 @@ use bevy_replicon::{
 };
 use serde::{Serialize, Deserialize, de::DeserializeSeed};
 use super::{
+    CatchUpHistory,
+    catch_up::{MigrationHistory, CATCH_UP_CHUNK_TICKS},
     ClientSendCommands,
     LockstepClientCommands,
-    ServerSendCommands
+    ServerSendCommands,
+    codec::{self, CompactCommandIds},
+    registry::{CommandSerializer, CommandDeserializer},
+    resend::ResentCommands,
+    streams::CommandStreamId,
 };
 
-use crate::prelude::SimTick;
+use crate::{prelude::{SimTick, SessionHash, hash_tick_commands}, lockstep_core::framing};
+
+/// Commands preallocated for a count read from the wire.  Larger counts
+/// grow as commands are actually read, so a corrupt count can't reserve
+/// memory the message doesn't contain.
+const MAX_PREALLOCATED_COMMANDS: usize = 64;
+
+/// Tag of a tick whose commands use the compact ids of the match, see
+/// [`codec`]
+const COMPACT_COMMAND_TICK: u8 = 2;
+
+/// Tag of an lz4 compressed tick
+const COMPRESSED_TICK: u8 = 3;
+
+/// Largest uncompressed tick accepted, so a corrupt size can't make a
+/// client allocate more than replicon would ever deliver in one message
+const MAX_DECOMPRESSED_TICK_BYTES: usize = 4 * 1024 * 1024;
+
+/// Ticks a client can send after host migration.  A client is only a few
+/// ticks ahead of the new host, this just bounds what a corrupt message
+/// can make the server allocate.
+const MAX_MIGRATION_TICKS: usize = 1024;
 
 pub(super) fn serialize_client_send_commands(
     ctx: &mut ClientSendCtx,
     event: &ClientSendCommands,
     message: &mut Vec<u8>,
+) -> postcard::Result<()> {
+    write_client_send_commands(event, ctx.type_registry, message)
+}
+
+pub(super) fn deserialize_client_send_commands(
+    ctx: &mut ServerReceiveCtx,
+    message: &mut Bytes,
+) -> postcard::Result<ClientSendCommands> {
+    read_client_send_commands(message, ctx.type_registry)
+}
+
+pub(crate) fn write_client_send_commands(
+    event: &ClientSendCommands,
+    registry: &TypeRegistry,
+    message: &mut Vec<u8>,
 ) -> postcard::Result<()> {
     let mut serializer = Serializer {
         output: ExtendMutFlavor::new(message),
     };
     (event.commands.len() as u16).serialize(&mut serializer)?;
     for command in &event.commands {
-        ReflectSerializer::new(&*command.as_partial_reflect(), ctx.type_registry)
+        CommandSerializer { command: command.as_partial_reflect(), registry, compact: None }
             .serialize(&mut serializer)?;
     }
     event.issued_tick.serialize(&mut serializer)?;
+    event.stream.serialize(&mut serializer)?;
+    event.sequence.serialize(&mut serializer)?;
     Ok(())
 }
 
-pub(super) fn deserialize_client_send_commands(
-    ctx: &mut ServerReceiveCtx,
+pub(crate) fn read_client_send_commands(
     message: &mut Bytes,
+    registry: &TypeRegistry,
 ) -> postcard::Result<ClientSendCommands> {
- let mut deserializer = Deserializer::from_flavor(BufFlavor::new(message));
+    let mut deserializer = Deserializer::from_flavor(BufFlavor::new(message));
     let num_commands = u16::deserialize(&mut deserializer)? as usize;
-    let mut commands = Vec::with_capacity(num_commands);
+    let mut commands = Vec::with_capacity(num_commands.min(MAX_PREALLOCATED_COMMANDS));
 
     for _ in 0..num_commands {
-        let reflect_deserializer = ReflectDeserializer::new(ctx.type_registry);
-        let payload = reflect_deserializer.deserialize(&mut deserializer)?
-            .as_partial_reflect()
-            .clone_value();
+        let payload = CommandDeserializer { registry, compact: None }
+            .deserialize(&mut deserializer)?;
         commands.push(payload);
     }
     let issued_tick = SimTick::deserialize(&mut deserializer)?;
-    Ok(ClientSendCommands { commands, issued_tick })
+    let stream = CommandStreamId::deserialize(&mut deserializer)?;
+    let sequence = u32::deserialize(&mut deserializer)?;
+    Ok(ClientSendCommands { commands, issued_tick, stream, sequence })
+}
+
+pub(super) fn serialize_resent_commands(
+    ctx: &mut ClientSendCtx,
+    event: &ResentCommands,
+    message: &mut Vec<u8>,
+) -> postcard::Result<()> {
+    write_client_send_commands(&event.0, ctx.type_registry, message)
+}
+
+pub(super) fn deserialize_resent_commands(
+    ctx: &mut ServerReceiveCtx,
+    message: &mut Bytes,
+) -> postcard::Result<ResentCommands> {
+    read_client_send_commands(message, ctx.type_registry).map(ResentCommands)
 }
 
 pub(super) fn serialize_server_send_commands(
@@ -60,44 +119,274 @@ pub(super) fn serialize_server_send_commands(
     event: &ServerSendCommands,
     message: &mut Vec<u8>,
 ) -> postcard::Result<()> {
+    write_server_send_commands(event, ctx.type_registry, message)
+}
+
+pub(super) fn deserialize_server_send_commands(
+    ctx: &mut ClientReceiveCtx,
+    message: &mut Bytes,
+) -> postcard::Result<ServerSendCommands> {
+    read_server_send_commands(message, ctx.type_registry)
+}
+
+pub(crate) fn write_server_send_commands(
+    event: &ServerSendCommands,
+    registry: &TypeRegistry,
+    message: &mut Vec<u8>,
+) -> postcard::Result<()> {
+    let start = message.len();
     let mut serializer = Serializer {
-        output: ExtendMutFlavor::new(message),
+        output: ExtendMutFlavor::new(&mut *message),
     };
-    (event.commands.len() as u8).serialize(&mut serializer)?;
-    for (client_id, commands) in event.commands.iter() {
-        client_id.serialize(&mut serializer)?;
-        (commands.len() as u16).serialize(&mut serializer)?;
-        for command in commands {
-            ReflectSerializer::new(&*command.as_partial_reflect(), ctx.type_registry)
-                .serialize(&mut serializer)?
-        }
+    if event.commands.is_empty() && event.streams.values().all(|commands| commands.is_empty()) {
+        // Idle ticks are just the tag, session and tick, the digest of no
+        // commands is known to the client
+        framing::EMPTY_TICK.serialize(&mut serializer)?;
+        event.session.0.serialize(&mut serializer)?;
+        event.tick.serialize(&mut serializer)?;
+        return Ok(());
+    }
+    let compact = codec::compact_ids(registry);
+    let tag = if compact.is_some() { COMPACT_COMMAND_TICK } else { framing::COMMAND_TICK };
+    tag.serialize(&mut serializer)?;
+    event.session.0.serialize(&mut serializer)?;
+    event.digest.serialize(&mut serializer)?;
+    serialize_client_commands(&mut serializer, &event.commands, registry, compact)?;
+    (event.streams.len() as u8).serialize(&mut serializer)?;
+    for (stream, commands) in event.streams.iter() {
+        stream.serialize(&mut serializer)?;
+        serialize_client_commands(&mut serializer, commands, registry, compact)?;
     }
     event.tick.serialize(&mut serializer)?;
-    Ok(())
+    match compact.and_then(|compact| compact.compress_above) {
+        Some(threshold) => compress_tick(message, start, threshold),
+        None => Ok(()),
+    }
 }
 
-pub(super) fn deserialize_server_send_commands(
-    ctx: &mut ClientReceiveCtx,
+pub(crate) fn read_server_send_commands(
     message: &mut Bytes,
+    registry: &TypeRegistry,
 ) -> postcard::Result<ServerSendCommands> {
+    if message.first() == Some(&COMPRESSED_TICK) {
+        return read_compressed_tick(message, registry);
+    }
     let mut deserializer = Deserializer::from_flavor(BufFlavor::new(message));
-
-    // Deserialize the number of commands
-    let num_clients = u8::deserialize(&mut deserializer)?;
-    let mut client_commands: BTreeMap<u64, Vec<_>> = BTreeMap::<u64, Vec<_>>::new();
-    for _ in 0..num_clients {
-        let client_id = u64::deserialize(&mut deserializer)?;
-        let num_commands = u16::deserialize(&mut deserializer)?;
-        let mut commands: Vec<Box<dyn PartialReflect>> = Vec::<_>::with_capacity(num_commands as usize);
-        for __ in 0..num_commands {
-            let reflect_deserializer = ReflectDeserializer::new(ctx.type_registry);
-            let payload = reflect_deserializer.deserialize(&mut deserializer)?
-                .as_partial_reflect()
-                .clone_value();
-            commands.push(payload);
+    let tag = u8::deserialize(&mut deserializer)?;
+    let session = SessionHash(u32::deserialize(&mut deserializer)?);
+    let compact = match tag {
+        framing::EMPTY_TICK => {
+            let tick = SimTick::deserialize(&mut deserializer)?;
+            let commands = LockstepClientCommands::default();
+            let digest = hash_tick_commands(&commands, registry);
+            return Ok(ServerSendCommands { session, digest, commands, streams: BTreeMap::new(), tick });
         }
-        client_commands.insert(client_id, commands);
+        framing::COMMAND_TICK => None,
+        // Without the ids of this match the commands can't be read
+        COMPACT_COMMAND_TICK => Some(codec::compact_ids(registry).ok_or(postcard::Error::SerdeDeCustom)?),
+        _ => return Err(postcard::Error::SerdeDeCustom),
+    };
+    let digest = u64::deserialize(&mut deserializer)?;
+    let commands = deserialize_client_commands(&mut deserializer, registry, compact)?;
+    let num_streams = u8::deserialize(&mut deserializer)?;
+    let mut streams = BTreeMap::new();
+    for _ in 0..num_streams {
+        let stream = CommandStreamId::deserialize(&mut deserializer)?;
+        streams.insert(stream, deserialize_client_commands(&mut deserializer, registry, compact)?);
     }
     let tick: SimTick = SimTick::deserialize(&mut deserializer)?;
-    Ok(ServerSendCommands { commands: LockstepClientCommands(client_commands), tick })
+    Ok(ServerSendCommands { session, digest, commands, streams, tick })
+}
+
+/// Replaces the tick encoded from `start` on with its lz4 compression, as
+/// [`COMPRESSED_TICK`], the uncompressed size and the compressed block, if
+/// it is larger than `threshold` and compression makes it smaller
+#[cfg(feature = "compression")]
+fn compress_tick(message: &mut Vec<u8>, start: usize, threshold: usize) -> postcard::Result<()> {
+    let size = message.len() - start;
+    if size <= threshold || size > MAX_DECOMPRESSED_TICK_BYTES { return Ok(()) }
+    let compressed = lz4_flex::block::compress(&message[start..]);
+    let mut header = Vec::new();
+    let mut serializer = Serializer { output: ExtendMutFlavor::new(&mut header) };
+    COMPRESSED_TICK.serialize(&mut serializer)?;
+    (size as u32).serialize(&mut serializer)?;
+    if header.len() + compressed.len() >= size { return Ok(()) }
+    message.truncate(start);
+    message.extend_from_slice(&header);
+    message.extend_from_slice(&compressed);
+    Ok(())
+}
+
+#[cfg(feature = "compression")]
+fn read_compressed_tick(message: &mut Bytes, registry: &TypeRegistry) -> postcard::Result<ServerSendCommands> {
+    let mut deserializer = Deserializer::from_flavor(BufFlavor::new(message));
+    let _tag = u8::deserialize(&mut deserializer)?;
+    let size = u32::deserialize(&mut deserializer)? as usize;
+    if size > MAX_DECOMPRESSED_TICK_BYTES {
+        return Err(postcard::Error::SerdeDeCustom);
+    }
+    let decompressed = lz4_flex::block::decompress(&message[..], size)
+        .map_err(|_| postcard::Error::SerdeDeCustom)?;
+    // A compressed tick holds an uncompressed one
+    if decompressed.first() == Some(&COMPRESSED_TICK) {
+        return Err(postcard::Error::SerdeDeCustom);
+    }
+    read_server_send_commands(&mut Bytes::from(decompressed), registry)
+}
+
+#[cfg(not(feature = "compression"))]
+fn compress_tick(_message: &mut Vec<u8>, _start: usize, _threshold: usize) -> postcard::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(feature = "compression"))]
+fn read_compressed_tick(_message: &mut Bytes, _registry: &TypeRegistry) -> postcard::Result<ServerSendCommands> {
+    error!("Received a compressed tick, enable the compression feature to read it");
+    Err(postcard::Error::SerdeDeCustom)
+}
+
+pub(super) fn serialize_catch_up_history(
+    ctx: &mut ServerSendCtx,
+    event: &CatchUpHistory,
+    message: &mut Vec<u8>,
+) -> postcard::Result<()> {
+    write_catch_up_history(event, ctx.type_registry, message)
+}
+
+pub(super) fn deserialize_catch_up_history(
+    ctx: &mut ClientReceiveCtx,
+    message: &mut Bytes,
+) -> postcard::Result<CatchUpHistory> {
+    read_catch_up_history(message, ctx.type_registry)
+}
+
+pub(crate) fn write_catch_up_history(
+    event: &CatchUpHistory,
+    registry: &TypeRegistry,
+    message: &mut Vec<u8>,
+) -> postcard::Result<()> {
+    let mut serializer = Serializer {
+        output: ExtendMutFlavor::new(message),
+    };
+    event.from_tick.serialize(&mut serializer)?;
+    event.to_tick.serialize(&mut serializer)?;
+    framing::write_tick_range(&mut serializer, event.ticks.iter().map(|commands| &commands.0), |serializer, command| {
+        CommandSerializer { command: command.as_partial_reflect(), registry, compact: None }
+            .serialize(&mut *serializer)
+    })
+}
+
+pub(crate) fn read_catch_up_history(
+    message: &mut Bytes,
+    registry: &TypeRegistry,
+) -> postcard::Result<CatchUpHistory> {
+    let mut deserializer = Deserializer::from_flavor(BufFlavor::new(message));
+    let from_tick = SimTick::deserialize(&mut deserializer)?;
+    let to_tick = SimTick::deserialize(&mut deserializer)?;
+    // A message holds one chunk, not the whole range up to `to_tick`
+    let max_ticks = (to_tick.saturating_sub(from_tick) as usize + 1).min(CATCH_UP_CHUNK_TICKS as usize);
+    let ticks: Vec<_> = framing::read_tick_range(&mut deserializer, max_ticks, |deserializer| {
+        CommandDeserializer { registry, compact: None }.deserialize(&mut *deserializer)
+    })?
+        .into_iter()
+        .map(LockstepClientCommands)
+        .collect();
+    check_tick_range(from_tick, ticks.len())?;
+    Ok(CatchUpHistory { from_tick, to_tick, ticks })
+}
+
+pub(crate) fn serialize_client_commands<F: ser_flavors::Flavor>(
+    serializer: &mut Serializer<F>,
+    client_commands: &LockstepClientCommands,
+    registry: &TypeRegistry,
+    compact: Option<&CompactCommandIds>,
+) -> postcard::Result<()> {
+    framing::write_client_commands(serializer, client_commands, |serializer, command| {
+        CommandSerializer { command: command.as_partial_reflect(), registry, compact }
+            .serialize(&mut *serializer)
+    })
+}
+
+/// Serialized size of a single command
+pub(crate) fn command_size(
+    command: &dyn PartialReflect,
+    registry: &TypeRegistry,
+    compact: Option<&CompactCommandIds>,
+) -> usize {
+    let mut message = Vec::new();
+    let mut serializer = Serializer {
+        output: ExtendMutFlavor::new(&mut message),
+    };
+    match (CommandSerializer { command, registry, compact }).serialize(&mut serializer) {
+        Ok(()) => message.len(),
+        Err(_) => 0,
+    }
+}
+
+pub(crate) fn deserialize_client_commands<'de, F: de_flavors::Flavor<'de>>(
+    deserializer: &mut Deserializer<'de, F>,
+    registry: &TypeRegistry,
+    compact: Option<&CompactCommandIds>,
+) -> postcard::Result<LockstepClientCommands> {
+    let client_commands = framing::read_client_commands(deserializer, |deserializer| {
+        CommandDeserializer { registry, compact }.deserialize(&mut *deserializer)
+    })?;
+    Ok(LockstepClientCommands(client_commands))
+}
+
+pub(super) fn serialize_migration_history(
+    ctx: &mut ClientSendCtx,
+    event: &MigrationHistory,
+    message: &mut Vec<u8>,
+) -> postcard::Result<()> {
+    write_migration_history(event, ctx.type_registry, message)
+}
+
+pub(super) fn deserialize_migration_history(
+    ctx: &mut ServerReceiveCtx,
+    message: &mut Bytes,
+) -> postcard::Result<MigrationHistory> {
+    read_migration_history(message, ctx.type_registry)
+}
+
+pub(crate) fn write_migration_history(
+    event: &MigrationHistory,
+    registry: &TypeRegistry,
+    message: &mut Vec<u8>,
+) -> postcard::Result<()> {
+    let mut serializer = Serializer {
+        output: ExtendMutFlavor::new(message),
+    };
+    event.from_tick.serialize(&mut serializer)?;
+    (event.ticks.len() as SimTick).serialize(&mut serializer)?;
+    framing::write_tick_range(&mut serializer, event.ticks.iter().map(|commands| &commands.0), |serializer, command| {
+        CommandSerializer { command: command.as_partial_reflect(), registry, compact: None }
+            .serialize(&mut *serializer)
+    })
+}
+
+pub(crate) fn read_migration_history(
+    message: &mut Bytes,
+    registry: &TypeRegistry,
+) -> postcard::Result<MigrationHistory> {
+    let mut deserializer = Deserializer::from_flavor(BufFlavor::new(message));
+    let from_tick = SimTick::deserialize(&mut deserializer)?;
+    let max_ticks = (SimTick::deserialize(&mut deserializer)? as usize).min(MAX_MIGRATION_TICKS);
+    let ticks: Vec<_> = framing::read_tick_range(&mut deserializer, max_ticks, |deserializer| {
+        CommandDeserializer { registry, compact: None }.deserialize(&mut *deserializer)
+    })?
+        .into_iter()
+        .map(LockstepClientCommands)
+        .collect();
+    check_tick_range(from_tick, ticks.len())?;
+    Ok(MigrationHistory { from_tick, ticks })
+}
+
+/// Receivers compute the last tick of a range, which must not overflow
+fn check_tick_range(from_tick: SimTick, len: usize) -> postcard::Result<()> {
+    SimTick::try_from(len)
+        .ok()
+        .and_then(|len| from_tick.checked_add(len))
+        .map(|_| ())
+        .ok_or(postcard::Error::SerdeDeCustom)
 }
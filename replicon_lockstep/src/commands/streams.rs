@@ -0,0 +1,84 @@
+use std::collections::BTreeMap;
+use bevy::prelude::*;
+use crate::prelude::*;
+
+/// Identifies a command stream.  Stream 0 is the standard gameplay stream
+/// stored in [`LockstepGameCommandBuffer`].  Additional streams are numbered
+/// from 1 in the order of `SimulationSettings::command_streams`.
+pub type CommandStreamId = u8;
+
+/// The standard gameplay command stream
+pub const GAMEPLAY_STREAM: CommandStreamId = 0;
+
+/// How the execution tick of commands in a stream is chosen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StreamDelay {
+    /// Same as gameplay commands: ping based delay plus `base_input_tick_delay`
+    #[default]
+    Standard,
+    /// Always execute this many ticks after the server's current tick,
+    /// regardless of ping.  Use 1 for next-tick feedback.
+    Fixed(SimTick),
+}
+
+/// Settings for an additional command stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandStreamSettings {
+    pub name: String,
+    pub delay: StreamDelay,
+}
+
+impl CommandStreamSettings {
+    pub fn new(name: impl Into<String>, delay: StreamDelay) -> Self {
+        Self { name: name.into(), delay }
+    }
+}
+
+impl SimulationSettings {
+    /// Look up the id of an additional command stream by name
+    pub fn command_stream(&self, name: &str) -> Option<CommandStreamId> {
+        self.command_streams
+            .iter()
+            .position(|stream| stream.name == name)
+            .map(|index| index as CommandStreamId + 1)
+    }
+
+    pub(crate) fn stream_settings(&self, stream: CommandStreamId) -> Option<&CommandStreamSettings> {
+        stream.checked_sub(1).and_then(|index| self.command_streams.get(index as usize))
+    }
+}
+
+/// Buffers for additional command streams, keyed by stream id.  Works like
+/// [`LockstepGameCommandBuffer`] and is filled on both clients and the server.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct LockstepStreamBuffers(BTreeMap<CommandStreamId, Vec<LockstepClientCommands>>);
+
+impl LockstepStreamBuffers {
+    pub fn get(&self, stream: CommandStreamId, tick: SimTick) -> Option<&LockstepClientCommands> {
+        self.0.get(&stream).and_then(|buffer| buffer.get(tick as usize))
+    }
+
+    /// Insert commands for a client, growing the stream buffer as needed
+    pub(crate) fn insert(
+        &mut self,
+        stream: CommandStreamId,
+        tick: SimTick,
+        client_id: ClientId,
+        commands: Vec<Box<dyn PartialReflect>>,
+    ) {
+        let buffer = self.0.entry(stream).or_default();
+        if tick as usize >= buffer.len() {
+            buffer.resize(tick as usize + 1, LockstepClientCommands::default());
+        }
+        buffer[tick as usize].entry(client_id).or_default().extend(commands);
+    }
+
+    /// Take all stream commands for a tick, for broadcasting
+    pub(crate) fn for_tick(&self, tick: SimTick) -> BTreeMap<CommandStreamId, LockstepClientCommands> {
+        self.0
+            .iter()
+            .filter_map(|(stream, buffer)| buffer.get(tick as usize).map(|commands| (*stream, commands.clone())))
+            .filter(|(_, commands)| !commands.is_empty())
+            .collect()
+    }
+}
@@ -0,0 +1,74 @@
+use bevy::prelude::*;
+use crate::{prelude::*, replay};
+
+/// How a peer that fell behind, e.g. after a stall, works through the
+/// ticks it received but hasn't executed yet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CatchUpPolicy {
+    /// Execute at most this many ticks per frame, keeping frame times
+    /// bounded while catching up.  A frame longer than this many ticks
+    /// still executes the ticks it took, and one more.
+    pub max_ticks_per_frame: u32,
+    /// Speed virtual time up by this factor while more than one tick is
+    /// queued, so fixed-step game systems catch up as well.  1 disables it.
+    pub time_dilation: f32,
+}
+
+impl Default for CatchUpPolicy {
+    fn default() -> Self {
+        Self {
+            max_ticks_per_frame: 16,
+            time_dilation: 1.0,
+        }
+    }
+}
+
+/// The number of received ticks not executed yet, for showing a
+/// "catching up" indicator
+#[derive(Resource, Debug, Clone, Copy, Default, Deref)]
+pub struct TicksBehind {
+    #[deref]
+    ticks: SimTick,
+    dilated: bool,
+}
+
+impl TicksBehind {
+    /// True while more than the current tick is queued
+    pub fn is_catching_up(&self) -> bool {
+        self.ticks > 1
+    }
+}
+
+pub(super) fn update_ticks_behind(
+    mut behind: ResMut<TicksBehind>,
+    sim_tick: Res<SimulationTick>,
+    last_applied: Res<LastAppliedTick>,
+    settings: Res<SimulationSettings>,
+    mut virtual_time: ResMut<Time<Virtual>>,
+    playback: Option<Res<ReplayPlayback>>,
+) {
+    let ticks = sim_tick.saturating_sub(**last_applied);
+    if behind.ticks != ticks {
+        behind.ticks = ticks;
+    }
+    // Replay playback drives virtual time itself
+    if replay::playing_back(playback) { return }
+    let dilate = behind.is_catching_up() && settings.catch_up.time_dilation > 1.0;
+    if dilate != behind.dilated {
+        debug!("{} ticks behind, time dilation {}", ticks, if dilate { "on" } else { "off" });
+        virtual_time.set_relative_speed(if dilate { settings.catch_up.time_dilation } else { 1.0 });
+        behind.dilated = dilate;
+    }
+}
+
+/// Time dilation only applies while Running, e.g. a pause or a reconnect
+/// while catching up must not keep virtual time sped up
+pub(super) fn reset_time_dilation(
+    mut behind: ResMut<TicksBehind>,
+    mut virtual_time: ResMut<Time<Virtual>>,
+) {
+    if !behind.dilated { return }
+    debug!("left Running, time dilation off");
+    virtual_time.set_relative_speed(1.0);
+    behind.dilated = false;
+}
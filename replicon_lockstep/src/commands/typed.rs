@@ -0,0 +1,111 @@
+use bevy::{ecs::schedule::ScheduleLabel, prelude::*, reflect::GetTypeRegistration};
+use crate::prelude::*;
+
+/// A trigger that fires for every command of type `T` once the tick it
+/// executes on is applied, in tick, client and command order.  Observe it
+/// with [`LockstepCommandAppExt::add_lockstep_command_observer`] instead of
+/// matching commands with `T::from_reflect` by hand.
+#[derive(Event, Debug, Clone)]
+pub struct LockstepCommand<T: Send + Sync + 'static> {
+    pub command: T,
+    pub ctx: CommandContext,
+}
+
+/// Type data attached to command types that have observers, see
+/// [`LockstepCommandAppExt::add_lockstep_command_observer`]
+#[derive(Clone, Copy)]
+pub struct ReflectCommandTrigger {
+    trigger: fn(&dyn PartialReflect, CommandContext, &mut World),
+}
+
+impl ReflectCommandTrigger {
+    pub(super) fn of<T: Reflect + FromReflect + TypePath + GetTypeRegistration>() -> Self {
+        Self {
+            trigger: |command, ctx, world| {
+                match T::from_reflect(command) {
+                    Some(command) => world.trigger(LockstepCommand { command, ctx }),
+                    None => warn!("could not convert command to {}", T::type_path()),
+                }
+            },
+        }
+    }
+
+    pub fn trigger(&self, command: &dyn PartialReflect, ctx: CommandContext, world: &mut World) {
+        (self.trigger)(command, ctx, world)
+    }
+}
+
+/// Runs once per applied tick, after the tick's commands were applied and
+/// their observers ran, and before [`BeforePhysicsStep`].  Handlers added
+/// with [`LockstepCommandAppExt::on_lockstep_command`] run here.
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LockstepCommandHandlers;
+
+/// Ordering of [`LockstepCommandHandlers`]: handlers read the commands of
+/// the tick one after the other in registration order, then they are
+/// cleared
+#[derive(SystemSet, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(super) enum CommandHandlerSet {
+    Handle,
+    /// The handlers added with the n-th call to `on_lockstep_command`
+    Handler(u32),
+    Clear,
+}
+
+/// Handlers added so far, the set of the next one follows the last
+#[derive(Resource, Default)]
+pub(super) struct CommandHandlerCount(pub(super) u32);
+
+/// The commands of type `T` in the tick being applied, in client and
+/// command order.  Read it from handlers added with
+/// [`LockstepCommandAppExt::on_lockstep_command`].
+#[derive(Resource)]
+pub struct LockstepCommandItems<T: Send + Sync + 'static>(Vec<(ClientId, SimTick, T)>);
+
+impl<T: Send + Sync + 'static> Default for LockstepCommandItems<T> {
+    fn default() -> Self {
+        Self(Vec::new())
+    }
+}
+
+impl<T: Send + Sync + 'static> LockstepCommandItems<T> {
+    pub fn iter(&self) -> impl Iterator<Item = (ClientId, SimTick, &T)> {
+        self.0.iter().map(|(client, tick, command)| (*client, *tick, command))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Type data attached to command types that have handlers, see
+/// [`LockstepCommandAppExt::on_lockstep_command`]
+#[derive(Clone, Copy)]
+pub struct ReflectCommandHandler {
+    collect: fn(&dyn PartialReflect, &CommandContext, &mut World),
+}
+
+impl ReflectCommandHandler {
+    pub(super) fn of<T: Reflect + FromReflect + TypePath + GetTypeRegistration>() -> Self {
+        Self {
+            collect: |command, ctx, world| {
+                match T::from_reflect(command) {
+                    Some(command) => world.resource_mut::<LockstepCommandItems<T>>().0.push((ctx.client, ctx.tick, command)),
+                    None => warn!("could not convert command to {}", T::type_path()),
+                }
+            },
+        }
+    }
+
+    pub fn collect(&self, command: &dyn PartialReflect, ctx: &CommandContext, world: &mut World) {
+        (self.collect)(command, ctx, world)
+    }
+}
+
+pub(super) fn clear_command_items<T: Send + Sync + 'static>(mut items: ResMut<LockstepCommandItems<T>>) {
+    items.0.clear();
+}
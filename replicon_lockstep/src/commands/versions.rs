@@ -0,0 +1,176 @@
+//! Versioned command types.  A command type that changed shape registers
+//! its previous version with an upgrade to the next one.  In Setup the
+//! server narrows the command types to the ones every peer registered, so
+//! builds that know different versions can still share a match, and
+//! upgrades commands of older versions to the newest agreed one before
+//! validating and broadcasting them.
+
+use std::{any::TypeId, collections::BTreeSet};
+use bevy::{prelude::*, reflect::{GetTypeRegistration, TypeRegistry}};
+use bevy_replicon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::prelude::*;
+
+/// A previous version of a command type.  Register with
+/// [`LockstepCommandAppExt::register_command_upgrade`].  Versions chain, so
+/// a `v1` upgrading to `v2` upgrading to `v3` reaches `v3` when every peer
+/// knows it.
+pub trait UpgradeCommand: Reflect + FromReflect + TypePath + GetTypeRegistration {
+    /// The version this one upgrades to
+    type Next: Reflect + TypePath + GetTypeRegistration;
+
+    fn upgrade(self) -> Self::Next;
+}
+
+/// Type data attached to the previous versions registered with
+/// [`LockstepCommandAppExt::register_command_upgrade`]
+#[derive(Clone, Copy)]
+pub struct ReflectUpgradeCommand {
+    pub next: TypeId,
+    upgrade: fn(&dyn PartialReflect) -> Option<Box<dyn PartialReflect>>,
+}
+
+impl ReflectUpgradeCommand {
+    pub(super) fn of<T: UpgradeCommand>() -> Self {
+        Self {
+            next: TypeId::of::<T::Next>(),
+            upgrade: |command| {
+                T::from_reflect(command).map(|command| Box::new(command.upgrade()) as Box<dyn PartialReflect>)
+            },
+        }
+    }
+
+    pub fn upgrade(&self, command: &dyn PartialReflect) -> Option<Box<dyn PartialReflect>> {
+        (self.upgrade)(command)
+    }
+}
+
+/// The command types every peer of the match registered.  The server
+/// narrows them down as the registry reports of clients arrive in Setup
+/// and sends the result to every verified client, including clients
+/// joining the match in progress.  Clients should issue the newest version
+/// that [`is_agreed`](Self::is_agreed), the server upgrades older ones and
+/// rejects newer ones.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct CommandVersions {
+    /// None until negotiated, when every registered type is usable
+    common: Option<BTreeSet<LockstepCommandId>>,
+}
+
+impl CommandVersions {
+    /// Whether every peer registered the command type with this id
+    pub fn contains(&self, id: LockstepCommandId) -> bool {
+        self.common.as_ref().is_none_or(|common| common.contains(&id))
+    }
+
+    fn contains_type(&self, type_id: TypeId, registry: &TypeRegistry) -> bool {
+        registry
+            .get_type_data::<ReflectLockstepCommand>(type_id)
+            .is_some_and(|data| self.contains(data.id))
+    }
+
+    /// The version commands of `type_id` are applied as this match: the
+    /// newest version reachable through upgrades that every peer
+    /// registered.  None if no such version exists.
+    pub fn agreed(&self, type_id: TypeId, registry: &TypeRegistry) -> Option<TypeId> {
+        let mut current = type_id;
+        loop {
+            let common = self.contains_type(current, registry);
+            match registry.get_type_data::<ReflectUpgradeCommand>(current) {
+                Some(data) if !common || self.contains_type(data.next, registry) => current = data.next,
+                _ => return common.then_some(current),
+            }
+        }
+    }
+
+    /// Whether `T` is the version of its command type this match applies
+    pub fn is_agreed<T: 'static>(&self, registry: &TypeRegistry) -> bool {
+        self.agreed(TypeId::of::<T>(), registry) == Some(TypeId::of::<T>())
+    }
+
+    /// Narrows the common types to the ones a client registered.  Returns
+    /// whether anything changed.
+    pub(super) fn retain(&mut self, client_types: &BTreeSet<LockstepCommandId>) -> bool {
+        let Some(common) = &mut self.common else { return false };
+        let before = common.len();
+        common.retain(|id| client_types.contains(id));
+        common.len() != before
+    }
+
+    pub(super) fn ids(&self) -> Option<&BTreeSet<LockstepCommandId>> {
+        self.common.as_ref()
+    }
+}
+
+/// Sent by the server to each client once its registry is verified, and to
+/// every verified client again whenever the common command types narrow
+#[derive(Event, Serialize, Deserialize, Debug, Clone)]
+pub(super) struct SessionCommandVersions {
+    pub(super) common: BTreeSet<LockstepCommandId>,
+}
+
+/// The upgrades a peer registered, as `(previous, next)` ids
+pub(super) fn registered_upgrades(registry: &TypeRegistry) -> Vec<(LockstepCommandId, LockstepCommandId)> {
+    registry
+        .iter_with_data::<ReflectUpgradeCommand>()
+        .filter_map(|(registration, data)| {
+            let previous = registry.get_type_data::<ReflectLockstepCommand>(registration.type_id())?.id;
+            let next = registry.get_type_data::<ReflectLockstepCommand>(data.next)?.id;
+            Some((previous, next))
+        })
+        .collect()
+}
+
+/// The server starts from its own command types, clients wait for the
+/// server's
+pub(super) fn reset_command_versions(
+    mut versions: ResMut<CommandVersions>,
+    registry: Res<AppTypeRegistry>,
+    server: Res<RepliconServer>,
+) {
+    versions.common = server.is_running().then(|| registry
+        .read()
+        .iter_with_data::<ReflectLockstepCommand>()
+        .map(|(_, data)| data.id)
+        .collect());
+}
+
+pub(super) fn receive_command_versions(
+    trigger: Trigger<SessionCommandVersions>,
+    mut versions: ResMut<CommandVersions>,
+    registry: Res<AppTypeRegistry>,
+    settings: Res<SimulationSettings>,
+    compression: Res<super::codec::TickCompression>,
+    server: Res<RepliconServer>,
+) {
+    // The host receives what it sends its own seat
+    if server.is_running() { return }
+    debug!("the match uses {} command types", trigger.common.len());
+    versions.common = Some(trigger.event().common.clone());
+    super::codec::number_command_types(&mut registry.write(), &versions, compression.threshold(&settings));
+}
+
+/// Upgrade commands of older versions in place to the agreed version,
+/// removing any without one.  Returns the number of rejected commands.
+pub(super) fn upgrade_commands(
+    commands: &mut Vec<Box<dyn PartialReflect>>,
+    versions: &CommandVersions,
+    registry: &TypeRegistry,
+) -> usize {
+    let before = commands.len();
+    commands.retain_mut(|command| {
+        let Some(type_id) = command.get_represented_type_info().map(|info| info.type_id()) else { return true };
+        // Unregistered types are sent by type path and not versioned
+        if registry.get_type_data::<ReflectLockstepCommand>(type_id).is_none() { return true }
+        let Some(agreed) = versions.agreed(type_id, registry) else { return false };
+        let mut current = type_id;
+        while current != agreed {
+            let Some(data) = registry.get_type_data::<ReflectUpgradeCommand>(current) else { return false };
+            let Some(upgraded) = data.upgrade(command.as_ref()) else { return false };
+            *command = upgraded;
+            current = data.next;
+        }
+        true
+    });
+    before - commands.len()
+}
@@ -1,32 +1,109 @@
-use std::{net::Ipv4Addr, time::Duration};
+use std::{net::Ipv4Addr, time::{Duration, SystemTime, UNIX_EPOCH}};
 use bevy::{prelude::*, time::Stopwatch};
 use bevy_replicon::{prelude::*, shared::backend::connected_client::NetworkId};
 use serde::{Deserialize, Serialize};
-use crate::{prelude::{SimulationSettings, SimulationState}, simulation::SetSimulationState};
+use crate::{replay, prelude::{SimulationSettings, SimulationState, AssetManifest, ManifestComplete, ManifestSettings, CommandRegistryVerified}, simulation::SetSimulationState};
 
+mod seats;
+mod qualification;
+mod role;
+mod lobby;
+mod host_seat;
+mod migration;
+mod join;
+mod practice;
+mod relay;
 
-pub type ClientId = u64;
+pub use crate::lockstep_core::ClientId;
+pub use qualification::{QualificationSettings, ConnectionQuality, QualificationFailed};
+pub use role::{LockstepRole, ClientRole};
+pub use seats::{EmptySeatPolicy, StartMatchEarly, Seat, SeatLayout};
+pub use lobby::{
+    LobbyPlugin, LobbySettings, LobbyMember, LobbyInfo, SetLobbyName, ClaimLobbySlot, SetLobbyReady,
+    SetLobbyPlayerCount, StartLobbyMatch, LobbyMatchStarting, LobbyStartError, LobbyStartFailed,
+};
+pub(crate) use lobby::opening_state;
+pub use host_seat::{HostSeat, HostingSeat, LockstepClients, HOST_CLIENT_ID};
+pub use migration::{HostMigrationSettings, HostMigrationCandidate, HostMigrationStarted, HostMigrationFinished};
+pub(crate) use migration::PendingHostMigration;
+pub(crate) use join::JoiningInProgress;
+pub use practice::StartPracticeMatch;
+pub use relay::{RequestTransportMigration, MigrateTransport, TransportMigrationFinished, TransportMigrationFailed};
+pub(crate) use relay::PendingTransportMigration;
 
-pub(crate) struct LockstepConnectionsPlugin;
+/// Client connections, local client identification and readiness
+#[derive(Default)]
+pub struct LockstepConnectionsPlugin {
+    pub settings: ConnectionSettings,
+}
 
 impl Plugin for LockstepConnectionsPlugin {
     fn build(&self, app: &mut App) {
         app
+            .insert_resource(self.settings.clone())
             .replicate::<NetworkId>()
             .replicate::<ClientReady>()
+            .replicate::<ClientRole>()
+            .replicate::<HostingSeat>()
             .add_observer(on_client_connect)
             .add_observer(on_client_requested_id)
             .add_observer(on_received_local_client_id)
             .add_observer(on_client_ready)
+            .add_observer(join::begin_setup_on_role)
+            .add_observer(practice::start_practice_match)
+            .add_systems(OnEnter(SimulationState::None), practice::end_practice_match
+                .run_if(resource_exists::<practice::PracticeOverrides>))
+            .add_server_trigger::<join::JoinInProgress>(Channel::Ordered)
+            .add_observer(join::receive_join_in_progress)
+            .add_systems(OnEnter(SimulationState::Setup), join::resync_after_join_setup
+                .run_if(resource_exists::<JoiningInProgress>))
+            .add_systems(OnEnter(SimulationState::Reconnecting), join::finish_join
+                .run_if(resource_exists::<JoiningInProgress>))
             .add_server_trigger::<LocalClientIdResponseEvent>(Channel::Unordered)
             .add_client_trigger::<LocalClientIdRequestEvent>(Channel::Unordered)
             .add_client_trigger::<ClientReadyEvent>(Channel::Unordered)
+            .init_resource::<SeatLayout>()
+            .add_server_trigger::<SeatLayout>(Channel::Ordered)
+            .add_observer(seats::start_match_early)
+            .add_observer(seats::receive_seat_layout)
+            .add_server_trigger::<QualificationFailed>(Channel::Ordered)
+            .replicate::<HostMigrationCandidate>()
+            .add_client_trigger::<migration::AnnounceMigrationCandidate>(Channel::Ordered)
+            .add_observer(migration::receive_migration_candidate)
+            .add_observer(migration::elect_new_host)
+            .add_observer(migration::count_rejoined_client)
+            .add_server_trigger::<MigrateTransport>(Channel::Ordered)
+            .add_client_trigger::<relay::VerifyTransportResume>(Channel::Ordered)
+            .add_server_trigger::<relay::TransportResumeVerified>(Channel::Ordered)
+            .add_observer(relay::request_transport_migration)
+            .add_observer(relay::receive_migrate_transport)
+            .add_observer(relay::verify_transport_resume)
+            .add_observer(relay::receive_transport_resume_verified)
+            .add_systems(Update, relay::send_resume_digests
+                .run_if(resource_exists::<PendingTransportMigration>.and(not(server_running))))
+            .add_systems(OnEnter(SimulationState::Setup), relay::clear_transport_migration)
+            .add_systems(OnEnter(SimulationState::Setup), migration::announce_migration_candidate
+                .run_if(client_connected.and(not(server_running))))
+            .add_systems(Update, migration::check_migration_finished
+                .run_if(resource_exists::<PendingHostMigration>.and(in_state(SimulationState::Paused)).and(server_running)))
+            .add_systems(OnEnter(SimulationState::Connecting), begin_if_all_connected.run_if(server_running))
             .add_systems(FixedPreUpdate, (
                 check_all_clients_ready
                     .run_if(in_state(SimulationState::Setup).and(server_running)),
                 handle_local_client_disconnect
-                    .run_if(not(server_running).and(not(client_connected)))
-            ));
+                    .run_if(not(server_running).and(not(client_connected)).and(not(replay::playing_back))),
+                handle_local_client_reconnected
+                    .run_if(in_state(SimulationState::Reconnecting).and(client_connected)),
+            ))
+            .add_systems(Update, qualification::measure_connection_quality
+                .run_if(resource_exists::<qualification::Qualification>.and(server_running)))
+            .init_resource::<LockstepRole>()
+            .add_event::<LockstepError>()
+            .add_systems(PreUpdate, (
+                host_seat::sync_host_seat,
+                match_local_client_id.run_if(resource_exists::<PendingLocalClientId>),
+                role::update_lockstep_role,
+            ).chain().after(ClientSet::Receive));
     }
 }
 
@@ -42,7 +119,31 @@ pub struct ConnectionSettings {
     pub server_mode: ServerMode,
     pub server_address: Ipv4Addr,
     pub server_port: u16,
-    pub reconnect_timer: Duration,
+    pub reconnect_policy: ReconnectPolicy,
+    /// How long a client waits for its own replicated client entity after
+    /// the server told it its id
+    pub local_client_id_timeout: Duration,
+    /// Settings for the asset manifest exchange during Setup
+    pub manifest: ManifestSettings,
+    /// If set, once all players are connected the server measures their
+    /// connection quality and only enters Setup if everyone qualifies
+    pub qualification: Option<QualificationSettings>,
+    /// Resume a paused simulation once a reconnected client has resynced and
+    /// no other connected client is still resyncing
+    pub resume_after_resync: bool,
+    /// Marks a remote client as a bot, e.g. a headless process whose
+    /// commands come from `InjectedInputs`.  Reported as [`LockstepRole::Bot`].
+    pub bot_client: bool,
+    /// If set, a [`ServerMode::Host`] match continues when the host leaves
+    pub host_migration: Option<HostMigrationSettings>,
+    /// The downstream bandwidth in bytes per second a remote client declares
+    /// to the server, see `SimulationSettings::bandwidth_mitigation`
+    pub max_downstream_bandwidth: Option<u32>,
+    /// The role a remote client asks for when it connects.  Clients without
+    /// a seat that connect to a match in progress spectate either way.
+    pub join_as: ClientRole,
+    /// What the server does when players disconnect during Setup
+    pub setup_disconnect_policy: SetupDisconnectPolicy,
 }
 
 impl Default for ConnectionSettings {
@@ -51,16 +152,101 @@ impl Default for ConnectionSettings {
             server_mode: ServerMode::Host,
             server_address: Ipv4Addr::LOCALHOST,
             server_port: 15342,
-            reconnect_timer: Duration::from_secs(5),
+            reconnect_policy: ReconnectPolicy::default(),
+            local_client_id_timeout: Duration::from_secs(5),
+            manifest: ManifestSettings::default(),
+            qualification: None,
+            resume_after_resync: true,
+            bot_client: false,
+            host_migration: None,
+            max_downstream_bandwidth: None,
+            join_as: ClientRole::Player,
+            setup_disconnect_policy: SetupDisconnectPolicy::Abort,
         }
     }
 }
 
+/// What the server does when players disconnect during Setup, reported
+/// with [`LockstepError::PlayersLeftDuringSetup`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SetupDisconnectPolicy {
+    /// Every peer returns to [`SimulationState::None`]
+    #[default]
+    Abort,
+    /// Setup doesn't finish until every seated player is connected again
+    Wait,
+    /// The match starts once the remaining players are ready
+    Continue,
+}
+
+/// Recoverable errors of the connection flow, as an event stream.  Read
+/// them with an `EventReader` to log or show them, the flow itself carries
+/// on as configured in [`ConnectionSettings`].
+#[derive(Event, Debug, Clone, PartialEq, Eq)]
+pub enum LockstepError {
+    /// The server got a message from a client entity that has no client id
+    UnknownClient(Entity),
+    /// The local client id from the server never matched a replicated
+    /// client entity, see [`LocalClientIdentificationFailed`]
+    LocalClientUnmatched(ClientId),
+    /// Fewer players than seated are connected during Setup, see
+    /// [`SetupDisconnectPolicy`]
+    PlayersLeftDuringSetup { seated: usize, connected: usize },
+}
+
+/// How the local client retries after losing its connection to the server.
+/// The wait after attempt `n` is `initial_delay * backoff_multiplier^(n-1)`,
+/// capped at `max_delay` and randomly varied by up to `jitter` (a fraction).
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub backoff_multiplier: f32,
+    pub jitter: f32,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            backoff_multiplier: 2.0,
+            jitter: 0.1,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// The time to wait after the given attempt (starting from 1) before the next one
+    pub fn delay_after(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = (self.initial_delay.as_secs_f32() * self.backoff_multiplier.powi(exponent))
+            .min(self.max_delay.as_secs_f32());
+        // Jitter only needs to spread clients out, it doesn't need to be good randomness
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.subsec_nanos());
+        let unit = (nanos % 1000) as f32 / 500.0 - 1.0;
+        Duration::from_secs_f32((delay * (1.0 + unit * self.jitter)).max(0.0))
+    }
+}
+
 /// A trigger that triggers when the local client should try to reconnect. Then
 /// a system will start a timer.  If the timer runs out, [`ClientDisconnect`] triggers.
 #[derive(Event)]
 pub struct ClientReconnect;
 
+/// A trigger that fires on the local client for every reconnect attempt,
+/// including the first.  Transport code should re-create the connection here.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct ReconnectAttempt {
+    /// The attempt number, starting from 1
+    pub attempt: u32,
+    pub max_attempts: u32,
+    /// How long we will wait for this attempt before trying again
+    pub timeout: Duration,
+}
+
 /// A trigger that fires when the client has disconnected. 
 /// It will be triggered on both the local client and the server.
 #[derive(Event)]
@@ -68,7 +254,9 @@ pub struct ClientDisconnect(pub ClientId);
 
 /// A trigger for the client to request the local client id from the server.
 #[derive(Event, Serialize, Deserialize)]
-struct LocalClientIdRequestEvent;
+struct LocalClientIdRequestEvent {
+    role: ClientRole,
+}
 
 /// A trigger for the server to send the local client id to a connected client.
 #[derive(Event, Serialize, Deserialize, Deref)]
@@ -90,53 +278,63 @@ pub struct ClientReadyEvent;
 /// Stopwatch for client reconnects
 #[derive(Component, Deref, DerefMut, Default)]
 struct ClientReconnectTimer {
-    time: Stopwatch
+    #[deref]
+    time: Stopwatch,
+    attempt: u32,
+    timeout: Duration,
 }
 
+/// If all players are connected begin the setup process.
+/// You can hook into the Setup state to run systems to prepare
+/// the game world before the game starts.  Send ClientReadyEvent
+/// trigger when client setup is finished.
+fn try_begin_setup(
+    commands: &mut Commands,
+    ids: &Query<(&NetworkId, Option<&LobbyMember>, &ClientRole)>,
+    server_settings: &ConnectionSettings,
+    simulation_settings: &SimulationSettings,
+) {
+    let players = ids.iter().filter(|(.., &role)| role == ClientRole::Player).count();
+    if players != simulation_settings.num_players as usize { return }
+    if server_settings.qualification.is_some() {
+        info!("All players connected, measuring connection quality");
+        commands.insert_resource(qualification::Qualification::default());
+    } else {
+        seats::begin_setup(commands, seats::full_layout(ids));
+    }
+}
+
+/// Players may already be connected when entering Connecting, e.g. from a lobby
+fn begin_if_all_connected(
+    mut commands: Commands,
+    ids: Query<(&NetworkId, Option<&LobbyMember>, &ClientRole)>,
+    server_settings: Res<ConnectionSettings>,
+    simulation_settings: Res<SimulationSettings>,
+) {
+    try_begin_setup(&mut commands, &ids, &server_settings, &simulation_settings);
+}
+
+/// Clients count towards the players once they identified themselves, see
+/// `join::begin_setup_on_role`
 fn on_client_connect(
     trigger: Trigger<OnAdd, NetworkId>,
-    ids: Query<&NetworkId>,
     local_client: Query<&LocalClient>,
     server: Res<RepliconServer>,
     server_settings: Res<ConnectionSettings>,
-    simulation_settings: Res<SimulationSettings>,
     mut commands: Commands,
 ) { 
-    // If all players are connected begin the setup process.
-    // You can hook into the Setup state to run systems to prepare
-    // the game world before the game starts.  Send ClientReadyEvent
-    // trigger when client setup is finished.
-    if ids.iter().len() == simulation_settings.num_players as usize {
-        commands.server_trigger(ToClients {
-            mode: SendMode::Broadcast,
-            event: SetSimulationState(SimulationState::Setup),
-        });
-    }
-
-    // Host entity/id(1) will be spawned below when first client connects. 
-    // We don't want to re-trigger the rest of this system when that happens
-    if ids.get(trigger.entity()).unwrap().get() == 1 { return }
+    // The host seat is spawned with everything it needs, see HostSeat
+    if local_client.contains(trigger.entity()) { return }
 
     if server.is_running() {
         // Replicate all remote client NetworkIds 
         commands.entity(trigger.entity()).insert(Replicated);
-
-        if server_settings.server_mode == ServerMode::Host {
-            // If no host entity exists yet (1st connection), create one
-            if local_client.get_single().is_err() {
-                commands.spawn((
-                    NetworkId::new(1),
-                    LocalClient,
-                    Replicated,
-                ));
-            }
-        }
     } else {
         // If we are a remote client and we don't know our local
         // client id, request it from the server, so we can apply the
         // LocalClient marker component.
         if local_client.is_empty() {
-            commands.client_trigger(LocalClientIdRequestEvent);
+            commands.client_trigger(LocalClientIdRequestEvent { role: server_settings.join_as });
         }
     }
 }
@@ -152,87 +350,189 @@ fn handle_local_client_disconnect(
     time: Res<Time<Fixed>>,
 ) {
     match *current_state.get() {
-        SimulationState::Ending | SimulationState::None | SimulationState::Connecting => {
+        SimulationState::Ending | SimulationState::PostGame | SimulationState::None | SimulationState::Lobby | SimulationState::Connecting => {
             return
         }
         SimulationState::Reconnecting => {
-            let (entity, mut timer) = timer.single_mut();
-            timer.tick(time.delta());
-            if timer.elapsed() >= settings.reconnect_timer {
+            let Ok((entity, mut timer)) = timer.get_single_mut() else {
+                commands.spawn(ClientReconnectTimer::default());
+                return
+            };
+            timer.time.tick(time.delta());
+            if timer.elapsed() < timer.timeout { return }
+            let policy = &settings.reconnect_policy;
+            if timer.attempt >= policy.max_attempts {
                 commands.trigger(ClientDisconnect(local_client.single().get()));
                 state.set(SimulationState::None);
                 commands.entity(entity).despawn();
                 info!("Client disconnected");
+                return;
             }
+            timer.attempt += 1;
+            timer.timeout = policy.delay_after(timer.attempt);
+            timer.time.reset();
+            info!("Reconnect attempt {} of {}", timer.attempt, policy.max_attempts);
+            commands.trigger(ReconnectAttempt {
+                attempt: timer.attempt,
+                max_attempts: policy.max_attempts,
+                timeout: timer.timeout,
+            });
         }
         _ => {
             info!("Disconnected from server.  Attempting to reconnect...");
             state.set(SimulationState::Reconnecting);
             commands.trigger(ClientReconnect);
-            commands.spawn(ClientReconnectTimer{ time: Stopwatch::new() });
+            // A zero timeout makes the first attempt start immediately
+            commands.spawn(ClientReconnectTimer::default());
         }
     }
 }
 
+/// Clean up the reconnect timer once the connection is back
+fn handle_local_client_reconnected(
+    mut commands: Commands,
+    timer: Query<Entity, With<ClientReconnectTimer>>,
+) {
+    for entity in timer.iter() {
+        info!("Reconnected to server");
+        commands.entity(entity).despawn();
+    }
+}
+
 fn on_client_requested_id (
     trigger: Trigger<FromClient<LocalClientIdRequestEvent>>,
     network_ids: Query<(Entity, &NetworkId)>,
+    layout: Res<SeatLayout>,
+    state: Res<State<SimulationState>>,
     mut commands: Commands,
+    mut errors: EventWriter<LockstepError>,
 ) {
-    let Ok((client, client_id)) = network_ids.get(trigger.client_entity)
-        else { panic!("Failed to find client entity on new connection") };
+    // The client may have disconnected before its request was handled
+    let Ok((client, client_id)) = network_ids.get(trigger.client_entity) else {
+        warn!("Client entity {} requested its id without one", trigger.client_entity);
+        errors.send(LockstepError::UnknownClient(trigger.client_entity));
+        return;
+    };
     trace!("Client {} requested id. Sending", client_id.get());
     commands.server_trigger(ToClients {
         mode: SendMode::Direct(client),
         event: LocalClientIdResponseEvent(*client_id),
     });
+    let role = join::assign_role(trigger.event.role, client_id.get(), &layout, *state.get());
+    debug!("client {} is a {:?}", client_id.get(), role);
+    commands.entity(client).insert(role);
+    let joinable = matches!(state.get(),
+        SimulationState::Setup | SimulationState::Starting | SimulationState::Running | SimulationState::Paused);
+    if role == ClientRole::Spectator && joinable && layout.seat_of(client_id.get()).is_none() {
+        join::send_join_in_progress(&mut commands, client, &layout);
+    }
 }
 
+/// The local client id received from the server, waiting for the matching
+/// replicated client entity to arrive.
+#[derive(Resource)]
+struct PendingLocalClientId {
+    id: NetworkId,
+    time: Stopwatch,
+}
+
+/// A trigger that fires if the local client id received from the server could
+/// not be matched to a replicated client entity within
+/// `ConnectionSettings::local_client_id_timeout`.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct LocalClientIdentificationFailed(pub ClientId);
+
 fn on_received_local_client_id(
     local_client: Trigger<LocalClientIdResponseEvent>,
     mut commands: Commands,
-    network_ids: Query<(Entity, &NetworkId)>,
 ) {
     trace!("Received local client id.");
-    let local_client_id = **local_client;
-    for (client, id) in network_ids.iter() {
-        if *id == local_client_id {
-            commands.entity(client).insert(LocalClient);
-            return;
-        }
+    // The replicated client entity may not have arrived yet, so matching
+    // happens in match_local_client_id
+    commands.insert_resource(PendingLocalClientId {
+        id: **local_client,
+        time: Stopwatch::new(),
+    });
+}
+
+fn match_local_client_id(
+    mut commands: Commands,
+    mut pending: ResMut<PendingLocalClientId>,
+    network_ids: Query<(Entity, &NetworkId)>,
+    settings: Res<ConnectionSettings>,
+    time: Res<Time>,
+    mut errors: EventWriter<LockstepError>,
+) {
+    if let Some((client, _)) = network_ids.iter().find(|(_, id)| **id == pending.id) {
+        trace!("Matched local client {}", pending.id.get());
+        commands.entity(client).insert(LocalClient);
+        commands.remove_resource::<PendingLocalClientId>();
+        return;
+    }
+    pending.time.tick(time.delta());
+    if pending.time.elapsed() >= settings.local_client_id_timeout {
+        error!("Failed to match local client {}", pending.id.get());
+        commands.trigger(LocalClientIdentificationFailed(pending.id.get()));
+        errors.send(LockstepError::LocalClientUnmatched(pending.id.get()));
+        commands.remove_resource::<PendingLocalClientId>();
     }
-    panic!("failed to match local client");
 }
 
 fn on_client_ready (
     ready: Trigger<FromClient<ClientReadyEvent>>,
-    host: Query<Entity, With<LocalClient>>,
+    clients: LockstepClients,
     mut commands: Commands,
     state: Res<State<SimulationState>>,
 ) {
     if *state.get() != SimulationState::Setup { return }
-    if ready.client_entity == Entity::PLACEHOLDER {
-        // This is the host server triggering the event
-        if let Ok(host_entity) = host.get_single() {
-            trace!("host is ready");
-            commands.entity(host_entity).insert(ClientReady);
-        }
-    } else {
-        trace!("client {} is ready", ready.client_entity);
-        commands.entity(ready.client_entity).insert(ClientReady);
-    }
+    let Some(client) = clients.entity(ready.client_entity) else { return };
+    trace!("client {} is ready", client);
+    commands.entity(client).insert(ClientReady);
 }
 
+/// Only players are waited for, spectators can join whenever
 fn check_all_clients_ready(
-    ids: Query<&NetworkId>,
-    settings: Res<SimulationSettings>,
-    not_ready: Query<Entity, (With<NetworkId>, Without<ClientReady>)>,
+    mut reported: Local<bool>,
+    ids: Query<&ClientRole, With<NetworkId>>,
+    layout: Res<SeatLayout>,
+    not_ready: Query<&ClientRole, (With<NetworkId>, Without<ClientReady>)>,
+    manifest_incomplete: Query<&ClientRole, (With<NetworkId>, Without<ManifestComplete>)>,
+    manifest: Option<Res<AssetManifest>>,
+    registry_unverified: Query<&ClientRole, (With<NetworkId>, Without<CommandRegistryVerified>)>,
+    settings: Res<ConnectionSettings>,
+    mut errors: EventWriter<LockstepError>,
     mut commands: Commands,
 ) {
-    if ids.iter().len() != settings.num_players as usize {
-        panic!("Player(s) disconnected during setup phase.  Need to handle this.")
+    let is_player = |role: &&ClientRole| **role == ClientRole::Player;
+    let connected = ids.iter().filter(is_player).count();
+    let seated = layout.players().count();
+    if connected < seated {
+        if !*reported {
+            *reported = true;
+            warn!("{} of {} player(s) connected during setup, {:?}", connected, seated, settings.setup_disconnect_policy);
+            errors.send(LockstepError::PlayersLeftDuringSetup { seated, connected });
+        }
+        match settings.setup_disconnect_policy {
+            SetupDisconnectPolicy::Abort => {
+                *reported = false;
+                commands.server_trigger(ToClients {
+                    mode: SendMode::Broadcast,
+                    event: SetSimulationState(SimulationState::None),
+                });
+                return;
+            }
+            SetupDisconnectPolicy::Wait => return,
+            SetupDisconnectPolicy::Continue => if connected == 0 { return },
+        }
+    } else {
+        *reported = false;
     }
-    if not_ready.is_empty() {
+    // With an asset manifest, clients also need to have verified every entry
+    let manifest_pending = manifest.is_some_and(|manifest| !manifest.entries.is_empty())
+        && manifest_incomplete.iter().any(|role| is_player(&role));
+    let not_ready = not_ready.iter().any(|role| is_player(&role));
+    let registry_unverified = registry_unverified.iter().any(|role| is_player(&role));
+    if !not_ready && !manifest_pending && !registry_unverified {
         commands.server_trigger(ToClients {
             mode: SendMode::Broadcast,
             event: SetSimulationState(SimulationState::Starting),
@@ -0,0 +1,103 @@
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy_replicon::{prelude::*, shared::backend::connected_client::NetworkId};
+use serde::{Deserialize, Serialize};
+use crate::prelude::*;
+use super::{LocalClient, ServerMode};
+
+/// The client id of the host's own seat in [`ServerMode::Host`]
+pub const HOST_CLIENT_ID: ClientId = 1;
+
+/// Replicon's sender entity for triggers the server sends to itself
+const SERVER_SENDER: Entity = Entity::PLACEHOLDER;
+
+/// The host's own client entity in [`ServerMode::Host`].  The crate spawns
+/// it as soon as the server runs and despawns it when the server stops, so
+/// the host is a client like any other: it has a [`NetworkId`] of
+/// [`HOST_CLIENT_ID`], or its own id after taking over in a host migration,
+/// is replicated to everyone with [`HostingSeat`], and its triggers are
+/// resolved to it by [`LockstepClients`].
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct HostSeat {
+    pub entity: Entity,
+    pub id: ClientId,
+}
+
+/// Replicated marker of the client entity that hosts the match
+#[derive(Component, Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct HostingSeat;
+
+/// The id the host seat is spawned with, set by a client taking over as
+/// host so it keeps its own id and its commands stay attributed to it
+#[derive(Resource, Debug, Clone, Copy, Deref)]
+pub(super) struct HostSeatId(pub(super) ClientId);
+
+/// Resolves the senders of client triggers, i.e. `FromClient::client_entity`,
+/// to client entities and ids.  Use this in server observers instead of
+/// querying [`NetworkId`] directly, so triggers from the host resolve to the
+/// [`HostSeat`].
+#[derive(SystemParam)]
+pub struct LockstepClients<'w, 's> {
+    ids: Query<'w, 's, &'static NetworkId>,
+    host: Option<Res<'w, HostSeat>>,
+}
+
+impl LockstepClients<'_, '_> {
+    /// The client entity of a sender, `None` for the server itself without
+    /// a host seat, e.g. a dedicated server
+    pub fn entity(&self, sender: Entity) -> Option<Entity> {
+        if sender == SERVER_SENDER {
+            self.host.as_ref().map(|host| host.entity)
+        } else {
+            Some(sender)
+        }
+    }
+
+    /// True for triggers the server sent to itself, e.g. from the host
+    pub fn is_local(&self, sender: Entity) -> bool {
+        sender == SERVER_SENDER
+    }
+
+    pub fn client_id(&self, sender: Entity) -> Option<ClientId> {
+        self.entity(sender).and_then(|entity| self.ids.get(entity).ok()).map(|id| id.get())
+    }
+
+    /// The ids of every connected client, including the host
+    pub fn ids(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.ids.iter().map(|id| id.get())
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.iter().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
+
+pub(super) fn sync_host_seat(
+    mut commands: Commands,
+    server: Res<RepliconServer>,
+    settings: Res<ConnectionSettings>,
+    host: Option<Res<HostSeat>>,
+    seat_id: Option<Res<HostSeatId>>,
+) {
+    let hosting = server.is_running() && settings.server_mode == ServerMode::Host;
+    match (hosting, host) {
+        (true, None) => {
+            let id = seat_id.map_or(HOST_CLIENT_ID, |id| **id);
+            let entity = commands.spawn((NetworkId::new(id), LocalClient, HostingSeat, ClientRole::Player, Replicated)).id();
+            debug!("spawned host seat {} for client {}", entity, id);
+            commands.insert_resource(HostSeat { entity, id });
+        }
+        (false, Some(host)) => {
+            debug!("removing host seat {}", host.entity);
+            if let Some(mut entity) = commands.get_entity(host.entity) {
+                entity.despawn();
+            }
+            commands.remove_resource::<HostSeat>();
+            commands.remove_resource::<HostSeatId>();
+        }
+        _ => {}
+    }
+}
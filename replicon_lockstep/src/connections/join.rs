@@ -0,0 +1,83 @@
+use bevy::prelude::*;
+use bevy_replicon::{prelude::*, shared::backend::connected_client::NetworkId};
+use serde::{Deserialize, Serialize};
+use crate::prelude::*;
+use super::{ClientRole, LobbyMember};
+
+/// Sent by the server to a spectator that connected to a match in progress.
+/// The client runs Setup like everyone did at the start of the match, then
+/// fetches the whole history the way a reconnecting client does.
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub(super) struct JoinInProgress;
+
+/// Present on a client from [`JoinInProgress`] until it starts resyncing.
+/// Live ticks are ignored meanwhile, the resync fetches them.
+#[derive(Resource)]
+pub(crate) struct JoiningInProgress;
+
+/// True once the seats of the match are taken
+pub(super) fn match_in_progress(state: SimulationState) -> bool {
+    !matches!(state, SimulationState::None | SimulationState::Lobby | SimulationState::Connecting)
+}
+
+/// The role a client gets when it identifies itself.  Once the match is in
+/// progress only seated players play, everyone else spectates.
+pub(super) fn assign_role(
+    requested: ClientRole,
+    client: ClientId,
+    layout: &SeatLayout,
+    state: SimulationState,
+) -> ClientRole {
+    if !match_in_progress(state) { return requested }
+    if layout.seat_of(client).is_some() { return ClientRole::Player }
+    if requested == ClientRole::Player {
+        info!("client {} has no seat in the match in progress, joining as spectator", client);
+    }
+    ClientRole::Spectator
+}
+
+/// Brings a spectator that connected mid-match up to the seat layout
+pub(super) fn send_join_in_progress(commands: &mut Commands, client_entity: Entity, layout: &SeatLayout) {
+    commands.server_trigger(ToClients {
+        mode: SendMode::Direct(client_entity),
+        event: layout.clone(),
+    });
+    commands.server_trigger(ToClients {
+        mode: SendMode::Direct(client_entity),
+        event: JoinInProgress,
+    });
+}
+
+pub(super) fn receive_join_in_progress(
+    _trigger: Trigger<JoinInProgress>,
+    mut commands: Commands,
+    mut next_state: ResMut<NextState<SimulationState>>,
+) {
+    info!("Joining the match in progress as spectator");
+    commands.insert_resource(JoiningInProgress);
+    next_state.set(SimulationState::Setup);
+}
+
+/// Setup ran, fetch the history like a reconnecting client
+pub(super) fn resync_after_join_setup(mut next_state: ResMut<NextState<SimulationState>>) {
+    next_state.set(SimulationState::Reconnecting);
+}
+
+pub(super) fn finish_join(mut commands: Commands) {
+    commands.remove_resource::<JoiningInProgress>();
+}
+
+/// Clients count towards the players once their role is known
+pub(super) fn begin_setup_on_role(
+    _trigger: Trigger<OnInsert, ClientRole>,
+    mut commands: Commands,
+    ids: Query<(&NetworkId, Option<&LobbyMember>, &ClientRole)>,
+    server: Res<RepliconServer>,
+    server_settings: Res<ConnectionSettings>,
+    simulation_settings: Res<SimulationSettings>,
+    state: Res<State<SimulationState>>,
+) {
+    if server.is_running() && *state.get() == SimulationState::Connecting {
+        super::try_begin_setup(&mut commands, &ids, &server_settings, &simulation_settings);
+    }
+}
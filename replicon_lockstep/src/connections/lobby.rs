@@ -0,0 +1,291 @@
+use bevy::prelude::*;
+use bevy_replicon::{prelude::*, shared::backend::connected_client::NetworkId};
+use serde::{Deserialize, Serialize};
+use crate::prelude::*;
+
+/// A lobby phase before `Connecting`.  With this plugin, opening a session
+/// enters [`SimulationState::Lobby`], where players join, pick a name and a
+/// slot, and the match only starts when the host triggers
+/// [`StartLobbyMatch`], instead of as soon as `num_players` clients are
+/// connected.
+#[derive(Default)]
+pub struct LobbyPlugin {
+    pub settings: LobbySettings,
+}
+
+impl Plugin for LobbyPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(self.settings.clone())
+            .replicate::<LobbyMember>()
+            .replicate::<LobbyInfo>()
+            .add_client_trigger::<SetLobbyName>(Channel::Ordered)
+            .add_client_trigger::<ClaimLobbySlot>(Channel::Ordered)
+            .add_client_trigger::<SetLobbyReady>(Channel::Ordered)
+            .add_server_trigger::<LobbyMatchStarting>(Channel::Ordered)
+            .add_observer(add_lobby_member)
+            .add_observer(set_lobby_name)
+            .add_observer(claim_lobby_slot)
+            .add_observer(set_lobby_ready)
+            .add_observer(set_lobby_player_count)
+            .add_observer(start_lobby_match)
+            .add_observer(receive_lobby_match_start)
+            .add_systems(OnEnter(SimulationState::Lobby), open_lobby.run_if(server_running))
+            .add_systems(OnExit(SimulationState::Lobby), close_lobby.run_if(server_running))
+            .add_systems(PreUpdate, sync_lobby_info
+                .run_if(not(server_running))
+                .after(ClientSet::Receive));
+    }
+}
+
+#[derive(Resource, Debug, Clone)]
+pub struct LobbySettings {
+    /// Longer display names are cut off
+    pub max_name_len: usize,
+    /// Only start once every member marked themselves ready
+    pub require_ready: bool,
+}
+
+impl Default for LobbySettings {
+    fn default() -> Self {
+        Self {
+            max_name_len: 32,
+            require_ready: false,
+        }
+    }
+}
+
+/// Replicated lobby state of a client, on its client entity
+#[derive(Component, Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct LobbyMember {
+    pub name: String,
+    /// The seat the member asked for.  Members are seated by slot, and
+    /// members without a slot after them in client id order.
+    pub slot: Option<u8>,
+    pub ready: bool,
+}
+
+/// Replicated lobby configuration, on an entity spawned by the server
+/// while in Lobby
+#[derive(Component, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LobbyInfo {
+    pub num_players: u8,
+}
+
+/// Sent by a client to change its display name
+#[derive(Event, Serialize, Deserialize, Debug, Clone)]
+pub struct SetLobbyName(pub String);
+
+/// Sent by a client to take a slot, or give it up with `None`
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ClaimLobbySlot(pub Option<u8>);
+
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct SetLobbyReady(pub bool);
+
+/// Trigger this on the server to change `SimulationSettings::num_players`
+/// while in Lobby
+#[derive(Event, Debug, Clone, Copy)]
+pub struct SetLobbyPlayerCount(pub u8);
+
+/// Trigger this on the server to start the match with the current members.
+/// `num_players` is set to the number of members, and every peer moves on
+/// to `Connecting` and from there to Setup as usual.
+#[derive(Event, Debug, Clone, Copy, Default)]
+pub struct StartLobbyMatch;
+
+/// Sent by the server to every peer when a [`StartLobbyMatch`] is accepted.
+/// Carries the final player count, as the [`LobbyInfo`] entity is despawned
+/// when the lobby closes and may not reach clients with the last change.
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct LobbyMatchStarting {
+    pub num_players: u8,
+}
+
+/// Why a [`StartLobbyMatch`] was not accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LobbyStartError {
+    /// Not the server, or not in Lobby
+    NotInLobby,
+    NoMembers,
+    /// More members than `num_players`
+    TooManyMembers,
+    /// `LobbySettings::require_ready` is set and these many aren't ready
+    NotReady(usize),
+}
+
+/// A trigger that fires on the server when a [`StartLobbyMatch`] fails
+#[derive(Event, Debug, Clone, Copy)]
+pub struct LobbyStartFailed(pub LobbyStartError);
+
+/// The state sessions open in, see [`LobbyPlugin`]
+pub(crate) fn opening_state(lobby: Option<&LobbySettings>) -> SimulationState {
+    if lobby.is_some() { SimulationState::Lobby } else { SimulationState::Connecting }
+}
+
+/// The seat order of the lobby members: by slot, then by client id
+pub(super) fn seat_order(members: impl Iterator<Item = (ClientId, Option<u8>)>) -> Vec<ClientId> {
+    let mut members: Vec<(ClientId, Option<u8>)> = members.collect();
+    members.sort_unstable_by_key(|&(client, slot)| (slot.unwrap_or(u8::MAX), client));
+    members.into_iter().map(|(client, _)| client).collect()
+}
+
+fn open_lobby(mut commands: Commands, settings: Res<SimulationSettings>) {
+    info!("Lobby open for {} player(s)", settings.num_players);
+    commands.spawn((LobbyInfo { num_players: settings.num_players }, Replicated));
+}
+
+fn close_lobby(mut commands: Commands, lobby: Query<Entity, With<LobbyInfo>>) {
+    for entity in lobby.iter() {
+        commands.entity(entity).despawn();
+    }
+}
+
+fn add_lobby_member(
+    trigger: Trigger<OnAdd, NetworkId>,
+    mut commands: Commands,
+    ids: Query<&NetworkId>,
+    server: Res<RepliconServer>,
+    state: Res<State<SimulationState>>,
+) {
+    if !server.is_running() || *state.get() != SimulationState::Lobby { return }
+    let Ok(id) = ids.get(trigger.entity()) else { return };
+    commands.entity(trigger.entity()).insert(LobbyMember {
+        name: format!("Player {}", id.get()),
+        ..default()
+    });
+}
+
+fn set_lobby_name(
+    trigger: Trigger<FromClient<SetLobbyName>>,
+    clients: LockstepClients,
+    mut members: Query<&mut LobbyMember>,
+    settings: Res<LobbySettings>,
+) {
+    let Some(entity) = clients.entity(trigger.client_entity) else { return };
+    let Ok(mut member) = members.get_mut(entity) else { return };
+    let name: String = trigger.event.0.trim().chars().take(settings.max_name_len).collect();
+    if name.is_empty() { return }
+    member.name = name;
+}
+
+fn claim_lobby_slot(
+    trigger: Trigger<FromClient<ClaimLobbySlot>>,
+    clients: LockstepClients,
+    mut members: Query<(Entity, &mut LobbyMember)>,
+    settings: Res<SimulationSettings>,
+) {
+    let Some(entity) = clients.entity(trigger.client_entity) else { return };
+    let slot = trigger.event.0;
+    if let Some(slot) = slot {
+        let taken = members.iter().any(|(other, member)| other != entity && member.slot == Some(slot));
+        if slot >= settings.num_players || taken {
+            debug!("rejected lobby slot {} for {}", slot, entity);
+            return;
+        }
+    }
+    if let Ok((_, mut member)) = members.get_mut(entity) {
+        member.slot = slot;
+    }
+}
+
+fn set_lobby_ready(
+    trigger: Trigger<FromClient<SetLobbyReady>>,
+    clients: LockstepClients,
+    mut members: Query<&mut LobbyMember>,
+) {
+    let Some(entity) = clients.entity(trigger.client_entity) else { return };
+    if let Ok(mut member) = members.get_mut(entity) {
+        member.ready = trigger.event.0;
+    }
+}
+
+fn set_lobby_player_count(
+    trigger: Trigger<SetLobbyPlayerCount>,
+    server: Res<RepliconServer>,
+    state: Res<State<SimulationState>>,
+    mut settings: ResMut<SimulationSettings>,
+    mut lobby: Query<&mut LobbyInfo>,
+    mut members: Query<&mut LobbyMember>,
+) {
+    let num_players = trigger.event().0;
+    if !server.is_running() || *state.get() != SimulationState::Lobby || num_players == 0 {
+        warn!("Can't change the lobby player count to {}", num_players);
+        return;
+    }
+    info!("Lobby player count changed to {}", num_players);
+    settings.num_players = num_players;
+    for mut info in lobby.iter_mut() {
+        info.num_players = num_players;
+    }
+    // Slots past the new count are free again
+    for mut member in members.iter_mut() {
+        if member.slot.is_some_and(|slot| slot >= num_players) {
+            member.slot = None;
+        }
+    }
+}
+
+fn start_lobby_match(
+    _trigger: Trigger<StartLobbyMatch>,
+    mut commands: Commands,
+    server: Res<RepliconServer>,
+    state: Res<State<SimulationState>>,
+    lobby_settings: Res<LobbySettings>,
+    mut settings: ResMut<SimulationSettings>,
+    mut lobby: Query<&mut LobbyInfo>,
+    members: Query<&LobbyMember>,
+) {
+    let result = if !server.is_running() || *state.get() != SimulationState::Lobby {
+        Err(LobbyStartError::NotInLobby)
+    } else if members.is_empty() {
+        Err(LobbyStartError::NoMembers)
+    } else if members.iter().len() > settings.num_players as usize {
+        Err(LobbyStartError::TooManyMembers)
+    } else {
+        match members.iter().filter(|member| !member.ready).count() {
+            not_ready if lobby_settings.require_ready && not_ready > 0 => Err(LobbyStartError::NotReady(not_ready)),
+            _ => Ok(members.iter().len() as u8),
+        }
+    };
+    let num_players = match result {
+        Ok(num_players) => num_players,
+        Err(err) => {
+            warn!("Can't start the lobby match: {:?}", err);
+            commands.trigger(LobbyStartFailed(err));
+            return;
+        }
+    };
+    info!("Starting lobby match with {} player(s)", num_players);
+    settings.num_players = num_players;
+    for mut info in lobby.iter_mut() {
+        info.num_players = num_players;
+    }
+    commands.server_trigger(ToClients {
+        mode: SendMode::Broadcast,
+        event: LobbyMatchStarting { num_players },
+    });
+}
+
+/// Every peer, the server included, takes the final player count and
+/// moves on to `Connecting`
+fn receive_lobby_match_start(
+    trigger: Trigger<LobbyMatchStarting>,
+    mut settings: ResMut<SimulationSettings>,
+    mut sim_state: ResMut<NextState<SimulationState>>,
+) {
+    info!("Simulation entering state {:#?}", SimulationState::Connecting);
+    settings.num_players = trigger.num_players;
+    sim_state.set(SimulationState::Connecting);
+}
+
+/// Clients follow the host's player count, which takes part in the
+/// session hash
+fn sync_lobby_info(
+    lobby: Query<&LobbyInfo, Changed<LobbyInfo>>,
+    mut settings: ResMut<SimulationSettings>,
+) {
+    for info in lobby.iter() {
+        settings.num_players = info.num_players;
+    }
+}
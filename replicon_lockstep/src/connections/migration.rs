@@ -0,0 +1,213 @@
+use std::{net::SocketAddr, time::Duration};
+use bevy::{prelude::*, time::Stopwatch};
+use bevy_replicon::{prelude::*, shared::backend::connected_client::NetworkId};
+use serde::{Deserialize, Serialize};
+use crate::{prelude::*, commands::LockstepGameCommandsReceived, simulation::LastBroadcastTick};
+use super::{ClientReconnectTimer, LocalClient, ServerMode, HostingSeat, host_seat::HostSeatId};
+
+/// Continuing a [`ServerMode::Host`] match after the host left.  Clients
+/// that can host announce an address during Setup.  When the connection
+/// drops, every client elects the candidate with the lowest client id as
+/// the new host.  The new host keeps its simulation, starts a server and
+/// pauses, the others reconnect to it and resync through the usual
+/// reconnect flow.  Once everyone is back, or after `rejoin_timeout`, the
+/// match resumes.
+///
+/// A client can't tell the host leaving apart from losing its own
+/// connection, so only enable this where hosts leaving is the likely cause.
+#[derive(Debug, Clone)]
+pub struct HostMigrationSettings {
+    /// The address other clients reach this peer on if it's elected.
+    /// Peers without one are never elected.
+    pub advertised_address: Option<SocketAddr>,
+    /// How long the new host waits for the others before resuming
+    pub rejoin_timeout: Duration,
+}
+
+impl Default for HostMigrationSettings {
+    fn default() -> Self {
+        Self {
+            advertised_address: None,
+            rejoin_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Replicated on the client entities that can take over as host
+#[derive(Component, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HostMigrationCandidate {
+    pub address: SocketAddr,
+}
+
+/// Sent by clients with an `advertised_address` during Setup
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub(super) struct AnnounceMigrationCandidate(SocketAddr);
+
+/// A trigger that fires on every client that lost the host and elected a
+/// new one.  With the `renet-helpers` feature the transports are switched
+/// over automatically, otherwise start a server on the elected peer and
+/// point reconnect attempts of the others at `address`.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct HostMigrationStarted {
+    pub new_host: ClientId,
+    pub address: SocketAddr,
+    /// True on the new host itself
+    pub elected: bool,
+    /// The last tick this peer received from the previous host
+    pub tick: SimTick,
+}
+
+/// A trigger that fires on the new host when the match resumes
+#[derive(Event, Debug, Clone, Copy)]
+pub struct HostMigrationFinished {
+    pub tick: SimTick,
+    /// Clients back in the match, not counting the new host
+    pub rejoined: usize,
+    /// Clients expected back
+    pub expected: usize,
+}
+
+/// Server-side state of the new host while waiting for the others
+#[derive(Resource, Debug)]
+pub(crate) struct PendingHostMigration {
+    expected: usize,
+    rejoined: usize,
+    time: Stopwatch,
+}
+
+pub(super) fn announce_migration_candidate(
+    mut commands: Commands,
+    settings: Res<ConnectionSettings>,
+) {
+    let Some(address) = settings.host_migration.as_ref().and_then(|migration| migration.advertised_address) else { return };
+    debug!("announcing host migration address {}", address);
+    commands.client_trigger(AnnounceMigrationCandidate(address));
+}
+
+pub(super) fn receive_migration_candidate(
+    trigger: Trigger<FromClient<AnnounceMigrationCandidate>>,
+    mut commands: Commands,
+    clients: LockstepClients,
+    settings: Res<ConnectionSettings>,
+) {
+    // Only a host can leave, and it doesn't take over from itself
+    if settings.server_mode != ServerMode::Host || settings.host_migration.is_none() { return }
+    if clients.is_local(trigger.client_entity) { return }
+    let Some(client) = clients.entity(trigger.client_entity) else { return };
+    commands.entity(client).insert(HostMigrationCandidate { address: trigger.event.0 });
+}
+
+/// Elects the new host as soon as the connection drops
+pub(super) fn elect_new_host(
+    _trigger: Trigger<ClientReconnect>,
+    mut commands: Commands,
+    settings: Res<ConnectionSettings>,
+    candidates: Query<(&NetworkId, &HostMigrationCandidate), Without<HostingSeat>>,
+    local_client: Query<&NetworkId, With<LocalClient>>,
+    sim_tick: Option<Res<SimulationTick>>,
+) {
+    if settings.host_migration.is_none() { return }
+    let Some((new_host, address)) = candidates
+        .iter()
+        .map(|(id, candidate)| (id.get(), candidate.address))
+        .min_by_key(|&(id, _)| id)
+    else {
+        warn!("Lost the host, but nobody can take over");
+        return;
+    };
+    let elected = local_client.get_single().is_ok_and(|id| id.get() == new_host);
+    let tick = sim_tick.map_or(0, |tick| **tick);
+    info!("Lost the host at tick {}, client {} at {} takes over", tick, new_host, address);
+    commands.trigger(HostMigrationStarted { new_host, address, elected, tick });
+    if elected {
+        commands.queue(take_over_as_host);
+    }
+}
+
+/// Turns the elected client into the server of the match.  The client
+/// entities of the previous host are stale, the new server spawns its own,
+/// with a host seat keeping the elected client's id.
+fn take_over_as_host(world: &mut World) {
+    let Ok(local) = world
+        .query_filtered::<&NetworkId, With<LocalClient>>()
+        .get_single(world)
+        .map(|id| id.get())
+    else {
+        error!("Elected as host without a client id");
+        return;
+    };
+    let stale: Vec<(Entity, ClientId, bool)> = world
+        .query::<(Entity, &NetworkId, Has<HostingSeat>)>()
+        .iter(world)
+        .map(|(entity, id, hosting)| (entity, id.get(), hosting))
+        .collect();
+    let expected = stale
+        .iter()
+        .filter(|&&(_, id, hosting)| !hosting && id != local)
+        .count();
+    for (entity, ..) in stale {
+        world.despawn(entity);
+    }
+    let timers: Vec<Entity> = world
+        .query_filtered::<Entity, With<ClientReconnectTimer>>()
+        .iter(world)
+        .collect();
+    for entity in timers {
+        world.despawn(entity);
+    }
+
+    world.resource_mut::<ConnectionSettings>().server_mode = ServerMode::Host;
+    world.insert_resource(HostSeatId(local));
+    let tick = **world.resource::<SimulationTick>();
+    world.resource_mut::<LockstepGameCommandsReceived>().resize(tick + 1, default());
+    // The previous host broadcast every tick received so far
+    **world.resource_mut::<LastBroadcastTick>() = tick;
+    info!("Taking over as host at tick {}, waiting for {} client(s)", tick, expected);
+    world.insert_resource(PendingHostMigration { expected, rejoined: 0, time: Stopwatch::new() });
+    world.resource_mut::<PauseStatus>().reason = Some(PauseReason::HostMigration);
+    world.resource_mut::<NextState<SimulationState>>().set(SimulationState::Paused);
+}
+
+/// Lets rejoined clients, and the new host's own seat, pass the tick gating
+/// on the ticks before they sent anything to this server
+pub(super) fn count_rejoined_client(
+    trigger: Trigger<ClientResynced>,
+    migration: Option<ResMut<PendingHostMigration>>,
+    host: Option<Res<HostSeat>>,
+    mut received: ResMut<LockstepGameCommandsReceived>,
+    sim_tick: Res<SimulationTick>,
+    settings: Res<SimulationSettings>,
+) {
+    let Some(mut migration) = migration else { return };
+    migration.rejoined += 1;
+    debug!("{} of {} client(s) rejoined the new host", migration.rejoined, migration.expected);
+    let from = sim_tick.saturating_sub(settings.disconnect_tick_threshold as SimTick);
+    if received.len() <= **sim_tick as usize {
+        received.resize(**sim_tick + 1, default());
+    }
+    for tick in from..=**sim_tick {
+        for client in std::iter::once(trigger.client).chain(host.as_ref().map(|host| host.id)) {
+            received[tick as usize].entry(client).or_default();
+        }
+    }
+}
+
+/// Resumes once everyone rejoined, or the others took too long
+pub(super) fn check_migration_finished(
+    mut commands: Commands,
+    mut migration: ResMut<PendingHostMigration>,
+    settings: Res<ConnectionSettings>,
+    sim_tick: Res<SimulationTick>,
+    time: Res<Time<Real>>,
+) {
+    let timeout = settings.host_migration.as_ref().map_or(Duration::ZERO, |migration| migration.rejoin_timeout);
+    migration.time.tick(time.delta());
+    if migration.rejoined < migration.expected {
+        if migration.time.elapsed() < timeout { return }
+        warn!("Only {} of {} client(s) rejoined the new host in time", migration.rejoined, migration.expected);
+    }
+    info!("Host migration finished at tick {}", **sim_tick);
+    commands.remove_resource::<PendingHostMigration>();
+    commands.trigger(HostMigrationFinished { tick: **sim_tick, rejoined: migration.rejoined, expected: migration.expected });
+    commands.trigger(ResumeSimulation);
+}
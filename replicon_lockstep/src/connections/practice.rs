@@ -0,0 +1,67 @@
+use bevy::prelude::*;
+use bevy_replicon::prelude::*;
+use crate::prelude::*;
+use super::ServerMode;
+
+/// Trigger this in [`SimulationState::None`] to start a single player match,
+/// e.g. practice or a sandbox, played by the host alone.  The server runs
+/// without a transport, the match has one player and the host's own
+/// [`ClientReadyEvent`] moves it from Setup to Starting, so no remote
+/// client is waited for.  The match runs like any other, commands are sent,
+/// scheduled and recorded as usual.  The connection and simulation
+/// settings it changes are restored when the match returns to `None`.
+#[derive(Event, Debug, Clone, Copy, Default)]
+pub struct StartPracticeMatch;
+
+/// The settings a practice match overrode, restored once it's over
+#[derive(Resource)]
+pub(super) struct PracticeOverrides {
+    server_mode: ServerMode,
+    num_players: u8,
+    started_server: bool,
+}
+
+pub(super) fn start_practice_match(
+    _trigger: Trigger<StartPracticeMatch>,
+    mut commands: Commands,
+    mut server: ResMut<RepliconServer>,
+    mut connection_settings: ResMut<ConnectionSettings>,
+    mut simulation_settings: ResMut<SimulationSettings>,
+    state: Res<State<SimulationState>>,
+    mut next_state: ResMut<NextState<SimulationState>>,
+) {
+    if *state.get() != SimulationState::None {
+        warn!("Can't start a practice match in state {:?}", state.get());
+        return;
+    }
+    info!("Starting a practice match");
+    commands.insert_resource(PracticeOverrides {
+        server_mode: connection_settings.server_mode.clone(),
+        num_players: simulation_settings.num_players,
+        started_server: !server.is_running(),
+    });
+    // The host seat is spawned as soon as the server runs, and is the only
+    // player Connecting waits for
+    connection_settings.server_mode = ServerMode::Host;
+    simulation_settings.num_players = 1;
+    if !server.is_running() {
+        server.set_running(true);
+    }
+    next_state.set(SimulationState::Connecting);
+}
+
+pub(super) fn end_practice_match(
+    mut commands: Commands,
+    overrides: Res<PracticeOverrides>,
+    mut server: ResMut<RepliconServer>,
+    mut connection_settings: ResMut<ConnectionSettings>,
+    mut simulation_settings: ResMut<SimulationSettings>,
+) {
+    debug!("practice match over, restoring the settings");
+    connection_settings.server_mode = overrides.server_mode.clone();
+    simulation_settings.num_players = overrides.num_players;
+    if overrides.started_server {
+        server.set_running(false);
+    }
+    commands.remove_resource::<PracticeOverrides>();
+}
@@ -0,0 +1,100 @@
+use std::time::Duration;
+use bevy::{prelude::*, time::Stopwatch, utils::hashbrown::HashMap};
+use bevy_replicon::{prelude::*, shared::backend::connected_client::NetworkId};
+use serde::{Deserialize, Serialize};
+use crate::prelude::*;
+use super::seats;
+
+/// Connection quality every client must show before a match starts
+#[derive(Debug, Clone)]
+pub struct QualificationSettings {
+    /// How long the server measures before deciding
+    pub duration: Duration,
+    /// Highest allowed mean round trip time
+    pub max_rtt: Duration,
+    /// Highest allowed standard deviation of the round trip time
+    pub max_jitter: Duration,
+}
+
+impl Default for QualificationSettings {
+    fn default() -> Self {
+        Self {
+            duration: Duration::from_secs(3),
+            max_rtt: Duration::from_millis(250),
+            max_jitter: Duration::from_millis(50),
+        }
+    }
+}
+
+/// Measured connection quality of one client
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ConnectionQuality {
+    pub client: ClientId,
+    /// Mean round trip time in seconds
+    pub rtt: f64,
+    /// Standard deviation of the round trip time in seconds
+    pub jitter: f64,
+}
+
+/// Broadcast by the server when clients failed qualification.  The match
+/// stays in Connecting.
+#[derive(Event, Debug, Clone, Serialize, Deserialize)]
+pub struct QualificationFailed {
+    pub offenders: Vec<ConnectionQuality>,
+}
+
+/// Server-only measurement in progress
+#[derive(Resource, Default)]
+pub(super) struct Qualification {
+    time: Stopwatch,
+    samples: HashMap<ClientId, Vec<f64>>,
+}
+
+impl Qualification {
+    fn quality(client: ClientId, samples: &[f64]) -> ConnectionQuality {
+        let count = samples.len().max(1) as f64;
+        let rtt = samples.iter().sum::<f64>() / count;
+        let variance = samples.iter().map(|sample| (sample - rtt).powi(2)).sum::<f64>() / count;
+        ConnectionQuality { client, rtt, jitter: variance.sqrt() }
+    }
+}
+
+pub(super) fn measure_connection_quality(
+    mut commands: Commands,
+    mut qualification: ResMut<Qualification>,
+    clients: Query<(&NetworkId, &NetworkStats)>,
+    ids: Query<(&NetworkId, Option<&LobbyMember>, &ClientRole)>,
+    settings: Res<ConnectionSettings>,
+    simulation_settings: Res<SimulationSettings>,
+    time: Res<Time>,
+) {
+    let Some(limits) = settings.qualification.as_ref() else { return };
+    let players = ids.iter().filter(|(.., &role)| role == ClientRole::Player).count();
+    if players < simulation_settings.num_players as usize {
+        // Measuring restarts once everyone is connected again
+        info!("Player disconnected during connection qualification");
+        commands.remove_resource::<Qualification>();
+        return
+    }
+    for (id, stats) in clients.iter() {
+        qualification.samples.entry(id.get()).or_default().push(stats.rtt);
+    }
+    qualification.time.tick(time.delta());
+    if qualification.time.elapsed() < limits.duration { return }
+
+    let offenders: Vec<ConnectionQuality> = qualification.samples
+        .iter()
+        .map(|(&client, samples)| Qualification::quality(client, samples))
+        .filter(|quality| quality.rtt > limits.max_rtt.as_secs_f64() || quality.jitter > limits.max_jitter.as_secs_f64())
+        .collect();
+    commands.remove_resource::<Qualification>();
+    if offenders.is_empty() {
+        seats::begin_setup(&mut commands, seats::full_layout(&ids));
+    } else {
+        warn!("{} client(s) failed connection qualification", offenders.len());
+        commands.server_trigger(ToClients {
+            mode: SendMode::Broadcast,
+            event: QualificationFailed { offenders },
+        });
+    }
+}
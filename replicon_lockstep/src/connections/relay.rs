@@ -0,0 +1,190 @@
+use std::net::SocketAddr;
+use bevy::prelude::*;
+use bevy_replicon::{prelude::*, shared::backend::connected_client::NetworkId};
+use serde::{Deserialize, Serialize};
+use crate::prelude::*;
+use super::LocalClient;
+
+/// Digests of the ticks up to `resume_tick` a client sends to the
+/// replacement server
+const VERIFIED_TICKS: SimTick = 32;
+
+/// Trigger on the server to move every client to a replacement endpoint,
+/// e.g. before a relay or dedicated server restarts for a deploy.  The
+/// simulation pauses and [`MigrateTransport`] is broadcast with the last
+/// tick.  The replacement must carry the same session and command history
+/// up to that tick, e.g. restored from a replay, and wait paused.  Clients
+/// reconnect to it through the usual reconnect flow, and it resumes once
+/// they resynced with `ConnectionSettings::resume_after_resync`.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct RequestTransportMigration {
+    pub new_addr: SocketAddr,
+}
+
+/// Broadcast by the server that is about to go away.  With the
+/// `renet-helpers` feature, reconnect attempts of clients go to `new_addr`
+/// once the connection drops, otherwise point them there from
+/// [`ReconnectAttempt`].
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MigrateTransport {
+    pub new_addr: SocketAddr,
+    /// The last tick of the previous server, the replacement resumes after it
+    pub resume_tick: SimTick,
+}
+
+/// Sent by a client once connected to the replacement, with the digests of
+/// its last ticks up to the resume tick
+#[derive(Event, Serialize, Deserialize, Debug, Clone)]
+pub(super) struct VerifyTransportResume {
+    resume_tick: SimTick,
+    digests: Vec<(SimTick, u64)>,
+}
+
+/// Sent by the replacement server in reply to [`VerifyTransportResume`]
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub(super) struct TransportResumeVerified {
+    resume_tick: SimTick,
+    /// The first tick whose digest didn't match the replacement's history
+    mismatch: Option<SimTick>,
+}
+
+/// A trigger that fires on the client when the replacement server carries
+/// the same ticks, and on the replacement for every verified client
+#[derive(Event, Debug, Clone, Copy)]
+pub struct TransportMigrationFinished {
+    pub client: ClientId,
+    pub resume_tick: SimTick,
+}
+
+/// A trigger that fires on the client when the replacement server's
+/// history differs from the ticks it received before, and on the
+/// replacement.  The client leaves the match, and the replacement makes it
+/// a spectator so no tick waits on it.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct TransportMigrationFailed {
+    pub client: ClientId,
+    /// The first tick that differs
+    pub tick: SimTick,
+}
+
+/// Client-side state between receiving [`MigrateTransport`] and the
+/// replacement verifying the resume
+#[derive(Resource, Debug)]
+pub(crate) struct PendingTransportMigration {
+    resume_tick: SimTick,
+    /// The connection to the previous server dropped
+    dropped: bool,
+    sent: bool,
+}
+
+pub(super) fn request_transport_migration(
+    trigger: Trigger<RequestTransportMigration>,
+    mut commands: Commands,
+    server: Res<RepliconServer>,
+    state: Res<State<SimulationState>>,
+    sim_tick: Res<SimulationTick>,
+) {
+    if !server.is_running() { return }
+    let new_addr = trigger.new_addr;
+    info!("Moving clients to {} after tick {}", new_addr, **sim_tick);
+    if *state.get() == SimulationState::Running {
+        commands.trigger(PauseSimulation { reason: PauseReason::TransportMigration });
+    }
+    commands.server_trigger(ToClients {
+        mode: SendMode::Broadcast,
+        event: MigrateTransport { new_addr, resume_tick: **sim_tick },
+    });
+}
+
+pub(super) fn receive_migrate_transport(
+    trigger: Trigger<MigrateTransport>,
+    mut commands: Commands,
+    server: Res<RepliconServer>,
+) {
+    // The server receives its own broadcast
+    if server.is_running() { return }
+    let MigrateTransport { new_addr, resume_tick } = *trigger.event();
+    info!("Server moves to {}, resuming after tick {}", new_addr, resume_tick);
+    commands.insert_resource(PendingTransportMigration { resume_tick, dropped: false, sent: false });
+}
+
+/// A migration left unfinished doesn't carry over into the next match
+pub(super) fn clear_transport_migration(mut commands: Commands) {
+    commands.remove_resource::<PendingTransportMigration>();
+}
+
+/// Sends the digests to the replacement once the connection moved over
+pub(super) fn send_resume_digests(
+    mut commands: Commands,
+    mut migration: ResMut<PendingTransportMigration>,
+    client: Res<RepliconClient>,
+    digests: Res<TickDigests>,
+) {
+    if !client.is_connected() {
+        migration.dropped = true;
+        return;
+    }
+    if !migration.dropped || migration.sent { return }
+    migration.sent = true;
+    let resume_tick = migration.resume_tick;
+    let digests = (resume_tick.saturating_sub(VERIFIED_TICKS - 1).max(1)..=resume_tick)
+        .filter_map(|tick| digests.get(tick).copied().flatten().map(|digest| (tick, digest)))
+        .collect();
+    debug!("verifying ticks up to {} with the replacement server", resume_tick);
+    commands.client_trigger(VerifyTransportResume { resume_tick, digests });
+}
+
+pub(super) fn verify_transport_resume(
+    trigger: Trigger<FromClient<VerifyTransportResume>>,
+    mut commands: Commands,
+    clients: LockstepClients,
+    history: Res<LockstepGameCommandBuffer>,
+    registry: Res<AppTypeRegistry>,
+) {
+    let Some(client) = clients.client_id(trigger.client_entity) else { return };
+    let request = &trigger.event;
+    let registry = registry.read();
+    let mismatch = request.digests
+        .iter()
+        .find(|&&(tick, digest)| history
+            .get(tick)
+            .is_none_or(|tick_commands| hash_tick_commands(tick_commands, &registry) != digest))
+        .map(|&(tick, _)| tick);
+    match mismatch {
+        Some(tick) => {
+            warn!("client {} received a different tick {} from the previous server", client, tick);
+            commands.trigger(TransportMigrationFailed { client, tick });
+            // The client leaves the match, so ticks must not wait on it
+            commands.trigger(MakeSpectator(client));
+        }
+        None => commands.trigger(TransportMigrationFinished { client, resume_tick: request.resume_tick }),
+    }
+    commands.server_trigger(ToClients {
+        mode: SendMode::Direct(trigger.client_entity),
+        event: TransportResumeVerified { resume_tick: request.resume_tick, mismatch },
+    });
+}
+
+pub(super) fn receive_transport_resume_verified(
+    trigger: Trigger<TransportResumeVerified>,
+    mut commands: Commands,
+    server: Res<RepliconServer>,
+    local_client: Query<&NetworkId, With<LocalClient>>,
+    mut next_state: ResMut<NextState<SimulationState>>,
+) {
+    if server.is_running() { return }
+    commands.remove_resource::<PendingTransportMigration>();
+    let client = local_client.get_single().map_or(0, |id| id.get());
+    let TransportResumeVerified { resume_tick, mismatch } = *trigger.event();
+    match mismatch {
+        Some(tick) => {
+            error!("The replacement server's tick {} differs from the previous server", tick);
+            commands.trigger(TransportMigrationFailed { client, tick });
+            next_state.set(SimulationState::None);
+        }
+        None => {
+            info!("Moved to the replacement server, resuming after tick {}", resume_tick);
+            commands.trigger(TransportMigrationFinished { client, resume_tick });
+        }
+    }
+}
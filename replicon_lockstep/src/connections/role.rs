@@ -0,0 +1,89 @@
+use bevy::prelude::*;
+use bevy_replicon::{prelude::*, shared::backend::connected_client::NetworkId};
+use serde::{Deserialize, Serialize};
+use crate::prelude::*;
+use super::LocalClient;
+
+/// Whether a connected client plays or only watches.  The server inserts it
+/// on client entities once the client identified itself, and replicates it.
+/// Spectators receive every tick but the server never waits for them, and
+/// they can join a match in progress.
+#[derive(Component, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ClientRole {
+    #[default]
+    Player,
+    Spectator,
+}
+
+/// What the local peer is in the current session.  Kept up to date by the
+/// crate every frame, including while reconnecting, so game code can check
+/// it instead of combining `RepliconServer` and [`LocalClient`] queries.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum LockstepRole {
+    /// Not part of a session
+    #[default]
+    None,
+    /// Dedicated server without a local player
+    Server,
+    /// Server with a local player.  A host that became a spectator keeps
+    /// this role, it still schedules ticks.
+    HostClient,
+    /// Remote client with a local player
+    RemoteClient,
+    /// Remote client that no longer plays, see [`LockstepSpectators`], or
+    /// joined as a [`ClientRole::Spectator`]
+    Spectator,
+    /// Remote client driven by injected inputs instead of a player, see
+    /// `ConnectionSettings::bot_client`
+    Bot,
+}
+
+impl LockstepRole {
+    /// True if the local peer schedules ticks for everyone
+    pub fn is_authority(&self) -> bool {
+        matches!(self, Self::Server | Self::HostClient)
+    }
+
+    /// True if the local peer sends commands
+    pub fn sends_commands(&self) -> bool {
+        matches!(self, Self::HostClient | Self::RemoteClient | Self::Bot)
+    }
+
+    pub fn is_remote(&self) -> bool {
+        matches!(self, Self::RemoteClient | Self::Spectator | Self::Bot)
+    }
+}
+
+pub(super) fn update_lockstep_role(
+    mut role: ResMut<LockstepRole>,
+    server: Res<RepliconServer>,
+    client: Res<RepliconClient>,
+    state: Res<State<SimulationState>>,
+    local_client: Query<(&NetworkId, Option<&ClientRole>), With<LocalClient>>,
+    spectators: Res<LockstepSpectators>,
+    sim_tick: Option<Res<SimulationTick>>,
+    settings: Res<ConnectionSettings>,
+) {
+    let local = local_client.get_single().ok();
+    let tick = sim_tick.map_or(0, |tick| **tick);
+    let spectating = local.is_some_and(|(id, role)| {
+        role == Some(&ClientRole::Spectator) || spectators.is_spectating(id.get(), tick)
+    });
+    let current = if server.is_running() {
+        if local.is_some() { LockstepRole::HostClient } else { LockstepRole::Server }
+    } else if !client.is_disconnected() || *state.get() == SimulationState::Reconnecting {
+        if spectating {
+            LockstepRole::Spectator
+        } else if settings.bot_client {
+            LockstepRole::Bot
+        } else {
+            LockstepRole::RemoteClient
+        }
+    } else {
+        LockstepRole::None
+    };
+    if *role != current {
+        debug!("local role changed from {:?} to {:?}", *role, current);
+        *role = current;
+    }
+}
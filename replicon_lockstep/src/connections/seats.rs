@@ -0,0 +1,119 @@
+use bevy::prelude::*;
+use bevy_replicon::{prelude::*, shared::backend::connected_client::NetworkId};
+use serde::{Deserialize, Serialize};
+use crate::{prelude::*, simulation::SetSimulationState};
+use super::lobby;
+
+/// What happens to seats that are still empty when the match starts early
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EmptySeatPolicy {
+    /// Empty seats are played by bots, driven by game code on every peer
+    #[default]
+    Bots,
+    /// Empty seats are removed, the match is played with fewer players
+    Remove,
+}
+
+/// Trigger this on the server to begin Setup before all
+/// `SimulationSettings::num_players` seats are filled
+#[derive(Event, Debug, Clone, Copy, Default)]
+pub struct StartMatchEarly(pub EmptySeatPolicy);
+
+/// A seat in the match
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Seat {
+    Player(ClientId),
+    /// A bot seat, numbered from 0
+    Bot(u8),
+}
+
+/// The final seat layout, broadcast by the server on entering Setup so every
+/// peer constructs the same player list.  Players are seated in client id
+/// order, followed by any bots.
+#[derive(Event, Resource, Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SeatLayout {
+    pub seats: Vec<Seat>,
+}
+
+impl SeatLayout {
+    fn new(mut players: Vec<ClientId>, bots: u8) -> Self {
+        players.sort_unstable();
+        Self::ordered(players, bots)
+    }
+
+    /// Seats players in the given order
+    fn ordered(players: Vec<ClientId>, bots: u8) -> Self {
+        let seats = players
+            .into_iter()
+            .map(Seat::Player)
+            .chain((0..bots).map(Seat::Bot))
+            .collect();
+        Self { seats }
+    }
+
+    pub fn players(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.seats.iter().filter_map(|seat| match seat {
+            Seat::Player(client) => Some(*client),
+            Seat::Bot(_) => None,
+        })
+    }
+
+    pub fn num_bots(&self) -> usize {
+        self.seats.iter().filter(|seat| matches!(seat, Seat::Bot(_))).count()
+    }
+
+    /// The seat index of a player
+    pub fn seat_of(&self, client: ClientId) -> Option<usize> {
+        self.seats.iter().position(|seat| *seat == Seat::Player(client))
+    }
+}
+
+/// Broadcasts the seat layout and moves every peer to Setup
+pub(super) fn begin_setup(commands: &mut Commands, layout: SeatLayout) {
+    info!("Starting match with {} player(s) and {} bot(s)", layout.players().count(), layout.num_bots());
+    commands.insert_resource(layout.clone());
+    commands.server_trigger(ToClients {
+        mode: SendMode::Broadcast,
+        event: layout,
+    });
+    commands.server_trigger(ToClients {
+        mode: SendMode::Broadcast,
+        event: SetSimulationState(SimulationState::Setup),
+    });
+}
+
+/// Every connected player, seated by lobby slot if there was a lobby
+pub(super) fn full_layout(ids: &Query<(&NetworkId, Option<&LobbyMember>, &ClientRole)>) -> SeatLayout {
+    let players = ids.iter()
+        .filter(|(.., &role)| role == ClientRole::Player)
+        .map(|(id, member, _)| (id.get(), member.and_then(|member| member.slot)));
+    let players = lobby::seat_order(players);
+    SeatLayout::ordered(players, 0)
+}
+
+pub(super) fn start_match_early(
+    trigger: Trigger<StartMatchEarly>,
+    mut commands: Commands,
+    ids: Query<(&NetworkId, &ClientRole)>,
+    server: Res<RepliconServer>,
+    state: Res<State<SimulationState>>,
+    settings: Res<SimulationSettings>,
+) {
+    if !server.is_running() || *state.get() != SimulationState::Connecting { return }
+    let players: Vec<ClientId> = ids.iter()
+        .filter(|(_, &role)| role == ClientRole::Player)
+        .map(|(id, _)| id.get())
+        .collect();
+    let bots = match trigger.event().0 {
+        EmptySeatPolicy::Bots => (settings.num_players as usize).saturating_sub(players.len()) as u8,
+        EmptySeatPolicy::Remove => 0,
+    };
+    begin_setup(&mut commands, SeatLayout::new(players, bots));
+}
+
+pub(super) fn receive_seat_layout(
+    trigger: Trigger<SeatLayout>,
+    mut commands: Commands,
+) {
+    commands.insert_resource(trigger.event().clone());
+}
@@ -0,0 +1,102 @@
+//! Match and tick correlation for logs.  Crate systems run inside a
+//! `lockstep` span carrying the match id and tick, so log lines from several
+//! sessions on one dedicated server can be told apart, and server and client
+//! logs of the same match can be lined up by tick.
+
+use std::fmt;
+use bevy::{
+    log::{tracing_subscriber::{layer::Context, registry::LookupSpan, Layer, Registry}, BoxedLayer},
+    prelude::*,
+    utils::tracing::{
+        self,
+        field::{Field, Visit},
+        span::{Attributes, Id},
+        Span, Subscriber,
+    },
+};
+use crate::prelude::*;
+
+/// Identifies a point in a match: the match id is the [`SessionHash`], which
+/// every peer shares and which changes with every new seed
+#[derive(Resource, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct MatchCorrelation {
+    pub match_id: u32,
+    pub tick: SimTick,
+}
+
+impl fmt::Display for MatchCorrelation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}@{}", self.match_id, self.tick)
+    }
+}
+
+impl MatchCorrelation {
+    pub fn new(session: SessionHash, tick: SimTick) -> Self {
+        Self { match_id: *session, tick }
+    }
+
+    /// A `lockstep` span tagging everything logged inside it
+    pub fn span(&self) -> Span {
+        info_span!("lockstep", match_id = self.match_id, tick = self.tick)
+    }
+
+    /// The correlation of the innermost enclosing `lockstep` span.  Requires
+    /// [`LockstepCorrelationLayer`] to be installed.
+    pub fn current() -> Option<Self> {
+        let id = Span::current().id()?;
+        tracing::dispatcher::get_default(|dispatch| {
+            let registry = dispatch.downcast_ref::<Registry>()?;
+            let span = registry.span(&id)?;
+            span.scope().find_map(|span| span.extensions().get::<MatchCorrelation>().copied())
+        })
+    }
+}
+
+/// Tracing layer that attaches a [`MatchCorrelation`] to every `lockstep`
+/// span, so [`MatchCorrelation::current`] and other layers can read it
+/// from the span extensions
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LockstepCorrelationLayer;
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for LockstepCorrelationLayer {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if attrs.metadata().name() != "lockstep" { return }
+        let mut correlation = CorrelationVisitor::default();
+        attrs.record(&mut correlation);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(correlation.0);
+        }
+    }
+}
+
+/// For `LogPlugin::custom_layer`
+pub fn lockstep_correlation_layer(_app: &mut App) -> Option<BoxedLayer> {
+    Some(Box::new(LockstepCorrelationLayer))
+}
+
+#[derive(Default)]
+struct CorrelationVisitor(MatchCorrelation);
+
+impl Visit for CorrelationVisitor {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match field.name() {
+            "match_id" => self.0.match_id = value as u32,
+            "tick" => self.0.tick = value as SimTick,
+            _ => {}
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn fmt::Debug) {}
+}
+
+pub(crate) fn update_match_correlation(
+    session: Res<SessionHash>,
+    sim_tick: Option<Res<SimulationTick>>,
+    mut correlation: ResMut<MatchCorrelation>,
+) {
+    let tick = sim_tick.map_or(0, |tick| **tick);
+    let current = MatchCorrelation::new(*session, tick);
+    if *correlation != current {
+        *correlation = current;
+    }
+}
@@ -0,0 +1,184 @@
+//! Desync detection.  Every peer hashes the simulation state once per tick
+//! with [`hash_simulation_state`] and periodically reports the hashes to the
+//! server, which compares them between peers.  Which components count as
+//! state is configured with [`StateHashAppExt`], e.g.
+//! `app.include_in_state_hash::<Transform>()`.
+
+use std::{collections::BTreeMap, sync::Arc};
+use bevy::{prelude::*, utils::hashbrown::HashMap};
+use bevy_replicon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::prelude::*;
+
+/// Per-tick state hashing and comparison between peers
+#[derive(Default)]
+pub struct DesyncDetectionPlugin {
+    pub settings: DesyncDetectionSettings,
+}
+
+impl Plugin for DesyncDetectionPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(self.settings.clone())
+            .init_resource::<LocalStateHashes>()
+            .init_resource::<ReportedStateHashes>()
+            .add_client_trigger::<StateHashReport>(Channel::Ordered)
+            .add_server_trigger::<DesyncDetected>(Channel::Ordered)
+            .add_observer(hash_after_game_commands)
+            .add_observer(hash_state)
+            .add_observer(compare_state_hashes)
+            .add_systems(OnEnter(SimulationState::Setup), clear_state_hashes)
+            .add_systems(PostUpdate, send_state_hashes
+                .run_if(in_state(SimulationState::Running).or(in_state(SimulationState::Ending)))
+                .before(ClientSet::Send));
+    }
+}
+
+#[derive(Resource, Debug, Clone)]
+pub struct DesyncDetectionSettings {
+    /// Peers report their hashes every this many ticks
+    pub report_interval: SimTick,
+    /// Hash the state whenever [`GameCommandsApplied`] fires.  Games that
+    /// step their simulation in their own systems should disable this and
+    /// trigger [`HashSimulationState`] once a tick is fully simulated.
+    pub hash_after_game_commands: bool,
+    /// The server forgets hashes this many ticks older than the current tick,
+    /// e.g. from peers that disconnected before reporting
+    pub retained_ticks: SimTick,
+}
+
+impl Default for DesyncDetectionSettings {
+    fn default() -> Self {
+        Self {
+            report_interval: 30,
+            hash_after_game_commands: true,
+            retained_ticks: 600,
+        }
+    }
+}
+
+/// Trigger this on every peer once a tick is fully simulated to record its
+/// state hash
+#[derive(Event, Debug, Clone, Copy)]
+pub struct HashSimulationState(pub SimTick);
+
+/// Hashes of consecutive ticks sent by a peer to the server
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StateHashReport {
+    pub hashes: Vec<(SimTick, u64)>,
+}
+
+/// Broadcast by the server when peers disagree on the state of a tick.
+/// `clients` are the peers whose hash differs from the most common one.
+#[derive(Event, Serialize, Deserialize, Debug, Clone)]
+pub struct DesyncDetected {
+    pub tick: SimTick,
+    pub clients: Vec<ClientId>,
+}
+
+/// Hashes recorded locally and not reported yet
+#[derive(Resource, Default, Deref, DerefMut)]
+struct LocalStateHashes(Vec<(SimTick, u64)>);
+
+/// Server-only hashes per tick, until every peer reported them
+#[derive(Resource, Default, Deref, DerefMut)]
+struct ReportedStateHashes(BTreeMap<SimTick, BTreeMap<ClientId, u64>>);
+
+fn hash_after_game_commands(
+    trigger: Trigger<GameCommandsApplied>,
+    mut commands: Commands,
+    settings: Res<DesyncDetectionSettings>,
+) {
+    if settings.hash_after_game_commands {
+        commands.trigger(HashSimulationState(trigger.event().0));
+    }
+}
+
+fn hash_state(
+    trigger: Trigger<HashSimulationState>,
+    world: &World,
+    mut commands: Commands,
+) {
+    let tick = trigger.event().0;
+    let hash = hash_simulation_state(world);
+    trace!("state hash of tick {} is {:016x}", tick, hash);
+    commands.queue(move |world: &mut World| {
+        world.resource_mut::<LocalStateHashes>().push((tick, hash));
+    });
+}
+
+fn send_state_hashes(
+    mut commands: Commands,
+    mut hashes: ResMut<LocalStateHashes>,
+    settings: Res<DesyncDetectionSettings>,
+    state: Res<State<SimulationState>>,
+) {
+    // Flush whatever is left when the match ends
+    let ending = *state.get() == SimulationState::Ending;
+    if hashes.is_empty() || (!ending && (hashes.len() as SimTick) < settings.report_interval) { return }
+    commands.client_trigger(StateHashReport { hashes: std::mem::take(&mut hashes.0) });
+}
+
+fn compare_state_hashes(
+    trigger: Trigger<FromClient<StateHashReport>>,
+    mut commands: Commands,
+    clients: LockstepClients,
+    mut reported: ResMut<ReportedStateHashes>,
+    sim_tick: Res<SimulationTick>,
+    settings: Res<DesyncDetectionSettings>,
+) {
+    let Some(client_id) = clients.client_id(trigger.client_entity) else { return };
+    for &(tick, hash) in &trigger.event.hashes {
+        reported.entry(tick).or_default().insert(client_id, hash);
+    }
+
+    let num_clients = clients.len();
+    let complete: Vec<SimTick> = reported
+        .iter()
+        .filter(|(_, hashes)| hashes.len() >= num_clients)
+        .map(|(&tick, _)| tick)
+        .collect();
+    for tick in complete {
+        let Some(hashes) = reported.remove(&tick) else { continue };
+        let mut counts: HashMap<u64, usize> = HashMap::new();
+        for hash in hashes.values() {
+            *counts.entry(*hash).or_default() += 1;
+        }
+        if counts.len() < 2 { continue }
+        // Ties are broken by the smallest hash so the choice is stable
+        let (&common, _) = counts
+            .iter()
+            .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+            .expect("at least two hashes");
+        let clients: Vec<ClientId> = hashes
+            .iter()
+            .filter(|(_, hash)| **hash != common)
+            .map(|(&client, _)| client)
+            .collect();
+        error!("Desync detected on tick {}, clients {:?} differ", tick, clients);
+        let mut report = format!("desync on tick {}, expected state hash {:016x}\n", tick, common);
+        for (client, hash) in hashes.iter() {
+            report.push_str(&format!("client {}: {:016x}\n", client, hash));
+        }
+        commands.trigger(StoreArtifact {
+            kind: ArtifactKind::CrashLog,
+            name: format!("desync-{}.txt", tick),
+            data: Arc::new(report.into_bytes()),
+        });
+        commands.server_trigger(ToClients {
+            mode: SendMode::Broadcast,
+            event: DesyncDetected { tick, clients },
+        });
+    }
+
+    let oldest = sim_tick.saturating_sub(settings.retained_ticks);
+    reported.retain(|&tick, _| tick >= oldest);
+}
+
+fn clear_state_hashes(
+    mut local: ResMut<LocalStateHashes>,
+    mut reported: ResMut<ReportedStateHashes>,
+) {
+    local.clear();
+    reported.clear();
+}
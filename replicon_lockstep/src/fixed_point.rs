@@ -0,0 +1,471 @@
+//! Deterministic math for simulation state and command payloads.  Floats
+//! can round differently between CPUs, compilers and optimization levels,
+//! so positions, velocities and forces that feed back into the simulation
+//! are better stored as [`Fixed`], a signed 32.32 fixed-point number whose
+//! arithmetic is plain integer math.  Floats only appear at the edges, when
+//! converting player input into commands and simulated state into a
+//! [`Transform`] for rendering.
+
+use std::{fmt, iter::Sum, ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssign}};
+use bevy::{prelude::*, transform::TransformSystem};
+use serde::{Deserialize, Serialize};
+
+/// Registers the deterministic math types and copies every
+/// [`DeterministicTransform`] into its entity's [`Transform`] each frame
+pub struct DeterministicMathPlugin;
+
+impl Plugin for DeterministicMathPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .register_type::<Fixed>()
+            .register_type::<FixedVec3>()
+            .register_type::<FixedQuat>()
+            .register_type::<DeterministicTransform>()
+            .add_systems(PostUpdate, sync_deterministic_transforms
+                .before(TransformSystem::TransformPropagate));
+    }
+}
+
+const FRACTION_BITS: u32 = 32;
+
+/// A signed 32.32 fixed-point number.  Every operation is integer math and
+/// gives the same result on every platform.  Overflow wraps, like integers
+/// in release builds, and division by zero panics.
+#[derive(Serialize, Deserialize, Reflect, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[reflect(Default, PartialEq, Hash)]
+pub struct Fixed(i64);
+
+impl Fixed {
+    pub const ZERO: Self = Self(0);
+    pub const ONE: Self = Self(1 << FRACTION_BITS);
+    pub const HALF: Self = Self(1 << (FRACTION_BITS - 1));
+    pub const MIN: Self = Self(i64::MIN);
+    pub const MAX: Self = Self(i64::MAX);
+
+    pub const fn from_bits(bits: i64) -> Self {
+        Self(bits)
+    }
+
+    pub const fn to_bits(self) -> i64 {
+        self.0
+    }
+
+    pub const fn from_int(value: i32) -> Self {
+        Self((value as i64) << FRACTION_BITS)
+    }
+
+    /// `numerator / denominator`, e.g. `Fixed::from_ratio(3, 2)` for 1.5
+    pub const fn from_ratio(numerator: i32, denominator: i32) -> Self {
+        Self(((numerator as i64) << FRACTION_BITS) / denominator as i64)
+    }
+
+    /// Converts a float, truncating towards zero.  The result only depends
+    /// on the bits of `value`, so it is deterministic as long as `value`
+    /// is, e.g. a constant or a value decoded from a command.  Don't feed
+    /// it floats computed differently on each peer.
+    pub fn from_f32(value: f32) -> Self {
+        Self::from_f64(value as f64)
+    }
+
+    pub fn from_f64(value: f64) -> Self {
+        Self((value * (1u64 << FRACTION_BITS) as f64) as i64)
+    }
+
+    /// For presentation only, the result must not flow back into the
+    /// simulation
+    pub fn to_f32(self) -> f32 {
+        self.to_f64() as f32
+    }
+
+    pub fn to_f64(self) -> f64 {
+        self.0 as f64 / (1u64 << FRACTION_BITS) as f64
+    }
+
+    /// The integer part, rounded towards negative infinity
+    pub const fn floor_to_int(self) -> i32 {
+        (self.0 >> FRACTION_BITS) as i32
+    }
+
+    pub const fn abs(self) -> Self {
+        Self(self.0.wrapping_abs())
+    }
+
+    pub const fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    pub fn min(self, other: Self) -> Self {
+        Ord::min(self, other)
+    }
+
+    pub fn max(self, other: Self) -> Self {
+        Ord::max(self, other)
+    }
+
+    pub fn clamp(self, min: Self, max: Self) -> Self {
+        Ord::clamp(self, min, max)
+    }
+
+    /// Square root, rounded down.  Negative values give zero.
+    pub fn sqrt(self) -> Self {
+        if self.0 <= 0 { return Self::ZERO }
+        Self(((self.0 as u128) << FRACTION_BITS).isqrt() as i64)
+    }
+}
+
+impl fmt::Debug for Fixed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Fixed({})", self.to_f64())
+    }
+}
+
+impl fmt::Display for Fixed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.to_f64(), f)
+    }
+}
+
+impl From<i32> for Fixed {
+    fn from(value: i32) -> Self {
+        Self::from_int(value)
+    }
+}
+
+impl Add for Fixed {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self {
+        Self(self.0.wrapping_add(rhs.0))
+    }
+}
+
+impl Sub for Fixed {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self {
+        Self(self.0.wrapping_sub(rhs.0))
+    }
+}
+
+impl Mul for Fixed {
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self {
+        Self(((self.0 as i128 * rhs.0 as i128) >> FRACTION_BITS) as i64)
+    }
+}
+
+impl Div for Fixed {
+    type Output = Self;
+    fn div(self, rhs: Self) -> Self {
+        Self((((self.0 as i128) << FRACTION_BITS) / rhs.0 as i128) as i64)
+    }
+}
+
+impl Neg for Fixed {
+    type Output = Self;
+    fn neg(self) -> Self {
+        Self(self.0.wrapping_neg())
+    }
+}
+
+impl AddAssign for Fixed {
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
+
+impl SubAssign for Fixed {
+    fn sub_assign(&mut self, rhs: Self) {
+        *self = *self - rhs;
+    }
+}
+
+impl MulAssign for Fixed {
+    fn mul_assign(&mut self, rhs: Self) {
+        *self = *self * rhs;
+    }
+}
+
+impl DivAssign for Fixed {
+    fn div_assign(&mut self, rhs: Self) {
+        *self = *self / rhs;
+    }
+}
+
+impl Sum for Fixed {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::ZERO, Add::add)
+    }
+}
+
+/// A vector of [`Fixed`] components
+#[derive(Serialize, Deserialize, Reflect, Clone, Copy, Default, Debug, PartialEq, Eq, Hash)]
+#[reflect(Default, PartialEq, Hash)]
+pub struct FixedVec3 {
+    pub x: Fixed,
+    pub y: Fixed,
+    pub z: Fixed,
+}
+
+impl FixedVec3 {
+    pub const ZERO: Self = Self::splat(Fixed::ZERO);
+    pub const ONE: Self = Self::splat(Fixed::ONE);
+    pub const X: Self = Self::new(Fixed::ONE, Fixed::ZERO, Fixed::ZERO);
+    pub const Y: Self = Self::new(Fixed::ZERO, Fixed::ONE, Fixed::ZERO);
+    pub const Z: Self = Self::new(Fixed::ZERO, Fixed::ZERO, Fixed::ONE);
+
+    pub const fn new(x: Fixed, y: Fixed, z: Fixed) -> Self {
+        Self { x, y, z }
+    }
+
+    pub const fn splat(value: Fixed) -> Self {
+        Self::new(value, value, value)
+    }
+
+    /// See [`Fixed::from_f32`] for when this is deterministic
+    pub fn from_vec3(value: Vec3) -> Self {
+        Self::new(Fixed::from_f32(value.x), Fixed::from_f32(value.y), Fixed::from_f32(value.z))
+    }
+
+    /// For presentation only
+    pub fn to_vec3(self) -> Vec3 {
+        Vec3::new(self.x.to_f32(), self.y.to_f32(), self.z.to_f32())
+    }
+
+    pub fn dot(self, rhs: Self) -> Fixed {
+        self.x * rhs.x + self.y * rhs.y + self.z * rhs.z
+    }
+
+    pub fn cross(self, rhs: Self) -> Self {
+        Self::new(
+            self.y * rhs.z - self.z * rhs.y,
+            self.z * rhs.x - self.x * rhs.z,
+            self.x * rhs.y - self.y * rhs.x,
+        )
+    }
+
+    pub fn length_squared(self) -> Fixed {
+        self.dot(self)
+    }
+
+    pub fn length(self) -> Fixed {
+        self.length_squared().sqrt()
+    }
+
+    /// The vector scaled to unit length, or zero if it has no length
+    pub fn normalize_or_zero(self) -> Self {
+        let length = self.length();
+        if length == Fixed::ZERO { return Self::ZERO }
+        self / length
+    }
+}
+
+impl Add for FixedVec3 {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self {
+        Self::new(self.x + rhs.x, self.y + rhs.y, self.z + rhs.z)
+    }
+}
+
+impl Sub for FixedVec3 {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self {
+        Self::new(self.x - rhs.x, self.y - rhs.y, self.z - rhs.z)
+    }
+}
+
+impl Mul<Fixed> for FixedVec3 {
+    type Output = Self;
+    fn mul(self, rhs: Fixed) -> Self {
+        Self::new(self.x * rhs, self.y * rhs, self.z * rhs)
+    }
+}
+
+impl Mul for FixedVec3 {
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self {
+        Self::new(self.x * rhs.x, self.y * rhs.y, self.z * rhs.z)
+    }
+}
+
+impl Div<Fixed> for FixedVec3 {
+    type Output = Self;
+    fn div(self, rhs: Fixed) -> Self {
+        Self::new(self.x / rhs, self.y / rhs, self.z / rhs)
+    }
+}
+
+impl Neg for FixedVec3 {
+    type Output = Self;
+    fn neg(self) -> Self {
+        Self::new(-self.x, -self.y, -self.z)
+    }
+}
+
+impl AddAssign for FixedVec3 {
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
+
+impl SubAssign for FixedVec3 {
+    fn sub_assign(&mut self, rhs: Self) {
+        *self = *self - rhs;
+    }
+}
+
+impl MulAssign<Fixed> for FixedVec3 {
+    fn mul_assign(&mut self, rhs: Fixed) {
+        *self = *self * rhs;
+    }
+}
+
+/// A rotation quaternion of [`Fixed`] components.  Products drift from unit
+/// length by rounding, call [`FixedQuat::normalize`] after accumulating many.
+#[derive(Serialize, Deserialize, Reflect, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[reflect(Default, PartialEq, Hash)]
+pub struct FixedQuat {
+    pub x: Fixed,
+    pub y: Fixed,
+    pub z: Fixed,
+    pub w: Fixed,
+}
+
+impl Default for FixedQuat {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+impl FixedQuat {
+    pub const IDENTITY: Self = Self::from_xyzw(Fixed::ZERO, Fixed::ZERO, Fixed::ZERO, Fixed::ONE);
+
+    pub const fn from_xyzw(x: Fixed, y: Fixed, z: Fixed, w: Fixed) -> Self {
+        Self { x, y, z, w }
+    }
+
+    /// See [`Fixed::from_f32`] for when this is deterministic
+    pub fn from_quat(value: Quat) -> Self {
+        Self::from_xyzw(
+            Fixed::from_f32(value.x),
+            Fixed::from_f32(value.y),
+            Fixed::from_f32(value.z),
+            Fixed::from_f32(value.w),
+        )
+    }
+
+    /// For presentation only
+    pub fn to_quat(self) -> Quat {
+        Quat::from_xyzw(self.x.to_f32(), self.y.to_f32(), self.z.to_f32(), self.w.to_f32()).normalize()
+    }
+
+    pub fn conjugate(self) -> Self {
+        Self::from_xyzw(-self.x, -self.y, -self.z, self.w)
+    }
+
+    pub fn normalize(self) -> Self {
+        let length = (self.x * self.x + self.y * self.y + self.z * self.z + self.w * self.w).sqrt();
+        if length == Fixed::ZERO { return Self::IDENTITY }
+        Self::from_xyzw(self.x / length, self.y / length, self.z / length, self.w / length)
+    }
+
+    pub fn mul_vec3(self, value: FixedVec3) -> FixedVec3 {
+        let axis = FixedVec3::new(self.x, self.y, self.z);
+        let t = axis.cross(value) * Fixed::from_int(2);
+        value + t * self.w + axis.cross(t)
+    }
+}
+
+impl Mul for FixedQuat {
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self {
+        Self::from_xyzw(
+            self.w * rhs.x + self.x * rhs.w + self.y * rhs.z - self.z * rhs.y,
+            self.w * rhs.y - self.x * rhs.z + self.y * rhs.w + self.z * rhs.x,
+            self.w * rhs.z + self.x * rhs.y - self.y * rhs.x + self.z * rhs.w,
+            self.w * rhs.w - self.x * rhs.x - self.y * rhs.y - self.z * rhs.z,
+        )
+    }
+}
+
+impl Mul<FixedVec3> for FixedQuat {
+    type Output = FixedVec3;
+    fn mul(self, rhs: FixedVec3) -> FixedVec3 {
+        self.mul_vec3(rhs)
+    }
+}
+
+/// The simulated placement of an entity.  Simulation systems and commands
+/// work on this instead of [`Transform`], which [`DeterministicMathPlugin`]
+/// overwrites from it every frame for rendering.  It is reflected and
+/// serializable, so it can be carried in command payloads and contributes
+/// to state hashes like any other registered component.
+#[derive(Component, Serialize, Deserialize, Reflect, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[reflect(Component, Default, PartialEq, Hash)]
+#[require(Transform)]
+pub struct DeterministicTransform {
+    pub translation: FixedVec3,
+    pub rotation: FixedQuat,
+    pub scale: FixedVec3,
+}
+
+impl Default for DeterministicTransform {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+impl DeterministicTransform {
+    pub const IDENTITY: Self = Self {
+        translation: FixedVec3::ZERO,
+        rotation: FixedQuat::IDENTITY,
+        scale: FixedVec3::ONE,
+    };
+
+    pub fn from_translation(translation: FixedVec3) -> Self {
+        Self { translation, ..Self::IDENTITY }
+    }
+
+    pub fn with_rotation(mut self, rotation: FixedQuat) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    pub fn with_scale(mut self, scale: FixedVec3) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// See [`Fixed::from_f32`] for when this is deterministic
+    pub fn from_transform(transform: &Transform) -> Self {
+        Self {
+            translation: FixedVec3::from_vec3(transform.translation),
+            rotation: FixedQuat::from_quat(transform.rotation),
+            scale: FixedVec3::from_vec3(transform.scale),
+        }
+    }
+
+    /// For presentation only
+    pub fn to_transform(&self) -> Transform {
+        Transform {
+            translation: self.translation.to_vec3(),
+            rotation: self.rotation.to_quat(),
+            scale: self.scale.to_vec3(),
+        }
+    }
+
+    pub fn forward(&self) -> FixedVec3 {
+        self.rotation * -FixedVec3::Z
+    }
+
+    /// Transform a point from local space into this transform's space
+    pub fn transform_point(&self, point: FixedVec3) -> FixedVec3 {
+        self.rotation * (point * self.scale) + self.translation
+    }
+}
+
+fn sync_deterministic_transforms(
+    mut transforms: Query<(&DeterministicTransform, &mut Transform), Changed<DeterministicTransform>>,
+) {
+    for (deterministic, mut transform) in &mut transforms {
+        *transform = deterministic.to_transform();
+    }
+}
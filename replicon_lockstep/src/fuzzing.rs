@@ -0,0 +1,106 @@
+//! Entry points into the wire format for fuzzers and property tests.  The
+//! network-facing encoders and decoders only need a type registry here, not
+//! a running replicon app.  Not meant for games.
+
+use bevy::{prelude::*, reflect::TypeRegistry};
+use bevy_replicon::{bytes::Bytes, postcard};
+use crate::{
+    prelude::*,
+    commands::{ServerSendCommands, catch_up::MigrationHistory, codec::{self, CommandWireCodec}, serialization},
+};
+
+/// A message format that is read from the network
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WireMessage {
+    /// Commands a client sends to the server
+    ClientCommands,
+    /// A tick of commands broadcast by the server
+    TickCommands,
+    /// A chunk of history sent to a catching up client
+    CatchUpHistory,
+    /// Ticks a client sends to the new host after host migration
+    MigrationHistory,
+}
+
+impl WireMessage {
+    pub const ALL: [Self; 4] = [
+        Self::ClientCommands,
+        Self::TickCommands,
+        Self::CatchUpHistory,
+        Self::MigrationHistory,
+    ];
+}
+
+/// Numbers the registered command types like every peer does on entering
+/// Setup, so ticks are encoded with compact ids from then on, and
+/// compressed above `compress_above` bytes with the `compression` feature
+pub fn number_command_types(registry: &mut TypeRegistry, compress_above: Option<usize>) {
+    registry.register::<CommandWireCodec>();
+    codec::number_command_types(registry, &CommandVersions::default(), compress_above);
+}
+
+/// Encodes the commands of one tick as `kind`.  Client commands carry the
+/// commands of every client, in client order.
+pub fn encode(
+    kind: WireMessage,
+    tick: SimTick,
+    commands: &LockstepClientCommands,
+    registry: &TypeRegistry,
+) -> postcard::Result<Vec<u8>> {
+    let mut message = Vec::new();
+    match kind {
+        WireMessage::ClientCommands => {
+            let event = ClientSendCommands {
+                issued_tick: tick,
+                commands: commands.values().flatten().map(|command| command.clone_value()).collect(),
+                stream: GAMEPLAY_STREAM,
+                ..default()
+            };
+            serialization::write_client_send_commands(&event, registry, &mut message)?;
+        }
+        WireMessage::TickCommands => {
+            let event = ServerSendCommands {
+                tick,
+                digest: hash_tick_commands(commands, registry),
+                commands: commands.clone(),
+                ..default()
+            };
+            serialization::write_server_send_commands(&event, registry, &mut message)?;
+        }
+        WireMessage::CatchUpHistory => {
+            let event = CatchUpHistory { from_tick: tick, to_tick: tick, ticks: vec![commands.clone()] };
+            serialization::write_catch_up_history(&event, registry, &mut message)?;
+        }
+        WireMessage::MigrationHistory => {
+            let event = MigrationHistory { from_tick: tick, ticks: vec![commands.clone()] };
+            serialization::write_migration_history(&event, registry, &mut message)?;
+        }
+    }
+    Ok(message)
+}
+
+/// Decodes `message` as `kind` and encodes the result again.  Decoding
+/// returns an error for malformed or truncated messages and never panics.
+pub fn reencode(kind: WireMessage, message: &[u8], registry: &TypeRegistry) -> postcard::Result<Vec<u8>> {
+    let mut bytes = Bytes::copy_from_slice(message);
+    let mut encoded = Vec::new();
+    match kind {
+        WireMessage::ClientCommands => {
+            let event = serialization::read_client_send_commands(&mut bytes, registry)?;
+            serialization::write_client_send_commands(&event, registry, &mut encoded)?;
+        }
+        WireMessage::TickCommands => {
+            let event = serialization::read_server_send_commands(&mut bytes, registry)?;
+            serialization::write_server_send_commands(&event, registry, &mut encoded)?;
+        }
+        WireMessage::CatchUpHistory => {
+            let event = serialization::read_catch_up_history(&mut bytes, registry)?;
+            serialization::write_catch_up_history(&event, registry, &mut encoded)?;
+        }
+        WireMessage::MigrationHistory => {
+            let event = serialization::read_migration_history(&mut bytes, registry)?;
+            serialization::write_migration_history(&event, registry, &mut encoded)?;
+        }
+    }
+    Ok(encoded)
+}
@@ -0,0 +1,74 @@
+use std::hash::Hasher;
+use bevy::reflect::{serde::ReflectSerializer, TypeRegistry};
+use crate::prelude::*;
+
+pub(crate) mod state;
+
+pub use state::{NotHashed, StateHashFilter, StateHashAppExt, hash_simulation_state};
+
+/// A 64-bit FNV-1a hasher.  Unlike the std `DefaultHasher`, its output is
+/// stable across processes, platforms and compiler versions, so hashes can be
+/// compared between peers.
+#[derive(Debug, Clone, Copy)]
+pub struct StableHasher(u64);
+
+impl StableHasher {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    pub fn new() -> Self {
+        Self(Self::OFFSET_BASIS)
+    }
+}
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(Self::PRIME);
+        }
+    }
+
+    // Integers are hashed as little endian so results match across platforms
+    fn write_u16(&mut self, i: u16) { self.write(&i.to_le_bytes()) }
+    fn write_u32(&mut self, i: u32) { self.write(&i.to_le_bytes()) }
+    fn write_u64(&mut self, i: u64) { self.write(&i.to_le_bytes()) }
+    fn write_u128(&mut self, i: u128) { self.write(&i.to_le_bytes()) }
+    fn write_usize(&mut self, i: usize) { self.write(&(i as u64).to_le_bytes()) }
+}
+
+/// Hash a byte slice with [`StableHasher`]
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    let mut hasher = StableHasher::new();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+/// Hash the commands for one tick.  Commands are hashed through their
+/// reflected serialization, so dynamic and concrete values hash the same.
+pub fn hash_tick_commands(commands: &LockstepClientCommands, registry: &TypeRegistry) -> u64 {
+    let mut hasher = StableHasher::new();
+    for (client_id, client_commands) in commands.iter() {
+        hasher.write_u64(*client_id);
+        hasher.write_usize(client_commands.len());
+        for command in client_commands {
+            let serializer = ReflectSerializer::new(command.as_partial_reflect(), registry);
+            match bincode::serialize(&serializer) {
+                Ok(bytes) => hasher.write(&bytes),
+                // Unserializable commands can't have been sent, hash the type only
+                Err(_) => hasher.write(command.reflect_type_path().as_bytes()),
+            }
+        }
+    }
+    hasher.finish()
+}
@@ -0,0 +1,115 @@
+use std::{any::TypeId, hash::Hasher};
+use bevy::{prelude::*, reflect::{serde::TypedReflectSerializer, TypeRegistry}, utils::HashSet};
+use crate::prelude::*;
+
+/// Marker for simulated entities that should be left out of state hashes,
+/// e.g. purely visual children carrying a [`ChildSimulationId`]
+#[derive(Component, Debug, Clone, Copy, Default, Reflect)]
+pub struct NotHashed;
+
+/// Scopes which component types count as simulation state in
+/// [`hash_simulation_state`].  If any types are included, only those are
+/// hashed.  Excluded types are never hashed.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct StateHashFilter {
+    included: HashSet<TypeId>,
+    excluded: HashSet<TypeId>,
+}
+
+impl StateHashFilter {
+    pub fn include<T: Component>(&mut self) -> &mut Self {
+        self.included.insert(TypeId::of::<T>());
+        self
+    }
+
+    pub fn exclude<T: Component>(&mut self) -> &mut Self {
+        self.excluded.insert(TypeId::of::<T>());
+        self
+    }
+
+    pub fn is_hashed(&self, type_id: TypeId) -> bool {
+        !self.excluded.contains(&type_id)
+            && (self.included.is_empty() || self.included.contains(&type_id))
+    }
+}
+
+pub trait StateHashAppExt {
+    /// Only hash the included component types, plus any others included
+    fn include_in_state_hash<T: Component>(&mut self) -> &mut Self;
+
+    /// Never hash this component type, e.g. animation or VFX state
+    fn exclude_from_state_hash<T: Component>(&mut self) -> &mut Self;
+}
+
+impl StateHashAppExt for App {
+    fn include_in_state_hash<T: Component>(&mut self) -> &mut Self {
+        self.world_mut().get_resource_or_insert_with(StateHashFilter::default).include::<T>();
+        self
+    }
+
+    fn exclude_from_state_hash<T: Component>(&mut self) -> &mut Self {
+        self.world_mut().get_resource_or_insert_with(StateHashFilter::default).exclude::<T>();
+        self
+    }
+}
+
+/// Hash the state of every simulated entity, i.e. every entity with a
+/// [`SimulationId`] or [`ChildSimulationId`] and without [`NotHashed`].
+/// Entities are visited in id order and components in type path order, so
+/// the result only depends on the simulation and can be compared between
+/// peers.  Only reflected components registered with `ReflectComponent` and
+/// accepted by [`StateHashFilter`] contribute.
+pub fn hash_simulation_state(world: &World) -> u64 {
+    let default_filter = StateHashFilter::default();
+    let filter = world.get_resource::<StateHashFilter>().unwrap_or(&default_filter);
+    let registry = world.resource::<AppTypeRegistry>().read();
+
+    let mut entities: Vec<((u32, u32), EntityRef)> = world
+        .iter_entities()
+        .filter(|entity| !entity.contains::<NotHashed>())
+        .filter_map(|entity| {
+            if let Some(id) = entity.get::<SimulationId>() {
+                Some(((**id, 0), entity))
+            } else {
+                entity.get::<ChildSimulationId>().map(|id| ((*id.parent, id.index + 1), entity))
+            }
+        })
+        .collect();
+    entities.sort_by_key(|(key, _)| *key);
+
+    let mut hasher = StableHasher::new();
+    for ((id, child), entity) in entities {
+        hasher.write_u32(id);
+        hasher.write_u32(child);
+        for (type_path, value) in reflected_components(world, entity, filter, &registry) {
+            let serializer = TypedReflectSerializer::new(value.as_partial_reflect(), &registry);
+            let Ok(bytes) = bincode::serialize(&serializer) else { continue };
+            hasher.write(type_path.as_bytes());
+            hasher.write(&bytes);
+        }
+    }
+    hasher.finish()
+}
+
+/// The reflected components of an entity accepted by the filter, in type
+/// path order
+pub(crate) fn reflected_components<'w>(
+    world: &'w World,
+    entity: EntityRef<'w>,
+    filter: &StateHashFilter,
+    registry: &TypeRegistry,
+) -> Vec<(&'static str, &'w dyn Reflect)> {
+    let mut components: Vec<(&'static str, &'w dyn Reflect)> = entity
+        .archetype()
+        .components()
+        .filter_map(|component_id| world.components().get_info(component_id)?.type_id())
+        .filter(|type_id| filter.is_hashed(*type_id))
+        .filter_map(|type_id| {
+            let registration = registry.get(type_id)?;
+            let value = registration.data::<ReflectComponent>()?.reflect(entity)?;
+            Some((registration.type_info().type_path(), value))
+        })
+        .collect();
+    components.sort_by_key(|(type_path, _)| *type_path);
+    components
+}
@@ -0,0 +1,72 @@
+use std::time::{Duration, Instant};
+use bevy::{app::{PluginGroupBuilder, PluginsState, ScheduleRunnerPlugin}, prelude::*, state::app::StatesPlugin};
+use crate::prelude::*;
+
+/// Plugins for a [`ServerMode::Dedicated`] server without a window or
+/// renderer: `MinimalPlugins` and the `StatesPlugin` the simulation state
+/// needs.  The app loop sleeps between frames instead of spinning, ticks are
+/// produced by the fixed timestep or [`TickClock::Dedicated`] as usual.  A
+/// pulse of the dedicated tick clock ends the sleep early, so the tick runs
+/// right away.
+/// Add before [`RepliconLockstepPlugins`], together with `RepliconPlugins`
+/// and a transport.
+#[derive(Debug, Clone, Copy)]
+pub struct HeadlessServerPlugins {
+    /// Time between app updates.  Shorter than the tick timestep, so
+    /// received commands are handled well before the next tick.
+    pub frame_interval: Duration,
+}
+
+impl HeadlessServerPlugins {
+    /// Updates four times per tick of `settings`
+    pub fn for_settings(settings: &SimulationSettings) -> Self {
+        Self { frame_interval: settings.tick_timestep / 4 }
+    }
+}
+
+impl Default for HeadlessServerPlugins {
+    fn default() -> Self {
+        Self::for_settings(&SimulationSettings::default())
+    }
+}
+
+impl PluginGroup for HeadlessServerPlugins {
+    fn build(self) -> PluginGroupBuilder {
+        MinimalPlugins
+            .build()
+            .disable::<ScheduleRunnerPlugin>()
+            .add(HeadlessRunnerPlugin { frame_interval: self.frame_interval })
+            .add(StatesPlugin)
+    }
+}
+
+/// Runs the app loop every `frame_interval`, or as soon as the dedicated
+/// tick clock pulses
+struct HeadlessRunnerPlugin {
+    frame_interval: Duration,
+}
+
+impl Plugin for HeadlessRunnerPlugin {
+    fn build(&self, app: &mut App) {
+        let frame_interval = self.frame_interval;
+        let waker = TickClockWaker::default();
+        app.insert_resource(waker.clone());
+        app.set_runner(move |mut app: App| {
+            if app.plugins_state() != PluginsState::Cleaned {
+                while app.plugins_state() == PluginsState::Adding {
+                    bevy::tasks::tick_global_task_pools_on_main_thread();
+                }
+                app.finish();
+                app.cleanup();
+            }
+            loop {
+                let start = Instant::now();
+                app.update();
+                if let Some(exit) = app.should_exit() {
+                    return exit;
+                }
+                waker.wait(frame_interval.saturating_sub(start.elapsed()));
+            }
+        });
+    }
+}
@@ -0,0 +1,201 @@
+//! Development tool for diagnosing a single diverging entity.  Any client
+//! can ask every peer to report the state of one [`SimulationId`]; the
+//! server picks a tick no peer applied yet, every peer reports the entity
+//! right after applying that tick, and the server sends the requester a
+//! comparison.
+
+use std::{collections::BTreeMap, hash::Hasher};
+use bevy::{prelude::*, reflect::serde::TypedReflectSerializer, utils::hashbrown::HashMap};
+use bevy_replicon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::{prelude::*, hashing::state::reflected_components};
+
+/// Freeze-frame entity inspection
+pub struct LockstepInspectPlugin;
+
+impl Plugin for LockstepInspectPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<PendingInspections>()
+            .init_resource::<ScheduledInspections>()
+            .add_client_trigger::<RequestEntityInspection>(Channel::Ordered)
+            .add_server_trigger::<InspectEntity>(Channel::Ordered)
+            .add_client_trigger::<EntityInspectionReport>(Channel::Ordered)
+            .add_server_trigger::<EntityInspectionResult>(Channel::Ordered)
+            .add_observer(start_inspection)
+            .add_observer(schedule_report)
+            .add_observer(report_on_tick)
+            .add_observer(collect_report);
+    }
+}
+
+/// Sent by a client to ask every peer for the state of an entity
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct RequestEntityInspection(pub SimulationId);
+
+/// Broadcast by the server to ask every peer for a report once it applied
+/// `tick`
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+struct InspectEntity {
+    request: u32,
+    id: SimulationId,
+    tick: SimTick,
+}
+
+/// The state of one component on one peer
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ComponentReport {
+    pub type_path: String,
+    /// Hash of the serialized component, comparable between peers
+    pub hash: u64,
+    /// Human readable value
+    pub value: String,
+}
+
+/// The state of the inspected entity on one peer after applying `tick`.
+/// `components` is empty if the peer has no entity with the id.
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EntityInspectionReport {
+    request: u32,
+    pub tick: SimTick,
+    pub found: bool,
+    pub components: Vec<ComponentReport>,
+}
+
+/// Sent by the server to the requester once every peer reported
+#[derive(Event, Serialize, Deserialize, Debug, Clone)]
+pub struct EntityInspectionResult {
+    pub id: SimulationId,
+    /// The tick every peer was asked to report after
+    pub tick: SimTick,
+    pub reports: BTreeMap<ClientId, EntityInspectionReport>,
+    /// Type paths of components whose hash differs between the peers that
+    /// reported on `tick`, or which only some of them have
+    pub diverging: Vec<String>,
+}
+
+#[derive(Resource, Default)]
+struct PendingInspections {
+    next_request: u32,
+    requests: HashMap<u32, (Entity, SimulationId, SimTick, BTreeMap<ClientId, EntityInspectionReport>)>,
+}
+
+/// Inspections a peer reports once it applied their tick
+#[derive(Resource, Default)]
+struct ScheduledInspections(Vec<InspectEntity>);
+
+fn start_inspection(
+    trigger: Trigger<FromClient<RequestEntityInspection>>,
+    mut commands: Commands,
+    mut pending: ResMut<PendingInspections>,
+    sim_tick: Res<SimulationTick>,
+) {
+    pending.next_request += 1;
+    let request = pending.next_request;
+    let id = trigger.event.0;
+    // Not broadcast yet, so no peer applied it
+    let tick = **sim_tick + 1;
+    pending.requests.insert(request, (trigger.client_entity, id, tick, BTreeMap::new()));
+    commands.server_trigger(ToClients {
+        mode: SendMode::Broadcast,
+        event: InspectEntity { request, id, tick },
+    });
+}
+
+fn schedule_report(
+    trigger: Trigger<InspectEntity>,
+    world: &World,
+    mut commands: Commands,
+    mut scheduled: ResMut<ScheduledInspections>,
+) {
+    let inspection = *trigger.event();
+    let applied = world.get_resource::<LastAppliedTick>().map_or(0, |tick| **tick);
+    if applied < inspection.tick {
+        scheduled.0.push(inspection);
+        return;
+    }
+    // Too late for the tick, the server leaves this report out of the comparison
+    warn!("asked to inspect {:?} on tick {}, already applied tick {}", inspection.id, inspection.tick, applied);
+    commands.client_trigger(inspect(world, inspection.request, inspection.id, applied));
+}
+
+fn report_on_tick(
+    trigger: Trigger<GameCommandsApplied>,
+    world: &World,
+    mut commands: Commands,
+) {
+    let tick = trigger.event().0;
+    let scheduled = &world.resource::<ScheduledInspections>().0;
+    if scheduled.iter().all(|inspection| inspection.tick > tick) { return }
+    for inspection in scheduled.iter().filter(|inspection| inspection.tick <= tick) {
+        commands.client_trigger(inspect(world, inspection.request, inspection.id, tick));
+    }
+    commands.queue(move |world: &mut World| {
+        world.resource_mut::<ScheduledInspections>().0.retain(|inspection| inspection.tick > tick);
+    });
+}
+
+/// The state of an entity as it is now, after applying `tick`
+fn inspect(world: &World, request: u32, id: SimulationId, tick: SimTick) -> EntityInspectionReport {
+    let mut report = EntityInspectionReport {
+        request,
+        tick,
+        ..default()
+    };
+    let entity = world
+        .resource::<SimulationIdEntityMap>()
+        .get(&id)
+        .and_then(|&entity| world.get_entity(entity).ok());
+    if let Some(entity) = entity {
+        let registry = world.resource::<AppTypeRegistry>().read();
+        report.found = true;
+        report.components = reflected_components(world, entity, &StateHashFilter::default(), &registry)
+            .into_iter()
+            .map(|(type_path, value)| {
+                let serializer = TypedReflectSerializer::new(value.as_partial_reflect(), &registry);
+                let mut hasher = StableHasher::new();
+                hasher.write(&bincode::serialize(&serializer).unwrap_or_default());
+                ComponentReport {
+                    type_path: type_path.to_string(),
+                    hash: hasher.finish(),
+                    value: format!("{:?}", value),
+                }
+            })
+            .collect();
+    }
+    report
+}
+
+fn collect_report(
+    trigger: Trigger<FromClient<EntityInspectionReport>>,
+    mut commands: Commands,
+    clients: LockstepClients,
+    mut pending: ResMut<PendingInspections>,
+) {
+    let Some(client_id) = clients.client_id(trigger.client_entity) else { return };
+    let report = trigger.event.clone();
+    let request = report.request;
+    let Some((.., reports)) = pending.requests.get_mut(&request) else { return };
+    reports.insert(client_id, report);
+    if reports.len() < clients.len() { return }
+
+    let (requester, id, tick, reports) = pending.requests.remove(&request).expect("request is pending");
+    // Only the state after the same tick is comparable
+    let on_tick: Vec<&EntityInspectionReport> = reports.values().filter(|report| report.tick == tick).collect();
+    let mut hashes: BTreeMap<&str, Vec<Option<u64>>> = BTreeMap::new();
+    for (index, report) in on_tick.iter().enumerate() {
+        for component in &report.components {
+            let entry = hashes.entry(&component.type_path).or_insert_with(|| vec![None; on_tick.len()]);
+            entry[index] = Some(component.hash);
+        }
+    }
+    let diverging = hashes
+        .into_iter()
+        .filter(|(_, hashes)| hashes.iter().any(|hash| *hash != hashes[0]))
+        .map(|(type_path, _)| type_path.to_string())
+        .collect();
+    commands.server_trigger(ToClients {
+        mode: SendMode::Direct(requester),
+        event: EntityInspectionResult { id, tick, reports, diverging },
+    });
+}
@@ -1,16 +1,112 @@
-use bevy::prelude::*;
+use bevy::{app::PluginGroupBuilder, prelude::*};
 
+extern crate alloc;
+
+pub mod lockstep_core;
 mod simulation;
+mod hashing;
 mod connections;
+mod manifest;
+mod replay;
+mod correlation;
+mod desync;
 pub mod commands;
+#[cfg(feature = "renet-helpers")]
+mod transport;
+#[cfg(feature = "soak")]
+pub mod soak;
+#[cfg(feature = "test-utils")]
+pub mod test_utils;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+#[cfg(feature = "inspect")]
+mod inspect;
+#[cfg(feature = "fixed-point")]
+mod fixed_point;
+#[cfg(feature = "status-endpoint")]
+mod status;
+mod snapshot;
+mod storage;
+mod checkpoint;
+mod chat;
+mod analytics;
+mod headless;
 
-use commands::LockstepCommandsPlugin;
-use connections::LockstepConnectionsPlugin;
-use simulation::LockstepSimulationPlugin;
+pub use commands::LockstepCommandsPlugin;
+pub use connections::LockstepConnectionsPlugin;
+pub use simulation::LockstepSimulationPlugin;
+pub use manifest::LockstepManifestPlugin;
+pub use desync::DesyncDetectionPlugin;
+pub use replay::ReplayPlugin;
+pub use snapshot::SnapshotStreamingPlugin;
+pub use storage::StoragePlugin;
+pub use checkpoint::CheckpointPlugin;
+pub use chat::LockstepChatPlugin;
+pub use analytics::MatchAnalyticsPlugin;
+pub use headless::HeadlessServerPlugins;
 use prelude::*;
 
 pub mod prelude {
-    pub use crate::RepliconLockstepPlugin;
+    pub use crate::{
+        RepliconLockstepPlugins,
+        LockstepConnectionsPlugin,
+        LockstepSimulationPlugin,
+        LockstepCommandsPlugin,
+        LockstepManifestPlugin,
+        DesyncDetectionPlugin,
+        ReplayPlugin,
+        SnapshotStreamingPlugin,
+        StoragePlugin,
+        CheckpointPlugin,
+        LockstepChatPlugin,
+        MatchAnalyticsPlugin,
+        HeadlessServerPlugins,
+    };
+    pub use crate::analytics::{AnalyticsSink, MatchAnalytics};
+    pub use crate::chat::{
+        ChatSettings,
+        ChatScope,
+        SendChatMessage,
+        ChatMessageReceived,
+        ChatTeams,
+    };
+    pub use crate::checkpoint::{
+        CheckpointSettings,
+        SnapshotProvider,
+        SnapshotApplier,
+        CheckpointAppExt,
+        MatchCheckpoint,
+        LatestCheckpoint,
+        CheckpointStored,
+        CheckpointRestored,
+    };
+    pub use crate::storage::{
+        LockstepStorage,
+        ArtifactKind,
+        StorageError,
+        FileStorage,
+        MemoryStorage,
+        MatchStorage,
+        StoreArtifact,
+        ArtifactStored,
+        ArtifactMatchId,
+        artifact_match_id,
+    };
+    pub use crate::snapshot::{
+        SnapshotSettings,
+        SendSnapshot,
+        SnapshotSendProgress,
+        SnapshotProgress,
+        SnapshotReceived,
+        SnapshotError,
+        SnapshotTransferFailed,
+    };
+    pub use crate::desync::{
+        DesyncDetectionSettings,
+        HashSimulationState,
+        StateHashReport,
+        DesyncDetected,
+    };
     pub use crate::simulation::{
         SimulationSettings,
         SimulationState,
@@ -18,40 +114,367 @@ pub mod prelude {
         SimulationTick,
         SimulationTickUpdate,
         SimulationId,
+        SimulationIdError,
         SimulationIdEntityMap,
+        SimulationIdAllocator,
+        ChildSimulationId,
+        ChildSimulationIdAllocator,
+        ChildSimulationIdEntityMap,
+        LockstepRng,
+        LockstepRngStream,
+        TickSalt,
+        TickGapTimedOut,
+        SimRef,
+        SimRefError,
+        CommandContext,
+        ResetSimulation,
+        RequestSimulationReset,
+        SimulationReset,
+        SessionIdentity,
+        SessionHash,
+        SessionMismatch,
+        PauseSimulation,
+        ResumeSimulation,
+        PauseReason,
+        RequestPause,
+        RequestResume,
+        SimulationPaused,
+        ResumeVoteStatus,
+        PauseStatus,
+        PauseAllowance,
+        PausesUsed,
+        PauseDenied,
+        PauseCountdown,
+        ClientHeartbeat,
+        ClientHeartbeats,
+        EmptyCommandSuppression,
+        LivenessHeartbeat,
+        ReplicationTickMapping,
+        ReplicationTicks,
+        ReplicationAlignment,
+        replication_aligned,
+        BroadcastPacing,
+        TickClock,
+        TickClockWaker,
+        SettingsChange,
+        VoteRule,
+        ProposeSettingsChange,
+        CastSettingsVote,
+        SettingsVoteStarted,
+        SettingsVoteResolved,
+        SettingsChanged,
+        RequestTickRate,
+        OverrunMitigation,
+        FixedUpdateOverrun,
+        ClientOverrunning,
+        BandwidthMitigation,
+        DeclareBandwidthBudget,
+        BandwidthBudget,
+        BroadcastBandwidth,
+        BandwidthConstrained,
+        EndSimulation,
+        FinalTickScheduled,
+        FinalTickApplied,
+        FinalTick,
+        TickStall,
+        UnscheduledDespawnPolicy,
+        UnscheduledDespawn,
+        PresentationFreeze,
+        PresentationResume,
+        SessionController,
+        SessionRequest,
+        SessionRequestRejected,
+        EnterPostGame,
+        RequestRematch,
+        RematchStatus,
+        RematchAccepted,
+        RestartSimulation,
+        SimulationRestarted,
+        LockstepServerTick,
+        Surrender,
+        MakeSpectator,
+        PlayerBecameSpectator,
+        LockstepSpectators,
+        TickAdvanced,
+        TickBlockedWaitingOn,
+        DisconnectSuspected,
+        ResumeAtTick,
+    };
+    pub use crate::hashing::{
+        StableHasher,
+        hash_tick_commands,
+        NotHashed,
+        StateHashFilter,
+        StateHashAppExt,
+        hash_simulation_state,
+    };
+    pub use crate::correlation::{MatchCorrelation, LockstepCorrelationLayer, lockstep_correlation_layer};
+    pub use crate::replay::{
+        MatchReplay,
+        ReplaySettings,
+        ReplayError,
+        ReplayVerification,
+        verify_replay,
+        reconstruct_world,
+        StartReplayPlayback,
+        ReplayPlaybackFinished,
+        ReplayRecorded,
+        ReplayPlayback,
+        ReplaySeeked,
+        REPLAY_VERSION,
+        migrate_history,
+        replay_version,
+        REPLAY_ARTIFACT,
     };
     pub use crate::connections::{
         LocalClient,
+        LocalClientIdentificationFailed,
+        LockstepError,
+        SetupDisconnectPolicy,
         ClientId,
         ClientReconnect,
+        ReconnectAttempt,
+        ReconnectPolicy,
         ClientDisconnect,
         ClientReadyEvent,
         ServerMode,
         ConnectionSettings,
+        EmptySeatPolicy,
+        StartMatchEarly,
+        StartPracticeMatch,
+        Seat,
+        SeatLayout,
+        HostSeat,
+        HostingSeat,
+        LockstepClients,
+        HOST_CLIENT_ID,
+        HostMigrationSettings,
+        HostMigrationCandidate,
+        HostMigrationStarted,
+        HostMigrationFinished,
+        RequestTransportMigration,
+        MigrateTransport,
+        TransportMigrationFinished,
+        TransportMigrationFailed,
+        LobbyPlugin,
+        LobbySettings,
+        LobbyMember,
+        LobbyInfo,
+        SetLobbyName,
+        ClaimLobbySlot,
+        SetLobbyReady,
+        SetLobbyPlayerCount,
+        StartLobbyMatch,
+        LobbyMatchStarting,
+        LobbyStartError,
+        LobbyStartFailed,
+        QualificationSettings,
+        ConnectionQuality,
+        QualificationFailed,
+        LockstepRole,
+        ClientRole,
     };
     pub use crate::commands::{
         ClientSendCommands,
         LockstepGameCommandBuffer,
+        FirstAvailableTick,
         LockstepClientCommands,
+        SERVER_CLIENT_ID,
+        ScheduledExecution,
+        ClientExecutionSchedule,
+        InputDelayJump,
+        CommandsRescheduled,
+        CommandsDeferred,
+        CommandLimitPolicy,
+        CommandRateLimitExceeded,
+        CommandsScheduled,
+        TickDigests,
+        LockstepCommandQueue,
+        LockstepCommands,
+        PendingOrderId,
+        PendingOrder,
+        PendingOrders,
+        OrderConfirmed,
+        LockstepDiagnostics,
+        CommandLatency,
+        LockstepNetworkDiagnostics,
+        LockstepBandwidth,
+        CommandBandwidth,
+        ClientNetworkStats,
+        InjectedInputSource,
+        InjectedInputs,
+        InjectedInputAppExt,
+        ScriptedInput,
+        ReflectCatchUpCommand,
+        RequestCatchUp,
+        CatchUpHistory,
+        CatchUpProgress,
+        ResyncStarted,
+        ResyncFinished,
+        ClientResynced,
+        DroppedClients,
+        TickDigestMismatch,
+        LockstepCommandAppExt,
+        GameCommand,
+        ReflectGameCommand,
+        ParallelGameCommand,
+        ReflectParallelGameCommand,
+        UpgradeCommand,
+        ReflectUpgradeCommand,
+        CommandVersions,
+        TickCompression,
+        LastAppliedTick,
+        GameCommandsApplied,
+        BeforePhysicsStep,
+        PhysicsStep,
+        AfterPhysicsStep,
+        LockstepCommand,
+        LockstepCommandHandlers,
+        LockstepCommandItems,
+        CatchUpPolicy,
+        TicksBehind,
+        InputDelayAdjustment,
+        SetInputDelay,
+        ServerSetInputDelay,
+        ClientInputDelay,
+        InputDelays,
+        InputDevice,
+        DeadZone,
+        InputDeadZones,
+        QuantizedAxis,
+        QuantizedStick,
+        ButtonBits,
+        CommandRegistryVerified,
+        CommandRegistryMismatch,
+        ReflectCommandTrigger,
+        ReflectCommandHandler,
+        LockstepCommandId,
+        ReflectLockstepCommand,
+        CommandStreamId,
+        GAMEPLAY_STREAM,
+        StreamDelay,
+        CommandStreamSettings,
+        LockstepStreamBuffers,
+        FloatSanitization,
+        NonFinitePolicy,
+    };
+    pub use crate::manifest::{
+        ManifestSettings,
+        ManifestEntry,
+        AssetManifest,
+        FetchManifestItem,
+        ManifestItemLoaded,
+        ManifestItemFailed,
+        ManifestLoadFailed,
+        ManifestComplete,
+        ManifestItemsVerified,
+        ManifestItemState,
+        ManifestProgress,
+    };
+    #[cfg(feature = "inspect")]
+    pub use crate::inspect::{
+        LockstepInspectPlugin,
+        RequestEntityInspection,
+        ComponentReport,
+        EntityInspectionReport,
+        EntityInspectionResult,
+    };
+    #[cfg(feature = "avian")]
+    pub use crate::commands::LockstepAvianPlugin;
+    #[cfg(feature = "fixed-point")]
+    pub use crate::fixed_point::{
+        DeterministicMathPlugin,
+        Fixed,
+        FixedVec3,
+        FixedQuat,
+        DeterministicTransform,
+    };
+    #[cfg(feature = "status-endpoint")]
+    pub use crate::status::{
+        StatusEndpointPlugin,
+        StatusEndpoint,
+        ServerStatus,
+        ClientStatus,
+    };
+    #[cfg(feature = "renet-helpers")]
+    pub use crate::transport::{
+        LockstepTransportExt,
+        LockstepTransportError,
+        LastServerAddress,
     };
 }
 
-#[derive(Default)]
-pub struct RepliconLockstepPlugin {
-    pub simulation: SimulationSettings,
-    pub server: ConnectionSettings,
+/// All lockstep plugins.  Settings are configured with the builder methods,
+/// and individual plugins can be disabled or replaced with the usual
+/// [`PluginGroup`] methods, e.g. to provide a custom command transport.
+#[derive(Default, Clone)]
+pub struct RepliconLockstepPlugins {
+    simulation: SimulationSettings,
+    connections: ConnectionSettings,
+    without_connections: bool,
+    without_simulation: bool,
+    without_commands: bool,
 }
 
-impl Plugin for RepliconLockstepPlugin {
-    fn build(&self, app: &mut App) {
-        app
-            .insert_resource(self.simulation.clone())
-            .insert_resource(self.server.clone())
-            .add_plugins((
-                LockstepConnectionsPlugin,
-                LockstepSimulationPlugin,
-                LockstepCommandsPlugin,
-            ))
-            .insert_resource(Time::<Fixed>::from_duration(self.simulation.tick_timestep));
+impl RepliconLockstepPlugins {
+    pub fn with_simulation(mut self, settings: SimulationSettings) -> Self {
+        self.simulation = settings;
+        self
+    }
+
+    pub fn with_connections(mut self, settings: ConnectionSettings) -> Self {
+        self.connections = settings;
+        self
+    }
+
+    pub fn without_connections(mut self) -> Self {
+        self.without_connections = true;
+        self
+    }
+
+    pub fn without_simulation(mut self) -> Self {
+        self.without_simulation = true;
+        self
     }
-}
\ No newline at end of file
+
+    pub fn without_commands(mut self) -> Self {
+        self.without_commands = true;
+        self
+    }
+}
+
+impl PluginGroup for RepliconLockstepPlugins {
+    fn build(self) -> PluginGroupBuilder {
+        let mut group = PluginGroupBuilder::start::<Self>()
+            .add(LockstepConnectionsPlugin { settings: self.connections })
+            .add(LockstepSimulationPlugin { settings: self.simulation })
+            .add(LockstepCommandsPlugin)
+            .add(LockstepManifestPlugin);
+        #[cfg(feature = "renet-helpers")]
+        {
+            group = group.add(transport::LockstepTransportPlugin);
+        }
+        #[cfg(feature = "inspect")]
+        {
+            group = group.add(inspect::LockstepInspectPlugin);
+        }
+        #[cfg(feature = "fixed-point")]
+        {
+            group = group.add(fixed_point::DeterministicMathPlugin);
+        }
+        #[cfg(feature = "avian")]
+        {
+            group = group.add(commands::LockstepAvianPlugin);
+        }
+        if self.without_connections {
+            group = group.disable::<LockstepConnectionsPlugin>();
+        }
+        if self.without_simulation {
+            group = group.disable::<LockstepSimulationPlugin>();
+        }
+        if self.without_commands {
+            group = group.disable::<LockstepCommandsPlugin>();
+        }
+        group
+    }
+}
@@ -0,0 +1,16 @@
+//! Pure lockstep logic with no dependency on Bevy or any transport, only
+//! `core`, `alloc` and `serde`.  Services such as matchmaking validators or
+//! replay verifiers can use it to reproduce the exact scheduling and wire
+//! framing of the plugin without running a Bevy app.
+
+mod buffer;
+pub mod schedule;
+pub mod framing;
+
+pub use buffer::TickBuffer;
+
+/// A simulation tick number
+pub type SimTick = u32;
+
+/// A client's network id
+pub type ClientId = u64;
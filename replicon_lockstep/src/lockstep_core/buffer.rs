@@ -0,0 +1,49 @@
+use alloc::vec::Vec;
+use super::SimTick;
+
+/// A buffer of values indexed by tick, which grows as needed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TickBuffer<T>(Vec<T>);
+
+impl<T> Default for TickBuffer<T> {
+    fn default() -> Self {
+        Self(Vec::new())
+    }
+}
+
+impl<T> TickBuffer<T> {
+    pub fn get(&self, tick: SimTick) -> Option<&T> {
+        self.0.get(tick as usize)
+    }
+
+    pub fn get_mut(&mut self, tick: SimTick) -> Option<&mut T> {
+        self.0.get_mut(tick as usize)
+    }
+
+    /// The number of ticks stored, including tick 0
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (SimTick, &T)> {
+        self.0.iter().enumerate().map(|(tick, value)| (tick as SimTick, value))
+    }
+}
+
+impl<T: Default> TickBuffer<T> {
+    /// Mutable access to a tick, filling any missing ticks up to it with defaults
+    pub fn get_or_default(&mut self, tick: SimTick) -> &mut T {
+        if tick as usize >= self.0.len() {
+            self.0.resize_with(tick as usize + 1, T::default);
+        }
+        &mut self.0[tick as usize]
+    }
+}
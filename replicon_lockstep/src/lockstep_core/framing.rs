@@ -0,0 +1,134 @@
+//! Wire framing of per-tick command maps.  Command payloads are written and
+//! read by caller supplied functions, so any payload encoding can be used
+//! with any serde format that serializes through `&mut S`, like postcard.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use super::ClientId;
+
+/// Write commands for one tick as: client count (u8), then for each client
+/// its id (u64), command count (u16) and the command payloads.
+pub fn write_client_commands<S, C, E>(
+    serializer: &mut S,
+    commands: &BTreeMap<ClientId, Vec<C>>,
+    mut write_payload: impl FnMut(&mut S, &C) -> Result<(), E>,
+) -> Result<(), E>
+where
+    for<'a> &'a mut S: Serializer<Ok = (), Error = E>,
+{
+    (commands.len() as u8).serialize(&mut *serializer)?;
+    for (client_id, client_commands) in commands.iter() {
+        client_id.serialize(&mut *serializer)?;
+        (client_commands.len() as u16).serialize(&mut *serializer)?;
+        for command in client_commands {
+            write_payload(serializer, command)?;
+        }
+    }
+    Ok(())
+}
+
+/// Commands preallocated per client for a count read from the wire.  Larger
+/// counts grow as payloads are actually read, so a corrupt count can't
+/// reserve memory the message doesn't contain.
+const MAX_PREALLOCATED_COMMANDS: usize = 64;
+
+/// Counterpart of [`write_client_commands`]
+pub fn read_client_commands<'de, D, C, E>(
+    deserializer: &mut D,
+    mut read_payload: impl FnMut(&mut D) -> Result<C, E>,
+) -> Result<BTreeMap<ClientId, Vec<C>>, E>
+where
+    for<'a> &'a mut D: Deserializer<'de, Error = E>,
+{
+    let num_clients = u8::deserialize(&mut *deserializer)?;
+    let mut client_commands = BTreeMap::new();
+    for _ in 0..num_clients {
+        let client_id = ClientId::deserialize(&mut *deserializer)?;
+        let num_commands = u16::deserialize(&mut *deserializer)?;
+        let mut commands = Vec::with_capacity((num_commands as usize).min(MAX_PREALLOCATED_COMMANDS));
+        for _ in 0..num_commands {
+            commands.push(read_payload(deserializer)?);
+        }
+        client_commands.insert(client_id, commands);
+    }
+    Ok(client_commands)
+}
+
+/// Tag of a tick with no commands from any client
+pub const EMPTY_TICK: u8 = 0;
+/// Tag of a tick framed with [`write_client_commands`]
+pub const COMMAND_TICK: u8 = 1;
+
+enum RangeEntry<'a, C> {
+    Empty(u32),
+    Commands(&'a BTreeMap<ClientId, Vec<C>>),
+}
+
+/// Write a range of consecutive ticks, run-length encoding empty ticks: entry
+/// count (u32), then per entry either [`EMPTY_TICK`] and the number of empty
+/// ticks in the run (u32), or [`COMMAND_TICK`] and the tick's commands.
+pub fn write_tick_range<'a, S, C: 'a, E>(
+    serializer: &mut S,
+    ticks: impl IntoIterator<Item = &'a BTreeMap<ClientId, Vec<C>>>,
+    mut write_payload: impl FnMut(&mut S, &C) -> Result<(), E>,
+) -> Result<(), E>
+where
+    for<'a> &'a mut S: Serializer<Ok = (), Error = E>,
+{
+    let mut entries: Vec<RangeEntry<'a, C>> = Vec::new();
+    for commands in ticks {
+        match (commands.is_empty(), entries.last_mut()) {
+            (true, Some(RangeEntry::Empty(run))) => *run += 1,
+            (true, _) => entries.push(RangeEntry::Empty(1)),
+            (false, _) => entries.push(RangeEntry::Commands(commands)),
+        }
+    }
+    (entries.len() as u32).serialize(&mut *serializer)?;
+    for entry in entries {
+        match entry {
+            RangeEntry::Empty(run) => {
+                EMPTY_TICK.serialize(&mut *serializer)?;
+                run.serialize(&mut *serializer)?;
+            }
+            RangeEntry::Commands(commands) => {
+                COMMAND_TICK.serialize(&mut *serializer)?;
+                write_client_commands(serializer, commands, &mut write_payload)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Counterpart of [`write_tick_range`].  Runs of empty ticks are expanded
+/// but never past `max_ticks`, so a corrupt run length can't exhaust memory.
+pub fn read_tick_range<'de, D, C, E>(
+    deserializer: &mut D,
+    max_ticks: usize,
+    mut read_payload: impl FnMut(&mut D) -> Result<C, E>,
+) -> Result<Vec<BTreeMap<ClientId, Vec<C>>>, E>
+where
+    for<'a> &'a mut D: Deserializer<'de, Error = E>,
+    E: serde::de::Error,
+{
+    let num_entries = u32::deserialize(&mut *deserializer)?;
+    let mut ticks = Vec::new();
+    for _ in 0..num_entries {
+        match u8::deserialize(&mut *deserializer)? {
+            EMPTY_TICK => {
+                let run = u32::deserialize(&mut *deserializer)? as usize;
+                if ticks.len() + run > max_ticks {
+                    return Err(E::custom("empty tick run exceeds the tick range"));
+                }
+                ticks.extend((0..run).map(|_| BTreeMap::new()));
+            }
+            COMMAND_TICK => {
+                if ticks.len() == max_ticks {
+                    return Err(E::custom("tick exceeds the tick range"));
+                }
+                ticks.push(read_client_commands(deserializer, &mut read_payload)?);
+            }
+            tag => return Err(E::custom(format_args!("unknown tick tag {tag}"))),
+        }
+    }
+    Ok(ticks)
+}
@@ -0,0 +1,49 @@
+//! Scheduling math shared by the server and external tools
+
+use super::SimTick;
+
+/// One way latency in ticks for a round trip time, both in seconds
+pub fn rtt_to_ticks(rtt: f64, tick_timestep: f64) -> SimTick {
+    if tick_timestep <= 0.0 || !rtt.is_finite() || rtt <= 0.0 {
+        return 0;
+    }
+    let ticks = (rtt / 2.0) / tick_timestep;
+    // Equivalent to ceil, which isn't available without std
+    let whole = ticks as SimTick;
+    if (whole as f64) < ticks { whole + 1 } else { whole }
+}
+
+/// Total input delay in ticks for a client's commands
+pub fn input_delay(latency_ticks: SimTick, base_input_tick_delay: SimTick) -> SimTick {
+    latency_ticks + base_input_tick_delay
+}
+
+/// The tick a client's commands execute on when received at `current_tick`
+pub fn execution_tick(current_tick: SimTick, delay: SimTick) -> SimTick {
+    current_tick + delay
+}
+
+/// The past tick the server checks for received commands before advancing
+pub fn tick_to_check(current_tick: SimTick, check_delay: SimTick) -> SimTick {
+    current_tick.saturating_sub(check_delay)
+}
+
+/// Whether commands for this tick can still be added to the broadcast history
+pub fn is_schedulable(tick: SimTick, last_broadcast_tick: SimTick) -> bool {
+    tick > last_broadcast_tick
+}
+
+/// Ticks a peer may apply in one frame of `frame_delta` seconds.  Always
+/// covers the ticks produced during the frame plus one, so a peer rendering
+/// slower than the tick rate doesn't fall further behind every frame.
+/// `max_ticks_per_frame` only bounds catching up beyond that.
+pub fn ticks_per_frame(frame_delta: f64, tick_timestep: f64, max_ticks_per_frame: SimTick) -> SimTick {
+    let max_ticks_per_frame = max_ticks_per_frame.max(1);
+    if tick_timestep <= 0.0 || !frame_delta.is_finite() || frame_delta <= 0.0 {
+        return max_ticks_per_frame;
+    }
+    let ticks = frame_delta / tick_timestep;
+    let whole = ticks as SimTick;
+    let produced = if (whole as f64) < ticks { whole.saturating_add(1) } else { whole };
+    max_ticks_per_frame.max(produced.saturating_add(1))
+}
@@ -0,0 +1,291 @@
+use std::time::Duration;
+use bevy::{prelude::*, time::Stopwatch, utils::hashbrown::{HashMap, HashSet}};
+use bevy_replicon::{prelude::*, shared::backend::connected_client::NetworkId};
+use serde::{Deserialize, Serialize};
+use crate::prelude::*;
+
+/// Asset manifest exchange during [`SimulationState::Setup`].  The server
+/// broadcasts the [`AssetManifest`], clients fetch and verify each entry, and
+/// a client only counts as ready once every entry is verified.
+pub struct LockstepManifestPlugin;
+
+impl Plugin for LockstepManifestPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<AssetManifest>()
+            .init_resource::<ManifestProgress>()
+            .add_server_trigger::<AssetManifest>(Channel::Ordered)
+            .add_client_trigger::<ManifestItemStatus>(Channel::Ordered)
+            .add_observer(receive_manifest)
+            .add_observer(on_item_loaded)
+            .add_observer(on_item_failed)
+            .add_observer(receive_item_status)
+            .add_systems(OnEnter(SimulationState::Setup), (clear_verified_items, broadcast_manifest)
+                .chain()
+                .run_if(server_running))
+            .add_systems(Update, (retry_items, check_manifest_timeout)
+                .run_if(in_state(SimulationState::Setup)));
+    }
+}
+
+/// Timing and retry settings for the manifest exchange
+#[derive(Debug, Clone)]
+pub struct ManifestSettings {
+    /// How long a client may take to verify the whole manifest
+    pub timeout: Duration,
+    /// How long to wait before fetching a missing or corrupt item again
+    pub retry_delay: Duration,
+    /// Maximum fetch attempts per item
+    pub max_attempts: u32,
+}
+
+impl Default for ManifestSettings {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(120),
+            retry_delay: Duration::from_secs(2),
+            max_attempts: 3,
+        }
+    }
+}
+
+/// An asset every peer must have before the match starts
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub id: String,
+    /// Expected content hash, computed by the game
+    pub hash: u64,
+}
+
+/// The list of required assets.  Set this on the server before entering
+/// [`SimulationState::Setup`].  An empty manifest disables the exchange.
+#[derive(Event, Resource, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AssetManifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+/// A trigger that fires on a client for each manifest entry it must fetch.
+/// Respond with [`ManifestItemLoaded`] or [`ManifestItemFailed`].
+#[derive(Event, Debug, Clone)]
+pub struct FetchManifestItem {
+    pub entry: ManifestEntry,
+    /// The attempt number, starting from 1
+    pub attempt: u32,
+}
+
+/// Trigger this on a client when a manifest item has been loaded, with the
+/// hash of the loaded content.  A wrong hash counts as a corrupt item.
+#[derive(Event, Debug, Clone)]
+pub struct ManifestItemLoaded {
+    pub id: String,
+    pub hash: u64,
+}
+
+/// Trigger this on a client when a manifest item could not be loaded
+#[derive(Event, Debug, Clone)]
+pub struct ManifestItemFailed {
+    pub id: String,
+}
+
+/// A trigger that fires on a client when an item ran out of attempts, or the
+/// manifest timed out.  The client will never become ready.
+#[derive(Event, Debug, Clone)]
+pub struct ManifestLoadFailed {
+    pub ids: Vec<String>,
+}
+
+/// Sent by clients to the server as items are verified
+#[derive(Event, Serialize, Deserialize, Debug, Clone)]
+struct ManifestItemStatus {
+    id: String,
+    complete: bool,
+}
+
+/// Marker on the server's client entities which have verified the whole manifest
+#[derive(Component, Default, Debug)]
+pub struct ManifestComplete;
+
+/// Server-side ids of the manifest entries a client entity verified
+#[derive(Component, Default, Debug, Deref, DerefMut)]
+pub struct ManifestItemsVerified(HashSet<String>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManifestItemState {
+    Fetching { attempt: u32 },
+    WaitingRetry { attempt: u32, since: Duration },
+    Complete,
+    Failed,
+}
+
+/// Client-side progress through the manifest
+#[derive(Resource, Default)]
+pub struct ManifestProgress {
+    pub entries: Vec<ManifestEntry>,
+    pub items: HashMap<String, ManifestItemState>,
+    time: Stopwatch,
+    timed_out: bool,
+}
+
+impl ManifestProgress {
+    pub fn completed(&self) -> usize {
+        self.items.values().filter(|state| **state == ManifestItemState::Complete).count()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.completed() == self.entries.len()
+    }
+}
+
+fn broadcast_manifest(
+    mut commands: Commands,
+    manifest: Res<AssetManifest>,
+) {
+    if manifest.entries.is_empty() { return }
+    info!("Broadcasting asset manifest with {} entries", manifest.entries.len());
+    commands.server_trigger(ToClients {
+        mode: SendMode::Broadcast,
+        event: manifest.clone(),
+    });
+}
+
+/// Every match verifies its manifest anew, e.g. a rematch with new assets
+fn clear_verified_items(
+    mut commands: Commands,
+    clients: Query<Entity, Or<(With<ManifestItemsVerified>, With<ManifestComplete>)>>,
+) {
+    for client in clients.iter() {
+        commands.entity(client).remove::<(ManifestItemsVerified, ManifestComplete)>();
+    }
+}
+
+fn receive_manifest(
+    trigger: Trigger<AssetManifest>,
+    mut commands: Commands,
+    mut progress: ResMut<ManifestProgress>,
+) {
+    *progress = ManifestProgress {
+        entries: trigger.entries.clone(),
+        ..default()
+    };
+    for entry in trigger.entries.iter() {
+        progress.items.insert(entry.id.clone(), ManifestItemState::Fetching { attempt: 1 });
+        commands.trigger(FetchManifestItem { entry: entry.clone(), attempt: 1 });
+    }
+}
+
+fn on_item_loaded(
+    trigger: Trigger<ManifestItemLoaded>,
+    mut commands: Commands,
+    mut progress: ResMut<ManifestProgress>,
+    settings: Res<ConnectionSettings>,
+) {
+    let Some(entry) = progress.entries.iter().find(|entry| entry.id == trigger.id).cloned() else { return };
+    if entry.hash == trigger.hash {
+        trace!("manifest item {} verified", entry.id);
+        progress.items.insert(entry.id.clone(), ManifestItemState::Complete);
+        commands.client_trigger(ManifestItemStatus { id: entry.id, complete: true });
+    } else {
+        warn!("manifest item {} is corrupt", entry.id);
+        fail_item(&mut commands, &mut progress, &entry.id, &settings.manifest);
+    }
+}
+
+fn on_item_failed(
+    trigger: Trigger<ManifestItemFailed>,
+    mut commands: Commands,
+    mut progress: ResMut<ManifestProgress>,
+    settings: Res<ConnectionSettings>,
+) {
+    warn!("manifest item {} failed to load", trigger.id);
+    fail_item(&mut commands, &mut progress, &trigger.id, &settings.manifest);
+}
+
+fn fail_item(
+    commands: &mut Commands,
+    progress: &mut ManifestProgress,
+    id: &str,
+    settings: &ManifestSettings,
+) {
+    let since = progress.time.elapsed();
+    let Some(state) = progress.items.get_mut(id) else { return };
+    let ManifestItemState::Fetching { attempt } = *state else { return };
+    if attempt >= settings.max_attempts {
+        *state = ManifestItemState::Failed;
+        commands.trigger(ManifestLoadFailed { ids: vec![id.to_string()] });
+        commands.client_trigger(ManifestItemStatus { id: id.to_string(), complete: false });
+    } else {
+        *state = ManifestItemState::WaitingRetry { attempt, since };
+    }
+}
+
+fn retry_items(
+    mut commands: Commands,
+    mut progress: ResMut<ManifestProgress>,
+    settings: Res<ConnectionSettings>,
+    time: Res<Time>,
+) {
+    if progress.entries.is_empty() { return }
+    progress.time.tick(time.delta());
+    let now = progress.time.elapsed();
+    let ManifestProgress { entries, items, .. } = &mut *progress;
+    for entry in entries.iter() {
+        let Some(state) = items.get_mut(&entry.id) else { continue };
+        if let ManifestItemState::WaitingRetry { attempt, since } = *state {
+            if now - since >= settings.manifest.retry_delay {
+                *state = ManifestItemState::Fetching { attempt: attempt + 1 };
+                commands.trigger(FetchManifestItem { entry: entry.clone(), attempt: attempt + 1 });
+            }
+        }
+    }
+}
+
+fn check_manifest_timeout(
+    mut commands: Commands,
+    mut progress: ResMut<ManifestProgress>,
+    settings: Res<ConnectionSettings>,
+) {
+    if progress.entries.is_empty() || progress.timed_out || progress.is_complete() { return }
+    if progress.time.elapsed() < settings.manifest.timeout { return }
+    progress.timed_out = true;
+    let ids: Vec<String> = progress.items
+        .iter()
+        .filter(|(_, state)| **state != ManifestItemState::Complete)
+        .map(|(id, _)| id.clone())
+        .collect();
+    error!("Asset manifest timed out with {} items missing", ids.len());
+    commands.trigger(ManifestLoadFailed { ids });
+}
+
+fn receive_item_status(
+    trigger: Trigger<FromClient<ManifestItemStatus>>,
+    mut commands: Commands,
+    manifest: Res<AssetManifest>,
+    clients: LockstepClients,
+    mut verified: Query<&mut ManifestItemsVerified, With<NetworkId>>,
+) {
+    let Some(client) = clients.entity(trigger.client_entity) else { return };
+    if !trigger.event.complete {
+        warn!("client {} failed to load manifest item {}", client, trigger.event.id);
+        return;
+    }
+    let id = &trigger.event.id;
+    if !manifest.entries.iter().any(|entry| entry.id == *id) {
+        warn!("client {} verified {}, which is not in the manifest", client, id);
+        return;
+    }
+    // Repeated statuses of an item count once
+    let count = match verified.get_mut(client) {
+        Ok(mut verified) => {
+            verified.insert(id.clone());
+            verified.len()
+        }
+        Err(_) => {
+            commands.entity(client).insert(ManifestItemsVerified(HashSet::from([id.clone()])));
+            1
+        }
+    };
+    if count >= manifest.entries.len() {
+        trace!("client {} verified the asset manifest", client);
+        commands.entity(client).insert(ManifestComplete);
+    }
+}
@@ -0,0 +1,538 @@
+//! Match replays.  A finished match is fully described by the commands the
+//! server broadcast for each tick, so a service can re-run the match
+//! headlessly with the game's own simulation code and compare the resulting
+//! state hash with the one the players reported, and any game can record
+//! replays and play them back with [`ReplayPlugin`].
+
+use std::{fmt, fs, io, path::{Path, PathBuf}, sync::Arc, time::Duration};
+use bevy::{prelude::*, reflect::TypeRegistry};
+use bevy_replicon::{
+    postcard::{self, Deserializer, Serializer},
+    shared::postcard_utils::ExtendMutFlavor,
+};
+use serde::{Serialize, Deserialize};
+use crate::{
+    prelude::*,
+    commands::serialization::{serialize_client_commands, deserialize_client_commands},
+    simulation::SettingsTimeline,
+};
+
+mod scrubber;
+mod migration;
+mod reconstruct;
+
+pub use scrubber::ReplaySeeked;
+pub use migration::{migrate_history, replay_version};
+pub use reconstruct::reconstruct_world;
+
+/// Leading bytes of an encoded replay
+const REPLAY_MAGIC: [u8; 4] = *b"LSRP";
+/// The format version of replays encoded by this version of the crate.
+/// Older replays are upgraded with [`migrate_history`] on decoding.
+pub const REPLAY_VERSION: u8 = 3;
+
+/// Name of the replay artifact of a match in a [`LockstepStorage`]
+pub const REPLAY_ARTIFACT: &str = "replay";
+
+/// Records the match to a file when it ends, and plays back recorded
+/// matches started with [`StartReplayPlayback`]
+pub struct ReplayPlugin {
+    /// Where to write the replay of every match that reaches `Ending`.
+    /// With the [`StoragePlugin`] the replay is also stored as
+    /// [`ArtifactKind::Replay`] named [`REPLAY_ARTIFACT`].
+    pub record_path: Option<PathBuf>,
+    /// During playback, the simulated entities are captured every this many
+    /// ticks so [`ReplayPlayback::seek`] can go backwards without starting
+    /// over
+    pub checkpoint_interval: SimTick,
+    /// Pause at the last tick instead of ending playback, so a replay
+    /// viewer can keep seeking until it calls [`ReplayPlayback::stop`]
+    pub hold_at_end: bool,
+}
+
+impl Default for ReplayPlugin {
+    fn default() -> Self {
+        Self {
+            record_path: None,
+            checkpoint_interval: 300,
+            hold_at_end: false,
+        }
+    }
+}
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(ReplayRecording { path: self.record_path.clone() })
+            .insert_resource(PlaybackConfig {
+                checkpoint_interval: self.checkpoint_interval.max(1),
+                hold_at_end: self.hold_at_end,
+            })
+            .add_observer(start_replay_playback)
+            .add_observer(record_checkpoint)
+            .add_systems(OnEnter(SimulationState::Ending), (
+                record_replay.run_if(not(resource_exists::<ReplayPlayback>)),
+                end_playback.run_if(resource_exists::<ReplayPlayback>),
+            ).chain())
+            .add_systems(Update, finish_playback_setup
+                .run_if(in_state(SimulationState::Setup).and(resource_exists::<ReplayPlayback>)))
+            .add_systems(OnEnter(SimulationState::Running), record_initial_checkpoint
+                .run_if(resource_exists::<ReplayPlayback>))
+            .add_systems(PreUpdate, scrubber::drive_playback
+                .run_if(in_state(SimulationState::Running).and(resource_exists::<ReplayPlayback>)))
+            .add_systems(FixedPostUpdate, advance_playback
+                .run_if(in_state(SimulationState::Running).and(resource_exists::<ReplayPlayback>)));
+    }
+}
+
+/// The simulation settings a replay was recorded with.  These are the
+/// settings that take part in the [`SessionHash`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReplaySettings {
+    pub tick_timestep: Duration,
+    pub num_players: u8,
+    pub base_input_tick_delay: u8,
+    pub connection_check_tick_delay: u32,
+    pub disconnect_tick_threshold: u8,
+}
+
+impl ReplaySettings {
+    pub fn from_settings(settings: &SimulationSettings) -> Self {
+        Self {
+            tick_timestep: settings.tick_timestep,
+            num_players: settings.num_players,
+            base_input_tick_delay: settings.base_input_tick_delay,
+            connection_check_tick_delay: settings.connection_check_tick_delay,
+            disconnect_tick_threshold: settings.disconnect_tick_threshold,
+        }
+    }
+
+    pub fn apply(&self, settings: &mut SimulationSettings) {
+        settings.tick_timestep = self.tick_timestep;
+        settings.num_players = self.num_players;
+        settings.base_input_tick_delay = self.base_input_tick_delay;
+        settings.connection_check_tick_delay = self.connection_check_tick_delay;
+        settings.disconnect_tick_threshold = self.disconnect_tick_threshold;
+    }
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(io::Error),
+    Encoding(postcard::Error),
+    /// The data is not a replay, or from an unsupported version
+    Format,
+    Storage(StorageError),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "replay file error: {}", err),
+            Self::Encoding(err) => write!(f, "replay encoding error: {}", err),
+            Self::Format => write!(f, "not a supported replay"),
+            Self::Storage(err) => write!(f, "replay {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl From<io::Error> for ReplayError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<StorageError> for ReplayError {
+    fn from(err: StorageError) -> Self {
+        Self::Storage(err)
+    }
+}
+
+impl From<postcard::Error> for ReplayError {
+    fn from(err: postcard::Error) -> Self {
+        Self::Encoding(err)
+    }
+}
+
+/// The recorded commands of a match, indexed by tick
+#[derive(Default, Clone)]
+pub struct MatchReplay {
+    pub session: SessionHash,
+    /// The settings the match started with
+    pub settings: ReplaySettings,
+    /// Settings changes passed during the match, by effective tick
+    pub settings_changes: Vec<(SimTick, SettingsChange)>,
+    /// The seed of the [`SessionIdentity`]
+    pub seed: u64,
+    /// The seed of the [`LockstepRng`]
+    pub rng_seed: u64,
+    pub ticks: Vec<LockstepClientCommands>,
+    /// The final state hash reported for the match, if any
+    pub reported_hash: Option<u64>,
+}
+
+impl MatchReplay {
+    /// Record a replay from the command buffer of a finished match
+    pub fn from_buffer(session: SessionHash, buffer: &LockstepGameCommandBuffer) -> Self {
+        Self { session, ticks: buffer.to_vec(), ..default() }
+    }
+
+    /// Record the settings and seed needed to play the replay back.  These
+    /// are the settings at the start of the match, later changes are
+    /// recorded with [`MatchReplay::with_settings_changes`].
+    pub fn with_settings(mut self, settings: &SimulationSettings, seed: u64) -> Self {
+        self.settings = ReplaySettings::from_settings(settings);
+        self.seed = seed;
+        self
+    }
+
+    /// Record the settings changes passed during the match, each with the
+    /// tick it took effect on
+    pub fn with_settings_changes(mut self, changes: Vec<(SimTick, SettingsChange)>) -> Self {
+        self.settings_changes = changes;
+        self
+    }
+
+    pub fn with_rng_seed(mut self, rng_seed: u64) -> Self {
+        self.rng_seed = rng_seed;
+        self
+    }
+
+    pub fn with_reported_hash(mut self, hash: u64) -> Self {
+        self.reported_hash = Some(hash);
+        self
+    }
+
+    /// The last tick of the match
+    pub fn last_tick(&self) -> SimTick {
+        self.ticks.len().saturating_sub(1) as SimTick
+    }
+
+    /// Encode the replay with the same command encoding used on the wire.
+    /// Every command type must be registered in `registry`.
+    pub fn to_bytes(&self, registry: &TypeRegistry) -> postcard::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        let mut serializer = Serializer { output: ExtendMutFlavor::new(&mut bytes) };
+        REPLAY_MAGIC.serialize(&mut serializer)?;
+        REPLAY_VERSION.serialize(&mut serializer)?;
+        self.session.0.serialize(&mut serializer)?;
+        self.settings.serialize(&mut serializer)?;
+        self.settings_changes.serialize(&mut serializer)?;
+        self.seed.serialize(&mut serializer)?;
+        self.rng_seed.serialize(&mut serializer)?;
+        self.reported_hash.serialize(&mut serializer)?;
+        (self.ticks.len() as u32).serialize(&mut serializer)?;
+        for tick_commands in &self.ticks {
+            serialize_client_commands(&mut serializer, tick_commands, registry, None)?;
+        }
+        Ok(bytes)
+    }
+
+    /// Decode a replay produced by [`MatchReplay::to_bytes`], of this or an
+    /// older version of the crate
+    pub fn from_bytes(bytes: &[u8], registry: &TypeRegistry) -> Result<Self, ReplayError> {
+        let version = replay_version(bytes)?;
+        if version < REPLAY_VERSION {
+            debug!("upgrading a replay of format version {}", version);
+            return Self::from_bytes(&migrate_history(bytes, version)?, registry);
+        }
+        let mut deserializer = Deserializer::from_bytes(bytes);
+        let magic = <[u8; 4]>::deserialize(&mut deserializer)?;
+        let version = u8::deserialize(&mut deserializer)?;
+        if magic != REPLAY_MAGIC || version != REPLAY_VERSION {
+            return Err(ReplayError::Format);
+        }
+        let session = SessionHash(u32::deserialize(&mut deserializer)?);
+        let settings = ReplaySettings::deserialize(&mut deserializer)?;
+        let settings_changes = Vec::<(SimTick, SettingsChange)>::deserialize(&mut deserializer)?;
+        let seed = u64::deserialize(&mut deserializer)?;
+        let rng_seed = u64::deserialize(&mut deserializer)?;
+        let reported_hash = Option::<u64>::deserialize(&mut deserializer)?;
+        let num_ticks = u32::deserialize(&mut deserializer)? as usize;
+        let mut ticks = Vec::with_capacity(num_ticks);
+        for _ in 0..num_ticks {
+            ticks.push(deserialize_client_commands(&mut deserializer, registry, None)?);
+        }
+        Ok(Self { session, settings, settings_changes, seed, rng_seed, ticks, reported_hash })
+    }
+
+    pub fn write_to_file(&self, path: impl AsRef<Path>, registry: &TypeRegistry) -> Result<(), ReplayError> {
+        fs::write(path, self.to_bytes(registry)?)?;
+        Ok(())
+    }
+
+    pub fn read_from_file(path: impl AsRef<Path>, registry: &TypeRegistry) -> Result<Self, ReplayError> {
+        Self::from_bytes(&fs::read(path)?, registry)
+    }
+
+    /// Read the replay the [`ReplayPlugin`] stored for a match
+    pub fn read_from_storage(storage: &dyn LockstepStorage, match_id: &str, registry: &TypeRegistry) -> Result<Self, ReplayError> {
+        Self::from_bytes(&storage.get(match_id, ArtifactKind::Replay, REPLAY_ARTIFACT)?, registry)
+    }
+}
+
+/// Result of re-simulating a [`MatchReplay`]
+#[derive(Debug, Clone, Default)]
+pub struct ReplayVerification {
+    /// State hash after each tick, starting with tick 1
+    pub tick_hashes: Vec<u64>,
+    pub final_hash: u64,
+    pub reported_hash: Option<u64>,
+}
+
+impl ReplayVerification {
+    /// True if the replay reproduced the reported final state
+    pub fn is_verified(&self) -> bool {
+        self.reported_hash == Some(self.final_hash)
+    }
+}
+
+/// Re-run a match headlessly.  `setup` builds the game world (register
+/// command types, insert resources, spawn the initial state).  `simulate`
+/// advances the world by one tick; the tick's commands are in
+/// [`LockstepGameCommandBuffer`] and [`SimulationTick`] is set to the tick.
+/// `state_hash` hashes the world after each tick.
+pub fn verify_replay<S, F, H>(
+    replay: &MatchReplay,
+    setup: S,
+    mut simulate: F,
+    state_hash: H,
+) -> ReplayVerification
+where
+    S: FnOnce(&mut App),
+    F: FnMut(&mut World, SimTick),
+    H: Fn(&mut World, SimTick) -> u64,
+{
+    let mut app = reconstruct::replay_app(replay, setup);
+    let world = app.world_mut();
+    let mut tick_hashes = Vec::with_capacity(replay.ticks.len());
+    for tick in 1..=replay.last_tick() {
+        **world.resource_mut::<SimulationTick>() = tick;
+        simulate(world, tick);
+        world.flush();
+        tick_hashes.push(state_hash(world, tick));
+    }
+    ReplayVerification {
+        final_hash: tick_hashes.last().copied().unwrap_or_default(),
+        tick_hashes,
+        reported_hash: replay.reported_hash,
+    }
+}
+
+/// Trigger this while no match is running to play a replay back.  The
+/// recorded settings and seed are applied and the simulation goes through
+/// Setup, where the game builds its initial world as usual, and on to
+/// Running the frame after.  Then one recorded tick is fed into
+/// [`LockstepGameCommandBuffer`] per fixed timestep, with the usual
+/// [`SimulationTickUpdate`] events, and no network involved.  Playback is
+/// controlled through the [`ReplayPlayback`] resource.
+#[derive(Event)]
+pub struct StartReplayPlayback(pub MatchReplay);
+
+/// A trigger that fires once every tick of a replay was played back,
+/// before entering `Ending`, or before pausing with
+/// `ReplayPlugin::hold_at_end`
+#[derive(Event, Debug, Clone, Copy)]
+pub struct ReplayPlaybackFinished {
+    pub last_tick: SimTick,
+}
+
+/// A trigger that fires after the replay of a match was written
+#[derive(Event, Debug, Clone)]
+pub struct ReplayRecorded {
+    pub path: PathBuf,
+}
+
+/// A replay being played back.  See the scrubber methods, e.g.
+/// [`ReplayPlayback::seek`], for controlling playback.
+#[derive(Resource)]
+pub struct ReplayPlayback {
+    replay: MatchReplay,
+    speed: f32,
+    paused: bool,
+    pending_steps: u32,
+    seek: Option<SimTick>,
+    stopped: bool,
+    finished: bool,
+    checkpoints: scrubber::ReplayCheckpoints,
+}
+
+impl ReplayPlayback {
+    pub fn replay(&self) -> &MatchReplay {
+        &self.replay
+    }
+}
+
+#[derive(Resource)]
+struct ReplayRecording {
+    path: Option<PathBuf>,
+}
+
+#[derive(Resource)]
+struct PlaybackConfig {
+    checkpoint_interval: SimTick,
+    hold_at_end: bool,
+}
+
+fn start_replay_playback(
+    mut trigger: Trigger<StartReplayPlayback>,
+    mut commands: Commands,
+    state: Res<State<SimulationState>>,
+    mut settings: ResMut<SimulationSettings>,
+    mut identity: ResMut<SessionIdentity>,
+    mut fixed_time: ResMut<Time<Fixed>>,
+    mut next_state: ResMut<NextState<SimulationState>>,
+) {
+    if *state.get() != SimulationState::None {
+        warn!("Can't play a replay back in state {:?}", state.get());
+        return;
+    }
+    let replay = core::mem::take(&mut trigger.event_mut().0);
+    info!("Playing back replay of {} ticks", replay.last_tick());
+    replay.settings.apply(&mut settings);
+    fixed_time.set_timestep(settings.tick_timestep);
+    identity.seed = replay.seed;
+    commands.insert_resource(LockstepRng::new(replay.rng_seed));
+    commands.insert_resource(ReplayPlayback {
+        replay,
+        speed: 1.0,
+        paused: false,
+        pending_steps: 0,
+        seek: None,
+        stopped: false,
+        finished: false,
+        checkpoints: default(),
+    });
+    next_state.set(SimulationState::Setup);
+}
+
+/// Runs after the timeline was reset for the match, so the recorded
+/// changes apply to the ticks played back
+fn finish_playback_setup(
+    playback: Res<ReplayPlayback>,
+    mut timeline: ResMut<SettingsTimeline>,
+    mut next_state: ResMut<NextState<SimulationState>>,
+) {
+    timeline.changes = playback.replay.settings_changes.clone();
+    next_state.set(SimulationState::Running);
+}
+
+fn record_initial_checkpoint(world: &mut World) {
+    let checkpoint = scrubber::capture_checkpoint(world, 0);
+    world.resource_mut::<ReplayPlayback>().checkpoints.insert(0, checkpoint);
+}
+
+fn record_checkpoint(
+    trigger: Trigger<GameCommandsApplied>,
+    world: &World,
+    mut commands: Commands,
+) {
+    let tick = trigger.event().0;
+    let Some(playback) = world.get_resource::<ReplayPlayback>() else { return };
+    let interval = world.resource::<PlaybackConfig>().checkpoint_interval;
+    if tick % interval != 0 || playback.checkpoints.contains_key(&tick) { return }
+    let checkpoint = scrubber::capture_checkpoint(world, tick);
+    commands.queue(move |world: &mut World| {
+        if let Some(mut playback) = world.get_resource_mut::<ReplayPlayback>() {
+            playback.checkpoints.insert(tick, checkpoint);
+        }
+    });
+}
+
+fn advance_playback(
+    mut commands: Commands,
+    mut playback: ResMut<ReplayPlayback>,
+    config: Res<PlaybackConfig>,
+    mut sim_tick: ResMut<SimulationTick>,
+    mut buffer: ResMut<LockstepGameCommandBuffer>,
+    mut tick_updates: EventWriter<SimulationTickUpdate>,
+    mut next_state: ResMut<NextState<SimulationState>>,
+    mut virtual_time: ResMut<Time<Virtual>>,
+) {
+    let last_tick = playback.replay.last_tick();
+    if playback.stopped || (**sim_tick >= last_tick && !config.hold_at_end) {
+        info!("Replay playback finished at tick {}", **sim_tick);
+        if !playback.finished {
+            commands.trigger(ReplayPlaybackFinished { last_tick });
+        }
+        // Removed once Ending is entered, so a played back match isn't
+        // recorded over the replay
+        playback.stopped = true;
+        playback.finished = true;
+        virtual_time.set_relative_speed(1.0);
+        next_state.set(SimulationState::Ending);
+        return;
+    }
+    if **sim_tick >= last_tick {
+        if !playback.finished {
+            info!("Replay playback reached the last tick {}", last_tick);
+            commands.trigger(ReplayPlaybackFinished { last_tick });
+            playback.finished = true;
+            playback.paused = true;
+        }
+        return;
+    }
+    if playback.paused {
+        if playback.pending_steps == 0 { return }
+        playback.pending_steps -= 1;
+    }
+    let tick = **sim_tick + 1;
+    if buffer.is_empty() {
+        buffer.push(playback.replay.ticks[0].clone());
+    }
+    buffer.push(playback.replay.ticks[tick as usize].clone());
+    **sim_tick = tick;
+    tick_updates.send(SimulationTickUpdate(tick));
+}
+
+fn end_playback(mut commands: Commands) {
+    commands.remove_resource::<ReplayPlayback>();
+}
+
+fn record_replay(world: &mut World) {
+    let path = world.resource::<ReplayRecording>().path.clone();
+    let stored = world.contains_resource::<MatchStorage>();
+    if path.is_none() && !stored { return }
+    let first_available = **world.resource::<FirstAvailableTick>();
+    if first_available > 1 {
+        warn!("Not recording a replay, ticks before {} were restored from a checkpoint", first_available);
+        return;
+    }
+    let timeline = world.resource::<SettingsTimeline>();
+    let settings = timeline.initial_settings(world.resource::<SimulationSettings>());
+    let replay = MatchReplay::from_buffer(*world.resource::<SessionHash>(), world.resource::<LockstepGameCommandBuffer>())
+        .with_settings(&settings, world.resource::<SessionIdentity>().seed)
+        .with_settings_changes(timeline.changes.clone())
+        .with_rng_seed(world.resource::<LockstepRng>().seed())
+        .with_reported_hash(hash_simulation_state(world));
+    let bytes = match replay.to_bytes(&world.resource::<AppTypeRegistry>().read()) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            error!("Failed to encode replay: {}", err);
+            return;
+        }
+    };
+    if let Some(path) = path {
+        match fs::write(&path, &bytes) {
+            Ok(()) => {
+                info!("Recorded replay to {}", path.display());
+                world.trigger(ReplayRecorded { path });
+            }
+            Err(err) => error!("Failed to record replay to {}: {}", path.display(), err),
+        }
+    }
+    if stored {
+        world.trigger(StoreArtifact {
+            kind: ArtifactKind::Replay,
+            name: REPLAY_ARTIFACT.to_string(),
+            data: Arc::new(bytes),
+        });
+    }
+}
+
+/// Run condition for systems that don't run during playback
+pub(crate) fn playing_back(playback: Option<Res<ReplayPlayback>>) -> bool {
+    playback.is_some()
+}
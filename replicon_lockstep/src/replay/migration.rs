@@ -0,0 +1,106 @@
+//! Upgrades of replays encoded by older versions of the crate.  Each format
+//! change adds a step from its previous version, and older replays go
+//! through every step up to [`REPLAY_VERSION`], so replay libraries stay
+//! playable across upgrades.  Steps only rewrite the framing around the
+//! commands, the commands themselves are copied as they are.
+//!
+//! Only replays are migrated.  Checkpoints are exchanged between the peers
+//! of one match, which run the same version, and their snapshots are in the
+//! game's own format, so no later version reads them.
+
+use bevy_replicon::{
+    postcard::{Deserializer, Serializer},
+    shared::postcard_utils::ExtendMutFlavor,
+};
+use serde::{Deserialize, Serialize};
+use crate::prelude::{SettingsChange, SimTick};
+use super::{ReplayError, ReplaySettings, REPLAY_MAGIC, REPLAY_VERSION};
+
+/// Rewrites an encoded replay of version `from_version` into one of
+/// version `from_version + 1`
+type MigrationStep = fn(&[u8]) -> Result<Vec<u8>, ReplayError>;
+
+/// The step from each version to the next, by version
+const MIGRATIONS: [(u8, MigrationStep); 2] = [
+    (1, add_rng_seed),
+    (2, add_settings_changes),
+];
+
+/// Upgrades a replay encoded with format `from_version` to the current
+/// [`REPLAY_VERSION`], ready for [`MatchReplay::from_bytes`].  Returns
+/// [`ReplayError::Format`] if the bytes aren't a replay of that version, or
+/// the version can't be upgraded.
+///
+/// [`MatchReplay::from_bytes`]: super::MatchReplay::from_bytes
+pub fn migrate_history(old_bytes: &[u8], from_version: u8) -> Result<Vec<u8>, ReplayError> {
+    if replay_version(old_bytes)? != from_version || from_version > REPLAY_VERSION {
+        return Err(ReplayError::Format);
+    }
+    let mut bytes = old_bytes.to_vec();
+    for version in from_version..REPLAY_VERSION {
+        let (_, step) = MIGRATIONS
+            .iter()
+            .find(|(from, _)| *from == version)
+            .ok_or(ReplayError::Format)?;
+        bytes = step(&bytes)?;
+    }
+    Ok(bytes)
+}
+
+/// The format version of an encoded replay
+pub fn replay_version(bytes: &[u8]) -> Result<u8, ReplayError> {
+    let mut deserializer = Deserializer::from_bytes(bytes);
+    let magic = <[u8; 4]>::deserialize(&mut deserializer)?;
+    if magic != REPLAY_MAGIC {
+        return Err(ReplayError::Format);
+    }
+    Ok(u8::deserialize(&mut deserializer)?)
+}
+
+/// Version 2 added the [`LockstepRng`](crate::prelude::LockstepRng) seed
+/// after the session seed.  Matches before it had no shared rng, so any
+/// seed replays them the same.
+fn add_rng_seed(bytes: &[u8]) -> Result<Vec<u8>, ReplayError> {
+    let mut deserializer = Deserializer::from_bytes(bytes);
+    let _magic = <[u8; 4]>::deserialize(&mut deserializer)?;
+    let _version = u8::deserialize(&mut deserializer)?;
+    let session = u32::deserialize(&mut deserializer)?;
+    let settings = ReplaySettings::deserialize(&mut deserializer)?;
+    let seed = u64::deserialize(&mut deserializer)?;
+    let reported_hash = Option::<u64>::deserialize(&mut deserializer)?;
+    let ticks = deserializer.finalize()?;
+
+    let mut upgraded = Vec::with_capacity(bytes.len() + 8);
+    let mut serializer = Serializer { output: ExtendMutFlavor::new(&mut upgraded) };
+    REPLAY_MAGIC.serialize(&mut serializer)?;
+    2u8.serialize(&mut serializer)?;
+    session.serialize(&mut serializer)?;
+    settings.serialize(&mut serializer)?;
+    seed.serialize(&mut serializer)?;
+    0u64.serialize(&mut serializer)?;
+    reported_hash.serialize(&mut serializer)?;
+    upgraded.extend_from_slice(ticks);
+    Ok(upgraded)
+}
+
+/// Version 3 added the settings changes passed during the match after the
+/// settings.  Older replays recorded the final settings instead, which are
+/// kept as they are, with no changes.
+fn add_settings_changes(bytes: &[u8]) -> Result<Vec<u8>, ReplayError> {
+    let mut deserializer = Deserializer::from_bytes(bytes);
+    let _magic = <[u8; 4]>::deserialize(&mut deserializer)?;
+    let _version = u8::deserialize(&mut deserializer)?;
+    let session = u32::deserialize(&mut deserializer)?;
+    let settings = ReplaySettings::deserialize(&mut deserializer)?;
+    let rest = deserializer.finalize()?;
+
+    let mut upgraded = Vec::with_capacity(bytes.len() + 1);
+    let mut serializer = Serializer { output: ExtendMutFlavor::new(&mut upgraded) };
+    REPLAY_MAGIC.serialize(&mut serializer)?;
+    3u8.serialize(&mut serializer)?;
+    session.serialize(&mut serializer)?;
+    settings.serialize(&mut serializer)?;
+    Vec::<(SimTick, SettingsChange)>::new().serialize(&mut serializer)?;
+    upgraded.extend_from_slice(rest);
+    Ok(upgraded)
+}
@@ -0,0 +1,68 @@
+use bevy::prelude::*;
+use crate::{
+    prelude::*,
+    commands::apply_tick,
+    simulation::{InLockstepSchedule, SettingsTimeline},
+};
+use super::MatchReplay;
+
+/// Builds a fresh world with the recorded match applied up to `tick`, e.g.
+/// for map editors, balance tools or comparing the state of a desynced
+/// tick, without any networking.  `setup` builds the game world like the
+/// game does on entering Setup: register the command types with
+/// [`LockstepCommandAppExt`], insert resources and spawn the initial state.
+/// Each tick is then applied like on a peer, through the registered
+/// [`GameCommand`] implementations and command observers, followed by the
+/// physics step schedules.  Ticks past the end of the replay are ignored.
+pub fn reconstruct_world<S>(replay: &MatchReplay, tick: SimTick, setup: S) -> World
+where
+    S: FnOnce(&mut App),
+{
+    let mut app = replay_app(replay, setup);
+    let world = app.world_mut();
+    world.resource_mut::<InLockstepSchedule>().0 = true;
+    for tick in 1..=tick.min(replay.last_tick()) {
+        **world.resource_mut::<SimulationTick>() = tick;
+        let Some(tick_commands) = world.resource::<LockstepGameCommandBuffer>().get(tick).cloned() else { break };
+        apply_tick(world, tick, &tick_commands);
+    }
+    world.resource_mut::<InLockstepSchedule>().0 = false;
+    std::mem::take(world)
+}
+
+/// A headless app with the recorded session, settings, settings changes and
+/// commands of the replay and the game world built by `setup`, before any tick is applied
+pub(super) fn replay_app<S>(replay: &MatchReplay, setup: S) -> App
+where
+    S: FnOnce(&mut App),
+{
+    let mut settings = SimulationSettings::default();
+    replay.settings.apply(&mut settings);
+    let timeline = SettingsTimeline { changes: replay.settings_changes.clone(), ..SettingsTimeline::new(&settings) };
+    let mut app = App::new();
+    app
+        .insert_resource(timeline)
+        .insert_resource(settings)
+        .init_resource::<Time>()
+        .insert_resource(LockstepGameCommandBuffer::default())
+        .init_resource::<SimulationTick>()
+        .init_resource::<LastAppliedTick>()
+        .init_resource::<InLockstepSchedule>()
+        .init_resource::<TickSalt>()
+        .init_resource::<SimulationIdEntityMap>()
+        .init_resource::<ChildSimulationIdEntityMap>()
+        .init_resource::<SimulationIdAllocator>()
+        .init_resource::<ChildSimulationIdAllocator>()
+        .init_schedule(LockstepCommandHandlers)
+        .init_schedule(BeforePhysicsStep)
+        .init_schedule(PhysicsStep)
+        .init_schedule(AfterPhysicsStep)
+        .insert_resource(replay.session)
+        .insert_resource(SessionIdentity { seed: replay.seed, ..default() })
+        .insert_resource(LockstepRng::new(replay.rng_seed));
+    setup(&mut app);
+    app.finish();
+    app.cleanup();
+    **app.world_mut().resource_mut::<LockstepGameCommandBuffer>() = replay.ticks.clone();
+    app
+}
@@ -0,0 +1,217 @@
+use std::collections::BTreeMap;
+use bevy::prelude::*;
+use crate::{prelude::*, hashing::state::reflected_components};
+use super::ReplayPlayback;
+
+/// A trigger that fires after playback jumped to another tick with
+/// [`ReplayPlayback::seek`].  When seeking backwards the simulated entities
+/// were restored from `checkpoint` first, games with simulation state
+/// outside of entities should rewind it here.  The ticks up to `to` are
+/// applied afterwards as in live play.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct ReplaySeeked {
+    pub from: SimTick,
+    pub to: SimTick,
+    pub checkpoint: Option<SimTick>,
+}
+
+/// The simulated entities after a tick was applied.  Entities are captured
+/// with all their reflected components, except the hierarchy, which is
+/// rebuilt from [`ChildSimulationId`]s on restore.
+pub(super) struct ReplayCheckpoint {
+    next_id: u32,
+    child_allocator: ChildSimulationIdAllocator,
+    entities: Vec<CheckpointEntity>,
+}
+
+struct CheckpointEntity {
+    id: Option<SimulationId>,
+    child_id: Option<ChildSimulationId>,
+    components: Vec<Box<dyn PartialReflect>>,
+}
+
+/// Checkpoints by tick
+pub(super) type ReplayCheckpoints = BTreeMap<SimTick, ReplayCheckpoint>;
+
+/// Scrubber controls, for building replay viewers
+impl ReplayPlayback {
+    /// Jump to a tick, clamped to the replay.  Takes effect at the start of
+    /// the next frame.  Later ticks are applied from the current one,
+    /// earlier ones from the closest checkpoint before them.
+    pub fn seek(&mut self, tick: SimTick) {
+        self.seek = Some(tick.min(self.replay.last_tick()));
+        self.pending_steps = 0;
+    }
+
+    /// Play at a multiple of the recorded tick rate.  The speed scales
+    /// virtual time, so the fixed timestep and everything else driven by
+    /// it speeds up along with the ticks.
+    pub fn play(&mut self, speed: f32) {
+        if speed <= 0.0 {
+            self.pause();
+            return;
+        }
+        self.speed = speed;
+        self.paused = false;
+    }
+
+    /// Stop advancing ticks.  Virtual time keeps running, so cameras and
+    /// UI stay responsive.
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Pause, and advance exactly one tick on the next fixed timestep
+    pub fn step(&mut self) {
+        self.paused = true;
+        self.pending_steps += 1;
+    }
+
+    /// End playback and move on to `Ending`
+    pub fn stop(&mut self) {
+        self.stopped = true;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Ticks that can be restored without re-applying from the start
+    pub fn checkpoint_ticks(&self) -> impl Iterator<Item = SimTick> + '_ {
+        self.checkpoints.keys().copied()
+    }
+}
+
+pub(super) fn capture_checkpoint(world: &World, tick: SimTick) -> ReplayCheckpoint {
+    let registry = world.resource::<AppTypeRegistry>().read();
+    let mut filter = StateHashFilter::default();
+    filter
+        .exclude::<SimulationId>()
+        .exclude::<ChildSimulationId>()
+        .exclude::<Parent>()
+        .exclude::<Children>();
+    let entities = world
+        .iter_entities()
+        .filter(|entity| entity.contains::<SimulationId>() || entity.contains::<ChildSimulationId>())
+        .map(|entity| CheckpointEntity {
+            id: entity.get::<SimulationId>().copied(),
+            child_id: entity.get::<ChildSimulationId>().copied(),
+            components: reflected_components(world, entity, &filter, &registry)
+                .into_iter()
+                .map(|(_, value)| value.clone_value())
+                .collect(),
+        })
+        .collect();
+    trace!("captured replay checkpoint at tick {}", tick);
+    ReplayCheckpoint {
+        next_id: world.resource::<SimulationIdAllocator>().next_id(),
+        child_allocator: world.resource::<ChildSimulationIdAllocator>().clone(),
+        entities,
+    }
+}
+
+fn restore_checkpoint(world: &mut World, checkpoint: &ReplayCheckpoint) {
+    let simulated: Vec<Entity> = world
+        .query_filtered::<Entity, Or<(With<SimulationId>, With<ChildSimulationId>)>>()
+        .iter(world)
+        .collect();
+    for entity in simulated {
+        if let Ok(entity) = world.get_entity_mut(entity) {
+            entity.despawn_recursive();
+        }
+    }
+    world.resource_mut::<SimulationIdEntityMap>().clear();
+    world.resource_mut::<ChildSimulationIdEntityMap>().clear();
+    *world.resource_mut::<ChildSimulationIdAllocator>() = checkpoint.child_allocator.clone();
+    if world.resource_mut::<SimulationIdAllocator>().set_next_id(checkpoint.next_id).is_err() {
+        warn!("replay checkpoint has an invalid next simulation id");
+    }
+
+    let registry = world.resource::<AppTypeRegistry>().clone();
+    let registry = registry.read();
+    let mut children = Vec::new();
+    for saved in &checkpoint.entities {
+        let mut entity = world.spawn_empty();
+        for component in &saved.components {
+            let Some(data) = component
+                .get_represented_type_info()
+                .and_then(|info| registry.get_type_data::<ReflectComponent>(info.type_id()))
+            else { continue };
+            data.insert(&mut entity, component.as_ref(), &registry);
+        }
+        if let Some(id) = saved.id {
+            entity.insert(id);
+        }
+        if let Some(child_id) = saved.child_id {
+            entity.insert(child_id);
+        }
+        let entity = entity.id();
+        if let Some(id) = saved.id {
+            world.resource_mut::<SimulationIdEntityMap>().insert(id, entity);
+        }
+        if let Some(child_id) = saved.child_id {
+            world.resource_mut::<ChildSimulationIdEntityMap>().insert(child_id, entity);
+            children.push((entity, child_id.parent));
+        }
+    }
+    for (child, parent) in children {
+        let Some(&parent) = world.resource::<SimulationIdEntityMap>().get(&parent) else { continue };
+        world.entity_mut(child).set_parent(parent);
+    }
+}
+
+/// Applies the playback speed and pending seeks, before the fixed timestep
+/// of the frame runs
+pub(super) fn drive_playback(world: &mut World) {
+    let speed = world.resource::<ReplayPlayback>().speed;
+    let mut virtual_time = world.resource_mut::<Time<Virtual>>();
+    if virtual_time.relative_speed() != speed {
+        virtual_time.set_relative_speed(speed);
+    }
+
+    let Some(target) = world.resource_mut::<ReplayPlayback>().seek.take() else { return };
+    let from = **world.resource::<SimulationTick>();
+    if target == from { return }
+
+    let mut checkpoint = None;
+    let mut tick = from;
+    if target < from {
+        world.resource_scope(|world, playback: Mut<ReplayPlayback>| {
+            let Some((&checkpoint_tick, saved)) = playback.checkpoints.range(..=target).next_back() else { return };
+            restore_checkpoint(world, saved);
+            checkpoint = Some(checkpoint_tick);
+        });
+        let Some(checkpoint_tick) = checkpoint else {
+            warn!("no replay checkpoint before tick {}", target);
+            return;
+        };
+        tick = checkpoint_tick;
+        **world.resource_mut::<LastAppliedTick>() = tick;
+        world.resource_mut::<LockstepGameCommandBuffer>().truncate(tick as usize + 1);
+    }
+    debug!("seeking replay from tick {} to {}", from, target);
+    **world.resource_mut::<SimulationTick>() = tick;
+    world.trigger(ReplaySeeked { from, to: target, checkpoint });
+
+    // Queue the ticks for the usual apply, checkpoints are recorded on the way
+    world.resource_scope(|world, mut playback: Mut<ReplayPlayback>| {
+        let mut buffer = world.resource_mut::<LockstepGameCommandBuffer>();
+        if buffer.is_empty() {
+            buffer.push(playback.replay.ticks[0].clone());
+        }
+        for tick in tick + 1..=target {
+            buffer.push(playback.replay.ticks[tick as usize].clone());
+        }
+        if target < playback.replay.last_tick() {
+            playback.finished = false;
+        }
+    });
+    for tick in tick + 1..=target {
+        world.send_event(SimulationTickUpdate(tick));
+    }
+    **world.resource_mut::<SimulationTick>() = target;
+}
@@ -1,33 +1,224 @@
-use bevy::prelude::*;
-use bevy::utils::hashbrown::HashMap;
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy::utils::hashbrown::{HashMap, HashSet};
 use bevy_replicon::{prelude::*, shared::backend::connected_client::NetworkId};
-use std::sync::atomic::{AtomicU32, Ordering};
 use std::time::Duration;
 use serde::{Serialize, Deserialize};
-use crate::{prelude::*, commands::{ServerSendCommands, LockstepGameCommandsReceived}, connections::ClientReady};
+use crate::{prelude::*, correlation, lockstep_core::schedule, commands::{ServerSendCommands, LockstepGameCommandsReceived, UnackedCommands, ClientSequences, catch_up::{self, PendingCatchUp}}, connections::{ClientReady, JoiningInProgress}};
 
-pub type SimTick = u32;
+mod sim_ref;
+mod reset;
+mod session;
+mod pause;
+mod spectate;
+mod tick_clock;
+mod votes;
+mod rematch;
+mod overrun;
+mod shutdown;
+mod control;
+mod stall;
+mod pacing;
+mod child_ids;
+mod rng;
+mod despawn_guard;
+mod bandwidth;
+mod tick_gap;
+mod tick_rate;
+mod liveness;
+mod replication_ticks;
 
-pub(crate) struct LockstepSimulationPlugin;
+pub use sim_ref::{SimRef, SimRefError, CommandContext};
+pub use reset::{ResetSimulation, RequestSimulationReset, SimulationReset};
+pub use session::{SessionIdentity, SessionHash, SessionMismatch};
+pub use pause::{PauseSimulation, ResumeSimulation, PauseReason, RequestPause, RequestResume, SimulationPaused, ResumeVoteStatus, PauseStatus, PauseAllowance, PausesUsed, PauseDenied, PauseCountdown, ClientHeartbeat, ClientHeartbeats};
+pub(crate) use pause::HeldCommands;
+pub use pacing::BroadcastPacing;
+pub use votes::{SettingsChange, VoteRule, ProposeSettingsChange, CastSettingsVote, SettingsVoteStarted, SettingsVoteResolved, SettingsChanged};
+pub use stall::{TickStall, PresentationFreeze, PresentationResume};
+pub use control::{SessionController, SessionRequest, SessionRequestRejected};
+pub use shutdown::{EndSimulation, FinalTickScheduled, FinalTickApplied, FinalTick};
+pub use overrun::{OverrunMitigation, FixedUpdateOverrun, ClientOverrunning};
+pub use bandwidth::{BandwidthMitigation, DeclareBandwidthBudget, BandwidthBudget, BroadcastBandwidth, BandwidthConstrained};
+pub use tick_gap::TickGapTimedOut;
+pub use tick_rate::RequestTickRate;
+pub use liveness::{EmptyCommandSuppression, LivenessHeartbeat};
+pub use replication_ticks::{ReplicationTickMapping, ReplicationTicks, ReplicationAlignment, replication_aligned};
+pub use rematch::{EnterPostGame, RequestRematch, RematchStatus, RematchAccepted, RestartSimulation, SimulationRestarted};
+pub use tick_clock::{TickClock, TickClockWaker, LockstepServerTick};
+pub use spectate::{Surrender, MakeSpectator, PlayerBecameSpectator, LockstepSpectators};
+pub use child_ids::{ChildSimulationId, ChildSimulationIdAllocator, ChildSimulationIdEntityMap};
+pub use rng::{LockstepRng, LockstepRngStream, TickSalt};
+pub use despawn_guard::{UnscheduledDespawnPolicy, UnscheduledDespawn};
+pub(crate) use despawn_guard::{InLockstepSchedule, despawn_simulated};
+pub(crate) use reset::apply_simulation_reset;
+pub(crate) use pacing::rtt_to_ticks;
+pub(crate) use votes::{PendingSettingsChanges, SettingsTimeline};
+
+pub use crate::lockstep_core::SimTick;
+
+/// Tick production, simulation state and simulation ids
+#[derive(Default)]
+pub struct LockstepSimulationPlugin {
+    pub settings: SimulationSettings,
+}
 
 impl Plugin for LockstepSimulationPlugin {
     fn build(&self, app: &mut App) {
+        let (controller, requests) = control::session_control_resources();
         app
+            .insert_resource(self.settings.clone())
+            .insert_resource(controller)
+            .insert_resource(requests)
+            .add_systems(PreUpdate, control::apply_session_requests)
+            .insert_resource(Time::<Fixed>::from_duration(self.settings.tick_timestep))
             .insert_state(SimulationState::None)
             .add_event::<SimulationTickUpdate>()
-            .add_systems(OnEnter(SimulationState::Setup), setup_simulation)
-            .add_systems(OnEnter(SimulationState::Starting), start_simulation)
-            .add_systems(Update, cache_ids)
+            .add_event::<TickAdvanced>()
+            .add_event::<TickBlockedWaitingOn>()
+            .add_event::<DisconnectSuspected>()
+            .init_resource::<DroppedFromGating>()
+            .add_server_trigger::<ResumeAtTick>(Channel::Ordered)
+            .add_observer(restore_gating)
+            .init_resource::<SessionIdentity>()
+            .init_resource::<SessionHash>()
+            .init_resource::<SimulationTick>()
+            .init_resource::<LastBroadcastTick>()
+            .add_systems(OnEnter(SimulationState::Setup), (setup_simulation, session::compute_session_hash))
+            .add_systems(Update, session::compute_session_hash
+                .run_if(in_state(SimulationState::Setup).and(resource_changed::<CommandVersions>)))
+            .add_systems(OnEnter(SimulationState::Starting), (rng::broadcast_rng_seed.run_if(server_running), start_simulation)
+                .chain())
+            .init_resource::<LockstepRng>()
+            .init_resource::<TickSalt>()
+            .add_server_trigger::<rng::LockstepRngSeed>(Channel::Ordered)
+            .add_observer(rng::receive_rng_seed)
+            .add_observer(rng::send_rng_seed_for_catch_up)
+            .add_systems(Update, (cache_ids, child_ids::cache_child_ids))
             .init_resource::<SimulationIdEntityMap>()
+            .init_resource::<SimulationIdAllocator>()
+            .init_resource::<InLockstepSchedule>()
+            .init_resource::<ChildSimulationIdEntityMap>()
+            .init_resource::<ChildSimulationIdAllocator>()
+            .register_type::<ChildSimulationId>()
+            .register_type::<NotHashed>()
+            .init_resource::<StateHashFilter>()
+            .init_resource::<MatchCorrelation>()
+            .add_systems(First, correlation::update_match_correlation)
             .add_observer(handle_sim_state_change)
             .add_observer(tick_client)
+            .init_resource::<tick_gap::TickGap>()
+            .add_systems(Update, tick_gap::check_tick_gap
+                .run_if(not(server_running).and(in_state(SimulationState::Running).or(in_state(SimulationState::Paused)))))
             .add_server_trigger::<SetSimulationState>(Channel::Ordered)
             .add_client_trigger::<ClientReadyEvent>(Channel::Unordered)
             .register_type::<SimulationId>()
-            .add_systems(FixedPostUpdate, 
-                tick_server
-                    .run_if(server_running.and(in_state(SimulationState::Running)))
-                    .before(ServerSet::Send)
+            .register_lockstep_command::<ResetSimulation>()
+            .add_observer(reset::schedule_simulation_reset)
+            .init_resource::<ClientHeartbeats>()
+            .init_resource::<HeldCommands>()
+            .add_client_trigger::<ClientHeartbeat>(Channel::Unreliable)
+            .init_resource::<liveness::ClientLiveness>()
+            .add_client_trigger::<LivenessHeartbeat>(Channel::Ordered)
+            .add_observer(liveness::receive_liveness_heartbeat)
+            .add_observer(liveness::record_commands_liveness)
+            .add_systems(Update, liveness::send_liveness_heartbeat
+                .run_if(not(server_running).and(in_state(SimulationState::Running).or(in_state(SimulationState::Paused)))))
+            .init_resource::<ReplicationTicks>()
+            .add_server_trigger::<ReplicationTickMapping>(Channel::Ordered)
+            .add_observer(replication_ticks::record_replication_tick)
+            .add_observer(replication_ticks::receive_replication_tick_mapping)
+            .add_observer(pause::pause_simulation)
+            .add_observer(pause::resume_simulation)
+            .init_resource::<PauseStatus>()
+            .init_resource::<pause::ResumeVotes>()
+            .add_client_trigger::<RequestPause>(Channel::Ordered)
+            .add_client_trigger::<RequestResume>(Channel::Ordered)
+            .add_server_trigger::<SimulationPaused>(Channel::Ordered)
+            .add_server_trigger::<ResumeVoteStatus>(Channel::Ordered)
+            .add_observer(pause::receive_pause_request)
+            .add_observer(pause::receive_resume_request)
+            .add_observer(pause::receive_simulation_paused)
+            .add_observer(pause::receive_resume_vote_status)
+            .add_systems(OnEnter(SimulationState::Running), pause::clear_pause_status)
+            .init_resource::<PausesUsed>()
+            .init_resource::<pause::PlayerPauseClock>()
+            .add_server_trigger::<PauseDenied>(Channel::Ordered)
+            .add_server_trigger::<PauseCountdown>(Channel::Ordered)
+            .add_observer(pause::receive_pause_countdown)
+            .add_systems(Update, pause::count_down_player_pause
+                .run_if(server_running.and(in_state(SimulationState::Paused))))
+            .add_observer(pause::receive_heartbeat)
+            .add_systems(Update, pause::send_heartbeat.run_if(in_state(SimulationState::Paused)))
+            .add_systems(OnEnter(SimulationState::Running), pause::release_held_commands)
+            .init_resource::<BroadcastPacing>()
+            .add_server_trigger::<BroadcastPacing>(Channel::Unordered)
+            .add_observer(pacing::receive_broadcast_pacing)
+            .init_resource::<LockstepSpectators>()
+            .add_client_trigger::<Surrender>(Channel::Ordered)
+            .add_server_trigger::<PlayerBecameSpectator>(Channel::Ordered)
+            .add_observer(spectate::receive_surrender)
+            .add_observer(spectate::make_spectator)
+            .add_observer(spectate::receive_player_became_spectator)
+            .init_resource::<votes::ActiveSettingsVote>()
+            .init_resource::<PendingSettingsChanges>()
+            .init_resource::<SettingsTimeline>()
+            .add_client_trigger::<ProposeSettingsChange>(Channel::Ordered)
+            .add_client_trigger::<CastSettingsVote>(Channel::Ordered)
+            .add_server_trigger::<SettingsVoteStarted>(Channel::Ordered)
+            .add_server_trigger::<SettingsVoteResolved>(Channel::Ordered)
+            .add_observer(votes::receive_proposal)
+            .add_observer(votes::receive_vote)
+            .add_observer(votes::receive_vote_resolved)
+            .add_observer(votes::apply_settings_changes)
+            .add_observer(votes::receive_resync_settings)
+            .add_observer(tick_rate::request_tick_rate)
+            .add_observer(tick_clock::restart_tick_clock)
+            .init_resource::<overrun::FixedStepCount>()
+            .add_client_trigger::<FixedUpdateOverrun>(Channel::Ordered)
+            .add_observer(overrun::receive_fixed_update_overrun)
+            .add_systems(FixedFirst, overrun::count_fixed_step.run_if(in_state(SimulationState::Running)))
+            .add_systems(Update, overrun::detect_fixed_update_overrun.run_if(in_state(SimulationState::Running)))
+            .init_resource::<BroadcastBandwidth>()
+            .add_client_trigger::<DeclareBandwidthBudget>(Channel::Ordered)
+            .add_observer(bandwidth::declare_bandwidth_budget)
+            .add_observer(bandwidth::receive_bandwidth_budget)
+            .add_observer(bandwidth::measure_broadcast)
+            .add_systems(Update, bandwidth::check_bandwidth_budgets
+                .run_if(server_running.and(in_state(SimulationState::Running))))
+            .init_resource::<rematch::RematchVotes>()
+            .add_client_trigger::<RequestRematch>(Channel::Ordered)
+            .add_server_trigger::<RematchStatus>(Channel::Ordered)
+            .add_server_trigger::<RematchAccepted>(Channel::Ordered)
+            .add_observer(rematch::enter_post_game)
+            .add_observer(rematch::receive_rematch_request)
+            .add_observer(rematch::start_rematch)
+            .add_server_trigger::<SimulationRestarted>(Channel::Ordered)
+            .add_observer(rematch::restart_simulation)
+            .add_observer(rematch::receive_simulation_restart)
+            .add_systems(OnEnter(SimulationState::PostGame), rematch::clear_rematch_votes)
+            .init_resource::<TickStall>()
+            .add_systems(OnEnter(SimulationState::Running), stall::reset_tick_stall)
+            .add_systems(Update, stall::track_tick_stall.run_if(in_state(SimulationState::Running)))
+            .init_resource::<FinalTick>()
+            .add_server_trigger::<FinalTickScheduled>(Channel::Ordered)
+            .add_observer(shutdown::end_simulation)
+            .add_observer(shutdown::receive_final_tick)
+            .add_observer(shutdown::finish_on_final_tick)
+            .add_systems(LockstepServerTick, (
+                tick_server,
+                pacing::send_broadcast_pacing,
+                votes::resolve_settings_vote,
+            ).chain())
+            .add_systems(FixedPostUpdate, tick_clock::run_fixed_tick
+                .run_if(server_running.and(in_state(SimulationState::Running)).and(tick_clock::fixed_clock()))
+                .before(ServerSet::Send)
+            )
+            .add_systems(OnEnter(SimulationState::Running), tick_clock::start_tick_clock
+                .run_if(server_running.and(tick_clock::dedicated_clock())))
+            .add_systems(OnExit(SimulationState::Running), tick_clock::stop_tick_clock)
+            .add_systems(PostUpdate, tick_clock::run_dedicated_ticks
+                .run_if(server_running.and(in_state(SimulationState::Running)))
+                .before(ServerSet::Send)
             );
     }
 }
@@ -37,7 +228,8 @@ impl Plugin for LockstepSimulationPlugin {
 pub struct SimulationSettings {
     /// The duration of each tick in the simulation
     pub tick_timestep: Duration,
-    /// The expected number of players for the game 
+    /// The expected number of players for the game.  With one player the
+    /// host plays alone, see [`StartPracticeMatch`].
     pub num_players: u8,
     /// Lockstep simulations have an inherent input lag.  The simulation is
     /// always executing commands issued in the past to avoid desyncs.
@@ -59,6 +251,109 @@ pub struct SimulationSettings {
     /// before declaring a client is disconnected.  The simulation will be
     /// paused while waiting.
     pub disconnect_tick_threshold: u8,
+    /// If set, clients still missing after `disconnect_tick_threshold` no
+    /// longer gate ticks and the others carry on, see [`ResumeAtTick`].
+    /// Otherwise the simulation pauses until they resync.
+    pub drop_missing_clients: bool,
+    /// If set, the server sanitizes floating point fields of received
+    /// commands before broadcasting them, so a single client sending NaN or
+    /// infinite values cannot corrupt the simulation on every peer.
+    pub command_sanitization: Option<FloatSanitization>,
+    /// If set, the server adjusts each client's input delay on its own
+    /// schedule instead of on every message, see [`InputDelayAdjustment`]
+    pub input_delay_adjustment: Option<InputDelayAdjustment>,
+    /// The server triggers [`InputDelayJump`](crate::commands::InputDelayJump)
+    /// when a client's input delay changes by at least this many ticks.
+    pub input_delay_jump_threshold: u32,
+    /// Commands are scheduled relative to the server's tick, so a client
+    /// lagging behind or whose input delay shrank sees them execute later
+    /// than its issued tick plus its delay.  In strict mode the server moves
+    /// commands whose issued tick plus delay was already broadcast to the
+    /// next tick instead, and notifies the client with
+    /// [`CommandsRescheduled`](crate::commands::CommandsRescheduled).
+    pub strict_scheduling: bool,
+    /// If set, the server executes at most this many gameplay commands per
+    /// client on a tick.  The rest is deferred to the following ticks in
+    /// order, and the client is notified with
+    /// [`CommandsDeferred`](crate::commands::CommandsDeferred), unless
+    /// `command_limit_policy` drops them.
+    pub max_commands_per_tick: Option<usize>,
+    /// What happens to commands past `max_commands_per_tick`, e.g. to stop
+    /// a flooding client from queueing commands for ticks to come
+    pub command_limit_policy: CommandLimitPolicy,
+    /// While paused, clients send a heartbeat at this interval instead of
+    /// per-tick empty commands.
+    pub pause_heartbeat_interval: Duration,
+    /// Additional command streams with their own buffers and delays,
+    /// e.g. a low latency stream for pings and markers.
+    pub command_streams: Vec<CommandStreamSettings>,
+    /// Commands pushed to [`LockstepCommandQueue`](crate::commands::LockstepCommandQueue)
+    /// are held for up to this long and sent as a single message per stream.
+    /// Zero sends once per frame.  Pending commands are always flushed when
+    /// the simulation tick changes.
+    pub command_coalescing_window: Duration,
+    /// Tick broadcasts encoding to more than this many bytes are compressed
+    /// with lz4, with the `compression` feature on every peer, see
+    /// [`TickCompression`](crate::commands::TickCompression).  None never
+    /// compresses.
+    pub compress_ticks_above: Option<usize>,
+    /// What paces tick production on the server
+    pub tick_clock: TickClock,
+    /// How many ticks players have to vote on a proposed settings change
+    pub settings_vote_duration: SimTick,
+    /// How many players have to approve a settings change
+    pub settings_vote_rule: VoteRule,
+    /// How many players have to send [`RequestResume`] to resume a pause.
+    /// The host resumes on its own.
+    pub resume_vote_rule: VoteRule,
+    /// If set, players can only call this many pauses of limited length per
+    /// match.  The server can always pause with [`PauseSimulation`].
+    pub pause_allowance: Option<PauseAllowance>,
+    /// A frame that runs more fixed steps than this counts as overrun, as
+    /// does any frame long enough for `Time<Virtual>` to be clamped
+    pub overrun_steps_per_frame: u32,
+    /// A client reports a persistent overrun to the server after this many
+    /// consecutive overrun frames
+    pub overrun_frame_threshold: u32,
+    /// What the server does when a client reports a persistent overrun
+    pub overrun_mitigation: OverrunMitigation,
+    /// What the server does when its tick broadcast outgrows a client's
+    /// declared downstream budget
+    pub bandwidth_mitigation: BandwidthMitigation,
+    /// [`TickGapTimedOut`] triggers on a client when ticks it skipped were
+    /// not received this long after the next one.  They are requested again
+    /// at this interval until they are.
+    pub tick_gap_timeout: Duration,
+    /// If set, clients send a low rate [`LivenessHeartbeat`] instead of
+    /// empty commands every tick, and the server infers the empty commands
+    pub empty_command_suppression: Option<EmptyCommandSuppression>,
+    /// [`PresentationFreeze`] triggers when no tick arrived for this long
+    pub stall_notify_threshold: Duration,
+    /// The server broadcasts [`LockstepNetworkDiagnostics`] every this many
+    /// ticks, 0 to keep them on the server
+    pub network_diagnostics_interval: SimTick,
+    /// Clients measure the size of their lockstep messages in
+    /// [`LockstepBandwidth`]
+    pub bandwidth_diagnostics: bool,
+    /// How queued ticks are executed after falling behind, see [`TicksBehind`]
+    pub catch_up: CatchUpPolicy,
+    /// Remote clients resend command messages the server hasn't
+    /// acknowledged after this long, over an unreliable channel so a
+    /// momentary loss doesn't stall the tick until the disconnect threshold.
+    /// Never sooner than one and a half round trips.  None sends every
+    /// message once.
+    pub command_resend_interval: Option<Duration>,
+    /// Whether despawning simulated entities outside the lockstep schedule
+    /// is checked, see [`UnscheduledDespawnPolicy`]
+    pub unscheduled_despawns: UnscheduledDespawnPolicy,
+    /// Runs of at least this many [`ParallelGameCommand`]s in a tick are
+    /// applied in parallel, grouped by target.  None applies every command
+    /// in order.
+    pub parallel_apply_threshold: Option<usize>,
+    /// The server records the replicon tick each tick was applied at in
+    /// [`ReplicationTicks`] and broadcasts it, for games that also use
+    /// replication, see [`ReplicationAlignment`]
+    pub replication_tick_mapping: bool,
 }
 
 impl Default for SimulationSettings {
@@ -69,6 +364,36 @@ impl Default for SimulationSettings {
             base_input_tick_delay: 1,
             connection_check_tick_delay: 1,
             disconnect_tick_threshold: 20,
+            drop_missing_clients: false,
+            command_sanitization: None,
+            input_delay_adjustment: None,
+            input_delay_jump_threshold: 3,
+            strict_scheduling: false,
+            max_commands_per_tick: None,
+            command_limit_policy: CommandLimitPolicy::Defer,
+            pause_heartbeat_interval: Duration::from_secs(1),
+            command_streams: Vec::new(),
+            command_coalescing_window: Duration::ZERO,
+            compress_ticks_above: Some(512),
+            tick_clock: TickClock::Fixed,
+            settings_vote_duration: 300,
+            settings_vote_rule: VoteRule::Majority,
+            resume_vote_rule: VoteRule::Majority,
+            pause_allowance: None,
+            overrun_steps_per_frame: 3,
+            overrun_frame_threshold: 30,
+            overrun_mitigation: OverrunMitigation::Notify,
+            bandwidth_mitigation: BandwidthMitigation::default(),
+            tick_gap_timeout: Duration::from_secs(5),
+            empty_command_suppression: None,
+            stall_notify_threshold: Duration::from_millis(100),
+            network_diagnostics_interval: 10,
+            bandwidth_diagnostics: false,
+            catch_up: CatchUpPolicy::default(),
+            command_resend_interval: Some(Duration::from_millis(100)),
+            unscheduled_despawns: UnscheduledDespawnPolicy::Allow,
+            parallel_apply_threshold: None,
+            replication_tick_mapping: false,
         }
     }
 }
@@ -79,6 +404,9 @@ pub enum SimulationState {
     /// No simulation
     #[default]
     None,
+    /// Players gather and configure the match before it starts, see
+    /// [`LobbyPlugin`].  Only entered with that plugin.
+    Lobby,
     /// Clients are connecting to the server/host.
     Connecting,
     /// Clients have all connected.  Hook into this state to load assets, set up teams, etc.
@@ -94,6 +422,8 @@ pub enum SimulationState {
     Paused,
     /// The game has ended.  Cleanup operations go here.
     Ending,
+    /// The match is over but everyone stays connected, e.g. to vote on a rematch.
+    PostGame,
 }
 
 /// An event for the server to change the simulation state on the clients
@@ -114,12 +444,56 @@ fn setup_simulation(
     mut command_history: ResMut<LockstepGameCommandBuffer>,
     mut commands_received: ResMut<LockstepGameCommandsReceived>,
     mut id_entity_map: ResMut<SimulationIdEntityMap>,
+    mut execution_schedule: ResMut<ClientExecutionSchedule>,
+    mut held_commands: ResMut<HeldCommands>,
+    mut heartbeats: ResMut<ClientHeartbeats>,
+    mut stream_buffers: ResMut<LockstepStreamBuffers>,
+    mut child_id_map: ResMut<ChildSimulationIdEntityMap>,
+    mut child_id_allocator: ResMut<ChildSimulationIdAllocator>,
+    mut id_allocator: ResMut<SimulationIdAllocator>,
+    mut digests: ResMut<TickDigests>,
+    mut spectators: ResMut<LockstepSpectators>,
+    settings: Res<SimulationSettings>,
 ) {
     commands.insert_resource(SimulationTick(0));
+    commands.insert_resource(LastBroadcastTick::default());
+    commands.insert_resource(LockstepDiagnostics::default());
+    commands.insert_resource(LockstepNetworkDiagnostics::default());
+    commands.insert_resource(LockstepBandwidth::default());
+    commands.insert_resource(PendingCatchUp::default());
+    commands.insert_resource(catch_up::PendingResync::default());
+    commands.insert_resource(DroppedClients::default());
+    commands.insert_resource(votes::ActiveSettingsVote::default());
+    commands.insert_resource(PendingSettingsChanges::default());
+    commands.insert_resource(SettingsTimeline::new(&settings));
+    commands.insert_resource(LastAppliedTick::default());
+    commands.insert_resource(FirstAvailableTick::default());
+    commands.insert_resource(TicksBehind::default());
+    commands.insert_resource(FinalTick::default());
+    commands.insert_resource(UnackedCommands::default());
+    commands.insert_resource(ClientSequences::default());
+    commands.insert_resource(BroadcastBandwidth::default());
+    commands.insert_resource(PausesUsed::default());
+    commands.insert_resource(TickSalt::default());
+    commands.insert_resource(InputDelays::default());
+    commands.insert_resource(tick_gap::TickGap::default());
+    commands.insert_resource(PendingOrders::default());
+    commands.insert_resource(liveness::ClientLiveness::default());
+    commands.insert_resource(DroppedFromGating::default());
+    commands.insert_resource(ReplicationTicks::default());
+    digests.clear();
+    spectators.clear();
+    child_id_map.clear();
+    child_id_allocator.clear();
     command_history.clear();
     commands_received.clear();
+    execution_schedule.clear();
+    held_commands.clear();
+    heartbeats.clear();
+    stream_buffers.clear();
     id_entity_map.clear();
-    SIMULATION_ID_COUNTER.store(1, Ordering::SeqCst);
+    // Directly, so the initial entities spawned in Setup get the first ids
+    *id_allocator = SimulationIdAllocator::default();
 }
 
 fn start_simulation(
@@ -133,7 +507,7 @@ fn start_simulation(
             event: SetSimulationState(SimulationState::Running),
         });
         for client in ready.iter() {
-            commands.entity(client).remove::<ClientReady>();
+            commands.entity(client).remove::<(ClientReady, CommandRegistryVerified)>();
         }
     }
 }
@@ -149,24 +523,94 @@ pub struct SimulationTickUpdate(pub SimTick);
 #[derive(Resource, Deref, DerefMut, Default)]
 pub struct SimulationTick(SimTick);
 
-/// An atomic counter for incrementing the simulation id on each assignment
-static SIMULATION_ID_COUNTER: AtomicU32 = AtomicU32::new(1);
+/// Server-only, the last tick broadcast to clients.  Its history is final,
+/// commands can only be scheduled after it.
+#[derive(Resource, Deref, DerefMut, Default)]
+pub(crate) struct LastBroadcastTick(SimTick);
 
 /// Unique Id for each entity in the simulation 
 #[derive(Component, Deref, Serialize, Deserialize, Debug, Clone, Copy, Reflect, Eq, PartialEq, Hash)]
+#[component(on_remove = despawn_guard::check_simulation_id_removed)]
 pub struct SimulationId(u32);
 
 impl SimulationId {
     // Use this when sending commands from clients
     pub const PLACEHOLDER: SimulationId = SimulationId(0);
 
-    // Use this when implementing the commands after receiving from server
-    pub fn new() -> Self {
-        // What happens if someone manages to reach u32::MAX ?
-        Self(SIMULATION_ID_COUNTER.fetch_add(1, Ordering::Relaxed))
+    /// Rebuild an id from its raw value, e.g. when loading a save.  Returns
+    /// `None` for 0, which is reserved for [`SimulationId::PLACEHOLDER`].
+    pub fn from_raw(raw: u32) -> Option<Self> {
+        (raw != 0).then_some(Self(raw))
+    }
+}
+
+/// Allocates the [`SimulationId`]s of a world.  Use it when applying
+/// commands that spawn simulated entities, in the same order on every peer.
+/// Reset on entering Setup, so worlds in one process, e.g. a server and its
+/// clients in tests, or a replay verified next to a live match, each count
+/// on their own.
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct SimulationIdAllocator {
+    next: u32,
+}
+
+impl Default for SimulationIdAllocator {
+    fn default() -> Self {
+        Self { next: 1 }
+    }
+}
+
+impl SimulationIdAllocator {
+    pub fn allocate(&mut self) -> SimulationId {
+        let id = SimulationId(self.next);
+        self.next = self.next.checked_add(1).expect("simulation ids exhausted");
+        id
+    }
+
+    /// The value the next call to [`allocate`](Self::allocate) will use
+    pub fn next_id(&self) -> u32 {
+        self.next
+    }
+
+    /// Set the value the next call to [`allocate`](Self::allocate) will
+    /// use.  Every peer must restore the same value, or ids will diverge.
+    pub fn set_next_id(&mut self, next: u32) -> Result<(), SimulationIdError> {
+        if next == 0 {
+            return Err(SimulationIdError::Reserved);
+        }
+        self.next = next;
+        Ok(())
+    }
+
+    /// Restore the counter after loading entities with these ids, so new ids
+    /// never collide with restored ones
+    pub fn restore_next_id(&mut self, restored: impl IntoIterator<Item = SimulationId>) -> Result<(), SimulationIdError> {
+        let max = restored.into_iter().map(|id| id.0).max().unwrap_or(0);
+        let next = max.checked_add(1).ok_or(SimulationIdError::Exhausted)?;
+        self.set_next_id(next.max(1))
     }
 }
 
+/// Reasons the [`SimulationId`] counter could not be restored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimulationIdError {
+    /// 0 is reserved for [`SimulationId::PLACEHOLDER`]
+    Reserved,
+    /// A restored id is `u32::MAX`, so no new ids are left
+    Exhausted,
+}
+
+impl std::fmt::Display for SimulationIdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Reserved => write!(f, "simulation id 0 is reserved for the placeholder"),
+            Self::Exhausted => write!(f, "no simulation ids left after the restored ids"),
+        }
+    }
+}
+
+impl std::error::Error for SimulationIdError {}
+
 /// Resource to map SimulationIds to Entities for quick look-up of entities
 #[derive(Resource, Deref, DerefMut, Default)]
 pub struct SimulationIdEntityMap(HashMap<SimulationId, Entity>);
@@ -187,31 +631,149 @@ fn tick_client(
     mut command_history: ResMut<LockstepGameCommandBuffer>,
     mut sim_tick_event: EventWriter<SimulationTickUpdate>,
     server: Res<RepliconServer>,
+    session: Res<SessionHash>,
+    mut commands: Commands,
+    mut next_state: ResMut<NextState<SimulationState>>,
+    mut stream_buffers: ResMut<LockstepStreamBuffers>,
+    mut digests: ResMut<TickDigests>,
+    registry: Res<AppTypeRegistry>,
+    mut pending_catch_up: ResMut<PendingCatchUp>,
+    state: Res<State<SimulationState>>,
+    joining: Option<Res<JoiningInProgress>>,
+    mut gap: ResMut<tick_gap::TickGap>,
+    time: Res<Time<Real>>,
 ) {
+    let _span = MatchCorrelation::new(tick.session, tick.tick).span().entered();
+    // A client joining mid-match fetches the history once it is set up
+    if joining.is_some() || matches!(state.get(),
+        SimulationState::None | SimulationState::Lobby | SimulationState::Connecting) { return }
+    if tick.session != *session {
+        error!("Received tick {} from a different session, expected {:08x} got {:08x}",
+            tick.tick, **session, *tick.session);
+        commands.trigger(SessionMismatch { expected: *session, received: tick.session });
+        next_state.set(SimulationState::None);
+        return;
+    }
+    let digest = hash_tick_commands(&tick.commands, &registry.read());
+    if digest != tick.digest {
+        warn!("Tick {} digest mismatch, expected {:016x} got {:016x}", tick.tick, tick.digest, digest);
+        commands.trigger(TickDigestMismatch { tick: tick.tick, expected: tick.digest, computed: digest });
+    } else {
+        *digests.get_or_default(tick.tick) = Some(digest);
+    }
     if !server.is_running() {
-        command_history.resize(tick.tick + 1, tick.commands.clone());
+        if sim_tick.0 != 0 && tick.tick <= sim_tick.0 {
+            trace!("Ignoring tick {} received again", tick.tick);
+            return;
+        }
+        // Reconnecting clients need every missed command to fast-forward,
+        // as does a running client that missed a tick
+        let full = *state.get() == SimulationState::Reconnecting || sim_tick.0 != 0;
+        catch_up::request_missing_history(&mut commands, &mut pending_catch_up, command_history.len(), tick.tick, full);
+        if command_history.len() <= tick.tick as usize {
+            command_history.resize(tick.tick + 1, LockstepClientCommands::default());
+        }
+        command_history[tick.tick as usize] = tick.commands.clone();
+        for (&stream, commands) in tick.streams.iter() {
+            for (&client_id, client_commands) in commands.iter() {
+                stream_buffers.insert(stream, tick.tick, client_id,
+                    client_commands.iter().map(|x| x.clone_value()).collect());
+            }
+        }
         trace!("Received tick {}", tick.tick);
         if tick.tick == sim_tick.0 + 1 || sim_tick.0 == 0 {
             sim_tick.0 = tick.tick;
         } else {
-            panic!("Received ticks out of order");
+            // Ticks after a gap are held, only ticks up to the first missing
+            // one are applied until the requested history fills it
+            catch_up::request_gap(&mut commands, &mut pending_catch_up, sim_tick.0 + 1, tick.tick - 1);
+            if matches!(state.get(), SimulationState::Running | SimulationState::Paused) {
+                gap.open(sim_tick.0 + 1, time.elapsed());
+            }
+            sim_tick.0 = tick.tick;
         }
     }
     sim_tick_event.send(SimulationTickUpdate(tick.tick));
 }
 
+/// Server event sent when the simulation advanced to a new tick
+#[derive(Event, Debug, Clone, Copy)]
+pub struct TickAdvanced {
+    pub tick: SimTick,
+    /// The past tick whose received commands allowed the advance
+    pub checked_tick: SimTick,
+}
+
+/// Server event sent each time the simulation could not advance because
+/// commands from some clients are missing
+#[derive(Event, Debug, Clone)]
+pub struct TickBlockedWaitingOn {
+    pub tick: SimTick,
+    pub checked_tick: SimTick,
+    pub clients: Vec<ClientId>,
+}
+
+/// Server event sent when a client has been missing commands for longer than
+/// `SimulationSettings::disconnect_tick_threshold`
+#[derive(Event, Debug, Clone, Copy)]
+pub struct DisconnectSuspected(pub ClientId);
+
+/// Broadcast by the server when it dropped missing clients from the tick
+/// gating, see `SimulationSettings::drop_missing_clients`.  The remaining
+/// players resume with `tick`, the first tick not waiting on `dropped`.
+#[derive(Event, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ResumeAtTick {
+    pub tick: SimTick,
+    pub dropped: Vec<ClientId>,
+}
+
+/// Server-only set of players that were dropped from the tick gating.  They
+/// gate ticks again once they resynced.
+#[derive(Resource, Default, Deref, DerefMut)]
+struct DroppedFromGating(HashSet<ClientId>);
+
+fn restore_gating(trigger: Trigger<ClientResynced>, mut dropped: ResMut<DroppedFromGating>) {
+    if dropped.remove(&trigger.client) {
+        info!("client {} gates ticks again", trigger.client);
+    }
+}
+
+/// How far the server broadcast ticks
+#[derive(SystemParam)]
+struct BroadcastProgress<'w> {
+    final_tick: Res<'w, FinalTick>,
+    last_broadcast: ResMut<'w, LastBroadcastTick>,
+}
+
+#[derive(SystemParam)]
+struct TickGatingEvents<'w> {
+    advanced: EventWriter<'w, TickAdvanced>,
+    blocked: EventWriter<'w, TickBlockedWaitingOn>,
+    suspected: EventWriter<'w, DisconnectSuspected>,
+}
+
 /// Handles incrementing the simulation tick on the server
 fn tick_server(
     mut disconnect_timer: Local<u8>,
-    mut next_state: ResMut<NextState<SimulationState>>,
     mut sim_tick: ResMut<SimulationTick>,
     mut commands: Commands,
-    clients: Query<&NetworkId>,
+    clients: Query<(&NetworkId, Option<&ClientRole>)>,
     stats: Query<&NetworkStats>,
-    commands_received: Res<LockstepGameCommandsReceived>,
+    mut commands_received: ResMut<LockstepGameCommandsReceived>,
     mut command_history: ResMut<LockstepGameCommandBuffer>,
     settings: Res<SimulationSettings>,
+    session: Res<SessionHash>,
+    stream_buffers: Res<LockstepStreamBuffers>,
+    mut gating_events: TickGatingEvents,
+    registry: Res<AppTypeRegistry>,
+    spectators: Res<LockstepSpectators>,
+    mut progress: BroadcastProgress,
+    liveness: liveness::LivenessInference,
+    mut dropped: ResMut<DroppedFromGating>,
 ) {
+    let _span = MatchCorrelation::new(*session, sim_tick.0).span().entered();
+    // The match is ending and every tick up to the final one was broadcast
+    if progress.final_tick.is_some_and(|final_tick| sim_tick.0 >= final_tick) { return }
     let mut tick_delay = 0u32;
     if stats.iter().len() > 0 {  // True if clients connected
         // Before ticking the sim for connected clients, we need to check received
@@ -221,49 +783,86 @@ fn tick_server(
         // Essentially, we are letting the server's sim run a few ticks ahead of clients
         // so that clients are sufficiently behind the server's time once they start
         // replicating each other's commands.
-        tick_delay = (stats
-                .iter()
-                .max_by(|a: &&NetworkStats, b: &&NetworkStats| a.rtt.partial_cmp(&b.rtt).unwrap())
-                .unwrap()
-                .rtt / 2.0).ceil() as u32 + settings.connection_check_tick_delay;
+        let max_rtt = stats
+            .iter()
+            .map(|stats| stats.rtt)
+            .fold(0.0, f64::max);
+        tick_delay = schedule::input_delay(rtt_to_ticks(max_rtt, &settings), settings.connection_check_tick_delay);
     }
-    let mut tick_to_check = sim_tick.0;
-    if tick_delay > tick_to_check {
-        tick_to_check = 0
-    } else {
-        tick_to_check -= tick_delay
+    let tick_to_check = schedule::tick_to_check(sim_tick.0, tick_delay);
+    // Spectators don't gate ticks
+    let players: Vec<ClientId> = clients
+        .iter()
+        .filter(|(_, role)| *role != Some(&ClientRole::Spectator))
+        .map(|(id, _)| id.get())
+        .filter(|&id| !spectators.is_spectating(id, tick_to_check))
+        .filter(|id| !dropped.contains(id))
+        .collect();
+    // Silent clients that are still live sent nothing because they had nothing to send
+    if let Some(suppression) = &settings.empty_command_suppression {
+        liveness.fill(&mut commands_received, &players, tick_to_check, suppression);
     }
 
-    if let Some(clients_for_tick) = commands_received.get(tick_to_check) {
-        if clients_for_tick.iter().len() == clients.iter().len() {
-            sim_tick.0 += 1;
-            trace!("ticked to {}", sim_tick.0);
+    let Some(clients_for_tick) = commands_received.get(tick_to_check) else {
+        gating_events.blocked.send(TickBlockedWaitingOn {
+            tick: sim_tick.0,
+            checked_tick: tick_to_check,
+            clients: players,
+        });
+        return;
+    };
+    if players.iter().all(|id| clients_for_tick.contains_key(id)) {
+        sim_tick.0 += 1;
+        trace!("ticked to {}", sim_tick.0);
+        gating_events.advanced.send(TickAdvanced { tick: sim_tick.0, checked_tick: tick_to_check });
+        *disconnect_timer = 0;
+        let tick_commands = command_history.get(sim_tick.0);
+        let tick_commands = tick_commands.cloned().unwrap_or_else(|| {
+            let default = LockstepClientCommands::default();
+            if command_history.len() <= sim_tick.0 as usize {
+                command_history.resize(sim_tick.0, default.clone());
+            }
+            default
+        });
+        commands.server_trigger(ToClients{
+            mode: SendMode::Broadcast,
+            event: ServerSendCommands {
+                session: *session,
+                tick: sim_tick.0,
+                digest: hash_tick_commands(&tick_commands, &registry.read()),
+                streams: stream_buffers.for_tick(sim_tick.0),
+                commands: tick_commands,
+            }
+        });
+        **progress.last_broadcast = sim_tick.0;
+    } else {
+        trace!("tick not ready");
+        let missing: Vec<ClientId> = players
+            .into_iter()
+            .filter(|id| !clients_for_tick.contains_key(id))
+            .collect();
+        gating_events.blocked.send(TickBlockedWaitingOn {
+            tick: sim_tick.0,
+            checked_tick: tick_to_check,
+            clients: missing.clone(),
+        });
+        *disconnect_timer += 1;
+        if *disconnect_timer > settings.disconnect_tick_threshold {
             *disconnect_timer = 0;
-            let tick_commands = command_history.get(sim_tick.0);
-            commands.server_trigger(ToClients{
-                mode: SendMode::Broadcast,
-                event: ServerSendCommands {
-                    tick: sim_tick.0,
-                    commands: tick_commands.cloned().unwrap_or_else(|| {
-                        let default = LockstepClientCommands::default();
-                        if command_history.len() <= sim_tick.0 as usize {
-                            command_history.resize(sim_tick.0, default.clone());
-                        }
-                        default
-                    }),
-                }
-            });
-        } else {
-            trace!("tick not ready");
-            *disconnect_timer += 1;
-            if *disconnect_timer > settings.disconnect_tick_threshold {
-                *disconnect_timer = 0;
+            for &client in missing.iter() {
+                gating_events.suspected.send(DisconnectSuspected(client));
+                commands.trigger(ClientDisconnect(client));
+            }
+            if settings.drop_missing_clients {
+                info!("Dropping clients {:?} missing commands from the tick gating.", missing);
+                dropped.extend(missing.iter().copied());
+                commands.server_trigger(ToClients {
+                    mode: SendMode::Broadcast,
+                    event: ResumeAtTick { tick: sim_tick.0 + 1, dropped: missing },
+                });
+            } else {
                 info!("Simulation paused due to missing client commands.");
-                next_state.set(SimulationState::Paused);
-                clients_for_tick
-                            .iter()
-                            .filter(|(c, _)| !clients_for_tick.contains_key(c))
-                            .for_each(|(&c, _)| commands.trigger(ClientDisconnect(c)));
+                pause::broadcast_pause(&mut commands, PauseReason::ConnectionLoss, sim_tick.0);
             }
         }
     }
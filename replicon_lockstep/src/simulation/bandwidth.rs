@@ -0,0 +1,159 @@
+use std::time::Duration;
+use bevy::prelude::*;
+use bevy_replicon::{prelude::*, shared::backend::connected_client::NetworkId};
+use serde::{Deserialize, Serialize};
+use crate::{prelude::*, commands::serialization};
+use super::{ServerSendCommands, votes::{self, ActiveSettingsVote}};
+
+/// Ticks measured before the projected broadcast is compared to budgets
+const MIN_MEASURED_TICKS: u32 = 30;
+
+/// Weight of the latest tick in the average broadcast size
+const SIZE_SMOOTHING: f64 = 0.05;
+
+/// What the server does when its projected tick broadcast exceeds the
+/// smallest downstream budget a client declared, see
+/// `ConnectionSettings::max_downstream_bandwidth`.  Applied once per match,
+/// after [`BandwidthConstrained`] triggers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BandwidthMitigation {
+    /// Raise `SimulationSettings::command_coalescing_window` on every peer
+    /// to at least this, so commands issued close together share a tick
+    pub batch_window: Option<Duration>,
+    /// Start a settings vote on a tick timestep multiplied by this factor,
+    /// with the constrained client's approval already counted
+    pub propose_lower_tick_rate: Option<f32>,
+}
+
+impl Default for BandwidthMitigation {
+    fn default() -> Self {
+        Self {
+            batch_window: Some(Duration::from_millis(100)),
+            propose_lower_tick_rate: None,
+        }
+    }
+}
+
+/// Sent by a client once it knows its id, when it has a downstream budget
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct DeclareBandwidthBudget {
+    pub bytes_per_second: u32,
+}
+
+/// The downstream budget a client declared, on its client entity on the
+/// server
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Deref)]
+pub struct BandwidthBudget(pub u32);
+
+/// Server-only size of the tick broadcast, averaged over recent ticks.
+/// Only measured while some client declared a budget.
+#[derive(Resource, Default, Debug, Clone)]
+pub struct BroadcastBandwidth {
+    /// Average serialized size of one tick broadcast
+    pub bytes_per_tick: f64,
+    /// Ticks measured this match
+    pub ticks: u32,
+    /// The constraint was reported and mitigated this match
+    mitigated: bool,
+}
+
+impl BroadcastBandwidth {
+    /// Projected broadcast bytes per second at the given tick timestep
+    pub fn projected(&self, tick_timestep: Duration) -> u32 {
+        let ticks_per_second = 1.0 / tick_timestep.as_secs_f64().max(f64::EPSILON);
+        (self.bytes_per_tick * ticks_per_second).round().min(u32::MAX as f64) as u32
+    }
+}
+
+/// A trigger that fires on the server when the projected tick broadcast
+/// exceeds the smallest declared budget, with the mitigations that were
+/// applied, so hosts know why settings changed
+#[derive(Event, Debug, Clone, Copy)]
+pub struct BandwidthConstrained {
+    /// The client with the smallest budget
+    pub client: ClientId,
+    /// Its budget in bytes per second
+    pub budget: u32,
+    /// The projected broadcast in bytes per second
+    pub projected: u32,
+    /// The coalescing window every peer switches to, if batching applied
+    pub batch_window: Option<Duration>,
+    /// The tick timestep put to a vote, if a vote could be started
+    pub proposed_tick_timestep: Option<Duration>,
+}
+
+pub(super) fn declare_bandwidth_budget(
+    _trigger: Trigger<OnAdd, LocalClient>,
+    mut commands: Commands,
+    server: Res<RepliconServer>,
+    settings: Res<ConnectionSettings>,
+) {
+    if server.is_running() { return }
+    let Some(bytes_per_second) = settings.max_downstream_bandwidth else { return };
+    debug!("declaring a downstream budget of {} bytes per second", bytes_per_second);
+    commands.client_trigger(DeclareBandwidthBudget { bytes_per_second });
+}
+
+pub(super) fn receive_bandwidth_budget(
+    trigger: Trigger<FromClient<DeclareBandwidthBudget>>,
+    mut commands: Commands,
+    clients: LockstepClients,
+) {
+    let Some(entity) = clients.entity(trigger.client_entity) else { return };
+    let Some(client) = clients.client_id(trigger.client_entity) else { return };
+    info!("client {} declared a downstream budget of {} bytes per second", client, trigger.event.bytes_per_second);
+    commands.entity(entity).insert(BandwidthBudget(trigger.event.bytes_per_second));
+}
+
+/// Measures the broadcast the server also delivers to itself
+pub(super) fn measure_broadcast(
+    tick: Trigger<ServerSendCommands>,
+    server: Res<RepliconServer>,
+    budgets: Query<(), With<BandwidthBudget>>,
+    registry: Res<AppTypeRegistry>,
+    mut bandwidth: ResMut<BroadcastBandwidth>,
+    mut message: Local<Vec<u8>>,
+) {
+    if !server.is_running() || budgets.is_empty() { return }
+    message.clear();
+    if serialization::write_server_send_commands(tick.event(), &registry.read(), &mut message).is_err() { return }
+    let size = message.len() as f64;
+    bandwidth.bytes_per_tick = if bandwidth.ticks == 0 {
+        size
+    } else {
+        bandwidth.bytes_per_tick + (size - bandwidth.bytes_per_tick) * SIZE_SMOOTHING
+    };
+    bandwidth.ticks += 1;
+}
+
+pub(super) fn check_bandwidth_budgets(
+    mut commands: Commands,
+    budgets: Query<(&NetworkId, &BandwidthBudget)>,
+    mut bandwidth: ResMut<BroadcastBandwidth>,
+    sim_tick: Res<SimulationTick>,
+    settings: Res<SimulationSettings>,
+    mut active: ResMut<ActiveSettingsVote>,
+) {
+    if bandwidth.mitigated || bandwidth.ticks < MIN_MEASURED_TICKS { return }
+    let Some((id, budget)) = budgets.iter().min_by_key(|(_, budget)| budget.0) else { return };
+    let projected = bandwidth.projected(settings.tick_timestep);
+    if projected <= budget.0 { return }
+    bandwidth.mitigated = true;
+    let client = id.get();
+    warn!("projected broadcast of {} bytes per second exceeds the {} bytes per second client {} declared",
+        projected, budget.0, client);
+
+    let mitigation = settings.bandwidth_mitigation;
+    let batch_window = mitigation.batch_window
+        .filter(|&window| window > settings.command_coalescing_window);
+    if let Some(window) = batch_window {
+        votes::broadcast_server_change(&mut commands, **sim_tick, &settings, SettingsChange::CommandCoalescingWindow(window));
+    }
+    let proposed_tick_timestep = mitigation.propose_lower_tick_rate.and_then(|factor| {
+        let timestep = settings.tick_timestep.mul_f32(factor.max(1.0));
+        let deadline = **sim_tick + settings.settings_vote_duration;
+        let change = SettingsChange::TickTimestep(timestep);
+        votes::start_vote(&mut commands, &mut active, client, change, deadline).then_some(timestep)
+    });
+    commands.trigger(BandwidthConstrained { client, budget: budget.0, projected, batch_window, proposed_tick_timestep });
+}
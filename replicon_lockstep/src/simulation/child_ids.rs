@@ -0,0 +1,74 @@
+use bevy::{prelude::*, utils::hashbrown::HashMap};
+use serde::{Deserialize, Serialize};
+use crate::prelude::*;
+
+/// Id for an entity spawned by a command handler as part of another simulated
+/// entity, e.g. a projectile or turret.  Allocated deterministically from the
+/// parent's [`SimulationId`], so no command is needed to keep it in sync.
+#[derive(Component, Serialize, Deserialize, Debug, Clone, Copy, Reflect, Eq, PartialEq, Hash)]
+pub struct ChildSimulationId {
+    pub parent: SimulationId,
+    /// Index of this child among all children allocated for the parent
+    pub index: u32,
+}
+
+/// Allocates [`ChildSimulationId`]s.  As long as command handlers allocate
+/// children in the same order on every peer, every peer gets the same ids.
+#[derive(Resource, Default, Debug, Clone)]
+pub struct ChildSimulationIdAllocator(HashMap<SimulationId, u32>);
+
+impl ChildSimulationIdAllocator {
+    /// Allocate the next child id for this parent
+    pub fn allocate(&mut self, parent: SimulationId) -> ChildSimulationId {
+        let next = self.0.entry(parent).or_insert(0);
+        let id = ChildSimulationId { parent, index: *next };
+        *next += 1;
+        id
+    }
+
+    /// The number of children allocated so far for this parent
+    pub fn allocated(&self, parent: SimulationId) -> u32 {
+        self.0.get(&parent).copied().unwrap_or(0)
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    /// Children allocated per parent, sorted by parent
+    pub(crate) fn allocations(&self) -> Vec<(SimulationId, u32)> {
+        let mut allocations: Vec<_> = self.0.iter().map(|(&parent, &next)| (parent, next)).collect();
+        allocations.sort_unstable_by_key(|(parent, _)| **parent);
+        allocations
+    }
+
+    pub(crate) fn restore(&mut self, allocations: impl IntoIterator<Item = (SimulationId, u32)>) {
+        self.0 = allocations.into_iter().collect();
+    }
+}
+
+/// Resource to map ChildSimulationIds to Entities for quick look-up of entities
+#[derive(Resource, Deref, DerefMut, Default)]
+pub struct ChildSimulationIdEntityMap(HashMap<ChildSimulationId, Entity>);
+
+impl ChildSimulationIdEntityMap {
+    /// All known children of a parent, in allocation order
+    pub fn children_of(&self, parent: SimulationId) -> Vec<(ChildSimulationId, Entity)> {
+        let mut children: Vec<_> = self.0
+            .iter()
+            .filter(|(id, _)| id.parent == parent)
+            .map(|(id, entity)| (*id, *entity))
+            .collect();
+        children.sort_by_key(|(id, _)| id.index);
+        children
+    }
+}
+
+pub(super) fn cache_child_ids(
+    new_ids: Query<(Entity, &ChildSimulationId), Added<ChildSimulationId>>,
+    mut id_map: ResMut<ChildSimulationIdEntityMap>,
+) {
+    new_ids.iter().for_each(|(entity, &id)| {
+        id_map.insert(id, entity);
+    })
+}
@@ -0,0 +1,155 @@
+use std::sync::{mpsc, Arc, Mutex};
+use bevy::prelude::*;
+use bevy_replicon::prelude::*;
+use crate::prelude::*;
+use crate::connections::opening_state;
+use super::SetSimulationState;
+
+/// A request sent through a [`SessionController`]
+#[derive(Debug, Clone)]
+pub enum SessionRequest {
+    /// Replace the simulation settings for the next match
+    Create(SimulationSettings),
+    /// Start accepting players, i.e. enter `Connecting`, or `Lobby` with
+    /// the [`LobbyPlugin`]
+    Open,
+    /// Start the match with the players connected so far
+    Begin(EmptySeatPolicy),
+    /// End the match with an ordered shutdown, see [`EndSimulation`]
+    End,
+    /// Drop the session on every peer and return to `None`
+    Destroy,
+}
+
+/// A thread safe handle for driving match lifecycles from outside of ECS,
+/// e.g. a matchmaking backend embedding the server in a service.  Clone it
+/// out of the resource and keep it wherever the backend needs it.
+/// Requests are applied at the start of the next frame and checked
+/// against the state at that point, so a request that depends on an
+/// earlier one taking effect should wait for [`SessionController::state`].
+/// Transports are not touched, the embedding service still starts the
+/// server and disconnects clients.
+#[derive(Resource, Clone)]
+pub struct SessionController {
+    requests: mpsc::Sender<SessionRequest>,
+    state: Arc<Mutex<SimulationState>>,
+}
+
+impl SessionController {
+    /// Use these settings for the next match.  Only valid before the
+    /// session is opened.
+    pub fn create_session(&self, settings: SimulationSettings) {
+        self.send(SessionRequest::Create(settings));
+    }
+
+    pub fn open_for_connections(&self) {
+        self.send(SessionRequest::Open);
+    }
+
+    /// Start the match, removing seats nobody connected to
+    pub fn begin(&self) {
+        self.send(SessionRequest::Begin(EmptySeatPolicy::Remove));
+    }
+
+    /// Start the match, with the given treatment of empty seats
+    pub fn begin_with(&self, policy: EmptySeatPolicy) {
+        self.send(SessionRequest::Begin(policy));
+    }
+
+    pub fn end(&self) {
+        self.send(SessionRequest::End);
+    }
+
+    pub fn destroy(&self) {
+        self.send(SessionRequest::Destroy);
+    }
+
+    /// The simulation state as of the last frame
+    pub fn state(&self) -> SimulationState {
+        *self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn send(&self, request: SessionRequest) {
+        // The receiver lives as long as the app
+        let _ = self.requests.send(request);
+    }
+}
+
+/// A trigger that fires on the server when a controller request doesn't
+/// apply in the current state
+#[derive(Event, Debug, Clone)]
+pub struct SessionRequestRejected {
+    pub request: SessionRequest,
+    pub state: SimulationState,
+}
+
+/// Receiving end of the [`SessionController`] channel
+#[derive(Resource)]
+pub(super) struct SessionRequests(Mutex<mpsc::Receiver<SessionRequest>>);
+
+pub(super) fn session_control_resources() -> (SessionController, SessionRequests) {
+    let (sender, receiver) = mpsc::channel();
+    let controller = SessionController {
+        requests: sender,
+        state: Arc::new(Mutex::new(SimulationState::None)),
+    };
+    (controller, SessionRequests(Mutex::new(receiver)))
+}
+
+pub(super) fn apply_session_requests(
+    mut commands: Commands,
+    requests: Res<SessionRequests>,
+    controller: Res<SessionController>,
+    server: Res<RepliconServer>,
+    state: Res<State<SimulationState>>,
+    mut next_state: ResMut<NextState<SimulationState>>,
+    mut fixed_time: ResMut<Time<Fixed>>,
+    lobby: Option<Res<LobbySettings>>,
+) {
+    let state = *state.get();
+    *controller.state.lock().unwrap_or_else(|e| e.into_inner()) = state;
+    let Ok(requests) = requests.0.lock() else { return };
+    for request in requests.try_iter() {
+        let accepted = match (&request, state) {
+            (SessionRequest::Create(settings), SimulationState::None) => {
+                fixed_time.set_timestep(settings.tick_timestep);
+                commands.insert_resource(settings.clone());
+                true
+            }
+            (SessionRequest::Open, SimulationState::None) => {
+                next_state.set(opening_state(lobby.as_deref()));
+                true
+            }
+            (SessionRequest::Begin(policy), SimulationState::Connecting) if server.is_running() => {
+                commands.trigger(StartMatchEarly(*policy));
+                true
+            }
+            (SessionRequest::Begin(_), SimulationState::Lobby) if server.is_running() => {
+                commands.trigger(StartLobbyMatch);
+                true
+            }
+            (SessionRequest::End, SimulationState::Running | SimulationState::Paused) if server.is_running() => {
+                commands.trigger(EndSimulation);
+                true
+            }
+            (SessionRequest::Destroy, _) => {
+                if server.is_running() {
+                    commands.server_trigger(ToClients {
+                        mode: SendMode::Broadcast,
+                        event: SetSimulationState(SimulationState::None),
+                    });
+                } else {
+                    next_state.set(SimulationState::None);
+                }
+                true
+            }
+            _ => false,
+        };
+        if accepted {
+            info!("Session request {:?} in state {:?}", request, state);
+        } else {
+            warn!("Rejected session request {:?} in state {:?}", request, state);
+            commands.trigger(SessionRequestRejected { request, state });
+        }
+    }
+}
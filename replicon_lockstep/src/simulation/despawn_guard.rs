@@ -0,0 +1,74 @@
+use bevy::{ecs::{component::ComponentId, world::DeferredWorld}, prelude::*};
+use crate::prelude::*;
+
+/// What happens when a [`SimulationId`] entity is despawned, or loses its
+/// id, outside the lockstep schedule.  Despawns from game commands, their
+/// observers and the physics step schedules are in the lockstep schedule
+/// and always allowed.  Local logic elsewhere, e.g. an `Update` system
+/// despawning units at zero health, runs at a frame rate dependent time and
+/// can make the peers' entity maps diverge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnscheduledDespawnPolicy {
+    /// No checks
+    #[default]
+    Allow,
+    /// Log a warning and trigger [`UnscheduledDespawn`]
+    Report,
+    /// Panic, for catching unsafe despawn paths during development
+    Deny,
+}
+
+/// A trigger that fires when a [`SimulationId`] entity is despawned outside
+/// the lockstep schedule with [`UnscheduledDespawnPolicy::Report`]
+#[derive(Event, Debug, Clone, Copy)]
+pub struct UnscheduledDespawn {
+    pub id: SimulationId,
+    pub entity: Entity,
+    /// The last applied tick when it happened
+    pub tick: SimTick,
+}
+
+/// True while the game commands of a tick are being applied
+#[derive(Resource, Default)]
+pub(crate) struct InLockstepSchedule(pub(crate) bool);
+
+/// Despawns simulated entities outside the game commands at a point every
+/// peer reaches alike, e.g. a restart, without tripping the
+/// [`UnscheduledDespawnPolicy`]
+pub(crate) fn despawn_simulated(commands: &mut Commands, entities: Vec<Entity>) {
+    commands.queue(move |world: &mut World| {
+        let in_schedule = world.resource::<InLockstepSchedule>().0;
+        world.resource_mut::<InLockstepSchedule>().0 = true;
+        for entity in entities {
+            if let Ok(entity) = world.get_entity_mut(entity) {
+                entity.despawn_recursive();
+            }
+        }
+        world.resource_mut::<InLockstepSchedule>().0 = in_schedule;
+    });
+}
+
+/// Only checked while the simulation runs, setup and teardown despawn
+/// simulated entities on their own schedule
+pub(super) fn check_simulation_id_removed(mut world: DeferredWorld, entity: Entity, _: ComponentId) {
+    let Some(settings) = world.get_resource::<SimulationSettings>() else { return };
+    let policy = settings.unscheduled_despawns;
+    if policy == UnscheduledDespawnPolicy::Allow { return }
+    if world.get_resource::<InLockstepSchedule>().is_some_and(|scope| scope.0) { return }
+    let running = world.get_resource::<State<SimulationState>>()
+        .is_some_and(|state| matches!(state.get(), SimulationState::Running | SimulationState::Paused));
+    if !running { return }
+    let Some(&id) = world.get::<SimulationId>(entity) else { return };
+    let tick = world.get_resource::<LastAppliedTick>().map_or(0, |tick| **tick);
+
+    match policy {
+        UnscheduledDespawnPolicy::Allow => {}
+        UnscheduledDespawnPolicy::Report => {
+            warn!("simulation entity {:?} ({}) despawned outside the lockstep schedule after tick {}", id, entity, tick);
+            world.commands().trigger(UnscheduledDespawn { id, entity, tick });
+        }
+        UnscheduledDespawnPolicy::Deny => {
+            panic!("simulation entity {:?} ({}) despawned outside the lockstep schedule after tick {}", id, entity, tick);
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::time::Duration;
+use bevy::{ecs::system::SystemParam, prelude::*, utils::hashbrown::HashMap};
+use bevy_replicon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::{prelude::*, commands::LockstepGameCommandsReceived};
+
+/// Clients stop sending empty commands every tick.  Instead they send a
+/// [`LivenessHeartbeat`] at a fixed interval, and the server fills the
+/// empty command slots of clients it heard from recently.  Commands that
+/// arrive for a filled slot still replace it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EmptyCommandSuppression {
+    /// How often clients send a [`LivenessHeartbeat`]
+    pub heartbeat_interval: Duration,
+    /// Clients not heard from for this long no longer have empty commands
+    /// inferred, so ticks wait on them like on any silent client
+    pub liveness_timeout: Duration,
+    /// How many ticks past the last tick a client confirmed the server
+    /// infers empty commands for
+    pub max_unconfirmed_ticks: SimTick,
+}
+
+impl Default for EmptyCommandSuppression {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: Duration::from_millis(100),
+            liveness_timeout: Duration::from_millis(500),
+            max_unconfirmed_ticks: 10,
+        }
+    }
+}
+
+/// Sent by clients in place of per-tick empty commands when
+/// `SimulationSettings::empty_command_suppression` is set.  It travels on
+/// the ordered channel like the commands, so every command issued up to
+/// `tick` arrived before it.
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct LivenessHeartbeat {
+    /// The last tick the client received
+    pub tick: SimTick,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Liveness {
+    /// Elapsed real time the client was last heard from
+    last_heard: Duration,
+    /// The client sent every command it issued up to this tick
+    confirmed_tick: SimTick,
+}
+
+/// Server-only liveness of each client, from its commands and heartbeats
+#[derive(Resource, Default, Deref, DerefMut)]
+pub(crate) struct ClientLiveness(HashMap<ClientId, Liveness>);
+
+impl ClientLiveness {
+    fn heard(&mut self, client_id: ClientId, tick: SimTick, now: Duration) {
+        let liveness = self.entry(client_id).or_insert(Liveness { last_heard: now, confirmed_tick: tick });
+        liveness.last_heard = now;
+        liveness.confirmed_tick = liveness.confirmed_tick.max(tick);
+    }
+}
+
+pub(super) fn send_liveness_heartbeat(
+    mut commands: Commands,
+    mut timer: Local<Timer>,
+    time: Res<Time<Real>>,
+    settings: Res<SimulationSettings>,
+    sim_tick: Res<SimulationTick>,
+    local_client: Query<&LocalClient>,
+    server: Res<RepliconServer>,
+) {
+    let Some(suppression) = settings.empty_command_suppression else { return };
+    // The host's own seat keeps sending empty commands
+    if local_client.is_empty() || server.is_running() { return }
+    if timer.duration() != suppression.heartbeat_interval {
+        *timer = Timer::new(suppression.heartbeat_interval, TimerMode::Repeating);
+    }
+    if timer.tick(time.delta()).just_finished() {
+        commands.client_trigger(LivenessHeartbeat { tick: **sim_tick });
+    }
+}
+
+pub(super) fn receive_liveness_heartbeat(
+    trigger: Trigger<FromClient<LivenessHeartbeat>>,
+    clients: LockstepClients,
+    mut liveness: ResMut<ClientLiveness>,
+    time: Res<Time<Real>>,
+) {
+    let Some(client_id) = clients.client_id(trigger.client_entity) else { return };
+    liveness.heard(client_id, trigger.event().tick, time.elapsed());
+}
+
+/// Commands confirm the tick they were issued on as well
+pub(super) fn record_commands_liveness(
+    trigger: Trigger<FromClient<ClientSendCommands>>,
+    clients: LockstepClients,
+    settings: Res<SimulationSettings>,
+    mut liveness: ResMut<ClientLiveness>,
+    time: Res<Time<Real>>,
+) {
+    if settings.empty_command_suppression.is_none() { return }
+    let Some(client_id) = clients.client_id(trigger.client_entity) else { return };
+    liveness.heard(client_id, trigger.event().issued_tick, time.elapsed());
+}
+
+/// Fills empty command slots for the server's tick gating
+#[derive(SystemParam)]
+pub(super) struct LivenessInference<'w> {
+    liveness: Res<'w, ClientLiveness>,
+    time: Res<'w, Time<Real>>,
+}
+
+impl LivenessInference<'_> {
+    /// Records empty commands on `tick` for every player without any that
+    /// is live and confirmed a recent enough tick
+    pub(super) fn fill(
+        &self,
+        received: &mut LockstepGameCommandsReceived,
+        players: &[ClientId],
+        tick: SimTick,
+        suppression: &EmptyCommandSuppression,
+    ) {
+        let now = self.time.elapsed();
+        for &client_id in players {
+            let Some(liveness) = self.liveness.get(&client_id) else { continue };
+            if now.saturating_sub(liveness.last_heard) > suppression.liveness_timeout { continue }
+            if tick > liveness.confirmed_tick + suppression.max_unconfirmed_ticks { continue }
+            if tick >= received.len() as SimTick {
+                received.resize(tick + 1, LockstepClientCommands::default());
+            }
+            received[tick as usize].entry(client_id).or_default();
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use bevy::prelude::*;
+use bevy_replicon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::prelude::*;
+use super::votes::{self, ActiveSettingsVote};
+
+/// What the server does when a client reports that it can't keep up with
+/// the tick rate
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OverrunMitigation {
+    /// Only trigger [`ClientOverrunning`] on the server
+    #[default]
+    Notify,
+    /// Start a settings vote on a tick timestep multiplied by this factor,
+    /// with the overrunning client's approval already counted
+    ProposeLowerTickRate(f32),
+    /// Move the client to spectator so nobody waits for its commands
+    Spectate,
+    /// Pause the simulation on all peers
+    Pause,
+}
+
+/// Sent by a client whose fixed update has persistently overrun
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct FixedUpdateOverrun {
+    /// Average fixed steps run per frame while overrunning
+    pub steps_per_frame: f32,
+}
+
+/// A trigger that fires on the server when a client reports a persistent
+/// fixed update overrun, before the mitigation is applied
+#[derive(Event, Debug, Clone, Copy)]
+pub struct ClientOverrunning {
+    pub client: ClientId,
+    pub steps_per_frame: f32,
+}
+
+/// Fixed steps run since the last frame
+#[derive(Resource, Default)]
+pub(super) struct FixedStepCount(u32);
+
+/// Consecutive overrun frames, and whether they were reported already
+#[derive(Default)]
+pub(super) struct OverrunDetector {
+    frames: u32,
+    steps: u32,
+    reported: bool,
+}
+
+pub(super) fn count_fixed_step(mut count: ResMut<FixedStepCount>) {
+    count.0 += 1;
+}
+
+/// A frame overruns when it had to run more fixed steps than allowed, or
+/// when it took so long that virtual time was clamped and the simulation
+/// fell behind real time
+pub(super) fn detect_fixed_update_overrun(
+    mut commands: Commands,
+    mut detector: Local<OverrunDetector>,
+    mut count: ResMut<FixedStepCount>,
+    settings: Res<SimulationSettings>,
+    real_time: Res<Time<Real>>,
+    virtual_time: Res<Time<Virtual>>,
+) {
+    let steps = std::mem::take(&mut count.0);
+    let clamped = real_time.delta() > virtual_time.max_delta();
+    if steps <= settings.overrun_steps_per_frame && !clamped {
+        *detector = OverrunDetector::default();
+        return;
+    }
+    detector.frames += 1;
+    detector.steps += steps;
+    if detector.reported || detector.frames < settings.overrun_frame_threshold { return }
+
+    let steps_per_frame = detector.steps as f32 / detector.frames as f32;
+    warn!("Fixed update overran for {} frames, {:.1} steps per frame", detector.frames, steps_per_frame);
+    detector.reported = true;
+    commands.client_trigger(FixedUpdateOverrun { steps_per_frame });
+}
+
+pub(super) fn receive_fixed_update_overrun(
+    trigger: Trigger<FromClient<FixedUpdateOverrun>>,
+    mut commands: Commands,
+    clients: LockstepClients,
+    sim_tick: Res<SimulationTick>,
+    settings: Res<SimulationSettings>,
+    mut active: ResMut<ActiveSettingsVote>,
+) {
+    let Some(client) = clients.client_id(trigger.client_entity) else { return };
+    let steps_per_frame = trigger.event.steps_per_frame;
+    warn!("client {} can't keep up with the tick rate", client);
+    commands.trigger(ClientOverrunning { client, steps_per_frame });
+    match settings.overrun_mitigation {
+        OverrunMitigation::Notify => {}
+        OverrunMitigation::ProposeLowerTickRate(factor) => {
+            let change = SettingsChange::TickTimestep(settings.tick_timestep.mul_f32(factor.max(1.0)));
+            let deadline = **sim_tick + settings.settings_vote_duration;
+            votes::start_vote(&mut commands, &mut active, client, change, deadline);
+        }
+        OverrunMitigation::Spectate => commands.trigger(MakeSpectator(client)),
+        OverrunMitigation::Pause => commands.trigger(PauseSimulation { reason: PauseReason::Overrun(client) }),
+    }
+}
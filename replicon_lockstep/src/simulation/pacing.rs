@@ -0,0 +1,68 @@
+use bevy::{prelude::*, utils::hashbrown::HashMap};
+use bevy_replicon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::{prelude::*, lockstep_core::schedule};
+
+/// Per-client pacing information sent by the server.  The slowest client
+/// still gates tick production, but each client is told how many ticks of
+/// commands it actually needs to keep buffered for its own latency.
+#[derive(Event, Resource, Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BroadcastPacing {
+    /// The server tick when this was computed
+    pub server_tick: SimTick,
+    /// One way latency of this client, in ticks
+    pub own_delay_ticks: SimTick,
+    /// One way latency of the slowest client, in ticks
+    pub gating_delay_ticks: SimTick,
+    /// How many ticks of received commands this client should hold before
+    /// executing them to absorb jitter.
+    pub recommended_buffer_ticks: SimTick,
+}
+
+/// Convert a round trip time in seconds to one way latency in ticks
+pub(crate) fn rtt_to_ticks(rtt: f64, settings: &SimulationSettings) -> SimTick {
+    schedule::rtt_to_ticks(rtt, settings.tick_timestep.as_secs_f64())
+}
+
+pub(super) fn send_broadcast_pacing(
+    mut commands: Commands,
+    mut last_sent: Local<HashMap<Entity, BroadcastPacing>>,
+    clients: Query<(Entity, &NetworkStats)>,
+    sim_tick: Res<SimulationTick>,
+    settings: Res<SimulationSettings>,
+) {
+    let gating_delay_ticks = clients
+        .iter()
+        .map(|(_, stats)| rtt_to_ticks(stats.rtt, &settings))
+        .max()
+        .unwrap_or(0);
+    last_sent.retain(|entity, _| clients.contains(*entity));
+    for (client, stats) in clients.iter() {
+        let own_delay_ticks = rtt_to_ticks(stats.rtt, &settings);
+        let pacing = BroadcastPacing {
+            server_tick: **sim_tick,
+            own_delay_ticks,
+            gating_delay_ticks,
+            recommended_buffer_ticks: own_delay_ticks + settings.base_input_tick_delay as SimTick,
+        };
+        // Only send when something besides the tick changed
+        let changed = last_sent.get(&client).is_none_or(|last| {
+            BroadcastPacing { server_tick: pacing.server_tick, ..*last } != pacing
+        });
+        if changed {
+            trace!("sending pacing {:?} to client {}", pacing, client);
+            commands.server_trigger(ToClients {
+                mode: SendMode::Direct(client),
+                event: pacing,
+            });
+            last_sent.insert(client, pacing);
+        }
+    }
+}
+
+pub(super) fn receive_broadcast_pacing(
+    trigger: Trigger<BroadcastPacing>,
+    mut commands: Commands,
+) {
+    commands.insert_resource(*trigger.event());
+}
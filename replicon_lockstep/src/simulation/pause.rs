@@ -0,0 +1,363 @@
+use std::time::Duration;
+use bevy::{prelude::*, utils::hashbrown::{HashMap, HashSet}};
+use bevy_replicon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::prelude::*;
+use super::SetSimulationState;
+
+/// Trigger this on the server to pause the simulation on all peers.
+/// No ticks are produced while paused.  `PauseSimulation::default()` pauses
+/// with [`PauseReason::Server`].
+#[derive(Event, Debug, Clone, Copy, Default)]
+pub struct PauseSimulation {
+    pub reason: PauseReason,
+}
+
+/// Trigger this on the server to resume a paused simulation on all peers,
+/// regardless of resume votes
+#[derive(Event)]
+pub struct ResumeSimulation;
+
+/// Why the simulation is paused, broadcast to every peer with
+/// [`SimulationPaused`]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PauseReason {
+    /// Paused through [`PauseSimulation`] by the server
+    #[default]
+    Server,
+    /// A player sent [`RequestPause`]
+    Player(ClientId),
+    /// Commands of some clients stopped arriving
+    ConnectionLoss,
+    /// A client can't keep up with the tick rate, see
+    /// [`OverrunMitigation::Pause`]
+    Overrun(ClientId),
+    /// The host left and a new one waits for the others to rejoin
+    HostMigration,
+    /// The server is being replaced, see [`RequestTransportMigration`]
+    TransportMigration,
+}
+
+/// Sent by a player to pause the running simulation.  Any player can
+/// pause, within `SimulationSettings::pause_allowance` if set.
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct RequestPause;
+
+/// How many pauses each player can call per match and for how long, e.g.
+/// for competitive games
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PauseAllowance {
+    pub pauses_per_player: u32,
+    /// A player's pause resumes on its own after this long, unless it was
+    /// resumed before
+    pub max_duration: Duration,
+}
+
+impl Default for PauseAllowance {
+    fn default() -> Self {
+        Self {
+            pauses_per_player: 3,
+            max_duration: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Pauses each player called this match.  Counted from
+/// [`SimulationPaused`], so it is the same on every peer.
+#[derive(Resource, Default, Debug, Clone, Deref)]
+pub struct PausesUsed(HashMap<ClientId, u32>);
+
+impl PausesUsed {
+    /// Pauses the player can still call
+    pub fn remaining(&self, client: ClientId, allowance: &PauseAllowance) -> u32 {
+        allowance.pauses_per_player.saturating_sub(self.get(&client).copied().unwrap_or(0))
+    }
+}
+
+/// Sent by the server to a player whose [`RequestPause`] was refused
+/// because it called all its pauses
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct PauseDenied {
+    pub pauses_used: u32,
+}
+
+/// Broadcast by the server every second of a player's pause, until it
+/// resumes on its own
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct PauseCountdown {
+    /// The player who paused
+    pub owner: ClientId,
+    /// The tick the simulation paused at
+    pub tick: SimTick,
+    pub remaining_secs: u32,
+}
+
+/// Sent by a player to vote for resuming a paused simulation.  The
+/// simulation resumes once `SimulationSettings::resume_vote_rule` is met,
+/// or immediately when sent by the host.
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct RequestResume;
+
+/// Broadcast by the server when the simulation pauses
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct SimulationPaused {
+    pub reason: PauseReason,
+    pub tick: SimTick,
+}
+
+/// Broadcast by the server whenever a resume vote arrives
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ResumeVoteStatus {
+    pub votes: Vec<ClientId>,
+    /// Votes needed to resume
+    pub needed: usize,
+}
+
+/// The current pause as seen by every peer, e.g. for a pause screen.
+/// Cleared when the simulation runs again.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct PauseStatus {
+    pub reason: Option<PauseReason>,
+    pub resume_votes: Vec<ClientId>,
+    pub resume_votes_needed: usize,
+    /// Time left until a player's pause resumes on its own, from the last
+    /// [`PauseCountdown`]
+    pub remaining: Option<Duration>,
+}
+
+/// Server-only resume votes of the current pause
+#[derive(Resource, Default, Deref, DerefMut)]
+pub(super) struct ResumeVotes(HashSet<ClientId>);
+
+/// Server-only time a player's pause lasted and the seconds of it counted
+/// down so far
+#[derive(Resource, Default)]
+pub(super) struct PlayerPauseClock {
+    elapsed: Duration,
+    counted_secs: u64,
+}
+
+/// Low rate keep-alive sent by clients while the simulation is paused,
+/// in place of the per-tick empty commands.
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ClientHeartbeat;
+
+/// Server-only resource with the time (as elapsed app time) each client was last
+/// heard from while paused.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct ClientHeartbeats(HashMap<ClientId, f64>);
+
+/// Commands received by the server while paused.  They are held back and
+/// scheduled relative to the resume tick, so the command buffer is frozen
+/// for the duration of the pause.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub(crate) struct HeldCommands(Vec<(ClientId, Vec<Box<dyn PartialReflect>>)>);
+
+pub(super) fn pause_simulation(
+    trigger: Trigger<PauseSimulation>,
+    mut commands: Commands,
+    server: Res<RepliconServer>,
+    state: Res<State<SimulationState>>,
+    sim_tick: Res<SimulationTick>,
+) {
+    if !server.is_running() || *state.get() != SimulationState::Running { return }
+    info!("Pausing simulation: {:?}", trigger.reason);
+    broadcast_pause(&mut commands, trigger.reason, **sim_tick);
+}
+
+/// Tells every peer why the simulation pauses, then pauses it
+pub(super) fn broadcast_pause(commands: &mut Commands, reason: PauseReason, tick: SimTick) {
+    commands.server_trigger(ToClients {
+        mode: SendMode::Broadcast,
+        event: SimulationPaused { reason, tick },
+    });
+    commands.server_trigger(ToClients {
+        mode: SendMode::Broadcast,
+        event: SetSimulationState(SimulationState::Paused),
+    });
+}
+
+pub(super) fn receive_pause_request(
+    trigger: Trigger<FromClient<RequestPause>>,
+    mut commands: Commands,
+    clients: LockstepClients,
+    spectators: Res<LockstepSpectators>,
+    sim_tick: Res<SimulationTick>,
+    state: Res<State<SimulationState>>,
+    settings: Res<SimulationSettings>,
+    used: Res<PausesUsed>,
+) {
+    let Some(client_id) = clients.client_id(trigger.client_entity) else { return };
+    if spectators.is_spectating(client_id, **sim_tick) { return }
+    if *state.get() != SimulationState::Running { return }
+    if let Some(allowance) = settings.pause_allowance {
+        if used.remaining(client_id, &allowance) == 0 {
+            info!("client {} has no pauses left", client_id);
+            commands.server_trigger(ToClients {
+                mode: SendMode::Direct(trigger.client_entity),
+                event: PauseDenied { pauses_used: used.get(&client_id).copied().unwrap_or(0) },
+            });
+            return;
+        }
+    }
+    commands.trigger(PauseSimulation { reason: PauseReason::Player(client_id) });
+}
+
+pub(super) fn receive_resume_request(
+    trigger: Trigger<FromClient<RequestResume>>,
+    mut commands: Commands,
+    clients: LockstepClients,
+    spectators: Res<LockstepSpectators>,
+    sim_tick: Res<SimulationTick>,
+    state: Res<State<SimulationState>>,
+    settings: Res<SimulationSettings>,
+    connection_settings: Res<ConnectionSettings>,
+    mut votes: ResMut<ResumeVotes>,
+) {
+    if *state.get() != SimulationState::Paused { return }
+    let Some(client_id) = clients.client_id(trigger.client_entity) else { return };
+    if spectators.is_spectating(client_id, **sim_tick) { return }
+    if clients.is_local(trigger.client_entity) && connection_settings.server_mode == ServerMode::Host {
+        info!("host resumed the simulation");
+        commands.trigger(ResumeSimulation);
+        return;
+    }
+
+    votes.insert(client_id);
+    let players: Vec<ClientId> = clients
+        .ids()
+        .filter(|&id| !spectators.is_spectating(id, **sim_tick))
+        .collect();
+    let mut status = ResumeVoteStatus {
+        votes: players.iter().copied().filter(|id| votes.contains(id)).collect(),
+        needed: (1..=players.len())
+            .find(|&needed| settings.resume_vote_rule.passes(needed, players.len()))
+            .unwrap_or(players.len()),
+    };
+    status.votes.sort_unstable();
+    let passed = settings.resume_vote_rule.passes(status.votes.len(), players.len());
+    debug!("{} of {} resume votes", status.votes.len(), status.needed);
+    commands.server_trigger(ToClients { mode: SendMode::Broadcast, event: status });
+    if passed {
+        info!("players voted to resume the simulation");
+        commands.trigger(ResumeSimulation);
+    }
+}
+
+pub(super) fn receive_simulation_paused(
+    trigger: Trigger<SimulationPaused>,
+    mut status: ResMut<PauseStatus>,
+    mut used: ResMut<PausesUsed>,
+    settings: Res<SimulationSettings>,
+) {
+    *status = PauseStatus { reason: Some(trigger.reason), ..default() };
+    let (PauseReason::Player(client_id), Some(allowance)) = (trigger.reason, settings.pause_allowance) else { return };
+    *used.0.entry(client_id).or_default() += 1;
+    status.remaining = Some(allowance.max_duration);
+}
+
+pub(super) fn receive_pause_countdown(trigger: Trigger<PauseCountdown>, mut status: ResMut<PauseStatus>) {
+    if status.reason != Some(PauseReason::Player(trigger.owner)) { return }
+    status.remaining = Some(Duration::from_secs(trigger.remaining_secs as u64));
+}
+
+/// Counts a player's pause down once a second and resumes it on expiry
+pub(super) fn count_down_player_pause(
+    mut commands: Commands,
+    mut clock: ResMut<PlayerPauseClock>,
+    status: Res<PauseStatus>,
+    settings: Res<SimulationSettings>,
+    sim_tick: Res<SimulationTick>,
+    time: Res<Time>,
+) {
+    let (Some(PauseReason::Player(owner)), Some(allowance)) = (status.reason, settings.pause_allowance) else { return };
+    clock.elapsed += time.delta();
+    if clock.elapsed >= allowance.max_duration {
+        info!("pause of client {} expired", owner);
+        commands.trigger(ResumeSimulation);
+        return;
+    }
+    let secs = clock.elapsed.as_secs();
+    if secs == clock.counted_secs { return }
+    clock.counted_secs = secs;
+    let remaining = allowance.max_duration - clock.elapsed;
+    // Whole seconds left, rounded up so 0 is only ever the resume
+    let remaining_secs = remaining.as_secs() as u32 + (remaining.subsec_nanos() > 0) as u32;
+    commands.server_trigger(ToClients {
+        mode: SendMode::Broadcast,
+        event: PauseCountdown { owner, tick: **sim_tick, remaining_secs },
+    });
+}
+
+pub(super) fn receive_resume_vote_status(trigger: Trigger<ResumeVoteStatus>, mut status: ResMut<PauseStatus>) {
+    status.resume_votes = trigger.votes.clone();
+    status.resume_votes_needed = trigger.needed;
+}
+
+pub(super) fn clear_pause_status(
+    mut status: ResMut<PauseStatus>,
+    mut votes: ResMut<ResumeVotes>,
+    mut clock: ResMut<PlayerPauseClock>,
+) {
+    *status = PauseStatus::default();
+    votes.clear();
+    *clock = PlayerPauseClock::default();
+}
+
+pub(super) fn resume_simulation(
+    _trigger: Trigger<ResumeSimulation>,
+    mut commands: Commands,
+    server: Res<RepliconServer>,
+    state: Res<State<SimulationState>>,
+) {
+    if !server.is_running() || *state.get() != SimulationState::Paused { return }
+    info!("Resuming simulation");
+    commands.server_trigger(ToClients {
+        mode: SendMode::Broadcast,
+        event: SetSimulationState(SimulationState::Running),
+    });
+}
+
+pub(super) fn send_heartbeat(
+    mut commands: Commands,
+    mut timer: Local<Timer>,
+    time: Res<Time>,
+    settings: Res<SimulationSettings>,
+    local_client: Query<&LocalClient>,
+) {
+    if local_client.is_empty() { return }
+    if timer.duration() != settings.pause_heartbeat_interval {
+        *timer = Timer::new(settings.pause_heartbeat_interval, TimerMode::Repeating);
+    }
+    if timer.tick(time.delta()).just_finished() {
+        trace!("sending heartbeat");
+        commands.client_trigger(ClientHeartbeat);
+    }
+}
+
+pub(super) fn receive_heartbeat(
+    trigger: Trigger<FromClient<ClientHeartbeat>>,
+    clients: LockstepClients,
+    mut heartbeats: ResMut<ClientHeartbeats>,
+    time: Res<Time>,
+) {
+    let Some(client_id) = clients.client_id(trigger.client_entity) else { return };
+    heartbeats.insert(client_id, time.elapsed_secs_f64());
+}
+
+/// On resume, schedule commands held during the pause on the next unbroadcast tick
+pub(super) fn release_held_commands(
+    server: Res<RepliconServer>,
+    mut held: ResMut<HeldCommands>,
+    mut history: ResMut<LockstepGameCommandBuffer>,
+    sim_tick: Option<Res<SimulationTick>>,
+    settings: Res<SimulationSettings>,
+) {
+    if !server.is_running() || held.is_empty() { return }
+    let Some(sim_tick) = sim_tick else { return };
+    let execution_tick = **sim_tick + 1 + settings.base_input_tick_delay as SimTick;
+    for (client_id, commands) in held.drain(..) {
+        trace!("releasing held commands from client {} for tick {}", client_id, execution_tick);
+        history.schedule_client_commands(execution_tick, client_id, commands, settings.max_commands_per_tick);
+    }
+}
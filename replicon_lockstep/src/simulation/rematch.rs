@@ -0,0 +1,162 @@
+use std::{hash::Hasher, time::{SystemTime, UNIX_EPOCH}};
+use bevy::{prelude::*, utils::hashbrown::HashMap};
+use bevy_replicon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::prelude::*;
+use super::SetSimulationState;
+
+/// Trigger this on the server once the match has ended to keep everyone
+/// connected in [`SimulationState::PostGame`] and collect rematch votes
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EnterPostGame;
+
+/// Sent by a client in PostGame to accept or decline a rematch
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct RequestRematch {
+    pub accept: bool,
+}
+
+/// Broadcast by the server whenever a rematch vote arrives
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RematchStatus {
+    pub accepted: Vec<ClientId>,
+    pub declined: Vec<ClientId>,
+}
+
+/// Broadcast by the server when every player accepted.  Every peer adopts
+/// the new seed, despawns the previous match and goes back to Setup.
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct RematchAccepted {
+    pub seed: u64,
+}
+
+/// Trigger this on the server to restart the match without a vote.  Every
+/// peer stays connected, despawns the current match and goes back to Setup
+/// with a new seed, and the match starts again once every client sent
+/// [`ClientReadyEvent`].
+#[derive(Event, Debug, Clone, Copy)]
+pub struct RestartSimulation;
+
+/// Broadcast by the server when it restarts the match with
+/// [`RestartSimulation`], handled like [`RematchAccepted`]
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct SimulationRestarted {
+    pub seed: u64,
+}
+
+/// Server-only rematch votes of the current PostGame
+#[derive(Resource, Default, Deref, DerefMut)]
+pub(super) struct RematchVotes(HashMap<ClientId, bool>);
+
+pub(super) fn enter_post_game(
+    _trigger: Trigger<EnterPostGame>,
+    mut commands: Commands,
+    server: Res<RepliconServer>,
+    state: Res<State<SimulationState>>,
+) {
+    if !server.is_running() { return }
+    if !matches!(state.get(), SimulationState::Running | SimulationState::Paused | SimulationState::Ending) { return }
+    commands.server_trigger(ToClients {
+        mode: SendMode::Broadcast,
+        event: SetSimulationState(SimulationState::PostGame),
+    });
+}
+
+pub(super) fn clear_rematch_votes(mut votes: ResMut<RematchVotes>) {
+    votes.clear();
+}
+
+pub(super) fn receive_rematch_request(
+    trigger: Trigger<FromClient<RequestRematch>>,
+    mut commands: Commands,
+    clients: LockstepClients,
+    state: Res<State<SimulationState>>,
+    identity: Res<SessionIdentity>,
+    mut votes: ResMut<RematchVotes>,
+) {
+    if *state.get() != SimulationState::PostGame { return }
+    let Some(client_id) = clients.client_id(trigger.client_entity) else { return };
+    votes.insert(client_id, trigger.event.accept);
+
+    let mut status = RematchStatus::default();
+    for id in clients.ids() {
+        match votes.get(&id) {
+            Some(true) => status.accepted.push(id),
+            Some(false) => status.declined.push(id),
+            None => {}
+        }
+    }
+    status.accepted.sort_unstable();
+    status.declined.sort_unstable();
+    let everyone_accepted = status.accepted.len() == clients.iter().len();
+    commands.server_trigger(ToClients { mode: SendMode::Broadcast, event: status });
+    if !everyone_accepted { return }
+
+    info!("All players accepted a rematch");
+    votes.clear();
+    commands.server_trigger(ToClients { mode: SendMode::Broadcast, event: RematchAccepted { seed: new_seed(&identity) } });
+}
+
+/// The seed only has to be new, not reproducible
+fn new_seed(identity: &SessionIdentity) -> u64 {
+    let mut hasher = StableHasher::new();
+    hasher.write_u64(identity.seed);
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos()));
+    hasher.finish()
+}
+
+pub(super) fn restart_simulation(
+    _trigger: Trigger<RestartSimulation>,
+    mut commands: Commands,
+    server: Res<RepliconServer>,
+    state: Res<State<SimulationState>>,
+    identity: Res<SessionIdentity>,
+    mut votes: ResMut<RematchVotes>,
+) {
+    if !server.is_running() {
+        warn!("Only the server can restart the simulation");
+        return;
+    }
+    if !matches!(state.get(), SimulationState::Running | SimulationState::Paused | SimulationState::Ending | SimulationState::PostGame) {
+        return;
+    }
+    info!("Restarting the simulation");
+    votes.clear();
+    commands.server_trigger(ToClients { mode: SendMode::Broadcast, event: SimulationRestarted { seed: new_seed(&identity) } });
+}
+
+pub(super) fn start_rematch(
+    trigger: Trigger<RematchAccepted>,
+    commands: Commands,
+    identity: ResMut<SessionIdentity>,
+    next_state: ResMut<NextState<SimulationState>>,
+    simulated: Query<Entity, Or<(With<SimulationId>, With<ChildSimulationId>)>>,
+) {
+    restart(trigger.seed, commands, identity, next_state, simulated);
+}
+
+pub(super) fn receive_simulation_restart(
+    trigger: Trigger<SimulationRestarted>,
+    commands: Commands,
+    identity: ResMut<SessionIdentity>,
+    next_state: ResMut<NextState<SimulationState>>,
+    simulated: Query<Entity, Or<(With<SimulationId>, With<ChildSimulationId>)>>,
+) {
+    restart(trigger.seed, commands, identity, next_state, simulated);
+}
+
+fn restart(
+    seed: u64,
+    mut commands: Commands,
+    mut identity: ResMut<SessionIdentity>,
+    mut next_state: ResMut<NextState<SimulationState>>,
+    simulated: Query<Entity, Or<(With<SimulationId>, With<ChildSimulationId>)>>,
+) {
+    identity.seed = seed;
+    // Every peer restarts on the same broadcast, so this is as deterministic
+    // as a despawn in the lockstep schedule
+    super::despawn_simulated(&mut commands, simulated.iter().collect());
+    // Setup resets the tick, all buffers and the simulation ids, and
+    // recomputes the session hash with the new seed
+    next_state.set(SimulationState::Setup);
+}
@@ -0,0 +1,109 @@
+use std::collections::BTreeMap;
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy_replicon::{
+    prelude::*,
+    client::ServerUpdateTick,
+    server::server_tick::ServerTick,
+    shared::replicon_tick::RepliconTick,
+};
+use serde::{Deserialize, Serialize};
+use crate::prelude::*;
+
+/// How many sim ticks [`ReplicationTicks`] keeps
+const KEPT_TICKS: SimTick = 256;
+
+/// Broadcast by the server after applying a tick when
+/// `SimulationSettings::replication_tick_mapping` is set
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplicationTickMapping {
+    pub tick: SimTick,
+    /// The server's replicon tick when it applied `tick`.  Replicated
+    /// snapshots with a later tick include the state after `tick`.
+    pub replicon_tick: RepliconTick,
+}
+
+/// The replicon tick each recent sim tick was applied at on the server, for
+/// games mixing replicated and lockstep state
+#[derive(Resource, Debug, Default, Deref)]
+pub struct ReplicationTicks(BTreeMap<SimTick, RepliconTick>);
+
+impl ReplicationTicks {
+    fn record(&mut self, tick: SimTick, replicon_tick: RepliconTick) {
+        self.0.insert(tick, replicon_tick);
+        while self.0.len() > KEPT_TICKS as usize {
+            self.0.pop_first();
+        }
+    }
+
+    /// The replicon tick the server applied `tick` at
+    pub fn replicon_tick(&self, tick: SimTick) -> Option<RepliconTick> {
+        self.0.get(&tick).copied()
+    }
+
+    /// The last sim tick whose state a snapshot of `replicon_tick` includes
+    pub fn sim_tick(&self, replicon_tick: RepliconTick) -> Option<SimTick> {
+        self.0
+            .iter()
+            .rev()
+            .find(|&(_, &applied_at)| applied_at < replicon_tick)
+            .map(|(&tick, _)| tick)
+    }
+}
+
+/// Whether the last replicated snapshot caught up with the lockstep state
+#[derive(SystemParam)]
+pub struct ReplicationAlignment<'w> {
+    ticks: Res<'w, ReplicationTicks>,
+    last_applied: Res<'w, LastAppliedTick>,
+    server: Res<'w, RepliconServer>,
+    update_tick: Option<Res<'w, ServerUpdateTick>>,
+}
+
+impl ReplicationAlignment<'_> {
+    /// The last sim tick included in the last received snapshot.  The
+    /// server's own world is always up to date.
+    pub fn snapshot_tick(&self) -> Option<SimTick> {
+        if self.server.is_running() { return Some(**self.last_applied) }
+        self.ticks.sim_tick(**self.update_tick.as_ref()?)
+    }
+
+    /// The last received snapshot includes at least the last applied tick,
+    /// so replicated and lockstep state can be read together
+    pub fn is_aligned(&self) -> bool {
+        self.snapshot_tick().is_some_and(|tick| tick >= **self.last_applied)
+    }
+}
+
+/// Run condition to wait until replicated and lockstep state align, see
+/// [`ReplicationAlignment::is_aligned`]
+pub fn replication_aligned(alignment: ReplicationAlignment) -> bool {
+    alignment.is_aligned()
+}
+
+pub(super) fn record_replication_tick(
+    trigger: Trigger<GameCommandsApplied>,
+    mut commands: Commands,
+    mut ticks: ResMut<ReplicationTicks>,
+    server: Res<RepliconServer>,
+    server_tick: Option<Res<ServerTick>>,
+    settings: Res<SimulationSettings>,
+) {
+    if !settings.replication_tick_mapping || !server.is_running() { return }
+    let Some(server_tick) = server_tick else { return };
+    let mapping = ReplicationTickMapping { tick: trigger.event().0, replicon_tick: **server_tick };
+    ticks.record(mapping.tick, mapping.replicon_tick);
+    commands.server_trigger(ToClients {
+        mode: SendMode::Broadcast,
+        event: mapping,
+    });
+}
+
+pub(super) fn receive_replication_tick_mapping(
+    trigger: Trigger<ReplicationTickMapping>,
+    mut ticks: ResMut<ReplicationTicks>,
+    server: Res<RepliconServer>,
+) {
+    // The server receives its own broadcast
+    if server.is_running() { return }
+    ticks.record(trigger.tick, trigger.replicon_tick);
+}
@@ -0,0 +1,60 @@
+use bevy::prelude::*;
+use bevy_replicon::prelude::*;
+use crate::{prelude::*, commands::SERVER_CLIENT_ID};
+
+/// Built-in command, issued by the server, which resets the simulation on
+/// every peer at the tick it is scheduled for, before the other commands of
+/// that tick.  Replays and [`reconstruct_world`]
+/// reset on the same tick.
+#[derive(Reflect, Default, Debug, Clone)]
+pub struct ResetSimulation;
+
+/// Trigger this on the server to schedule a [`ResetSimulation`] command
+/// on the next tick that has not yet been broadcast.
+#[derive(Event)]
+pub struct RequestSimulationReset;
+
+/// A trigger that fires on all peers after a [`ResetSimulation`] command is applied.
+/// All entities with a [`SimulationId`] or [`ChildSimulationId`] have been
+/// despawned at this point.
+#[derive(Event, Deref)]
+pub struct SimulationReset(pub SimTick);
+
+pub(super) fn schedule_simulation_reset(
+    _trigger: Trigger<RequestSimulationReset>,
+    server: Res<RepliconServer>,
+    sim_tick: Res<SimulationTick>,
+    settings: Res<SimulationSettings>,
+    mut command_history: ResMut<LockstepGameCommandBuffer>,
+) {
+    if !server.is_running() {
+        warn!("Only the server can reset the simulation");
+        return;
+    }
+    // Ticks up to the current one have already been broadcast
+    let reset_tick = **sim_tick + 1 + settings.base_input_tick_delay as SimTick;
+    info!("Scheduling simulation reset for tick {}", reset_tick);
+    command_history.insert_server_command(reset_tick, Box::new(ResetSimulation));
+}
+
+/// Resets the simulation when the server scheduled [`ResetSimulation`] on
+/// `tick`.  Run from `apply_tick` before the commands of the tick, so live
+/// peers, catching up peers and replays all reset exactly at that tick and
+/// the tick's own commands spawn into the fresh simulation.
+pub(crate) fn apply_simulation_reset(world: &mut World, tick: SimTick, tick_commands: &LockstepClientCommands) {
+    let Some(server_commands) = tick_commands.get(&SERVER_CLIENT_ID) else { return };
+    if !server_commands.iter().any(|cmd| cmd.represents::<ResetSimulation>()) { return }
+
+    info!("Resetting simulation on tick {}", tick);
+    let entities: Vec<Entity> = world
+        .query_filtered::<Entity, Or<(With<SimulationId>, With<ChildSimulationId>)>>()
+        .iter(world)
+        .collect();
+    super::despawn_simulated(&mut world.commands(), entities);
+    world.flush();
+    world.resource_mut::<SimulationIdEntityMap>().clear();
+    world.resource_mut::<ChildSimulationIdEntityMap>().clear();
+    world.resource_mut::<ChildSimulationIdAllocator>().clear();
+    *world.resource_mut::<SimulationIdAllocator>() = SimulationIdAllocator::default();
+    world.trigger(SimulationReset(tick));
+}
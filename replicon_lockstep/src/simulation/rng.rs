@@ -0,0 +1,166 @@
+use std::{hash::Hasher, ops::Range, time::{SystemTime, UNIX_EPOCH}};
+use bevy::prelude::*;
+use bevy_replicon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::{prelude::*, hashing::StableHasher};
+
+/// Deterministic random numbers shared by all peers.  The server generates
+/// the seed when the match is Starting and broadcasts it before the first
+/// tick, late joiners get it along with their history.  Random numbers are
+/// drawn from sub-streams derived from the seed, a tick and a key, so a
+/// system that draws for a tick gets the same numbers on every peer no
+/// matter what else drew before it.  Use a different key per system, or
+/// per entity, to keep their numbers independent.
+#[derive(Resource, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LockstepRng {
+    seed: u64,
+}
+
+impl LockstepRng {
+    pub fn new(seed: u64) -> Self {
+        Self { seed }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The sub-stream of a tick
+    pub fn for_tick(&self, tick: SimTick) -> LockstepRngStream {
+        self.stream(tick, 0)
+    }
+
+    /// The sub-stream of a tick and key, e.g. a [`SimulationId`]
+    pub fn stream(&self, tick: SimTick, key: u64) -> LockstepRngStream {
+        let mut hasher = StableHasher::new();
+        hasher.write_u64(self.seed);
+        hasher.write_u32(tick);
+        hasher.write_u64(key);
+        // Odd increments select one of 2^63 independent sequences
+        LockstepRngStream::new(hasher.finish(), (key << 1) | 1)
+    }
+}
+
+/// A PCG32 (XSH RR) generator.  Its output only depends on its seed, on
+/// every platform.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockstepRngStream {
+    state: u64,
+    increment: u64,
+}
+
+impl LockstepRngStream {
+    const MULTIPLIER: u64 = 6_364_136_223_846_793_005;
+
+    pub fn new(seed: u64, sequence: u64) -> Self {
+        let mut rng = Self { state: 0, increment: sequence | 1 };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let state = self.state;
+        self.state = state.wrapping_mul(Self::MULTIPLIER).wrapping_add(self.increment);
+        let xorshifted = (((state >> 18) ^ state) >> 27) as u32;
+        xorshifted.rotate_right((state >> 59) as u32)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        ((self.next_u32() as u64) << 32) | self.next_u32() as u64
+    }
+
+    /// Uniform in `[0, 1)`, exact on every platform
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 * (1.0 / (1u32 << 24) as f32)
+    }
+
+    /// Uniform in `range`, without modulo bias.  Empty ranges return the start.
+    pub fn range(&mut self, range: Range<u32>) -> u32 {
+        let span = range.end.saturating_sub(range.start);
+        if span == 0 { return range.start }
+        // Reject the values that would make lower results more likely
+        let threshold = span.wrapping_neg() % span;
+        loop {
+            let value = self.next_u32();
+            if value >= threshold {
+                return range.start + value % span;
+            }
+        }
+    }
+
+    /// True with the given probability
+    pub fn chance(&mut self, probability: f32) -> bool {
+        self.next_f32() < probability
+    }
+}
+
+/// A salt for breaking ties in game logic, e.g. two units reaching a
+/// resource on the same tick.  Derived from the [`LockstepRng`] seed and the
+/// tick being applied, so every peer breaks ties identically.  Updated
+/// before the commands of each tick are applied.
+#[derive(Resource, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TickSalt {
+    pub tick: SimTick,
+    pub salt: u64,
+}
+
+impl TickSalt {
+    /// Keeps salts independent of the [`LockstepRng`] streams of a tick
+    const DOMAIN: u64 = 0x7469_6562_7265_616b;
+
+    pub fn new(rng: &LockstepRng, tick: SimTick) -> Self {
+        let mut hasher = StableHasher::new();
+        hasher.write_u64(Self::DOMAIN);
+        hasher.write_u64(rng.seed());
+        hasher.write_u32(tick);
+        Self { tick, salt: hasher.finish() }
+    }
+
+    /// A tie-break key for a candidate, e.g. its [`SimulationId`].  Sort
+    /// tied candidates by it, the order changes from tick to tick.
+    pub fn key(&self, candidate: u64) -> u64 {
+        let mut hasher = StableHasher::new();
+        hasher.write_u64(self.salt);
+        hasher.write_u64(candidate);
+        hasher.finish()
+    }
+
+    /// The index of the winner among `count` tied candidates in a
+    /// deterministic order, None if there are none
+    pub fn pick(&self, count: usize) -> Option<usize> {
+        (count > 0).then(|| (self.salt % count as u64) as usize)
+    }
+}
+
+/// Broadcast by the server before the match starts, and sent to clients
+/// catching up
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub(super) struct LockstepRngSeed(u64);
+
+pub(super) fn broadcast_rng_seed(mut commands: Commands, identity: Res<SessionIdentity>) {
+    // The seed only has to be unpredictable, not reproducible
+    let mut hasher = StableHasher::new();
+    hasher.write_u64(identity.seed);
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos()));
+    let seed = hasher.finish();
+    debug!("broadcasting rng seed {:016x}", seed);
+    commands.server_trigger(ToClients { mode: SendMode::Broadcast, event: LockstepRngSeed(seed) });
+}
+
+pub(super) fn send_rng_seed_for_catch_up(
+    trigger: Trigger<FromClient<RequestCatchUp>>,
+    mut commands: Commands,
+    rng: Res<LockstepRng>,
+) {
+    commands.server_trigger(ToClients {
+        mode: SendMode::Direct(trigger.client_entity),
+        event: LockstepRngSeed(rng.seed),
+    });
+}
+
+pub(super) fn receive_rng_seed(trigger: Trigger<LockstepRngSeed>, mut rng: ResMut<LockstepRng>) {
+    trace!("received rng seed {:016x}", trigger.0);
+    *rng = LockstepRng::new(trigger.0);
+}
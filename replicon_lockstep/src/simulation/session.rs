@@ -0,0 +1,67 @@
+use std::{collections::BTreeSet, hash::{Hash, Hasher}};
+use bevy::prelude::*;
+use crate::{prelude::*, hashing::StableHasher, commands::lockstep_command_id};
+
+/// Everything that identifies a match besides [`SimulationSettings`].  Peers
+/// must agree on all of it, or they are not playing the same game.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct SessionIdentity {
+    /// Seed shared by all peers for this match
+    pub seed: u64,
+    /// Type paths of all command types used by the game
+    pub command_types: BTreeSet<String>,
+    /// Names (and versions) of any mods or content packs loaded
+    pub mods: BTreeSet<String>,
+}
+
+impl SessionIdentity {
+    /// Add a command type to the session identity
+    pub fn with_command_type<T: TypePath>(mut self) -> Self {
+        self.command_types.insert(T::type_path().to_string());
+        self
+    }
+}
+
+/// Short hash of the [`SimulationSettings`] and [`SessionIdentity`] for the current
+/// match.  The server embeds it in every tick broadcast and clients reject
+/// ticks that don't match their own.
+#[derive(Resource, Debug, Clone, Copy, Default, Deref, PartialEq, Eq)]
+pub struct SessionHash(pub u32);
+
+impl SessionHash {
+    pub fn compute(settings: &SimulationSettings, identity: &SessionIdentity) -> Self {
+        let mut hasher = StableHasher::new();
+        settings.tick_timestep.as_nanos().hash(&mut hasher);
+        settings.num_players.hash(&mut hasher);
+        settings.base_input_tick_delay.hash(&mut hasher);
+        settings.connection_check_tick_delay.hash(&mut hasher);
+        settings.disconnect_tick_threshold.hash(&mut hasher);
+        identity.seed.hash(&mut hasher);
+        identity.command_types.hash(&mut hasher);
+        identity.mods.hash(&mut hasher);
+        let hash = hasher.finish();
+        Self((hash ^ (hash >> 32)) as u32)
+    }
+}
+
+/// A trigger that fires on a client which received a tick from a different session
+#[derive(Event, Debug, Clone, Copy)]
+pub struct SessionMismatch {
+    pub expected: SessionHash,
+    pub received: SessionHash,
+}
+
+/// Only the command types every peer registered count, so builds with
+/// different versions of a command type agree once they negotiated
+pub(super) fn compute_session_hash(
+    mut commands: Commands,
+    settings: Res<SimulationSettings>,
+    identity: Res<SessionIdentity>,
+    versions: Res<CommandVersions>,
+) {
+    let mut identity = identity.clone();
+    identity.command_types.retain(|path| versions.contains(lockstep_command_id(path)));
+    let hash = SessionHash::compute(&settings, &identity);
+    debug!("Session hash {:08x}", *hash);
+    commands.insert_resource(hash);
+}
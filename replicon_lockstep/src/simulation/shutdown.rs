@@ -0,0 +1,89 @@
+use bevy::prelude::*;
+use bevy_replicon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::prelude::*;
+use super::HeldCommands;
+
+/// Trigger this on the server to end the match.  The server picks the last
+/// tick any commands are scheduled for as the final tick, keeps producing
+/// ticks up to it and then stops.  Every peer enters
+/// [`SimulationState::Ending`] on its own once it applied the final tick.
+/// If the simulation is paused, shutdown completes after it resumes.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EndSimulation;
+
+/// Broadcast by the server when the match ends
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct FinalTickScheduled {
+    pub final_tick: SimTick,
+}
+
+/// A trigger that fires on every peer once all ticks up to the final tick
+/// were applied, right before entering `Ending`
+#[derive(Event, Debug, Clone, Copy)]
+pub struct FinalTickApplied(pub SimTick);
+
+/// The final tick of an ending match, kept on all peers.  The server drops
+/// commands received after the end was announced.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct FinalTick(Option<SimTick>);
+
+pub(super) fn end_simulation(
+    _trigger: Trigger<EndSimulation>,
+    mut commands: Commands,
+    server: Res<RepliconServer>,
+    state: Res<State<SimulationState>>,
+    sim_tick: Res<SimulationTick>,
+    history: Res<LockstepGameCommandBuffer>,
+    mut held: ResMut<HeldCommands>,
+    final_tick: Res<FinalTick>,
+) {
+    if !server.is_running() || final_tick.is_some() { return }
+    if !matches!(state.get(), SimulationState::Running | SimulationState::Paused) { return }
+    if !held.is_empty() {
+        warn!("dropping {} held command message(s), the match is ending", held.len());
+        held.clear();
+    }
+    let final_tick = (**sim_tick).max(history.len().saturating_sub(1) as SimTick);
+    info!("Ending simulation at tick {}", final_tick);
+    commands.server_trigger(ToClients {
+        mode: SendMode::Broadcast,
+        event: FinalTickScheduled { final_tick },
+    });
+}
+
+pub(super) fn receive_final_tick(
+    trigger: Trigger<FinalTickScheduled>,
+    mut commands: Commands,
+    mut final_tick: ResMut<FinalTick>,
+    last_applied: Res<LastAppliedTick>,
+    mut next_state: ResMut<NextState<SimulationState>>,
+) {
+    let tick = trigger.event().final_tick;
+    debug!("final tick is {}", tick);
+    **final_tick = Some(tick);
+    finish_if_applied(&mut commands, tick, **last_applied, &mut next_state);
+}
+
+pub(super) fn finish_on_final_tick(
+    trigger: Trigger<GameCommandsApplied>,
+    mut commands: Commands,
+    final_tick: Res<FinalTick>,
+    mut next_state: ResMut<NextState<SimulationState>>,
+) {
+    let Some(tick) = **final_tick else { return };
+    finish_if_applied(&mut commands, tick, trigger.event().0, &mut next_state);
+}
+
+fn finish_if_applied(
+    commands: &mut Commands,
+    final_tick: SimTick,
+    applied_tick: SimTick,
+    next_state: &mut NextState<SimulationState>,
+) {
+    // Only once, a peer that applied past the final tick already finished
+    if applied_tick != final_tick { return }
+    info!("Applied final tick {}", final_tick);
+    commands.trigger(FinalTickApplied(final_tick));
+    next_state.set(SimulationState::Ending);
+}
@@ -0,0 +1,99 @@
+use std::fmt;
+use bevy::{
+    ecs::{
+        query::{QueryData, QueryFilter, ROQueryItem},
+        system::SystemParam,
+    },
+    prelude::*,
+};
+use crate::prelude::*;
+
+/// Identifies which command is currently being applied.  Attached to
+/// [`SimRefError`] so a failed lookup can be traced back to its source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandContext {
+    /// The simulation tick the command is executing on
+    pub tick: SimTick,
+    /// The client that issued the command
+    pub client: ClientId,
+    /// Position of the command within the client's commands for this tick
+    pub command_index: usize,
+}
+
+impl CommandContext {
+    pub fn new(tick: SimTick, client: ClientId, command_index: usize) -> Self {
+        Self { tick, client, command_index }
+    }
+}
+
+/// Reasons a [`SimulationId`] could not be resolved by [`SimRef`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimRefError {
+    /// No entity is registered for this id in [`SimulationIdEntityMap`]
+    UnknownId {
+        context: CommandContext,
+        id: SimulationId,
+    },
+    /// The entity exists but does not match the requested query
+    QueryMismatch {
+        context: CommandContext,
+        id: SimulationId,
+        entity: Entity,
+    },
+}
+
+impl SimRefError {
+    pub fn context(&self) -> CommandContext {
+        match self {
+            Self::UnknownId { context, .. } | Self::QueryMismatch { context, .. } => *context,
+        }
+    }
+}
+
+impl fmt::Display for SimRefError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownId { context, id } => write!(f,
+                "tick {} client {} command {}: no entity for simulation id {}",
+                context.tick, context.client, context.command_index, **id),
+            Self::QueryMismatch { context, id, entity } => write!(f,
+                "tick {} client {} command {}: entity {} with simulation id {} does not match query",
+                context.tick, context.client, context.command_index, entity, **id),
+        }
+    }
+}
+
+impl std::error::Error for SimRefError {}
+
+/// System parameter for resolving [`SimulationId`]s to component data while
+/// applying commands.  Unlike a bare `ids.get(..)` followed by `query.get(..)`,
+/// failed lookups return a [`SimRefError`] describing which command failed
+/// instead of being silently skipped.
+#[derive(SystemParam)]
+pub struct SimRef<'w, 's, D: QueryData + 'static, F: QueryFilter + 'static = ()> {
+    ids: Res<'w, SimulationIdEntityMap>,
+    query: Query<'w, 's, D, F>,
+}
+
+impl<'w, 's, D: QueryData + 'static, F: QueryFilter + 'static> SimRef<'w, 's, D, F> {
+    /// Look up the entity for a simulation id
+    pub fn entity(&self, id: SimulationId, context: CommandContext) -> Result<Entity, SimRefError> {
+        self.ids.get(&id).copied().ok_or(SimRefError::UnknownId { context, id })
+    }
+
+    /// Read-only access to the query data of the entity with this simulation id
+    pub fn get(&self, id: SimulationId, context: CommandContext) -> Result<ROQueryItem<'_, D>, SimRefError> {
+        let entity = self.entity(id, context)?;
+        self.query
+            .get(entity)
+            .map_err(|_| SimRefError::QueryMismatch { context, id, entity })
+    }
+
+    /// Mutable access to the query data of the entity with this simulation id
+    pub fn get_mut(&mut self, id: SimulationId, context: CommandContext) -> Result<D::Item<'_>, SimRefError> {
+        let entity = self.entity(id, context)?;
+        self.query
+            .get_mut(entity)
+            .map_err(|_| SimRefError::QueryMismatch { context, id, entity })
+    }
+}
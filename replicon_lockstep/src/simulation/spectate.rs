@@ -0,0 +1,75 @@
+use bevy::{prelude::*, utils::hashbrown::HashMap};
+use bevy_replicon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::prelude::*;
+
+/// Sent by a defeated player to give up their seat and keep watching the
+/// match as a spectator
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Surrender;
+
+/// Trigger this on the server to move a player to spectator, e.g. when the
+/// game rules decide they were eliminated
+#[derive(Event, Debug, Clone, Copy)]
+pub struct MakeSpectator(pub ClientId);
+
+/// Broadcast by the server when a player becomes a spectator.  From `tick`
+/// on the server no longer waits for their commands, and any commands they
+/// send are ignored.
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct PlayerBecameSpectator {
+    pub client: ClientId,
+    pub tick: SimTick,
+}
+
+/// Players that became spectators, with the tick they stopped playing at.
+/// Kept on all peers.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct LockstepSpectators(HashMap<ClientId, SimTick>);
+
+impl LockstepSpectators {
+    /// True if the client no longer plays at the given tick
+    pub fn is_spectating(&self, client: ClientId, tick: SimTick) -> bool {
+        self.get(&client).is_some_and(|&from| tick >= from)
+    }
+}
+
+pub(super) fn receive_surrender(
+    trigger: Trigger<FromClient<Surrender>>,
+    clients: LockstepClients,
+    mut commands: Commands,
+) {
+    let Some(client_id) = clients.client_id(trigger.client_entity) else { return };
+    info!("client {} surrendered", client_id);
+    commands.trigger(MakeSpectator(client_id));
+}
+
+pub(super) fn make_spectator(
+    trigger: Trigger<MakeSpectator>,
+    mut commands: Commands,
+    server: Res<RepliconServer>,
+    sim_tick: Res<SimulationTick>,
+    settings: Res<SimulationSettings>,
+    mut spectators: ResMut<LockstepSpectators>,
+) {
+    if !server.is_running() { return }
+    let client = trigger.event().0;
+    if spectators.contains_key(&client) { return }
+    // Same tick that commands issued now would execute on, so every peer
+    // sees the role change at the same point of the simulation
+    let tick = sim_tick.0 + 1 + settings.base_input_tick_delay as SimTick;
+    spectators.insert(client, tick);
+    commands.server_trigger(ToClients {
+        mode: SendMode::Broadcast,
+        event: PlayerBecameSpectator { client, tick },
+    });
+}
+
+pub(super) fn receive_player_became_spectator(
+    trigger: Trigger<PlayerBecameSpectator>,
+    mut spectators: ResMut<LockstepSpectators>,
+) {
+    let event = trigger.event();
+    debug!("client {} spectating from tick {}", event.client, event.tick);
+    spectators.insert(event.client, event.tick);
+}
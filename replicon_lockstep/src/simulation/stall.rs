@@ -0,0 +1,68 @@
+use std::time::Duration;
+use bevy::prelude::*;
+use crate::prelude::*;
+
+/// How long the simulation has gone without a new tick, kept on every peer
+/// while Running.  Presentation layers can use it to keep cameras, UI and
+/// audio alive and show an indicator instead of appearing frozen.
+#[derive(Resource, Debug, Clone, Copy, Default)]
+pub struct TickStall {
+    /// The last tick seen
+    pub tick: SimTick,
+    /// Wall clock time since `tick` arrived
+    pub stalled_for: Duration,
+    /// True from when `stalled_for` passes
+    /// `SimulationSettings::stall_notify_threshold` until the next tick
+    pub frozen: bool,
+}
+
+/// A trigger that fires when no tick has arrived for longer than
+/// `SimulationSettings::stall_notify_threshold`.  Interpolation of simulated
+/// state should hold still from here on.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct PresentationFreeze {
+    pub tick: SimTick,
+}
+
+/// A trigger that fires when ticks arrive again after a [`PresentationFreeze`]
+#[derive(Event, Debug, Clone, Copy)]
+pub struct PresentationResume {
+    pub tick: SimTick,
+    pub stalled_for: Duration,
+}
+
+pub(super) fn reset_tick_stall(
+    mut commands: Commands,
+    mut stall: ResMut<TickStall>,
+    sim_tick: Option<Res<SimulationTick>>,
+) {
+    let tick = sim_tick.map_or(0, |tick| **tick);
+    // E.g. frozen before a pause, which is over now
+    if stall.frozen {
+        commands.trigger(PresentationResume { tick, stalled_for: stall.stalled_for });
+    }
+    *stall = TickStall { tick, ..default() };
+}
+
+pub(super) fn track_tick_stall(
+    mut commands: Commands,
+    mut stall: ResMut<TickStall>,
+    sim_tick: Res<SimulationTick>,
+    settings: Res<SimulationSettings>,
+    time: Res<Time<Real>>,
+) {
+    if **sim_tick != stall.tick {
+        if stall.frozen {
+            debug!("ticks resumed after {:?}", stall.stalled_for);
+            commands.trigger(PresentationResume { tick: **sim_tick, stalled_for: stall.stalled_for });
+        }
+        *stall = TickStall { tick: **sim_tick, ..default() };
+        return;
+    }
+    stall.stalled_for += time.delta();
+    if !stall.frozen && stall.stalled_for > settings.stall_notify_threshold {
+        debug!("no tick since {} for {:?}", stall.tick, stall.stalled_for);
+        stall.frozen = true;
+        commands.trigger(PresentationFreeze { tick: stall.tick });
+    }
+}
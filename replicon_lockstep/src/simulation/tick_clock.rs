@@ -0,0 +1,150 @@
+use std::{
+    sync::{atomic::{AtomicBool, Ordering}, mpsc, Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use bevy::{ecs::schedule::ScheduleLabel, prelude::*};
+use crate::prelude::*;
+
+/// What paces tick production on the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TickClock {
+    /// Ticks are produced in `FixedPostUpdate`, following `Time<Fixed>`
+    #[default]
+    Fixed,
+    /// A dedicated thread keeps wall clock time and sends one pulse per tick
+    /// into the app.  Pulses are independent of how long unrelated frame
+    /// work takes, so tick cadence has less jitter on busy dedicated
+    /// servers.  With [`HeadlessServerPlugins`](crate::HeadlessServerPlugins)
+    /// every pulse wakes the app loop, so its tick runs when the pulse is
+    /// sent instead of with the next frame.  The thread is named
+    /// `lockstep-tick-clock` so it can be given a higher priority with
+    /// platform tools.
+    Dedicated,
+}
+
+/// Schedule that produces one server tick.  Run from `FixedPostUpdate` or
+/// once per pulse of the dedicated tick clock.
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LockstepServerTick;
+
+/// Handle of the dedicated tick clock thread
+#[derive(Resource)]
+pub(super) struct TickClockThread {
+    pulses: Mutex<mpsc::Receiver<Instant>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Drop for TickClockThread {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Wakes a sleeping app loop when the dedicated tick clock sends a pulse.
+/// Inserted by [`HeadlessServerPlugins`](crate::HeadlessServerPlugins).
+#[derive(Resource, Clone, Default)]
+pub struct TickClockWaker(Arc<(Mutex<bool>, Condvar)>);
+
+impl TickClockWaker {
+    fn wake(&self) {
+        let (pulsed, condvar) = &*self.0;
+        if let Ok(mut pulsed) = pulsed.lock() {
+            *pulsed = true;
+            condvar.notify_one();
+        }
+    }
+
+    /// Sleeps until a pulse was sent since the last call, or for `timeout`
+    pub fn wait(&self, timeout: Duration) {
+        let (pulsed, condvar) = &*self.0;
+        let Ok(guard) = pulsed.lock() else { return };
+        let Ok((mut guard, _)) = condvar.wait_timeout_while(guard, timeout, |pulsed| !*pulsed) else { return };
+        *guard = false;
+    }
+}
+
+fn uses_clock(clock: TickClock) -> impl Fn(Res<SimulationSettings>) -> bool {
+    move |settings: Res<SimulationSettings>| settings.tick_clock == clock
+}
+
+pub(super) fn fixed_clock() -> impl Fn(Res<SimulationSettings>) -> bool {
+    uses_clock(TickClock::Fixed)
+}
+
+pub(super) fn dedicated_clock() -> impl Fn(Res<SimulationSettings>) -> bool {
+    uses_clock(TickClock::Dedicated)
+}
+
+pub(super) fn run_fixed_tick(world: &mut World) {
+    world.run_schedule(LockstepServerTick);
+}
+
+pub(super) fn start_tick_clock(
+    mut commands: Commands,
+    settings: Res<SimulationSettings>,
+    waker: Option<Res<TickClockWaker>>,
+) {
+    spawn_tick_clock(&mut commands, settings.tick_timestep, waker.as_deref().cloned());
+}
+
+/// Restarts a running tick clock when a vote or the server changed the tick
+/// timestep
+pub(super) fn restart_tick_clock(
+    trigger: Trigger<SettingsChanged>,
+    mut commands: Commands,
+    clock: Option<Res<TickClockThread>>,
+    waker: Option<Res<TickClockWaker>>,
+) {
+    let SettingsChange::TickTimestep(timestep) = trigger.event().change else { return };
+    if clock.is_none() { return }
+    // Replacing the resource drops the old handle, which stops its thread
+    spawn_tick_clock(&mut commands, timestep, waker.as_deref().cloned());
+}
+
+fn spawn_tick_clock(commands: &mut Commands, timestep: Duration, waker: Option<TickClockWaker>) {
+    let (sender, receiver) = mpsc::channel();
+    let stop = Arc::new(AtomicBool::new(false));
+    let timestep = timestep.max(Duration::from_micros(100));
+    let thread_stop = stop.clone();
+    let spawned = thread::Builder::new()
+        .name("lockstep-tick-clock".into())
+        .spawn(move || {
+            let mut next = Instant::now() + timestep;
+            while !thread_stop.load(Ordering::Relaxed) {
+                // Sleep most of the way, then spin for precision
+                let now = Instant::now();
+                if next > now + Duration::from_millis(1) {
+                    thread::sleep(next - now - Duration::from_millis(1));
+                    continue;
+                }
+                while Instant::now() < next { std::hint::spin_loop() }
+                if sender.send(next).is_err() { break }
+                if let Some(waker) = &waker { waker.wake() }
+                next += timestep;
+            }
+        });
+    match spawned {
+        Ok(_) => commands.insert_resource(TickClockThread { pulses: Mutex::new(receiver), stop }),
+        Err(err) => error!("Failed to start tick clock thread: {}", err),
+    }
+}
+
+pub(super) fn stop_tick_clock(mut commands: Commands) {
+    commands.remove_resource::<TickClockThread>();
+}
+
+/// Runs one server tick for every pulse received since the last frame.
+/// Woken by the pulses, that's one per frame unless frame work overran.
+pub(super) fn run_dedicated_ticks(world: &mut World) {
+    let pulses: Vec<Instant> = match world.get_resource::<TickClockThread>() {
+        Some(clock) => clock.pulses.lock().map_or_else(|_| Vec::new(), |pulses| pulses.try_iter().collect()),
+        None => return,
+    };
+    if pulses.len() > 1 {
+        trace!("running {} ticks from the tick clock, the first {:?} late", pulses.len(), pulses[0].elapsed());
+    }
+    for _ in pulses {
+        world.run_schedule(LockstepServerTick);
+    }
+}
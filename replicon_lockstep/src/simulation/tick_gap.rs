@@ -0,0 +1,53 @@
+use std::time::Duration;
+use bevy::prelude::*;
+use crate::{prelude::*, commands::catch_up::{self, PendingCatchUp}};
+
+/// A trigger that fires on a client when ticks it skipped didn't arrive
+/// within `SimulationSettings::tick_gap_timeout`.  The ticks after the gap
+/// are held until they do, and the missing ticks are requested again every
+/// timeout.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct TickGapTimedOut {
+    pub first_missing: SimTick,
+    /// The latest tick received from the server
+    pub received_up_to: SimTick,
+}
+
+/// When the client noticed ticks missing mid-match
+#[derive(Resource, Default)]
+pub(crate) struct TickGap {
+    since: Option<Duration>,
+    first_missing: SimTick,
+    reported: bool,
+}
+
+impl TickGap {
+    pub(super) fn open(&mut self, first_missing: SimTick, now: Duration) {
+        if self.since.is_some() { return }
+        debug!("ticks from {} are missing", first_missing);
+        *self = Self { since: Some(now), first_missing, reported: false };
+    }
+}
+
+pub(super) fn check_tick_gap(
+    mut commands: Commands,
+    mut gap: ResMut<TickGap>,
+    pending: Res<PendingCatchUp>,
+    sim_tick: Res<SimulationTick>,
+    settings: Res<SimulationSettings>,
+    time: Res<Time<Real>>,
+) {
+    let Some(since) = gap.since else { return };
+    if !pending.is_catching_up() {
+        trace!("ticks from {} arrived", gap.first_missing);
+        *gap = TickGap::default();
+        return;
+    }
+    if time.elapsed() - since < settings.tick_gap_timeout { return }
+    gap.since = Some(time.elapsed());
+    catch_up::request_again(&mut commands, &pending);
+    if gap.reported { return }
+    gap.reported = true;
+    error!("Ticks from {} still missing after {:?}, requesting them again", gap.first_missing, settings.tick_gap_timeout);
+    commands.trigger(TickGapTimedOut { first_missing: gap.first_missing, received_up_to: **sim_tick });
+}
@@ -0,0 +1,34 @@
+use std::time::Duration;
+use bevy::prelude::*;
+use bevy_replicon::prelude::*;
+use crate::prelude::*;
+use super::votes::broadcast_server_change;
+
+/// Trigger this on the server to change `SimulationSettings::tick_timestep`
+/// without a vote, e.g. to slow the simulation down while every client
+/// struggles to keep up.  Every peer switches on the same tick, like for a
+/// passed vote.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct RequestTickRate {
+    pub timestep: Duration,
+}
+
+pub(super) fn request_tick_rate(
+    trigger: Trigger<RequestTickRate>,
+    mut commands: Commands,
+    server: Res<RepliconServer>,
+    state: Res<State<SimulationState>>,
+    sim_tick: Res<SimulationTick>,
+    settings: Res<SimulationSettings>,
+) {
+    if !server.is_running() {
+        warn!("Only the server can change the tick rate");
+        return;
+    }
+    let timestep = trigger.timestep;
+    if timestep.is_zero() || !matches!(state.get(), SimulationState::Running | SimulationState::Paused) {
+        warn!("Can't change the tick timestep to {:?} in state {:?}", timestep, state.get());
+        return;
+    }
+    broadcast_server_change(&mut commands, **sim_tick, &settings, SettingsChange::TickTimestep(timestep));
+}
@@ -0,0 +1,306 @@
+use std::time::Duration;
+use bevy::{prelude::*, utils::hashbrown::HashMap};
+use bevy_replicon::{prelude::*, shared::backend::connected_client::NetworkId};
+use serde::{Deserialize, Serialize};
+use crate::prelude::*;
+use super::ServerSendCommands;
+
+/// The settings players may change mid-match by vote
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SettingsChange {
+    /// See `SimulationSettings::disconnect_tick_threshold`
+    DisconnectTickThreshold(u8),
+    /// See `SimulationSettings::connection_check_tick_delay`
+    ConnectionCheckTickDelay(u32),
+    /// See `SimulationSettings::base_input_tick_delay`
+    BaseInputTickDelay(u8),
+    /// See `SimulationSettings::tick_timestep`
+    TickTimestep(Duration),
+    /// See `SimulationSettings::command_coalescing_window`
+    CommandCoalescingWindow(Duration),
+}
+
+impl SettingsChange {
+    pub(crate) fn apply(&self, settings: &mut SimulationSettings) {
+        match *self {
+            Self::DisconnectTickThreshold(value) => settings.disconnect_tick_threshold = value,
+            Self::ConnectionCheckTickDelay(value) => settings.connection_check_tick_delay = value,
+            Self::BaseInputTickDelay(value) => settings.base_input_tick_delay = value,
+            Self::TickTimestep(value) => settings.tick_timestep = value,
+            Self::CommandCoalescingWindow(value) => settings.command_coalescing_window = value,
+        }
+    }
+
+    /// The current value of every setting that can change mid-match
+    pub(crate) fn current(settings: &SimulationSettings) -> Vec<Self> {
+        vec![
+            Self::DisconnectTickThreshold(settings.disconnect_tick_threshold),
+            Self::ConnectionCheckTickDelay(settings.connection_check_tick_delay),
+            Self::BaseInputTickDelay(settings.base_input_tick_delay),
+            Self::TickTimestep(settings.tick_timestep),
+            Self::CommandCoalescingWindow(settings.command_coalescing_window),
+        ]
+    }
+}
+
+/// How many players have to approve a settings change
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VoteRule {
+    /// More than half of the players
+    #[default]
+    Majority,
+    Unanimous,
+}
+
+impl VoteRule {
+    pub(super) fn passes(&self, approvals: usize, players: usize) -> bool {
+        match self {
+            Self::Majority => approvals * 2 > players,
+            Self::Unanimous => approvals >= players,
+        }
+    }
+}
+
+/// Sent by a client to propose a settings change.  The proposer's vote
+/// counts as an approval.
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ProposeSettingsChange(pub SettingsChange);
+
+/// Sent by a client to vote on the active proposal
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct CastSettingsVote {
+    pub vote: u32,
+    pub approve: bool,
+}
+
+/// Broadcast by the server when a vote starts.  Votes arriving after the
+/// server reaches `deadline` are not counted.
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct SettingsVoteStarted {
+    pub vote: u32,
+    pub change: SettingsChange,
+    pub proposer: ClientId,
+    pub deadline: SimTick,
+}
+
+/// Broadcast by the server when a vote ends.  If it passed, every peer
+/// applies the change once it reaches `effective_tick`.  Vote 0 is a change
+/// the server made on its own, e.g. a [`BandwidthConstrained`] mitigation.
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct SettingsVoteResolved {
+    pub vote: u32,
+    pub change: SettingsChange,
+    pub passed: bool,
+    pub effective_tick: SimTick,
+}
+
+/// A trigger that fires on every peer when a voted settings change is applied
+#[derive(Event, Debug, Clone, Copy)]
+pub struct SettingsChanged {
+    pub change: SettingsChange,
+    pub tick: SimTick,
+}
+
+/// The vote in progress on the server
+#[derive(Resource, Default)]
+pub(super) struct ActiveSettingsVote {
+    next_id: u32,
+    current: Option<(SettingsVoteStarted, HashMap<ClientId, bool>)>,
+}
+
+/// Passed changes waiting for their effective tick
+#[derive(Resource, Default, Deref, DerefMut)]
+pub(crate) struct PendingSettingsChanges(Vec<(SimTick, SettingsChange)>);
+
+/// The settings the match started with and every passed change with its
+/// effective tick.  The live settings change when the effective tick is
+/// broadcast, but a tick applied later, e.g. while catching up, has to be
+/// simulated with the settings of its own tick.
+#[derive(Resource, Default, Debug, Clone)]
+pub(crate) struct SettingsTimeline {
+    pub(crate) initial: Vec<SettingsChange>,
+    pub(crate) changes: Vec<(SimTick, SettingsChange)>,
+}
+
+impl SettingsTimeline {
+    pub(crate) fn new(settings: &SimulationSettings) -> Self {
+        Self { initial: SettingsChange::current(settings), changes: Vec::new() }
+    }
+
+    /// `settings` as they were when the match started
+    pub(crate) fn initial_settings(&self, settings: &SimulationSettings) -> SimulationSettings {
+        let mut initial = settings.clone();
+        for change in self.initial.iter() {
+            change.apply(&mut initial);
+        }
+        initial
+    }
+
+    /// The tick timestep `tick` is simulated with, if the timeline knows it
+    pub(crate) fn tick_timestep(&self, tick: SimTick) -> Option<Duration> {
+        let changes = self.changes.iter().filter(|(effective_tick, _)| *effective_tick <= tick).map(|(_, change)| change);
+        self.initial.iter().chain(changes).fold(None, |timestep, change| match *change {
+            SettingsChange::TickTimestep(value) => Some(value),
+            _ => timestep,
+        })
+    }
+}
+
+pub(super) fn receive_proposal(
+    trigger: Trigger<FromClient<ProposeSettingsChange>>,
+    mut commands: Commands,
+    clients: LockstepClients,
+    sim_tick: Res<SimulationTick>,
+    settings: Res<SimulationSettings>,
+    mut active: ResMut<ActiveSettingsVote>,
+) {
+    let Some(proposer) = clients.client_id(trigger.client_entity) else { return };
+    let deadline = **sim_tick + settings.settings_vote_duration;
+    start_vote(&mut commands, &mut active, proposer, trigger.event.0, deadline);
+}
+
+/// Starts a vote on behalf of `proposer` unless one is already in progress
+pub(super) fn start_vote(
+    commands: &mut Commands,
+    active: &mut ActiveSettingsVote,
+    proposer: ClientId,
+    change: SettingsChange,
+    deadline: SimTick,
+) -> bool {
+    if active.current.is_some() {
+        debug!("ignoring proposal from client {}, a vote is in progress", proposer);
+        return false;
+    }
+    active.next_id += 1;
+    let started = SettingsVoteStarted { vote: active.next_id, change, proposer, deadline };
+    info!("client {} proposed {:?}", proposer, started.change);
+    active.current = Some((started, HashMap::from([(proposer, true)])));
+    commands.server_trigger(ToClients { mode: SendMode::Broadcast, event: started });
+    true
+}
+
+pub(super) fn receive_vote(
+    trigger: Trigger<FromClient<CastSettingsVote>>,
+    clients: LockstepClients,
+    mut active: ResMut<ActiveSettingsVote>,
+) {
+    let Some(voter) = clients.client_id(trigger.client_entity) else { return };
+    let Some((started, votes)) = active.current.as_mut() else { return };
+    if started.vote != trigger.event.vote { return }
+    votes.insert(voter, trigger.event.approve);
+}
+
+/// Ends the active vote once its outcome is certain or its deadline passed
+pub(super) fn resolve_settings_vote(
+    mut commands: Commands,
+    clients: Query<&NetworkId>,
+    spectators: Res<LockstepSpectators>,
+    sim_tick: Res<SimulationTick>,
+    settings: Res<SimulationSettings>,
+    mut active: ResMut<ActiveSettingsVote>,
+) {
+    let Some((started, votes)) = active.current.as_ref() else { return };
+    let players: Vec<ClientId> = clients
+        .iter()
+        .map(|id| id.get())
+        .filter(|&id| !spectators.is_spectating(id, **sim_tick))
+        .collect();
+    let approvals = players.iter().filter(|id| votes.get(*id) == Some(&true)).count();
+    let undecided = players.iter().filter(|id| !votes.contains_key(*id)).count();
+    let rule = settings.settings_vote_rule;
+    let passed = rule.passes(approvals, players.len());
+    let failed = !rule.passes(approvals + undecided, players.len()) || **sim_tick >= started.deadline;
+    if !passed && !failed { return }
+
+    let resolved = SettingsVoteResolved {
+        vote: started.vote,
+        change: started.change,
+        passed,
+        effective_tick: **sim_tick + 1 + settings.base_input_tick_delay as SimTick,
+    };
+    info!("vote {} on {:?} {}", resolved.vote, resolved.change, if passed { "passed" } else { "failed" });
+    active.current = None;
+    commands.server_trigger(ToClients { mode: SendMode::Broadcast, event: resolved });
+}
+
+/// Applies a change on every peer without a vote
+pub(super) fn broadcast_server_change(
+    commands: &mut Commands,
+    sim_tick: SimTick,
+    settings: &SimulationSettings,
+    change: SettingsChange,
+) {
+    let resolved = SettingsVoteResolved {
+        vote: 0,
+        change,
+        passed: true,
+        effective_tick: sim_tick + 1 + settings.base_input_tick_delay as SimTick,
+    };
+    info!("server changed {:?}", change);
+    commands.server_trigger(ToClients { mode: SendMode::Broadcast, event: resolved });
+}
+
+pub(super) fn receive_vote_resolved(
+    trigger: Trigger<SettingsVoteResolved>,
+    mut pending: ResMut<PendingSettingsChanges>,
+    mut timeline: ResMut<SettingsTimeline>,
+) {
+    let resolved = trigger.event();
+    if resolved.passed {
+        pending.push((resolved.effective_tick, resolved.change));
+        timeline.changes.push((resolved.effective_tick, resolved.change));
+    }
+}
+
+/// Applies passed changes when their effective tick arrives
+pub(super) fn apply_settings_changes(
+    tick: Trigger<ServerSendCommands>,
+    mut commands: Commands,
+    mut pending: ResMut<PendingSettingsChanges>,
+    mut settings: ResMut<SimulationSettings>,
+    mut fixed_time: ResMut<Time<Fixed>>,
+) {
+    let tick = tick.tick;
+    pending.retain(|(effective_tick, change)| {
+        if *effective_tick > tick { return true }
+        apply_change(*change, &mut settings, &mut fixed_time);
+        commands.trigger(SettingsChanged { change: *change, tick });
+        false
+    });
+}
+
+fn apply_change(change: SettingsChange, settings: &mut SimulationSettings, fixed_time: &mut Time<Fixed>) {
+    change.apply(settings);
+    if let SettingsChange::TickTimestep(timestep) = change {
+        fixed_time.set_timestep(timestep);
+    }
+}
+
+/// A resyncing client missed the changes made while it was away, or joined
+/// after them.  It takes the server's timeline, so the ticks it fast-forwards
+/// through are simulated with their own settings, and the live settings as
+/// of the last tick sent.
+pub(super) fn receive_resync_settings(
+    trigger: Trigger<ResyncStarted>,
+    server: Res<RepliconServer>,
+    mut pending: ResMut<PendingSettingsChanges>,
+    mut timeline: ResMut<SettingsTimeline>,
+    mut settings: ResMut<SimulationSettings>,
+    mut fixed_time: ResMut<Time<Fixed>>,
+) {
+    if server.is_running() { return }
+    let started = trigger.event();
+    for &change in started.initial_settings.iter() {
+        apply_change(change, &mut settings, &mut fixed_time);
+    }
+    pending.clear();
+    for &(effective_tick, change) in started.settings_changes.iter() {
+        if effective_tick <= started.to_tick {
+            apply_change(change, &mut settings, &mut fixed_time);
+        } else {
+            pending.push((effective_tick, change));
+        }
+    }
+    timeline.initial = started.initial_settings.clone();
+    timeline.changes = started.settings_changes.clone();
+}
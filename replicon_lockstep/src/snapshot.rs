@@ -0,0 +1,457 @@
+//! Streaming large world snapshots, e.g. for late joiners of big worlds,
+//! without stalling the live session.  The game serializes its world however
+//! it likes and triggers [`SendSnapshot`] on the server.  The bytes are sent
+//! in chunks, at most `SnapshotSettings::window` unacknowledged at a time, a
+//! few per frame.  The client verifies the assembled snapshot against the
+//! hash in the offer and triggers [`SnapshotReceived`].  A transfer
+//! interrupted by a brief drop continues where it stopped once the client
+//! reconnects.
+
+use std::{collections::BTreeMap, hash::Hasher, sync::Arc, time::Duration};
+use bevy::{
+    prelude::*,
+    tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task},
+    time::Stopwatch,
+    utils::hashbrown::HashMap,
+};
+use bevy_replicon::{prelude::*, shared::backend::connected_client::NetworkId};
+use serde::{Deserialize, Serialize};
+use crate::{prelude::*, hashing::{stable_hash, StableHasher}};
+
+/// Chunked snapshot transfers from the server to clients
+#[derive(Default)]
+pub struct SnapshotStreamingPlugin {
+    pub settings: SnapshotSettings,
+}
+
+impl Plugin for SnapshotStreamingPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(self.settings.clone())
+            .init_resource::<OutgoingSnapshots>()
+            .init_resource::<IncomingSnapshot>()
+            .add_server_trigger::<SnapshotOffer>(Channel::Ordered)
+            .add_server_trigger::<SnapshotChunk>(Channel::Ordered)
+            .add_client_trigger::<SnapshotAck>(Channel::Unordered)
+            .add_client_trigger::<ResumeSnapshot>(Channel::Ordered)
+            .add_observer(send_snapshot)
+            .add_observer(receive_snapshot_ack)
+            .add_observer(resume_outgoing_snapshot)
+            .add_observer(receive_snapshot_offer)
+            .add_observer(receive_snapshot_chunk)
+            .add_systems(Update, (
+                stream_snapshots.run_if(server_running),
+                resume_incoming_snapshot.run_if(not(server_running)),
+            ));
+    }
+}
+
+#[derive(Resource, Debug, Clone)]
+pub struct SnapshotSettings {
+    /// Bytes per chunk message
+    pub chunk_size: usize,
+    /// Chunks sent ahead of the last acknowledged one
+    pub window: u32,
+    /// Chunks sent per transfer and frame at most
+    pub chunks_per_frame: u32,
+    /// How long the server keeps a transfer to a disconnected client around
+    /// for it to resume
+    pub resume_timeout: Duration,
+    /// Transfers that fail verification are restarted this many times
+    pub max_attempts: u32,
+    /// Store every snapshot the server sends, named by tick, with the
+    /// [`StoragePlugin`]
+    pub store_sent: bool,
+}
+
+impl Default for SnapshotSettings {
+    fn default() -> Self {
+        Self {
+            chunk_size: 16 * 1024,
+            window: 64,
+            chunks_per_frame: 16,
+            resume_timeout: Duration::from_secs(10),
+            max_attempts: 3,
+            store_sent: false,
+        }
+    }
+}
+
+/// Trigger this on the server to stream a snapshot of the world at `tick`
+/// to a client
+#[derive(Event, Debug, Clone)]
+pub struct SendSnapshot {
+    pub client: ClientId,
+    pub tick: SimTick,
+    pub data: Arc<Vec<u8>>,
+}
+
+/// A trigger that fires on the server as a client acknowledges chunks
+#[derive(Event, Debug, Clone, Copy)]
+pub struct SnapshotSendProgress {
+    pub client: ClientId,
+    pub tick: SimTick,
+    pub acked_bytes: usize,
+    pub total_bytes: usize,
+}
+
+/// A trigger that fires on a client for every chunk that extends the
+/// assembled part of a snapshot
+#[derive(Event, Debug, Clone, Copy)]
+pub struct SnapshotProgress {
+    pub tick: SimTick,
+    pub received_bytes: usize,
+    pub total_bytes: usize,
+}
+
+/// A trigger that fires on a client with a complete and verified snapshot
+#[derive(Event, Debug, Clone)]
+pub struct SnapshotReceived {
+    pub tick: SimTick,
+    pub data: Arc<Vec<u8>>,
+}
+
+/// Why a snapshot transfer was given up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// The client didn't reconnect within `SnapshotSettings::resume_timeout`
+    ClientGone,
+    /// The assembled snapshot didn't match the hash `max_attempts` times
+    Corrupt,
+}
+
+/// A trigger that fires on the server, and for corrupt snapshots on the
+/// client, when a transfer is given up
+#[derive(Event, Debug, Clone, Copy)]
+pub struct SnapshotTransferFailed {
+    pub tick: SimTick,
+    pub error: SnapshotError,
+}
+
+/// Announces a transfer to the client
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+struct SnapshotOffer {
+    transfer: u32,
+    tick: SimTick,
+    total_bytes: usize,
+    chunk_size: usize,
+    /// Chunks the client should acknowledge at a time
+    ack_interval: u32,
+    hash: u64,
+}
+
+#[derive(Event, Serialize, Deserialize, Debug, Clone)]
+struct SnapshotChunk {
+    transfer: u32,
+    index: u32,
+    data: Vec<u8>,
+}
+
+/// The number of chunks a client has assembled
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+struct SnapshotAck {
+    transfer: u32,
+    received: u32,
+}
+
+/// Sent by a client after reconnecting, or to restart a corrupt transfer
+#[derive(Event, Serialize, Deserialize, Debug, Clone, Copy)]
+struct ResumeSnapshot {
+    transfer: u32,
+    received: u32,
+    restart: bool,
+}
+
+struct OutgoingSnapshot {
+    client: Entity,
+    client_id: ClientId,
+    tick: SimTick,
+    data: Arc<Vec<u8>>,
+    /// Hashing a large snapshot runs in the background, the offer is sent
+    /// once it's done
+    hash_task: Option<Task<u64>>,
+    next_chunk: u32,
+    acked: u32,
+    attempts: u32,
+    /// Time since the client disconnected
+    gone_for: Option<Stopwatch>,
+}
+
+impl OutgoingSnapshot {
+    fn num_chunks(&self, chunk_size: usize) -> u32 {
+        self.data.len().div_ceil(chunk_size.max(1)) as u32
+    }
+}
+
+/// Server-side transfers by id
+#[derive(Resource, Default)]
+struct OutgoingSnapshots {
+    next_transfer: u32,
+    transfers: HashMap<u32, OutgoingSnapshot>,
+}
+
+struct PartialSnapshot {
+    transfer: u32,
+    tick: SimTick,
+    total_bytes: usize,
+    chunk_size: usize,
+    ack_interval: u32,
+    hash: u64,
+    data: Vec<u8>,
+    /// Chunks that arrived ahead of the assembled part
+    ahead: BTreeMap<u32, Vec<u8>>,
+    hasher: StableHasher,
+    attempts: u32,
+}
+
+impl PartialSnapshot {
+    fn received_chunks(&self) -> u32 {
+        self.data.len().div_ceil(self.chunk_size.max(1)) as u32
+    }
+
+    fn is_complete(&self) -> bool {
+        self.data.len() >= self.total_bytes
+    }
+}
+
+/// The transfer a client is receiving
+#[derive(Resource, Default)]
+struct IncomingSnapshot(Option<PartialSnapshot>);
+
+fn send_snapshot(
+    trigger: Trigger<SendSnapshot>,
+    mut commands: Commands,
+    mut snapshots: ResMut<OutgoingSnapshots>,
+    clients: Query<(Entity, &NetworkId)>,
+    settings: Res<SnapshotSettings>,
+) {
+    let request = trigger.event();
+    let Some((client, _)) = clients.iter().find(|(_, id)| id.get() == request.client) else {
+        warn!("can't send a snapshot to unknown client {}", request.client);
+        return;
+    };
+    if settings.store_sent {
+        commands.trigger(StoreArtifact {
+            kind: ArtifactKind::Snapshot,
+            name: request.tick.to_string(),
+            data: request.data.clone(),
+        });
+    }
+    let data = request.data.clone();
+    let hash_task = AsyncComputeTaskPool::get().spawn(async move { stable_hash(&data) });
+    let transfer = snapshots.next_transfer;
+    snapshots.next_transfer = snapshots.next_transfer.wrapping_add(1);
+    debug!("streaming {} byte snapshot of tick {} to client {}", request.data.len(), request.tick, request.client);
+    snapshots.transfers.insert(transfer, OutgoingSnapshot {
+        client,
+        client_id: request.client,
+        tick: request.tick,
+        data: request.data.clone(),
+        hash_task: Some(hash_task),
+        next_chunk: 0,
+        acked: 0,
+        attempts: 1,
+        gone_for: None,
+    });
+}
+
+fn stream_snapshots(
+    mut commands: Commands,
+    mut snapshots: ResMut<OutgoingSnapshots>,
+    settings: Res<SnapshotSettings>,
+    clients: Query<&NetworkId>,
+    time: Res<Time<Real>>,
+) {
+    let mut given_up = Vec::new();
+    for (&transfer, snapshot) in snapshots.transfers.iter_mut() {
+        if let Some(task) = &mut snapshot.hash_task {
+            let Some(hash) = block_on(future::poll_once(task)) else { continue };
+            snapshot.hash_task = None;
+            commands.server_trigger(ToClients {
+                mode: SendMode::Direct(snapshot.client),
+                event: SnapshotOffer {
+                    transfer,
+                    tick: snapshot.tick,
+                    total_bytes: snapshot.data.len(),
+                    chunk_size: settings.chunk_size,
+                    ack_interval: (settings.window / 4).max(1),
+                    hash,
+                },
+            });
+        }
+        if !clients.contains(snapshot.client) {
+            let gone_for = snapshot.gone_for.get_or_insert_with(Stopwatch::new);
+            gone_for.tick(time.delta());
+            if gone_for.elapsed() >= settings.resume_timeout {
+                given_up.push(transfer);
+            }
+            continue;
+        }
+        let num_chunks = snapshot.num_chunks(settings.chunk_size);
+        let last = num_chunks
+            .min(snapshot.acked + settings.window.max(1))
+            .min(snapshot.next_chunk + settings.chunks_per_frame.max(1));
+        for index in snapshot.next_chunk..last {
+            let start = index as usize * settings.chunk_size;
+            let end = (start + settings.chunk_size).min(snapshot.data.len());
+            commands.server_trigger(ToClients {
+                mode: SendMode::Direct(snapshot.client),
+                event: SnapshotChunk { transfer, index, data: snapshot.data[start..end].to_vec() },
+            });
+        }
+        snapshot.next_chunk = snapshot.next_chunk.max(last);
+    }
+    for transfer in given_up {
+        let Some(snapshot) = snapshots.transfers.remove(&transfer) else { continue };
+        warn!("client {} didn't come back for the snapshot of tick {}", snapshot.client_id, snapshot.tick);
+        commands.trigger(SnapshotTransferFailed { tick: snapshot.tick, error: SnapshotError::ClientGone });
+    }
+}
+
+fn receive_snapshot_ack(
+    trigger: Trigger<FromClient<SnapshotAck>>,
+    mut commands: Commands,
+    mut snapshots: ResMut<OutgoingSnapshots>,
+    settings: Res<SnapshotSettings>,
+) {
+    let SnapshotAck { transfer, received } = trigger.event;
+    let Some(snapshot) = snapshots.transfers.get_mut(&transfer) else { return };
+    if trigger.client_entity != snapshot.client || received <= snapshot.acked { return }
+    snapshot.acked = received.min(snapshot.num_chunks(settings.chunk_size));
+    let total_bytes = snapshot.data.len();
+    let acked_bytes = (snapshot.acked as usize * settings.chunk_size).min(total_bytes);
+    commands.trigger(SnapshotSendProgress { client: snapshot.client_id, tick: snapshot.tick, acked_bytes, total_bytes });
+    if acked_bytes == total_bytes {
+        debug!("client {} received the snapshot of tick {}", snapshot.client_id, snapshot.tick);
+        snapshots.transfers.remove(&transfer);
+    }
+}
+
+fn resume_outgoing_snapshot(
+    trigger: Trigger<FromClient<ResumeSnapshot>>,
+    mut commands: Commands,
+    mut snapshots: ResMut<OutgoingSnapshots>,
+    settings: Res<SnapshotSettings>,
+    clients: LockstepClients,
+) {
+    let ResumeSnapshot { transfer, received, restart } = trigger.event;
+    let Some(snapshot) = snapshots.transfers.get_mut(&transfer) else {
+        debug!("can't resume unknown snapshot transfer {}", transfer);
+        return;
+    };
+    // Only the client the snapshot is for may take the transfer over
+    if clients.client_id(trigger.client_entity) != Some(snapshot.client_id) {
+        warn!("ignoring resume of snapshot transfer {} by another client", transfer);
+        return;
+    }
+    if restart {
+        if snapshot.attempts >= settings.max_attempts {
+            let tick = snapshot.tick;
+            snapshots.transfers.remove(&transfer);
+            commands.trigger(SnapshotTransferFailed { tick, error: SnapshotError::Corrupt });
+            return;
+        }
+        snapshot.attempts += 1;
+    }
+    let Some(client) = clients.entity(trigger.client_entity) else { return };
+    let num_chunks = snapshot.num_chunks(settings.chunk_size);
+    let received = received.min(num_chunks);
+    if received == num_chunks && !restart {
+        debug!("client {} already received the snapshot of tick {}", snapshot.client_id, snapshot.tick);
+        snapshots.transfers.remove(&transfer);
+        return;
+    }
+    debug!("resuming snapshot transfer {} from chunk {}", transfer, received);
+    snapshot.client = client;
+    snapshot.acked = received;
+    snapshot.next_chunk = received;
+    snapshot.gone_for = None;
+}
+
+fn receive_snapshot_offer(
+    trigger: Trigger<SnapshotOffer>,
+    mut commands: Commands,
+    mut incoming: ResMut<IncomingSnapshot>,
+) {
+    let offer = *trigger.event();
+    debug!("receiving {} byte snapshot of tick {}", offer.total_bytes, offer.tick);
+    incoming.0 = Some(PartialSnapshot {
+        transfer: offer.transfer,
+        tick: offer.tick,
+        total_bytes: offer.total_bytes,
+        chunk_size: offer.chunk_size,
+        ack_interval: offer.ack_interval,
+        hash: offer.hash,
+        data: Vec::with_capacity(offer.total_bytes),
+        ahead: BTreeMap::new(),
+        hasher: StableHasher::new(),
+        attempts: 1,
+    });
+    commands.trigger(SnapshotProgress { tick: offer.tick, received_bytes: 0, total_bytes: offer.total_bytes });
+}
+
+fn receive_snapshot_chunk(
+    trigger: Trigger<SnapshotChunk>,
+    mut commands: Commands,
+    mut incoming: ResMut<IncomingSnapshot>,
+    settings: Res<SnapshotSettings>,
+) {
+    let chunk = trigger.event();
+    let Some(snapshot) = incoming.0.as_mut().filter(|snapshot| snapshot.transfer == chunk.transfer) else { return };
+    let received = snapshot.received_chunks();
+    if chunk.index < received { return }
+    snapshot.ahead.insert(chunk.index, chunk.data.clone());
+    // Assemble every chunk that continues the received part
+    while let Some(data) = snapshot.ahead.remove(&snapshot.received_chunks()) {
+        snapshot.hasher.write(&data);
+        snapshot.data.extend(data);
+    }
+    let now_received = snapshot.received_chunks();
+    if now_received == received { return }
+    commands.trigger(SnapshotProgress {
+        tick: snapshot.tick,
+        received_bytes: snapshot.data.len(),
+        total_bytes: snapshot.total_bytes,
+    });
+    let complete = snapshot.is_complete();
+    if complete || now_received / snapshot.ack_interval != received / snapshot.ack_interval {
+        commands.client_trigger(SnapshotAck { transfer: snapshot.transfer, received: now_received });
+    }
+    if !complete { return }
+
+    let snapshot = incoming.0.take().expect("snapshot is being received");
+    if snapshot.hasher.finish() != snapshot.hash {
+        if snapshot.attempts >= settings.max_attempts {
+            error!("snapshot of tick {} is corrupt, giving up", snapshot.tick);
+            commands.trigger(SnapshotTransferFailed { tick: snapshot.tick, error: SnapshotError::Corrupt });
+            return;
+        }
+        warn!("snapshot of tick {} is corrupt, requesting it again", snapshot.tick);
+        commands.client_trigger(ResumeSnapshot { transfer: snapshot.transfer, received: 0, restart: true });
+        incoming.0 = Some(PartialSnapshot {
+            data: Vec::with_capacity(snapshot.total_bytes),
+            hasher: StableHasher::new(),
+            attempts: snapshot.attempts + 1,
+            ..snapshot
+        });
+        return;
+    }
+    info!("received snapshot of tick {}", snapshot.tick);
+    commands.trigger(SnapshotReceived { tick: snapshot.tick, data: Arc::new(snapshot.data) });
+}
+
+/// Continues a transfer interrupted by a drop once connected again
+fn resume_incoming_snapshot(
+    mut commands: Commands,
+    incoming: Res<IncomingSnapshot>,
+    client: Res<RepliconClient>,
+    mut was_connected: Local<bool>,
+) {
+    let connected = client.is_connected();
+    let reconnected = connected && !*was_connected;
+    *was_connected = connected;
+    if !reconnected { return }
+    let Some(snapshot) = &incoming.0 else { return };
+    info!("resuming snapshot of tick {} at {} of {} bytes", snapshot.tick, snapshot.data.len(), snapshot.total_bytes);
+    commands.client_trigger(ResumeSnapshot { transfer: snapshot.transfer, received: snapshot.received_chunks(), restart: false });
+}
@@ -0,0 +1,186 @@
+//! Headless soak testing.  Runs a dedicated server and several clients in one
+//! process over replicon's in-memory test transport and reports whether every
+//! client saw the same simulation on every tick.  Intended to be called from
+//! downstream `cargo test` suites.
+
+use std::{collections::BTreeMap, time::{Duration, Instant}};
+use bevy::{prelude::*, state::app::StatesPlugin, time::TimeUpdateStrategy};
+use bevy_replicon::{
+    prelude::*,
+    shared::backend::connected_client::{ConnectedClient, NetworkId},
+    test_app::ServerTestAppExt,
+};
+use crate::{prelude::*, hashing::hash_tick_commands, commands::send_client_commands};
+
+/// Result of a soak run
+#[derive(Debug, Clone, Default)]
+pub struct SoakReport {
+    /// Hash of each tick on each client, indexed by client
+    pub tick_hashes: BTreeMap<SimTick, Vec<Option<u64>>>,
+    /// Ticks where clients disagreed
+    pub desynced_ticks: Vec<SimTick>,
+    /// The last tick reached by every client
+    pub ticks_completed: SimTick,
+    /// Number of frames it took to reach `ticks_completed`
+    pub frames: usize,
+    pub total_time: Duration,
+    pub mean_frame_time: Duration,
+    pub max_frame_time: Duration,
+}
+
+impl SoakReport {
+    pub fn is_in_sync(&self) -> bool {
+        self.desynced_ticks.is_empty()
+    }
+
+    /// Panics with the first desynced tick, if any
+    pub fn assert_in_sync(&self) {
+        if let Some(tick) = self.desynced_ticks.first() {
+            panic!("Clients desynced on tick {}: {:?}", tick, self.tick_hashes[tick]);
+        }
+    }
+}
+
+/// Run a soak test where each tick's hash is the hash of the commands the
+/// clients received for it.  `command_generator` is called with the client
+/// index and tick whenever a client reaches a new tick.
+pub fn run_soak<G>(
+    settings: SimulationSettings,
+    clients: usize,
+    ticks: SimTick,
+    command_generator: G,
+) -> SoakReport
+where
+    G: FnMut(usize, SimTick) -> Vec<Box<dyn PartialReflect>>,
+{
+    run_soak_with(settings, clients, ticks, command_generator, |_| {}, |world, tick| {
+        let registry = world.resource::<AppTypeRegistry>().read();
+        world
+            .resource::<LockstepGameCommandBuffer>()
+            .get(tick)
+            .map_or(0, |commands| hash_tick_commands(commands, &registry))
+    })
+}
+
+/// Like [`run_soak`], but `setup` is applied to every app (register command
+/// types, add game plugins) and `state_hash` computes the hash of a client
+/// world for a tick after the client has reached it.
+pub fn run_soak_with<G, S, H>(
+    settings: SimulationSettings,
+    clients: usize,
+    ticks: SimTick,
+    mut command_generator: G,
+    setup: S,
+    state_hash: H,
+) -> SoakReport
+where
+    G: FnMut(usize, SimTick) -> Vec<Box<dyn PartialReflect>>,
+    S: Fn(&mut App),
+    H: Fn(&mut World, SimTick) -> u64,
+{
+    let settings = SimulationSettings { num_players: clients as u8, ..settings };
+    let mut server_app = soak_app(&settings, &setup);
+    server_app.world_mut().resource_mut::<RepliconServer>().set_running(true);
+
+    let mut client_apps: Vec<App> = (0..clients).map(|_| soak_app(&settings, &setup)).collect();
+    for (index, client_app) in client_apps.iter_mut().enumerate() {
+        server_app.connect_client(client_app);
+        let world = server_app.world_mut();
+        let new_clients: Vec<Entity> = world
+            .query_filtered::<Entity, (With<ConnectedClient>, Without<NetworkId>)>()
+            .iter(world)
+            .collect();
+        for entity in new_clients {
+            // Id 1 is reserved for a host
+            world.entity_mut(entity).insert(NetworkId::new(index as u64 + 2));
+        }
+    }
+    for app in std::iter::once(&mut server_app).chain(client_apps.iter_mut()) {
+        app.world_mut().resource_mut::<NextState<SimulationState>>().set(SimulationState::Connecting);
+    }
+
+    let mut report = SoakReport::default();
+    let mut ready_sent = vec![false; clients];
+    let mut last_generated: Vec<Option<SimTick>> = vec![None; clients];
+    let mut last_hashed: Vec<SimTick> = vec![0; clients];
+    let mut frame_times = Vec::new();
+    // Generous upper bound so a stalled simulation can't hang the test
+    let max_frames = ticks as usize * 20 + 1000;
+    let start = Instant::now();
+
+    for _ in 0..max_frames {
+        let frame_start = Instant::now();
+        server_app.update();
+        for client_app in client_apps.iter_mut() {
+            server_app.exchange_with_client(client_app);
+            client_app.update();
+            server_app.exchange_with_client(client_app);
+        }
+        frame_times.push(frame_start.elapsed());
+
+        for (index, client_app) in client_apps.iter_mut().enumerate() {
+            let world = client_app.world_mut();
+            let state = *world.resource::<State<SimulationState>>().get();
+            let has_local_client = world.query_filtered::<(), With<LocalClient>>().iter(world).next().is_some();
+            if state == SimulationState::Setup && has_local_client && !ready_sent[index] {
+                world.commands().client_trigger(ClientReadyEvent);
+                world.flush();
+                ready_sent[index] = true;
+            }
+            if state != SimulationState::Running { continue }
+
+            let sim_tick = world.get_resource::<SimulationTick>().map_or(0, |tick| **tick);
+            if last_generated[index].is_none_or(|last| sim_tick > last) {
+                let commands = command_generator(index, sim_tick);
+                if !commands.is_empty() {
+                    send_client_commands(&mut world.commands(), ClientSendCommands { issued_tick: sim_tick, commands, ..default() });
+                    world.flush();
+                }
+                last_generated[index] = Some(sim_tick);
+            }
+            for tick in last_hashed[index] + 1..=sim_tick.min(ticks) {
+                let hash = state_hash(world, tick);
+                report.tick_hashes.entry(tick).or_insert_with(|| vec![None; clients])[index] = Some(hash);
+            }
+            last_hashed[index] = last_hashed[index].max(sim_tick.min(ticks));
+        }
+
+        report.ticks_completed = last_hashed.iter().copied().min().unwrap_or(0);
+        if report.ticks_completed >= ticks { break }
+    }
+
+    report.frames = frame_times.len();
+    report.total_time = start.elapsed();
+    report.max_frame_time = frame_times.iter().copied().max().unwrap_or_default();
+    report.mean_frame_time = report.total_time / report.frames.max(1) as u32;
+    report.desynced_ticks = report.tick_hashes
+        .iter()
+        .filter(|(_, hashes)| hashes.iter().any(|hash| *hash != hashes[0]))
+        .map(|(tick, _)| *tick)
+        .collect();
+    report
+}
+
+fn soak_app(settings: &SimulationSettings, setup: &impl Fn(&mut App)) -> App {
+    let mut app = App::new();
+    app.add_plugins((
+        MinimalPlugins,
+        StatesPlugin,
+        RepliconPlugins.set(ServerPlugin {
+            tick_policy: TickPolicy::EveryFrame,
+            ..default()
+        }),
+        RepliconLockstepPlugins::default()
+            .with_simulation(settings.clone())
+            .with_connections(ConnectionSettings {
+                server_mode: ServerMode::Dedicated,
+                ..default()
+            }),
+    ));
+    // Every update advances the fixed clock by exactly one tick
+    app.insert_resource(TimeUpdateStrategy::ManualDuration(settings.tick_timestep));
+    setup(&mut app);
+    app.finish();
+    app.cleanup();
+    app
+}
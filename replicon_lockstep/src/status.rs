@@ -0,0 +1,256 @@
+//! Read-only HTTP status endpoint for dedicated servers, so hosting
+//! providers can health-check match servers.  A listener thread answers
+//! `GET /status` with a JSON [`ServerStatus`] and `GET /health` with an
+//! empty 200.  The thread never touches the world, it asks the app for a
+//! snapshot through a channel and waits for the reply.  Every request is
+//! answered on its own short-lived thread, so a slow caller can't hold up
+//! the health checks of others.
+
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{atomic::{AtomicBool, Ordering}, mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use bevy::prelude::*;
+use bevy_replicon::{prelude::*, shared::backend::connected_client::NetworkId};
+use serde::Serialize;
+use crate::prelude::*;
+
+/// How long the listener waits for the app to answer before replying 503
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a caller has to send its request line
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Longest request line read, anything after it is ignored
+const MAX_REQUEST_LINE_BYTES: u64 = 1024;
+
+/// How often the listener checks for new connections and whether to stop
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Serves [`ServerStatus`] over HTTP on `addr`
+pub struct StatusEndpointPlugin {
+    pub addr: SocketAddr,
+}
+
+impl Default for StatusEndpointPlugin {
+    fn default() -> Self {
+        Self { addr: SocketAddr::from(([0, 0, 0, 0], 8080)) }
+    }
+}
+
+impl Plugin for StatusEndpointPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(StatusEndpointAddr(self.addr))
+            .init_resource::<StallCount>()
+            .add_observer(count_stalls)
+            .add_systems(Startup, start_status_endpoint)
+            .add_systems(Update, answer_status_queries.run_if(resource_exists::<StatusEndpoint>));
+    }
+}
+
+/// Handle of the running listener thread.  Removing the resource stops it.
+#[derive(Resource)]
+pub struct StatusEndpoint {
+    queries: Mutex<mpsc::Receiver<StatusQuery>>,
+    stop: Arc<AtomicBool>,
+    local_addr: SocketAddr,
+}
+
+impl StatusEndpoint {
+    /// The address the endpoint is bound to, e.g. the port picked for port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for StatusEndpoint {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+#[derive(Resource, Deref)]
+struct StatusEndpointAddr(SocketAddr);
+
+/// The state of the match server as reported by the status endpoint
+#[derive(Serialize, Debug, Clone)]
+pub struct ServerStatus {
+    pub state: SimulationState,
+    pub server_running: bool,
+    pub tick: SimTick,
+    pub clients: Vec<ClientStatus>,
+    /// Milliseconds since the last tick while Running
+    pub stalled_for_ms: u64,
+    /// True while presentation is frozen by a stall
+    pub stalled: bool,
+    /// Number of [`PresentationFreeze`] triggers since startup
+    pub stall_count: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ClientStatus {
+    pub id: ClientId,
+    pub rtt_ms: f64,
+    pub packet_loss: f64,
+}
+
+/// A snapshot request from a request thread
+struct StatusQuery(mpsc::Sender<ServerStatus>);
+
+#[derive(Resource, Default, Deref, DerefMut)]
+struct StallCount(u64);
+
+fn count_stalls(_trigger: Trigger<PresentationFreeze>, mut count: ResMut<StallCount>) {
+    **count += 1;
+}
+
+fn start_status_endpoint(mut commands: Commands, addr: Res<StatusEndpointAddr>) {
+    let bound = TcpListener::bind(**addr).and_then(|listener| {
+        // Polled, so the thread notices when it should stop
+        listener.set_nonblocking(true)?;
+        Ok((listener.local_addr()?, listener))
+    });
+    let (local_addr, listener) = match bound {
+        Ok(bound) => bound,
+        Err(err) => {
+            error!("Failed to bind status endpoint on {}: {}", **addr, err);
+            return;
+        }
+    };
+    let (sender, receiver) = mpsc::channel();
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread_stop = stop.clone();
+    let spawned = thread::Builder::new()
+        .name("lockstep-status-endpoint".into())
+        .spawn(move || serve_status(listener, sender, thread_stop));
+    match spawned {
+        Ok(_) => {
+            info!("Status endpoint listening on {}", local_addr);
+            commands.insert_resource(StatusEndpoint { queries: Mutex::new(receiver), stop, local_addr });
+        }
+        Err(err) => error!("Failed to start status endpoint thread: {}", err),
+    }
+}
+
+fn answer_status_queries(
+    endpoint: Res<StatusEndpoint>,
+    server: Res<RepliconServer>,
+    state: Res<State<SimulationState>>,
+    sim_tick: Option<Res<SimulationTick>>,
+    stall: Option<Res<TickStall>>,
+    stall_count: Res<StallCount>,
+    clients: Query<(&NetworkId, &NetworkStats)>,
+) {
+    let Ok(queries) = endpoint.queries.lock() else { return };
+    let pending: Vec<StatusQuery> = queries.try_iter().collect();
+    if pending.is_empty() { return }
+
+    let mut client_status: Vec<ClientStatus> = clients
+        .iter()
+        .map(|(id, stats)| ClientStatus {
+            id: id.get(),
+            rtt_ms: stats.rtt * 1000.0,
+            packet_loss: stats.packet_loss,
+        })
+        .collect();
+    client_status.sort_by_key(|client| client.id);
+    let stall = stall.map(|stall| *stall).unwrap_or_default();
+    let status = ServerStatus {
+        state: *state.get(),
+        server_running: server.is_running(),
+        tick: sim_tick.map_or(0, |tick| **tick),
+        clients: client_status,
+        stalled_for_ms: stall.stalled_for.as_millis() as u64,
+        stalled: stall.frozen,
+        stall_count: **stall_count,
+    };
+    for StatusQuery(reply) in pending {
+        // The listener may have timed out already
+        let _ = reply.send(status.clone());
+    }
+}
+
+fn serve_status(listener: TcpListener, queries: mpsc::Sender<StatusQuery>, stop: Arc<AtomicBool>) {
+    while !stop.load(Ordering::Relaxed) {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_INTERVAL);
+                continue;
+            }
+            Err(err) => {
+                debug!("status connection failed: {}", err);
+                continue;
+            }
+        };
+        let queries = queries.clone();
+        let spawned = thread::Builder::new()
+            .name("lockstep-status-request".into())
+            .spawn(move || {
+                if let Err(err) = respond(stream, &queries) {
+                    debug!("status request failed: {}", err);
+                }
+            });
+        if let Err(err) = spawned {
+            warn!("Failed to start status request thread: {}", err);
+        }
+    }
+    debug!("Status endpoint stopped");
+}
+
+/// Reads the request line, giving up on callers that don't send it within
+/// [`REQUEST_TIMEOUT`] and ignoring anything past [`MAX_REQUEST_LINE_BYTES`]
+fn read_request_line(stream: &TcpStream) -> io::Result<String> {
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    let mut reader = stream.take(MAX_REQUEST_LINE_BYTES);
+    let mut line = Vec::new();
+    let mut buffer = [0; 256];
+    while !line.contains(&b'\n') {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        stream.set_read_timeout(Some(remaining))?;
+        let read = reader.read(&mut buffer)?;
+        if read == 0 { break }
+        line.extend_from_slice(&buffer[..read]);
+    }
+    let end = line.iter().position(|&byte| byte == b'\n').unwrap_or(line.len());
+    Ok(String::from_utf8_lossy(&line[..end]).into_owned())
+}
+
+fn respond(mut stream: TcpStream, queries: &mpsc::Sender<StatusQuery>) -> io::Result<()> {
+    // Accepted from a non-blocking listener, which some platforms inherit
+    stream.set_nonblocking(false)?;
+    let request_line = read_request_line(&stream)?;
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+
+    let (status, body) = match (method, path) {
+        ("GET", "/health") => ("200 OK", String::new()),
+        ("GET", "/status") => {
+            let (reply, snapshot) = mpsc::channel();
+            let snapshot = queries
+                .send(StatusQuery(reply))
+                .ok()
+                .and_then(|_| snapshot.recv_timeout(SNAPSHOT_TIMEOUT).ok());
+            match snapshot.map(|status| serde_json::to_string(&status)) {
+                Some(Ok(json)) => ("200 OK", json),
+                _ => ("503 Service Unavailable", String::new()),
+            }
+        }
+        ("GET", _) => ("404 Not Found", String::new()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body,
+    )
+}
@@ -0,0 +1,231 @@
+//! Persistence of match artifacts.  Replays, desync crash logs and sent
+//! snapshots are handed to a [`LockstepStorage`] backend, keyed by match id
+//! and [`ArtifactKind`], so server operators can keep them on disk, in
+//! memory for tests, or in their own object store or database without the
+//! crate knowing about it.
+
+use std::{collections::BTreeMap, fmt, fs, io, path::PathBuf, sync::{Arc, Mutex}, time::{SystemTime, UNIX_EPOCH}};
+use bevy::prelude::*;
+use crate::prelude::*;
+
+/// Routes match artifacts to a storage backend
+pub struct StoragePlugin {
+    pub storage: Arc<dyn LockstepStorage>,
+}
+
+impl StoragePlugin {
+    pub fn new(storage: impl LockstepStorage) -> Self {
+        Self { storage: Arc::new(storage) }
+    }
+}
+
+impl Plugin for StoragePlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(MatchStorage(self.storage.clone()))
+            .init_resource::<ArtifactMatchId>()
+            .add_systems(OnEnter(SimulationState::Setup), reset_artifact_match_id)
+            .add_observer(store_artifact);
+    }
+}
+
+/// What an artifact is, backends may store each kind separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ArtifactKind {
+    /// An encoded [`MatchReplay`]
+    Replay,
+    /// A text report written when something went wrong, e.g. a desync
+    CrashLog,
+    /// A world snapshot sent with [`SendSnapshot`]
+    Snapshot,
+}
+
+impl ArtifactKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Replay => "replay",
+            Self::CrashLog => "crash-log",
+            Self::Snapshot => "snapshot",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(io::Error),
+    NotFound,
+    /// An error of a custom backend
+    Backend(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "storage io error: {}", err),
+            Self::NotFound => write!(f, "artifact not found"),
+            Self::Backend(err) => write!(f, "storage error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<io::Error> for StorageError {
+    fn from(err: io::Error) -> Self {
+        if err.kind() == io::ErrorKind::NotFound { Self::NotFound } else { Self::Io(err) }
+    }
+}
+
+/// A place to keep match artifacts.  Calls are made from systems, so
+/// backends with slow round trips should hand the work to a task of their
+/// own and return.
+pub trait LockstepStorage: Send + Sync + 'static {
+    /// Store an artifact, replacing one with the same name
+    fn put(&self, match_id: &str, kind: ArtifactKind, name: &str, data: &[u8]) -> Result<(), StorageError>;
+    fn get(&self, match_id: &str, kind: ArtifactKind, name: &str) -> Result<Vec<u8>, StorageError>;
+    /// Names of the stored artifacts of a kind, sorted
+    fn list(&self, match_id: &str, kind: ArtifactKind) -> Result<Vec<String>, StorageError>;
+}
+
+/// Stores artifacts as files under `root/<match id>/<kind>/<name>`
+#[derive(Debug, Clone)]
+pub struct FileStorage {
+    pub root: PathBuf,
+}
+
+impl FileStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn dir(&self, match_id: &str, kind: ArtifactKind) -> PathBuf {
+        self.root.join(match_id).join(kind.name())
+    }
+}
+
+impl LockstepStorage for FileStorage {
+    fn put(&self, match_id: &str, kind: ArtifactKind, name: &str, data: &[u8]) -> Result<(), StorageError> {
+        let dir = self.dir(match_id, kind);
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join(name), data)?;
+        Ok(())
+    }
+
+    fn get(&self, match_id: &str, kind: ArtifactKind, name: &str) -> Result<Vec<u8>, StorageError> {
+        Ok(fs::read(self.dir(match_id, kind).join(name))?)
+    }
+
+    fn list(&self, match_id: &str, kind: ArtifactKind) -> Result<Vec<String>, StorageError> {
+        let entries = match fs::read_dir(self.dir(match_id, kind)) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+}
+
+/// Keeps artifacts in memory, e.g. for tests.  Clones share the artifacts.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage(Arc<Mutex<BTreeMap<(String, ArtifactKind, String), Vec<u8>>>>);
+
+impl MemoryStorage {
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<(String, ArtifactKind, String), Vec<u8>>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl LockstepStorage for MemoryStorage {
+    fn put(&self, match_id: &str, kind: ArtifactKind, name: &str, data: &[u8]) -> Result<(), StorageError> {
+        self.lock().insert((match_id.to_string(), kind, name.to_string()), data.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, match_id: &str, kind: ArtifactKind, name: &str) -> Result<Vec<u8>, StorageError> {
+        self.lock()
+            .get(&(match_id.to_string(), kind, name.to_string()))
+            .cloned()
+            .ok_or(StorageError::NotFound)
+    }
+
+    fn list(&self, match_id: &str, kind: ArtifactKind) -> Result<Vec<String>, StorageError> {
+        Ok(self.lock()
+            .keys()
+            .filter(|(id, artifact_kind, _)| id == match_id && *artifact_kind == kind)
+            .map(|(_, _, name)| name.clone())
+            .collect())
+    }
+}
+
+/// The backend of the [`StoragePlugin`]
+#[derive(Resource, Clone, Deref)]
+pub struct MatchStorage(pub Arc<dyn LockstepStorage>);
+
+/// The match id artifacts of the current match are stored under, assigned
+/// when the first one is stored.  Every match, rematches included, gets a
+/// new one.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct ArtifactMatchId(Option<String>);
+
+impl ArtifactMatchId {
+    pub fn get(&self) -> Option<&str> {
+        self.0.as_deref()
+    }
+}
+
+/// A match id for the artifacts of a session started at `time`.  The
+/// session hash only depends on the players, settings and seed, so the time
+/// keeps matches that share them apart.
+pub fn artifact_match_id(session: SessionHash, time: SystemTime) -> String {
+    let nanos = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos());
+    format!("{:08x}-{:x}", *session, nanos)
+}
+
+fn reset_artifact_match_id(mut match_id: ResMut<ArtifactMatchId>) {
+    match_id.0 = None;
+}
+
+/// Trigger this to store an artifact of the current match.  Without the
+/// [`StoragePlugin`] nothing happens.
+#[derive(Event, Debug, Clone)]
+pub struct StoreArtifact {
+    pub kind: ArtifactKind,
+    pub name: String,
+    pub data: Arc<Vec<u8>>,
+}
+
+/// A trigger that fires after an artifact was stored
+#[derive(Event, Debug, Clone)]
+pub struct ArtifactStored {
+    pub match_id: String,
+    pub kind: ArtifactKind,
+    pub name: String,
+}
+
+fn store_artifact(
+    trigger: Trigger<StoreArtifact>,
+    mut commands: Commands,
+    storage: Res<MatchStorage>,
+    session: Res<SessionHash>,
+    mut match_id: ResMut<ArtifactMatchId>,
+) {
+    let artifact = trigger.event();
+    let match_id = match_id.0
+        .get_or_insert_with(|| artifact_match_id(*session, SystemTime::now()))
+        .clone();
+    match storage.put(&match_id, artifact.kind, &artifact.name, &artifact.data) {
+        Ok(()) => {
+            info!("Stored {} {} of match {}", artifact.kind.name(), artifact.name, match_id);
+            commands.trigger(ArtifactStored { match_id, kind: artifact.kind, name: artifact.name.clone() });
+        }
+        Err(err) => error!("Failed to store {} {} of match {}: {}", artifact.kind.name(), artifact.name, match_id, err),
+    }
+}
@@ -0,0 +1,233 @@
+//! In-process determinism tests.  [`LockstepTestMatch`] runs a dedicated
+//! server and several clients in one process over replicon's in-memory test
+//! transport, advances them in lockstep and compares the state hash of every
+//! client, see [`hash_simulation_state`].  Register the components that make
+//! up the game state with `include_in_state_hash` in the setup function.
+
+use std::time::Duration;
+use bevy::{prelude::*, state::app::StatesPlugin, time::TimeUpdateStrategy};
+use bevy_replicon::{
+    prelude::*,
+    shared::backend::connected_client::{ConnectedClient, NetworkId},
+    test_app::ServerTestAppExt,
+};
+use crate::prelude::*;
+
+/// Frames to wait for the match to reach Running, or a tick to be applied
+const MAX_FRAMES: usize = 1000;
+
+/// A dedicated server and its clients, all in this process
+pub struct LockstepTestMatch {
+    pub server: App,
+    pub clients: Vec<App>,
+    /// The entity of each client on the server
+    pub client_entities: Vec<Entity>,
+    /// Clients whose messages are not delivered, simulating a stall
+    pub stalled: Vec<bool>,
+    /// Frames between updates of each client, see [`Self::set_frame_interval`]
+    intervals: Vec<u32>,
+    frame_time: Duration,
+    frames: u32,
+    setup: Box<dyn Fn(&mut App)>,
+}
+
+impl LockstepTestMatch {
+    /// Connects `clients` players to a dedicated server.  `setup` is applied
+    /// to every app, e.g. to register command types, game plugins and the
+    /// hashed components.
+    pub fn new(settings: SimulationSettings, clients: usize, setup: impl Fn(&mut App) + 'static) -> Self {
+        let settings = SimulationSettings { num_players: clients as u8, ..settings };
+        let mut server = peer_app(&settings, dedicated(ClientRole::Player), &setup);
+        server.world_mut().resource_mut::<RepliconServer>().set_running(true);
+        server.world_mut().resource_mut::<NextState<SimulationState>>().set(SimulationState::Connecting);
+        let mut game = Self {
+            server,
+            clients: Vec::new(),
+            client_entities: Vec::new(),
+            stalled: Vec::new(),
+            intervals: Vec::new(),
+            frame_time: settings.tick_timestep,
+            frames: 0,
+            setup: Box::new(setup),
+        };
+        for _ in 0..clients {
+            game.join(ClientRole::Player);
+        }
+        game
+    }
+
+    /// Connects another client asking to join as `role`, e.g. a spectator
+    /// mid-match.  Returns its index.
+    pub fn join(&mut self, role: ClientRole) -> usize {
+        let settings = self.server.world().resource::<SimulationSettings>().clone();
+        let mut client = peer_app(&settings, dedicated(role), &self.setup);
+        client.insert_resource(TimeUpdateStrategy::ManualDuration(self.frame_time));
+        self.server.connect_client(&mut client);
+        let world = self.server.world_mut();
+        let entity = world
+            .query_filtered::<Entity, (With<ConnectedClient>, Without<NetworkId>)>()
+            .single(world);
+        // Id 1 is reserved for a host
+        world.entity_mut(entity).insert(NetworkId::new(self.clients.len() as u64 + 2));
+        client.world_mut().resource_mut::<NextState<SimulationState>>().set(SimulationState::Connecting);
+        self.clients.push(client);
+        self.client_entities.push(entity);
+        self.stalled.push(false);
+        self.intervals.push(1);
+        self.clients.len() - 1
+    }
+
+    /// Advances the clock of every peer by `frame` per frame instead of one
+    /// tick
+    pub fn set_frame_time(&mut self, frame: Duration) {
+        self.frame_time = frame;
+        self.server.insert_resource(TimeUpdateStrategy::ManualDuration(frame));
+        for client in 0..self.clients.len() {
+            self.set_frame_interval(client, self.intervals[client]);
+        }
+    }
+
+    /// Updates a client only every `frames` frames, each update advancing
+    /// its clock by as many frame times, e.g. a client rendering at a lower
+    /// frame rate
+    pub fn set_frame_interval(&mut self, client: usize, frames: u32) {
+        self.intervals[client] = frames.max(1);
+        self.clients[client].insert_resource(TimeUpdateStrategy::ManualDuration(self.frame_time * self.intervals[client]));
+    }
+
+    /// Frames run so far
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// Whether a client updates in the next frame
+    pub fn renders(&self, client: usize) -> bool {
+        self.frames % self.intervals[client] == 0
+    }
+
+    /// Updates the server, then every client due, exchanging messages
+    /// around each client update unless the client is stalled
+    pub fn frame(&mut self) {
+        self.server.update();
+        for (index, client) in self.clients.iter_mut().enumerate() {
+            if self.frames % self.intervals[index] != 0 { continue }
+            let stalled = self.stalled[index];
+            if !stalled { self.server.exchange_with_client(client) }
+            client.update();
+            if !stalled { self.server.exchange_with_client(client) }
+        }
+        self.frames += 1;
+    }
+
+    /// Runs frames until every peer is Running, sending [`ClientReadyEvent`]
+    /// for every client in Setup.  Panics if the match doesn't start.
+    pub fn start(&mut self) {
+        let mut ready = vec![false; self.clients.len()];
+        for _ in 0..MAX_FRAMES {
+            self.frame();
+            for (index, client) in self.clients.iter_mut().enumerate() {
+                let world = client.world_mut();
+                let identified = world.query_filtered::<(), With<LocalClient>>().iter(world).next().is_some();
+                if state(world) == SimulationState::Setup && identified && !ready[index] {
+                    world.commands().client_trigger(ClientReadyEvent);
+                    world.flush();
+                    ready[index] = true;
+                }
+            }
+            if std::iter::once(&self.server).chain(self.clients.iter()).all(|app| state(app.world()) == SimulationState::Running) {
+                return;
+            }
+        }
+        panic!("match never started");
+    }
+
+    /// Queues commands on a client, they are sent with its next frame
+    pub fn send(&mut self, client: usize, commands: impl IntoIterator<Item = Box<dyn PartialReflect>>) {
+        let queue = self.clients[client].world().resource::<LockstepCommandQueue>().clone();
+        for command in commands {
+            queue.push_boxed(GAMEPLAY_STREAM, command);
+        }
+    }
+
+    /// The tick the server reached
+    pub fn server_tick(&self) -> SimTick {
+        **self.server.world().resource::<SimulationTick>()
+    }
+
+    /// The last tick every client applied
+    pub fn applied_tick(&self) -> SimTick {
+        self.clients
+            .iter()
+            .map(|client| **client.world().resource::<LastAppliedTick>())
+            .min()
+            .unwrap_or_default()
+    }
+
+    /// Runs frames until every client applied `ticks` more ticks.  Panics
+    /// if the match stalls.
+    pub fn advance_ticks(&mut self, ticks: SimTick) {
+        let target = self.applied_tick() + ticks;
+        for _ in 0..MAX_FRAMES + ticks as usize * 20 {
+            if self.applied_tick() >= target { return }
+            self.frame();
+        }
+        panic!("clients never applied tick {}, stuck at {}", target, self.applied_tick());
+    }
+
+    /// The state hash of every client
+    pub fn state_hashes(&self) -> Vec<u64> {
+        self.clients.iter().map(|client| hash_simulation_state(client.world())).collect()
+    }
+
+    /// Panics unless every client applied the same tick and hashes the same
+    /// state
+    pub fn assert_in_sync(&self) {
+        let ticks: Vec<SimTick> = self.clients
+            .iter()
+            .map(|client| **client.world().resource::<LastAppliedTick>())
+            .collect();
+        assert!(ticks.iter().all(|&tick| tick == ticks[0]), "clients applied different ticks: {:?}", ticks);
+        let hashes = self.state_hashes();
+        assert!(
+            hashes.iter().all(|&hash| hash == hashes[0]),
+            "clients desynced on tick {}: {:016x?}", ticks[0], hashes,
+        );
+    }
+}
+
+/// A peer of a test match, with its clock advancing one tick per update.
+/// `setup` is applied before the app is finished.  Also for tests outside
+/// a [`LockstepTestMatch`], e.g. a host playing alone.
+pub fn peer_app(settings: &SimulationSettings, connection: ConnectionSettings, setup: &impl Fn(&mut App)) -> App {
+    let mut app = App::new();
+    app.add_plugins((
+        MinimalPlugins,
+        StatesPlugin,
+        RepliconPlugins.set(ServerPlugin {
+            tick_policy: TickPolicy::EveryFrame,
+            ..default()
+        }),
+        RepliconLockstepPlugins::default()
+            .with_simulation(settings.clone())
+            .with_connections(connection),
+    ));
+    // Every update advances the fixed clock by exactly one tick
+    app.insert_resource(TimeUpdateStrategy::ManualDuration(settings.tick_timestep));
+    setup(&mut app);
+    app.finish();
+    app.cleanup();
+    app
+}
+
+fn dedicated(join_as: ClientRole) -> ConnectionSettings {
+    ConnectionSettings {
+        server_mode: ServerMode::Dedicated,
+        join_as,
+        ..default()
+    }
+}
+
+/// The simulation state of a peer
+pub fn state(world: &World) -> SimulationState {
+    *world.resource::<State<SimulationState>>().get()
+}
@@ -0,0 +1,204 @@
+use std::{
+    error::Error,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    time::SystemTime,
+};
+use bevy::prelude::*;
+use bevy_replicon::prelude::*;
+use bevy_replicon_renet::{
+    netcode::{ClientAuthentication, NetcodeClientTransport, NetcodeServerTransport, ServerAuthentication, ServerConfig},
+    renet::{ConnectionConfig, RenetClient, RenetServer},
+    RenetChannelsExt,
+};
+use crate::{prelude::*, connections::opening_state};
+
+pub(crate) struct LockstepTransportPlugin;
+
+impl Plugin for LockstepTransportPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .add_observer(reconnect_client)
+            .add_observer(migrate_transport)
+            .add_observer(follow_relay_migration);
+    }
+}
+
+/// The address of the last server we connected to, used for reconnect attempts
+#[derive(Resource, Clone, Copy, Deref)]
+pub struct LastServerAddress(pub SocketAddr);
+
+/// A trigger that fires when the renet helpers fail to create a transport.
+#[derive(Event, Debug, Clone)]
+pub enum LockstepTransportError {
+    /// The server could not be started
+    Host(String),
+    /// The client could not connect to the server
+    Connect(String),
+}
+
+/// Convenience methods for creating renet transports from [`ConnectionSettings`].
+/// Both methods move the simulation into [`SimulationState::Connecting`] on success,
+/// or [`SimulationState::Lobby`] with the [`LobbyPlugin`],
+/// and trigger [`LockstepTransportError`] on failure.
+pub trait LockstepTransportExt {
+    /// Start a server on `ConnectionSettings::server_port`.  In [`ServerMode::Host`]
+    /// one of the player seats is taken by the host itself.
+    fn lockstep_host(&mut self);
+    /// Connect to a server at the given address
+    fn lockstep_connect(&mut self, addr: SocketAddr);
+    /// Remove any server or client transport and despawn replicated entities
+    fn lockstep_disconnect(&mut self);
+}
+
+impl LockstepTransportExt for Commands<'_, '_> {
+    fn lockstep_host(&mut self) {
+        self.queue(|world: &mut World| {
+            if let Err(err) = start_server(world) {
+                error!("Failed to start server: {}", err);
+                world.trigger(LockstepTransportError::Host(err.to_string()));
+                return;
+            }
+            let state = opening_state(world.get_resource::<LobbySettings>());
+            world.resource_mut::<NextState<SimulationState>>().set(state);
+        });
+    }
+
+    fn lockstep_connect(&mut self, addr: SocketAddr) {
+        self.queue(move |world: &mut World| {
+            if let Err(err) = connect_client(world, addr) {
+                error!("Failed to connect to {}: {}", addr, err);
+                world.trigger(LockstepTransportError::Connect(err.to_string()));
+                return;
+            }
+            let state = opening_state(world.get_resource::<LobbySettings>());
+            world.resource_mut::<NextState<SimulationState>>().set(state);
+        });
+    }
+
+    fn lockstep_disconnect(&mut self) {
+        self.remove_resource::<LastServerAddress>();
+        self.queue(|world: &mut World| {
+            world.remove_resource::<RenetServer>();
+            world.remove_resource::<NetcodeServerTransport>();
+            world.remove_resource::<RenetClient>();
+            world.remove_resource::<NetcodeClientTransport>();
+            let replicated: Vec<Entity> = world
+                .query_filtered::<Entity, With<Replicated>>()
+                .iter(world)
+                .collect();
+            info!("Cleaning up replicated entities {}", replicated.len());
+            for entity in replicated {
+                world.despawn(entity);
+            }
+        });
+    }
+}
+
+fn start_server(world: &mut World) -> Result<(), Box<dyn Error>> {
+    let channels = world.resource::<RepliconChannels>();
+    let server = RenetServer::new(ConnectionConfig {
+        server_channels_config: channels.server_configs(),
+        client_channels_config: channels.client_configs(),
+        ..Default::default()
+    });
+
+    let connection_settings = world.resource::<ConnectionSettings>();
+    let num_players = world.resource::<SimulationSettings>().num_players as usize;
+    // The host occupies a seat itself, so it accepts one less remote client
+    let max_clients = match connection_settings.server_mode {
+        ServerMode::Host => num_players.saturating_sub(1).max(1),
+        ServerMode::Dedicated => num_players,
+    };
+
+    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, connection_settings.server_port))?;
+    let server_config = ServerConfig {
+        current_time,
+        max_clients,
+        protocol_id: 0,
+        authentication: ServerAuthentication::Unsecure,
+        public_addresses: Default::default(),
+    };
+    let transport = NetcodeServerTransport::new(server_config, socket)?;
+
+    world.insert_resource(server);
+    world.insert_resource(transport);
+    Ok(())
+}
+
+fn connect_client(world: &mut World, server_addr: SocketAddr) -> Result<(), Box<dyn Error>> {
+    info!("connecting to {}", server_addr);
+    let channels = world.resource::<RepliconChannels>();
+    let client = RenetClient::new(ConnectionConfig {
+        server_channels_config: channels.server_configs(),
+        client_channels_config: channels.client_configs(),
+        ..Default::default()
+    });
+
+    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    let client_id = current_time.as_millis() as u64;
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    let authentication = ClientAuthentication::Unsecure {
+        protocol_id: 0,
+        client_id,
+        server_addr,
+        user_data: None,
+    };
+    let transport = NetcodeClientTransport::new(current_time, authentication, socket)?;
+
+    world.insert_resource(client);
+    world.insert_resource(transport);
+    world.insert_resource(LastServerAddress(server_addr));
+    Ok(())
+}
+
+/// Re-create the client transport for each reconnect attempt
+fn reconnect_client(
+    trigger: Trigger<ReconnectAttempt>,
+    mut commands: Commands,
+    address: Option<Res<LastServerAddress>>,
+) {
+    let Some(address) = address else { return };
+    let server_addr = **address;
+    let attempt = trigger.attempt;
+    commands.queue(move |world: &mut World| {
+        world.remove_resource::<RenetClient>();
+        world.remove_resource::<NetcodeClientTransport>();
+        if let Err(err) = connect_client(world, server_addr) {
+            warn!("Reconnect attempt {} failed: {}", attempt, err);
+            world.trigger(LockstepTransportError::Connect(err.to_string()));
+        }
+    });
+}
+
+/// Start a server on the new host, and reconnect everyone else to it
+fn migrate_transport(
+    trigger: Trigger<HostMigrationStarted>,
+    mut commands: Commands,
+) {
+    let HostMigrationStarted { address, elected, .. } = *trigger.event();
+    if !elected {
+        commands.insert_resource(LastServerAddress(address));
+        return;
+    }
+    commands.queue(move |world: &mut World| {
+        world.remove_resource::<RenetClient>();
+        world.remove_resource::<NetcodeClientTransport>();
+        world.remove_resource::<LastServerAddress>();
+        world.resource_mut::<ConnectionSettings>().server_port = address.port();
+        if let Err(err) = start_server(world) {
+            error!("Failed to take over as host: {}", err);
+            world.trigger(LockstepTransportError::Host(err.to_string()));
+        }
+    });
+}
+
+/// Point reconnect attempts at the replacement server
+fn follow_relay_migration(
+    trigger: Trigger<MigrateTransport>,
+    mut commands: Commands,
+    server: Res<RepliconServer>,
+) {
+    if server.is_running() { return }
+    commands.insert_resource(LastServerAddress(trigger.new_addr));
+}
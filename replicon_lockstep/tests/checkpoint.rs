@@ -0,0 +1,75 @@
+//! A spectator joining late restores the latest checkpoint instead of
+//! replaying the whole match, and ends up with the same state
+
+use std::time::Duration;
+use bevy::prelude::*;
+use bevy_replicon_lockstep::{prelude::*, test_utils::LockstepTestMatch};
+
+const INTERVAL: SimTick = 10;
+
+#[derive(Component, Reflect, Default)]
+#[reflect(Component)]
+struct Counter(u64);
+
+#[derive(Reflect, Debug)]
+struct Add(u64);
+
+impl GameCommand for Add {
+    fn apply(&self, _ctx: &CommandContext, world: &mut World) {
+        let mut counters = world.query::<&mut Counter>();
+        for mut counter in counters.iter_mut(world) {
+            counter.0 = counter.0.wrapping_mul(31).wrapping_add(self.0);
+        }
+    }
+}
+
+fn setup(app: &mut App) {
+    app.register_type::<Counter>()
+        .register_game_command::<Add>()
+        .include_in_state_hash::<Counter>()
+        .insert_resource(CheckpointSettings { interval: INTERVAL, ..default() })
+        .set_snapshot_provider(|world: &World, _tick: SimTick| {
+            let counter = world.iter_entities().find_map(|entity| entity.get::<Counter>()).expect("spawned in setup");
+            counter.0.to_le_bytes().to_vec()
+        })
+        .set_snapshot_applier(|world: &mut World, _tick: SimTick, data: &[u8]| {
+            let value = u64::from_le_bytes(data.try_into().expect("a counter"));
+            let mut counters = world.query::<&mut Counter>();
+            for mut counter in counters.iter_mut(world) {
+                counter.0 = value;
+            }
+        });
+    let id = SimulationId::from_raw(1).expect("1 is a valid id");
+    app.world_mut().spawn((id, Counter::default()));
+}
+
+fn settings() -> SimulationSettings {
+    SimulationSettings {
+        tick_timestep: Duration::from_millis(33),
+        ..default()
+    }
+}
+
+#[test]
+fn late_spectator_restores_the_latest_checkpoint() {
+    let mut game = LockstepTestMatch::new(settings(), 2, setup);
+    game.start();
+    for value in 1..=20 {
+        game.send(0, [Box::new(Add(value)) as Box<dyn PartialReflect>]);
+        game.advance_ticks(1);
+    }
+    game.advance_ticks(INTERVAL * 2);
+    let checkpoint = game.server.world().resource::<LatestCheckpoint>().0.clone().expect("a checkpoint was taken");
+    assert!(checkpoint.tick >= INTERVAL);
+
+    let spectator = game.join(ClientRole::Spectator);
+    game.advance_ticks(INTERVAL);
+    let world = game.clients[spectator].world();
+    let restored = world.resource::<LatestCheckpoint>().0.clone().expect("the spectator kept the checkpoint");
+    assert!(restored.tick >= checkpoint.tick);
+    // The ticks up to the checkpoint were never received
+    assert_eq!(**world.resource::<FirstAvailableTick>(), restored.tick + 1);
+    assert!(**world.resource::<LastAppliedTick>() > restored.tick);
+    let hashes = game.state_hashes();
+    assert!(hashes.iter().all(|&hash| hash == hashes[0]), "restored state differs: {:016x?}", hashes);
+}
@@ -0,0 +1,394 @@
+//! Clients applying the same commands reach the same state, checked with the
+//! in-process test match of the `test-utils` feature
+
+use std::time::Duration;
+use bevy::prelude::*;
+use bevy_replicon_lockstep::{prelude::*, test_utils::LockstepTestMatch};
+
+#[derive(Component, Reflect, Default)]
+#[reflect(Component)]
+struct Counter(u64);
+
+#[derive(Reflect, Debug)]
+struct Add(u64);
+
+impl GameCommand for Add {
+    fn apply(&self, ctx: &CommandContext, world: &mut World) {
+        let mut counters = world.query::<&mut Counter>();
+        for mut counter in counters.iter_mut(world) {
+            // Order sensitive, so commands applied out of order desync
+            counter.0 = counter.0.wrapping_mul(31).wrapping_add(self.0 + ctx.client);
+        }
+    }
+}
+
+fn setup(app: &mut App) {
+    app.register_type::<Counter>()
+        .register_game_command::<Add>()
+        .include_in_state_hash::<Counter>();
+    let id = SimulationId::from_raw(1).expect("1 is a valid id");
+    app.world_mut().spawn((id, Counter::default()));
+}
+
+fn settings() -> SimulationSettings {
+    SimulationSettings {
+        tick_timestep: Duration::from_millis(33),
+        ..default()
+    }
+}
+
+#[test]
+fn clients_apply_commands_identically() {
+    let mut game = LockstepTestMatch::new(settings(), 3, setup);
+    game.start();
+    for round in 0..20 {
+        for client in 0..3 {
+            game.send(client, [Box::new(Add(round * 3 + client as u64)) as Box<dyn PartialReflect>]);
+        }
+        game.advance_ticks(1);
+    }
+    game.advance_ticks(10);
+    game.assert_in_sync();
+    let world = game.clients[0].world_mut();
+    let counter = world.query::<&Counter>().single(world);
+    assert_ne!(counter.0, 0, "no command was applied");
+}
+
+#[test]
+fn inferred_empty_commands_keep_clients_in_sync() {
+    let settings = SimulationSettings {
+        empty_command_suppression: Some(EmptyCommandSuppression::default()),
+        ..settings()
+    };
+    let mut game = LockstepTestMatch::new(settings, 2, setup);
+    game.start();
+    // Only client 0 sends commands, client 1 stays silent between heartbeats
+    for round in 0..10 {
+        game.send(0, [Box::new(Add(round)) as Box<dyn PartialReflect>]);
+        game.advance_ticks(3);
+    }
+    game.advance_ticks(30);
+    game.assert_in_sync();
+}
+
+#[derive(Reflect, Debug)]
+struct Double;
+
+fn double_counters(items: Res<LockstepCommandItems<Double>>, mut counters: Query<&mut Counter>) {
+    for (client, _tick, _) in items.iter() {
+        for mut counter in counters.iter_mut() {
+            counter.0 = counter.0.wrapping_mul(2).wrapping_add(client);
+        }
+    }
+}
+
+/// The commands each handler saw, as (handler, client, tick)
+#[derive(Resource, Default)]
+struct HandlerLog(Vec<(u8, ClientId, SimTick)>);
+
+fn log_doubles<const HANDLER: u8>(items: Res<LockstepCommandItems<Double>>, mut log: ResMut<HandlerLog>) {
+    for (client, tick, _) in items.iter() {
+        log.0.push((HANDLER, client, tick));
+    }
+}
+
+#[test]
+fn command_handlers_run_in_order() {
+    let setup = |app: &mut App| {
+        setup(app);
+        app.init_resource::<HandlerLog>()
+            .on_lockstep_command::<Double, _>(log_doubles::<0>)
+            .on_lockstep_command::<Double, _>(double_counters)
+            .on_lockstep_command::<Double, _>(log_doubles::<1>);
+    };
+    let mut game = LockstepTestMatch::new(settings(), 2, setup);
+    game.start();
+    for round in 0..10 {
+        game.send(0, [Box::new(Add(round)) as Box<dyn PartialReflect>]);
+        game.send(1, [Box::new(Double) as Box<dyn PartialReflect>]);
+        game.advance_ticks(1);
+    }
+    game.advance_ticks(10);
+    game.assert_in_sync();
+
+    // Every tick, the first handler saw its commands before the last one
+    let log = &game.clients[1].world().resource::<HandlerLog>().0;
+    let first: Vec<(ClientId, SimTick)> = log
+        .iter()
+        .filter(|(handler, ..)| *handler == 0)
+        .map(|&(_, client, tick)| (client, tick))
+        .collect();
+    assert_eq!(first.len(), 10);
+    assert!(first.iter().all(|&(client, _)| client == 3), "commands of another client: {:?}", first);
+    let mut ticks: Vec<SimTick> = first.iter().map(|&(_, tick)| tick).collect();
+    ticks.dedup();
+    let mut expected = Vec::new();
+    for tick in ticks {
+        for handler in [0, 1] {
+            expected.extend(first
+                .iter()
+                .filter(|&&(_, at)| at == tick)
+                .map(|&(client, _)| (handler, client, tick)));
+        }
+    }
+    assert_eq!(*log, expected);
+    assert_eq!(game.clients[0].world().resource::<HandlerLog>().0, expected);
+}
+
+#[derive(Reflect, Debug)]
+struct Bump {
+    target: SimulationId,
+    value: u64,
+}
+
+impl Bump {
+    fn bump(&self, ctx: &CommandContext, counter: &mut Counter) {
+        counter.0 = counter.0.wrapping_mul(31).wrapping_add(self.value + ctx.client);
+    }
+}
+
+impl GameCommand for Bump {
+    fn apply(&self, ctx: &CommandContext, world: &mut World) {
+        let Some(&entity) = world.resource::<SimulationIdEntityMap>().get(&self.target) else { return };
+        if let Some(mut counter) = world.get_mut::<Counter>(entity) {
+            self.bump(ctx, &mut counter);
+        }
+    }
+}
+
+impl ParallelGameCommand for Bump {
+    fn target(&self) -> SimulationId {
+        self.target
+    }
+
+    fn apply_to(&self, ctx: &CommandContext, entity: &mut bevy::ecs::world::EntityMut) {
+        if let Some(mut counter) = entity.get_mut::<Counter>() {
+            self.bump(ctx, &mut counter);
+        }
+    }
+}
+
+fn run_bumps(parallel_apply_threshold: Option<usize>) -> Vec<u64> {
+    let setup = |app: &mut App| {
+        app.register_type::<Counter>()
+            .register_parallel_game_command::<Bump>()
+            .register_game_command::<Add>()
+            .include_in_state_hash::<Counter>();
+        for raw in 1..=4 {
+            let id = SimulationId::from_raw(raw).expect("not 0");
+            app.world_mut().spawn((id, Counter::default()));
+        }
+    };
+    let settings = SimulationSettings { parallel_apply_threshold, ..settings() };
+    let mut game = LockstepTestMatch::new(settings, 2, setup);
+    game.start();
+    for round in 0..10u64 {
+        for client in 0..2 {
+            // Overlapping targets, and a sequential command in between
+            let mut commands: Vec<Box<dyn PartialReflect>> = (0..8)
+                .map(|i| Box::new(Bump {
+                    target: SimulationId::from_raw(1 + (i + round as u32) % 4).expect("not 0"),
+                    value: round * 8 + i as u64,
+                }) as Box<dyn PartialReflect>)
+                .collect();
+            commands.insert(4, Box::new(Add(round)));
+            game.send(client, commands);
+        }
+        game.advance_ticks(1);
+    }
+    game.advance_ticks(10);
+    game.assert_in_sync();
+    let world = game.clients[0].world_mut();
+    let mut counters: Vec<(SimulationId, u64)> = world
+        .query::<(&SimulationId, &Counter)>()
+        .iter(world)
+        .map(|(id, counter)| (*id, counter.0))
+        .collect();
+    counters.sort_by_key(|(id, _)| **id);
+    counters.into_iter().map(|(_, value)| value).collect()
+}
+
+#[test]
+fn parallel_application_matches_sequential() {
+    let sequential = run_bumps(None);
+    assert!(sequential.iter().any(|&value| value != 0), "no command was applied");
+    assert_eq!(run_bumps(Some(2)), sequential);
+}
+
+#[test]
+fn clients_receive_the_replication_tick_of_each_applied_tick() {
+    let settings = SimulationSettings { replication_tick_mapping: true, ..settings() };
+    let mut game = LockstepTestMatch::new(settings, 2, setup);
+    game.start();
+    game.advance_ticks(10);
+    game.frame();
+    let tick = game.applied_tick();
+    let server_ticks = game.server.world().resource::<ReplicationTicks>();
+    let expected = server_ticks.replicon_tick(tick).expect("the server recorded the applied tick");
+    for client in game.clients.iter() {
+        let ticks = client.world().resource::<ReplicationTicks>();
+        assert_eq!(ticks.replicon_tick(tick), Some(expected));
+    }
+}
+
+#[test]
+fn reconstructed_world_matches_the_clients() {
+    let mut game = LockstepTestMatch::new(settings(), 2, setup);
+    game.start();
+    for round in 0..10 {
+        for client in 0..2 {
+            game.send(client, [Box::new(Add(round * 2 + client as u64)) as Box<dyn PartialReflect>]);
+        }
+        game.advance_ticks(1);
+    }
+    game.advance_ticks(5);
+    let client = game.clients[0].world_mut();
+    let tick = **client.resource::<LastAppliedTick>();
+    let replay = MatchReplay::from_buffer(*client.resource::<SessionHash>(), client.resource::<LockstepGameCommandBuffer>())
+        .with_rng_seed(client.resource::<LockstepRng>().seed());
+    let expected = client.query::<&Counter>().single(client).0;
+    assert_ne!(expected, 0, "no command was applied");
+
+    let mut world = reconstruct_world(&replay, tick, setup);
+    assert_eq!(world.query::<&Counter>().single(&world).0, expected);
+}
+
+#[derive(Reflect, Debug)]
+struct SpawnCounter;
+
+impl GameCommand for SpawnCounter {
+    fn apply(&self, _ctx: &CommandContext, world: &mut World) {
+        let id = world.resource_mut::<SimulationIdAllocator>().allocate();
+        world.spawn((id, Counter::default()));
+    }
+}
+
+#[test]
+fn peers_in_one_process_allocate_the_same_ids() {
+    let setup = |app: &mut App| {
+        setup(app);
+        app.register_game_command::<SpawnCounter>();
+    };
+    let mut game = LockstepTestMatch::new(settings(), 2, setup);
+    game.start();
+    for _ in 0..3 {
+        game.send(0, [Box::new(SpawnCounter) as Box<dyn PartialReflect>]);
+        game.send(1, [Box::new(SpawnCounter) as Box<dyn PartialReflect>]);
+        game.advance_ticks(1);
+    }
+    game.advance_ticks(5);
+    let ids: Vec<Vec<u32>> = game.clients
+        .iter_mut()
+        .map(|client| {
+            let world = client.world_mut();
+            let mut ids: Vec<u32> = world.query::<&SimulationId>().iter(world).map(|id| **id).collect();
+            ids.sort_unstable();
+            ids
+        })
+        .collect();
+    assert_eq!(ids[0].len(), 7, "the initial entity and six spawned ones");
+    assert_eq!(ids[0], ids[1]);
+}
+
+#[test]
+fn restarting_despawns_simulated_entities_in_the_lockstep_schedule() {
+    let settings = SimulationSettings { unscheduled_despawns: UnscheduledDespawnPolicy::Deny, ..settings() };
+    let mut game = LockstepTestMatch::new(settings, 2, setup);
+    game.start();
+    game.advance_ticks(5);
+    game.server.world_mut().trigger(RestartSimulation);
+    // Denied unscheduled despawns would panic here
+    game.start();
+    for client in game.clients.iter_mut() {
+        let world = client.world_mut();
+        assert_eq!(world.query::<&SimulationId>().iter(world).count(), 0);
+    }
+}
+
+#[test]
+fn resets_apply_on_their_tick_in_peers_and_replays() {
+    let settings = SimulationSettings { unscheduled_despawns: UnscheduledDespawnPolicy::Deny, ..settings() };
+    let mut game = LockstepTestMatch::new(settings, 2, setup);
+    game.start();
+    game.advance_ticks(3);
+    let before_reset = game.server_tick();
+    // Denied unscheduled despawns would panic here
+    game.server.world_mut().trigger(RequestSimulationReset);
+    game.advance_ticks(5);
+    game.assert_in_sync();
+    let client = game.clients[0].world_mut();
+    assert_eq!(client.query::<&SimulationId>().iter(client).count(), 0);
+
+    let tick = **client.resource::<LastAppliedTick>();
+    let replay = MatchReplay::from_buffer(*client.resource::<SessionHash>(), client.resource::<LockstepGameCommandBuffer>())
+        .with_rng_seed(client.resource::<LockstepRng>().seed());
+    let mut world = reconstruct_world(&replay, before_reset, setup);
+    assert_eq!(world.query::<&SimulationId>().iter(&world).count(), 1);
+    let mut world = reconstruct_world(&replay, tick, setup);
+    assert_eq!(world.query::<&SimulationId>().iter(&world).count(), 0);
+}
+
+/// Counts the simulated time in milliseconds, so a tick stepped with the
+/// wrong timestep shows up in the state hash
+fn count_step_time(time: Res<Time>, mut counters: Query<&mut Counter>) {
+    for mut counter in counters.iter_mut() {
+        counter.0 += time.delta().as_millis() as u64;
+    }
+}
+
+#[test]
+fn late_joiners_step_each_tick_with_the_timestep_of_that_tick() {
+    let setup = |app: &mut App| {
+        setup(app);
+        app.add_systems(PhysicsStep, count_step_time);
+    };
+    let mut game = LockstepTestMatch::new(settings(), 2, setup);
+    game.start();
+    game.advance_ticks(3);
+    game.server.world_mut().trigger(RequestTickRate { timestep: Duration::from_millis(50) });
+    game.advance_ticks(10);
+
+    // Fast-forwards through the ticks before and after the change
+    let spectator = game.join(ClientRole::Spectator);
+    game.clients[spectator].insert_resource(settings());
+    for _ in 0..30 { game.frame() }
+    game.advance_ticks(1);
+    game.assert_in_sync();
+}
+
+#[derive(Resource, Default)]
+struct ResolvedChanges(Vec<(SimTick, SettingsChange)>);
+
+fn record_resolved(trigger: Trigger<SettingsVoteResolved>, mut resolved: ResMut<ResolvedChanges>) {
+    let event = trigger.event();
+    if event.passed {
+        resolved.0.push((event.effective_tick, event.change));
+    }
+}
+
+#[test]
+fn reconstructed_world_follows_tick_rate_changes() {
+    let setup = |app: &mut App| {
+        setup(app);
+        app.add_systems(PhysicsStep, count_step_time);
+    };
+    let mut game = LockstepTestMatch::new(settings(), 2, setup);
+    game.clients[0].init_resource::<ResolvedChanges>().add_observer(record_resolved);
+    game.start();
+    game.advance_ticks(3);
+    game.server.world_mut().trigger(RequestTickRate { timestep: Duration::from_millis(50) });
+    game.advance_ticks(10);
+
+    let client = game.clients[0].world_mut();
+    let changes = client.resource::<ResolvedChanges>().0.clone();
+    assert_eq!(changes.len(), 1);
+    let tick = **client.resource::<LastAppliedTick>();
+    let replay = MatchReplay::from_buffer(*client.resource::<SessionHash>(), client.resource::<LockstepGameCommandBuffer>())
+        .with_settings(&settings(), 0)
+        .with_settings_changes(changes)
+        .with_rng_seed(client.resource::<LockstepRng>().seed());
+    let expected = client.query::<&Counter>().single(client).0;
+
+    let mut world = reconstruct_world(&replay, tick, setup);
+    assert_eq!(world.query::<&Counter>().single(&world).0, expected);
+}
@@ -0,0 +1,121 @@
+//! Peers rendering at different frame rates stay in lockstep: a dedicated
+//! server and a 240 FPS client against a 30 FPS client, over replicon's
+//! in-memory test transport
+
+use std::time::Duration;
+use bevy::prelude::*;
+use bevy_replicon_lockstep::{prelude::*, test_utils::{state, LockstepTestMatch}};
+
+/// Frames of the fast client per frame of the slow client
+const SLOW_FRAME_STEPS: u32 = 8;
+const FAST_FRAME: Duration = Duration::from_nanos(1_000_000_000 / 240);
+
+/// Game commands applied on a peer, in order
+#[derive(Resource, Default)]
+struct Applied(Vec<(SimTick, ClientId, u32)>);
+
+#[derive(Reflect, Debug)]
+struct Push(u32);
+
+impl GameCommand for Push {
+    fn apply(&self, ctx: &CommandContext, world: &mut World) {
+        world.resource_mut::<Applied>().0.push((ctx.tick, ctx.client, self.0));
+    }
+}
+
+/// A dedicated server and a 240 FPS client, then a 30 FPS client
+fn new_match() -> LockstepTestMatch {
+    let setup = |app: &mut App| {
+        app.init_resource::<Applied>()
+            .register_game_command::<Push>();
+    };
+    let mut game = LockstepTestMatch::new(settings(), 2, setup);
+    game.set_frame_time(FAST_FRAME);
+    game.set_frame_interval(1, SLOW_FRAME_STEPS);
+    game
+}
+
+fn last_applied(game: &LockstepTestMatch, index: usize) -> SimTick {
+    **game.clients[index].world().resource::<LastAppliedTick>()
+}
+
+fn applied(game: &LockstepTestMatch, index: usize, up_to: SimTick) -> Vec<(SimTick, ClientId, u32)> {
+    game.clients[index]
+        .world()
+        .resource::<Applied>()
+        .0
+        .iter()
+        .filter(|(tick, ..)| *tick <= up_to)
+        .copied()
+        .collect()
+}
+
+/// A 10ms tick: several ticks per slow frame, several fast frames per tick.
+/// Catching up is limited to one tick per frame, which a slow frame must
+/// not be held to.
+fn settings() -> SimulationSettings {
+    SimulationSettings {
+        num_players: 2,
+        tick_timestep: Duration::from_millis(10),
+        catch_up: CatchUpPolicy { max_ticks_per_frame: 1, ..default() },
+        ..default()
+    }
+}
+
+#[test]
+fn slow_client_applies_every_tick_it_receives() {
+    let mut game = new_match();
+    game.start();
+    for _ in 0..240 * 2 { game.frame() }
+
+    assert_eq!(state(game.server.world()), SimulationState::Running);
+    // Two seconds of 10ms ticks, give or take the start up and input delay
+    assert!(game.server_tick() > 150, "server only reached tick {}", game.server_tick());
+    // Neither client lags more than a slow frame and its latency behind
+    for index in 0..2 {
+        let behind = game.server_tick() - last_applied(&game, index);
+        assert!(behind <= 2 * SLOW_FRAME_STEPS, "client {} is {} ticks behind", index, behind);
+    }
+}
+
+#[test]
+fn empty_commands_echo_every_tick_at_any_frame_rate() {
+    let mut game = new_match();
+    game.start();
+    let started_at = game.server_tick();
+    for _ in 0..240 {
+        game.frame();
+        // Every received tick is echoed, however many arrive in one frame,
+        // so the slow client trails by its frame time, not more
+        let diagnostics = game.server.world().resource::<LockstepNetworkDiagnostics>();
+        for client in [2, 3] {
+            let received = diagnostics.client(client).map_or(0, |stats| stats.last_received_tick);
+            let behind = game.server_tick().saturating_sub(received);
+            assert!(behind <= 2 * SLOW_FRAME_STEPS, "client {} echoes {} ticks behind", client, behind);
+        }
+    }
+    assert_eq!(state(game.server.world()), SimulationState::Running);
+    assert!(game.server_tick() > started_at + 50);
+}
+
+#[test]
+fn clients_at_different_frame_rates_apply_the_same_commands() {
+    let mut game = new_match();
+    game.start();
+    for _ in 0..240 * 2 {
+        // Both clients issue a command every frame they render
+        let frame = game.frames();
+        for (index, client) in game.clients.iter().enumerate() {
+            if !game.renders(index) { continue }
+            client.world().resource::<LockstepCommandQueue>().push(Push(frame));
+        }
+        game.frame();
+    }
+
+    let up_to = last_applied(&game, 0).min(last_applied(&game, 1));
+    let fast = applied(&game, 0, up_to);
+    let slow = applied(&game, 1, up_to);
+    assert!(fast.iter().any(|(_, client, _)| *client == 3), "no commands from the slow client applied");
+    assert!(slow.iter().any(|(_, client, _)| *client == 2), "no commands from the fast client applied");
+    assert_eq!(fast, slow);
+}
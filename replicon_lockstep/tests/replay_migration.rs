@@ -0,0 +1,78 @@
+//! Replays recorded by older versions of the crate still load.  The
+//! fixtures are replays encoded by those versions and must never be
+//! regenerated: `replay_v1.bin` holds three ticks, an empty one, a
+//! `Marker(7)` from client 2, then `Marker(8)` from client 2 and
+//! `Marker(9)` from client 3.
+
+use std::time::Duration;
+use bevy::prelude::*;
+use bevy_replicon_lockstep::prelude::*;
+
+/// The command type of the fixtures.  Its type path, and so its command id,
+/// must stay the same.
+#[derive(Reflect, Debug, PartialEq)]
+struct Marker(u32);
+
+const REPLAY_V1: &[u8] = include_bytes!("fixtures/replay_v1.bin");
+
+fn registry() -> AppTypeRegistry {
+    let mut app = App::new();
+    app.register_lockstep_command::<Marker>();
+    app.world().resource::<AppTypeRegistry>().clone()
+}
+
+fn markers(replay: &MatchReplay, tick: usize) -> Vec<(ClientId, Vec<Marker>)> {
+    replay.ticks[tick]
+        .iter()
+        .map(|(&client, commands)| {
+            let markers = commands
+                .iter()
+                .map(|command| Marker::from_reflect(command.as_ref()).expect("a marker command"))
+                .collect();
+            (client, markers)
+        })
+        .collect()
+}
+
+#[test]
+fn version_1_replay_loads() {
+    assert_eq!(replay_version(REPLAY_V1).unwrap(), 1);
+    let replay = MatchReplay::from_bytes(REPLAY_V1, &registry().read()).unwrap();
+
+    assert_eq!(replay.session, SessionHash(0x1234_abcd));
+    assert_eq!(replay.settings, ReplaySettings {
+        tick_timestep: Duration::from_millis(33),
+        num_players: 2,
+        base_input_tick_delay: 2,
+        connection_check_tick_delay: 5,
+        disconnect_tick_threshold: 10,
+    });
+    // Version 1 recorded no settings changes
+    assert!(replay.settings_changes.is_empty());
+    assert_eq!(replay.seed, 42);
+    // Version 1 had no shared rng
+    assert_eq!(replay.rng_seed, 0);
+    assert_eq!(replay.reported_hash, Some(0xdead_beef));
+    assert_eq!(replay.last_tick(), 2);
+    assert!(replay.ticks[0].is_empty());
+    assert_eq!(markers(&replay, 1), vec![(2, vec![Marker(7)])]);
+    assert_eq!(markers(&replay, 2), vec![(2, vec![Marker(8)]), (3, vec![Marker(9)])]);
+}
+
+#[test]
+fn migrated_replay_is_current() {
+    let migrated = migrate_history(REPLAY_V1, 1).unwrap();
+    assert_eq!(replay_version(&migrated).unwrap(), REPLAY_VERSION);
+    // Encoding again gives the same bytes as migrating
+    let registry = registry();
+    let replay = MatchReplay::from_bytes(&migrated, &registry.read()).unwrap();
+    assert_eq!(replay.to_bytes(&registry.read()).unwrap(), migrated);
+}
+
+#[test]
+fn replay_of_another_version_is_rejected() {
+    assert!(matches!(migrate_history(REPLAY_V1, 2), Err(ReplayError::Format)));
+    let mut truncated = REPLAY_V1.to_vec();
+    truncated.truncate(REPLAY_V1.len() - 1);
+    assert!(MatchReplay::from_bytes(&truncated, &registry().read()).is_err());
+}
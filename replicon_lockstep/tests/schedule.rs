@@ -0,0 +1,82 @@
+//! Scheduling math of the lockstep core
+
+use bevy_replicon_lockstep::lockstep_core::{schedule::*, TickBuffer};
+
+const TIMESTEP: f64 = 0.033;
+
+#[test]
+fn tick_zero_bootstrap_checks_tick_zero() {
+    // Early in the match the check delay reaches before tick 0
+    assert_eq!(tick_to_check(0, 3), 0);
+    assert_eq!(tick_to_check(2, 3), 0);
+    assert_eq!(tick_to_check(5, 3), 2);
+}
+
+#[test]
+fn rtt_rounds_up_to_whole_ticks() {
+    assert_eq!(rtt_to_ticks(0.0, TIMESTEP), 0);
+    assert_eq!(rtt_to_ticks(0.05, TIMESTEP), 1);
+    assert_eq!(rtt_to_ticks(0.1, TIMESTEP), 2);
+    assert_eq!(rtt_to_ticks(0.2, TIMESTEP), 4);
+}
+
+#[test]
+fn invalid_rtt_adds_no_delay() {
+    assert_eq!(rtt_to_ticks(f64::NAN, TIMESTEP), 0);
+    assert_eq!(rtt_to_ticks(f64::INFINITY, TIMESTEP), 0);
+    assert_eq!(rtt_to_ticks(-1.0, TIMESTEP), 0);
+    assert_eq!(rtt_to_ticks(0.1, 0.0), 0);
+}
+
+#[test]
+fn rtt_spike_only_delays_later_commands() {
+    let base = 1;
+    let before = execution_tick(100, input_delay(rtt_to_ticks(0.05, TIMESTEP), base));
+    let during = execution_tick(101, input_delay(rtt_to_ticks(1.0, TIMESTEP), base));
+    let after = execution_tick(102, input_delay(rtt_to_ticks(0.05, TIMESTEP), base));
+    assert_eq!(before, 102);
+    assert_eq!(during, 118);
+    // After the spike commands can execute before the ones sent during it
+    assert!(after < during);
+}
+
+#[test]
+fn execution_is_relative_to_the_server_tick() {
+    // The issued tick plays no part, a client reporting a tick far ahead
+    // still executes relative to the server's current tick
+    assert_eq!(execution_tick(10, input_delay(0, 1)), 11);
+}
+
+#[test]
+fn broadcast_ticks_are_final() {
+    assert!(!is_schedulable(10, 10));
+    assert!(!is_schedulable(9, 10));
+    assert!(is_schedulable(11, 10));
+}
+
+#[test]
+fn tick_buffer_fills_gaps_with_defaults() {
+    let mut buffer = TickBuffer::<u32>::default();
+    *buffer.get_or_default(3) = 7;
+    assert_eq!(buffer.len(), 4);
+    assert_eq!(buffer.get(0), Some(&0));
+    assert_eq!(buffer.get(3), Some(&7));
+    assert_eq!(buffer.get(4), None);
+}
+
+#[test]
+fn slow_frames_apply_every_tick_they_took() {
+    // 30 FPS with a 10ms tick produces 3.3 ticks per frame
+    assert_eq!(ticks_per_frame(1.0 / 30.0, 0.01, 1), 5);
+    assert_eq!(ticks_per_frame(0.03, 0.01, 1), 4);
+    // Fast frames are bounded by the catch-up limit only
+    assert_eq!(ticks_per_frame(1.0 / 240.0, 0.01, 16), 16);
+    assert_eq!(ticks_per_frame(1.0 / 240.0, 0.01, 0), 2);
+}
+
+#[test]
+fn invalid_frame_times_use_the_catch_up_limit() {
+    assert_eq!(ticks_per_frame(f64::NAN, 0.01, 16), 16);
+    assert_eq!(ticks_per_frame(0.0, 0.01, 16), 16);
+    assert_eq!(ticks_per_frame(0.1, 0.0, 16), 16);
+}
@@ -0,0 +1,66 @@
+//! Storage backends keep the artifacts of every match apart
+
+use std::{sync::Arc, time::{Duration, SystemTime}};
+use bevy::prelude::*;
+use bevy_replicon_lockstep::{prelude::*, StoragePlugin};
+
+/// Puts, gets and lists artifacts of two matches
+fn check_backend(storage: &dyn LockstepStorage) {
+    storage.put("a", ArtifactKind::Replay, "replay", b"first").unwrap();
+    storage.put("a", ArtifactKind::CrashLog, "tick-7", b"desync").unwrap();
+    storage.put("b", ArtifactKind::Replay, "replay", b"second").unwrap();
+    assert_eq!(storage.get("a", ArtifactKind::Replay, "replay").unwrap(), b"first");
+    assert_eq!(storage.get("b", ArtifactKind::Replay, "replay").unwrap(), b"second");
+    assert_eq!(storage.list("a", ArtifactKind::Replay).unwrap(), vec!["replay"]);
+    assert_eq!(storage.list("a", ArtifactKind::CrashLog).unwrap(), vec!["tick-7"]);
+    assert!(storage.list("a", ArtifactKind::Snapshot).unwrap().is_empty());
+    assert!(matches!(storage.get("c", ArtifactKind::Replay, "replay"), Err(StorageError::NotFound)));
+
+    storage.put("a", ArtifactKind::Replay, "replay", b"replaced").unwrap();
+    assert_eq!(storage.get("a", ArtifactKind::Replay, "replay").unwrap(), b"replaced");
+}
+
+#[test]
+fn memory_storage_keeps_matches_apart() {
+    check_backend(&MemoryStorage::default());
+}
+
+#[test]
+fn file_storage_keeps_matches_apart() {
+    let root = std::env::temp_dir().join(format!("lockstep-storage-{}", std::process::id()));
+    check_backend(&FileStorage::new(&root));
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn matches_with_the_same_session_get_different_ids() {
+    let session = SessionHash(0x1234);
+    let started = SystemTime::now();
+    let id = artifact_match_id(session, started);
+    assert_ne!(id, artifact_match_id(session, started + Duration::from_millis(1)));
+    assert!(id.starts_with("00001234-"));
+}
+
+#[test]
+fn artifacts_of_a_match_share_its_id() {
+    let storage = MemoryStorage::default();
+    let mut app = App::new();
+    app.add_plugins(StoragePlugin::new(storage.clone()))
+        .insert_resource(SessionHash(0x1234));
+    let store = |app: &mut App, name: &str| app.world_mut().trigger(StoreArtifact {
+        kind: ArtifactKind::CrashLog,
+        name: name.to_string(),
+        data: Arc::new(name.as_bytes().to_vec()),
+    });
+    store(&mut app, "first");
+    store(&mut app, "second");
+    let match_id = app.world().resource::<ArtifactMatchId>().get().expect("assigned on store").to_string();
+    assert_eq!(storage.list(&match_id, ArtifactKind::CrashLog).unwrap(), vec!["first", "second"]);
+
+    // A rematch of the same session starts a new id
+    app.insert_resource(ArtifactMatchId::default());
+    store(&mut app, "rematch");
+    let rematch_id = app.world().resource::<ArtifactMatchId>().get().expect("assigned on store").to_string();
+    assert_ne!(match_id, rematch_id);
+    assert_eq!(storage.list(&rematch_id, ArtifactKind::CrashLog).unwrap(), vec!["rematch"]);
+}
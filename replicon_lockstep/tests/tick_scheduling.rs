@@ -0,0 +1,591 @@
+//! Tick gating and scheduling of a running match, over replicon's in-memory
+//! test transport with a dedicated server and two clients, or a host
+//! playing alone
+
+use std::time::Duration;
+use bevy::prelude::*;
+use bevy_replicon::{prelude::*, test_app::ServerTestAppExt};
+use bevy_replicon_lockstep::{prelude::*, commands::lockstep_command_id, test_utils::{peer_app, state, LockstepTestMatch}};
+
+#[derive(Reflect, Debug, PartialEq)]
+struct Marker(u32);
+
+/// A dedicated server and two players
+fn new_match(settings: SimulationSettings) -> LockstepTestMatch {
+    LockstepTestMatch::new(settings, 2, |app: &mut App| {
+        app.register_lockstep_command::<Marker>();
+    })
+}
+
+fn client_tick(game: &LockstepTestMatch, index: usize) -> SimTick {
+    **game.clients[index].world().resource::<SimulationTick>()
+}
+
+/// Sends a marker command from a client as issued on `issued_tick`
+fn send(game: &mut LockstepTestMatch, index: usize, issued_tick: SimTick, value: u32) {
+    let world = game.clients[index].world_mut();
+    world.commands().client_trigger(ClientSendCommands {
+        issued_tick,
+        commands: vec![Box::new(Marker(value))],
+        ..default()
+    });
+    world.flush();
+}
+
+fn blocked_this_frame(game: &LockstepTestMatch) -> usize {
+    game.server.world().resource::<Events<TickBlockedWaitingOn>>().iter_current_update_events().count()
+}
+
+fn suspected_this_frame(game: &LockstepTestMatch) -> Vec<ClientId> {
+    game.server
+        .world()
+        .resource::<Events<DisconnectSuspected>>()
+        .iter_current_update_events()
+        .map(|event| event.0)
+        .collect()
+}
+
+/// Panics unless both players received the same commands for every tick
+/// they reached
+fn assert_clients_agree(game: &LockstepTestMatch) {
+    let last = client_tick(game, 0).min(client_tick(game, 1));
+    let registry = game.server.world().resource::<AppTypeRegistry>().read();
+    for tick in 1..=last {
+        let hashes: Vec<Option<u64>> = game.clients
+            .iter()
+            .map(|client| client
+                .world()
+                .resource::<LockstepGameCommandBuffer>()
+                .get(tick)
+                .map(|commands| hash_tick_commands(commands, &registry)))
+            .collect();
+        assert_eq!(hashes[0], hashes[1], "clients disagree on tick {}", tick);
+    }
+}
+
+/// Runs a frame in which everything the server sends to a client is lost
+fn lose_server_messages(game: &mut LockstepTestMatch, index: usize) {
+    game.server.update();
+    let lost = game.client_entities[index];
+    let mut server = game.server.world_mut().resource_mut::<RepliconServer>();
+    let kept: Vec<_> = server.drain_sent().filter(|&(client, ..)| client != lost).collect();
+    for (client, channel, message) in kept {
+        server.send(client, channel, message);
+    }
+    for client in game.clients.iter_mut() {
+        game.server.exchange_with_client(client);
+        client.update();
+        game.server.exchange_with_client(client);
+    }
+}
+
+fn settings() -> SimulationSettings {
+    SimulationSettings {
+        tick_timestep: Duration::from_millis(33),
+        disconnect_tick_threshold: 5,
+        ..default()
+    }
+}
+
+#[test]
+fn bootstraps_from_tick_zero() {
+    let mut game = new_match(settings());
+    game.start();
+    assert_eq!(game.server_tick(), 0);
+    for _ in 0..10 { game.frame() }
+    assert!(game.server_tick() > 0);
+    assert!(client_tick(&game, 0) > 0 && client_tick(&game, 1) > 0);
+    assert_clients_agree(&game);
+}
+
+#[test]
+fn idle_clients_keep_the_simulation_running() {
+    let mut game = new_match(settings());
+    game.start();
+    // Clients send empty commands every tick, so a match where nobody
+    // issues anything never stalls
+    let mut last_tick = game.server_tick();
+    for _ in 0..20 {
+        for _ in 0..10 { game.frame() }
+        assert!(game.server_tick() > last_tick);
+        last_tick = game.server_tick();
+    }
+    assert_eq!(state(game.server.world()), SimulationState::Running);
+    assert_clients_agree(&game);
+}
+
+#[test]
+fn measures_lockstep_bandwidth_per_command_type() {
+    let mut game = new_match(SimulationSettings { bandwidth_diagnostics: true, ..settings() });
+    game.start();
+    for _ in 0..5 { game.frame() }
+    let tick = client_tick(&game, 0);
+    send(&mut game, 0, tick, 1);
+    for _ in 0..20 { game.frame() }
+
+    let bandwidth = game.clients[1].world().resource::<LockstepBandwidth>();
+    assert!(bandwidth.total_sent > 0 && bandwidth.total_received > 0);
+    assert!(bandwidth.sent_per_tick > 0.0 && bandwidth.received_per_tick > 0.0);
+    let (type_path, marker) = bandwidth.heaviest_commands()[0];
+    assert!(type_path.ends_with("Marker"));
+    assert_eq!(marker.received, 1);
+    assert!(marker.received_bytes > 0);
+}
+
+#[test]
+fn rtt_spike_increases_input_delay() {
+    let mut game = new_match(settings());
+    game.start();
+    for _ in 0..5 { game.frame() }
+
+    let tick = client_tick(&game, 0);
+    send(&mut game, 0, tick, 1);
+    game.frame();
+    let before = game.server.world().resource::<ClientExecutionSchedule>()[&2];
+
+    let entity = game.client_entities[0];
+    game.server.world_mut().entity_mut(entity).insert(NetworkStats { rtt: 0.5, ..default() });
+    let tick = client_tick(&game, 0);
+    send(&mut game, 0, tick, 2);
+    game.frame();
+    let during = game.server.world().resource::<ClientExecutionSchedule>()[&2];
+
+    // 250ms one way latency is 8 ticks of 33ms
+    assert_eq!(during.delay, 8 + settings().base_input_tick_delay as SimTick);
+    assert!(during.delay > before.delay);
+    assert!(during.execution_tick > before.execution_tick);
+
+    game.server.world_mut().entity_mut(entity).insert(NetworkStats::default());
+    for _ in 0..30 { game.frame() }
+    assert_clients_agree(&game);
+}
+
+#[test]
+fn commands_issued_far_ahead_execute_relative_to_the_server() {
+    let mut game = new_match(settings());
+    game.start();
+    for _ in 0..5 { game.frame() }
+
+    send(&mut game, 0, 10_000, 7);
+    game.frame();
+    let scheduled = game.server.world().resource::<ClientExecutionSchedule>()[&2];
+    assert_eq!(scheduled.issued_tick, 10_000);
+    assert!(scheduled.execution_tick <= game.server_tick() + 1 + settings().base_input_tick_delay as SimTick);
+
+    for _ in 0..10 { game.frame() }
+    let buffer = game.clients[1].world().resource::<LockstepGameCommandBuffer>();
+    let commands = buffer.get(scheduled.execution_tick).expect("tick was broadcast");
+    assert_eq!(commands[&2].len(), 1);
+}
+
+#[test]
+fn strict_scheduling_moves_commands_for_broadcast_ticks() {
+    let mut game = new_match(SimulationSettings { strict_scheduling: true, ..settings() });
+    game.start();
+    for _ in 0..10 { game.frame() }
+
+    #[derive(Resource, Default)]
+    struct Rescheduled(Vec<CommandsRescheduled>);
+    game.clients[0].init_resource::<Rescheduled>();
+    game.clients[0].add_observer(|trigger: Trigger<CommandsRescheduled>, mut rescheduled: ResMut<Rescheduled>| {
+        rescheduled.0.push(*trigger.event());
+    });
+    // Issued long ago, so the tick the client expects it on was broadcast
+    send(&mut game, 0, 1, 7);
+    let broadcast = game.server_tick();
+    game.frame();
+    game.frame();
+
+    let rescheduled = &game.clients[0].world().resource::<Rescheduled>().0;
+    assert_eq!(rescheduled.len(), 1);
+    let rescheduled = rescheduled[0];
+    assert_eq!(rescheduled.issued_tick, 1);
+    assert!(rescheduled.requested_tick <= broadcast);
+    assert!(rescheduled.execution_tick > broadcast);
+    let scheduled = game.server.world().resource::<ClientExecutionSchedule>()[&2];
+    assert_eq!(scheduled.execution_tick, rescheduled.execution_tick);
+
+    for _ in 0..10 { game.frame() }
+    let buffer = game.clients[1].world().resource::<LockstepGameCommandBuffer>();
+    let commands = buffer.get(rescheduled.execution_tick).expect("tick was broadcast");
+    assert_eq!(commands[&2].len(), 1);
+    assert!(buffer.get(rescheduled.requested_tick).is_none_or(|commands| !commands.contains_key(&2)));
+}
+
+#[test]
+fn commands_past_the_cap_are_truncated() {
+    let mut game = new_match(SimulationSettings {
+        max_commands_per_tick: Some(2),
+        command_limit_policy: CommandLimitPolicy::Truncate,
+        ..settings()
+    });
+    game.start();
+    for _ in 0..5 { game.frame() }
+
+    #[derive(Resource, Default)]
+    struct Exceeded(Vec<CommandRateLimitExceeded>);
+    game.server.init_resource::<Exceeded>();
+    game.server.add_observer(|trigger: Trigger<CommandRateLimitExceeded>, mut exceeded: ResMut<Exceeded>| {
+        exceeded.0.push(*trigger.event());
+    });
+    let world = game.clients[0].world_mut();
+    world.commands().client_trigger(ClientSendCommands {
+        issued_tick: 5,
+        commands: (0..5).map(|value| Box::new(Marker(value)) as Box<dyn PartialReflect>).collect(),
+        ..default()
+    });
+    world.flush();
+    game.frame();
+    let scheduled = game.server.world().resource::<ClientExecutionSchedule>()[&2];
+    let exceeded = &game.server.world().resource::<Exceeded>().0;
+    assert_eq!(exceeded.len(), 1);
+    assert_eq!((exceeded[0].client, exceeded[0].sent, exceeded[0].allowed), (2, 5, 2));
+
+    for _ in 0..10 { game.frame() }
+    let buffer = game.clients[1].world().resource::<LockstepGameCommandBuffer>();
+    let commands = buffer.get(scheduled.execution_tick).expect("tick was broadcast");
+    assert_eq!(commands[&2].len(), 2);
+    assert!(buffer.get(scheduled.execution_tick + 1).is_none_or(|commands| !commands.contains_key(&2)));
+}
+
+#[test]
+fn lost_commands_are_resent_once() {
+    let mut game = new_match(settings());
+    game.start();
+    for _ in 0..5 { game.frame() }
+
+    game.clients[0].world().resource::<LockstepCommandQueue>().push(Marker(7));
+    // Everything client 0 sends this frame is lost
+    game.server.update();
+    game.server.exchange_with_client(&mut game.clients[0]);
+    game.clients[0].update();
+    game.clients[0].world_mut().resource_mut::<RepliconClient>().drain_sent().for_each(drop);
+    game.server.exchange_with_client(&mut game.clients[1]);
+    game.clients[1].update();
+    game.server.exchange_with_client(&mut game.clients[1]);
+
+    for _ in 0..20 { game.frame() }
+    assert_eq!(state(game.server.world()), SimulationState::Running);
+    let buffer = game.clients[1].world().resource::<LockstepGameCommandBuffer>();
+    let executed: usize = (0..=client_tick(&game, 1))
+        .filter_map(|tick| buffer.get(tick))
+        .filter_map(|commands| commands.get(&2))
+        .map(Vec::len)
+        .sum();
+    assert_eq!(executed, 1);
+    assert_clients_agree(&game);
+}
+
+#[test]
+fn overlapping_tick_gaps_are_both_filled() {
+    // Lost history is requested again on the next frame
+    let mut game = new_match(SimulationSettings { tick_gap_timeout: Duration::ZERO, ..settings() });
+    game.start();
+    for _ in 0..5 { game.frame() }
+
+    // The first gap is requested, then its history is lost along with the
+    // next tick, opening a second gap while the first is still pending
+    lose_server_messages(&mut game, 1);
+    game.frame();
+    let second_gap = client_tick(&game, 1) + 1;
+    lose_server_messages(&mut game, 1);
+    for _ in 0..20 { game.frame() }
+
+    assert_eq!(state(game.server.world()), SimulationState::Running);
+    assert!(**game.clients[1].world().resource::<LastAppliedTick>() > second_gap);
+    // Filling a gap with defaults instead would leave the empty commands
+    // of both players out of its ticks
+    assert_clients_agree(&game);
+}
+
+#[test]
+fn pauses_after_threshold_blocked_ticks() {
+    let mut game = new_match(settings());
+    game.start();
+    for _ in 0..5 { game.frame() }
+
+    game.stalled[1] = true;
+    let mut blocked = 0;
+    for _ in 0..100 {
+        game.frame();
+        blocked += blocked_this_frame(&game);
+        let suspected = suspected_this_frame(&game);
+        if !suspected.is_empty() {
+            assert_eq!(suspected, vec![3]);
+            // The frame that exceeds the threshold is the one that pauses
+            assert_eq!(blocked, settings().disconnect_tick_threshold as usize + 1);
+            game.frame();
+            assert_eq!(state(game.server.world()), SimulationState::Paused);
+            return;
+        }
+        assert_eq!(state(game.server.world()), SimulationState::Running);
+    }
+    panic!("stalled client never suspected");
+}
+
+#[test]
+fn drops_missing_clients_from_gating() {
+    let mut game = new_match(SimulationSettings { drop_missing_clients: true, ..settings() });
+    game.start();
+    for _ in 0..5 { game.frame() }
+
+    game.stalled[1] = true;
+    for _ in 0..100 {
+        game.frame();
+        if !suspected_this_frame(&game).is_empty() { break }
+    }
+    let dropped_at = game.server_tick();
+    for _ in 0..20 { game.frame() }
+    assert_eq!(state(game.server.world()), SimulationState::Running);
+    assert!(game.server_tick() > dropped_at + 10, "remaining player didn't resume");
+    assert!(client_tick(&game, 0) > dropped_at);
+}
+
+#[test]
+fn stalled_client_catches_up_below_threshold() {
+    let mut game = new_match(settings());
+    game.start();
+    for _ in 0..5 { game.frame() }
+
+    let missed = settings().disconnect_tick_threshold as usize - 1;
+    game.stalled[1] = true;
+    for _ in 0..missed { game.frame() }
+    let stalled_at = game.server_tick();
+    game.stalled[1] = false;
+
+    for _ in 0..20 { game.frame() }
+    assert_eq!(state(game.server.world()), SimulationState::Running);
+    assert!(game.server_tick() > stalled_at);
+    assert!(client_tick(&game, 1) >= stalled_at);
+    assert_clients_agree(&game);
+}
+
+#[test]
+fn reconnects_after_pause_and_resumes() {
+    let mut game = new_match(settings());
+    game.start();
+    for _ in 0..5 { game.frame() }
+
+    game.stalled[1] = true;
+    for _ in 0..50 {
+        game.frame();
+        if state(game.server.world()) == SimulationState::Paused { break }
+    }
+    assert_eq!(state(game.server.world()), SimulationState::Paused);
+    let paused_at = game.server_tick();
+
+    game.stalled[1] = false;
+    for _ in 0..5 { game.frame() }
+    // Nothing ticks until the server resumes
+    assert_eq!(game.server_tick(), paused_at);
+
+    game.server.world_mut().trigger(ResumeSimulation);
+    for _ in 0..30 { game.frame() }
+    assert_eq!(state(game.server.world()), SimulationState::Running);
+    assert!(game.server_tick() > paused_at);
+    assert_clients_agree(&game);
+}
+
+#[test]
+fn spectator_joins_mid_match_without_gating_ticks() {
+    let mut game = new_match(settings());
+    game.start();
+    for _ in 0..5 { game.frame() }
+
+    let spectator = game.join(ClientRole::Spectator);
+    for _ in 0..30 { game.frame() }
+    let entity = game.client_entities[spectator];
+    assert_eq!(game.server.world().get::<ClientRole>(entity), Some(&ClientRole::Spectator));
+    assert_eq!(state(game.clients[spectator].world()), SimulationState::Running);
+    assert!(client_tick(&game, spectator) > 5);
+
+    // A stalled spectator is never waited for
+    game.stalled[spectator] = true;
+    let stalled_at = game.server_tick();
+    for _ in 0..settings().disconnect_tick_threshold * 4 {
+        game.frame();
+        assert!(suspected_this_frame(&game).is_empty());
+    }
+    assert_eq!(state(game.server.world()), SimulationState::Running);
+    assert!(game.server_tick() > stalled_at + settings().disconnect_tick_threshold as SimTick);
+    assert_clients_agree(&game);
+}
+
+#[test]
+fn spectator_joining_after_a_tick_rate_change_adopts_it() {
+    let mut game = new_match(settings());
+    game.start();
+    let timestep = Duration::from_millis(50);
+    game.server.world_mut().trigger(RequestTickRate { timestep });
+    game.advance_ticks(5);
+    assert_eq!(game.server.world().resource::<SimulationSettings>().tick_timestep, timestep);
+
+    let spectator = game.join(ClientRole::Spectator);
+    // A real client starts from its own configuration, not the changed settings
+    game.clients[spectator].insert_resource(settings());
+    for _ in 0..30 { game.frame() }
+    let world = game.clients[spectator].world();
+    assert_eq!(state(world), SimulationState::Running);
+    assert_eq!(world.resource::<SimulationSettings>().tick_timestep, timestep);
+    assert_eq!(world.resource::<Time<Fixed>>().timestep(), timestep);
+}
+
+#[test]
+fn player_pause_expires_and_uses_up_the_allowance() {
+    let allowance = PauseAllowance { pauses_per_player: 1, max_duration: Duration::from_millis(330) };
+    let mut game = new_match(SimulationSettings { pause_allowance: Some(allowance), ..settings() });
+    game.start();
+    for _ in 0..5 { game.frame() }
+
+    let pause = |game: &mut LockstepTestMatch| {
+        let world = game.clients[0].world_mut();
+        world.commands().client_trigger(RequestPause);
+        world.flush();
+        for _ in 0..3 { game.frame() }
+    };
+    pause(&mut game);
+    assert_eq!(state(game.server.world()), SimulationState::Paused);
+    let status = game.clients[1].world().resource::<PauseStatus>();
+    assert_eq!(status.reason, Some(PauseReason::Player(2)));
+    assert!(status.remaining.is_some());
+
+    // Resumes on its own once the allowed duration passed
+    for _ in 0..20 { game.frame() }
+    assert_eq!(state(game.server.world()), SimulationState::Running);
+    for app in std::iter::once(&game.server).chain(game.clients.iter()) {
+        assert_eq!(app.world().resource::<PausesUsed>().get(&2), Some(&1));
+    }
+
+    pause(&mut game);
+    assert_eq!(state(game.server.world()), SimulationState::Running);
+    assert_clients_agree(&game);
+}
+
+#[test]
+fn player_leaving_during_setup_aborts_the_match() {
+    let mut game = new_match(settings());
+    for _ in 0..100 {
+        game.frame();
+        if state(game.server.world()) == SimulationState::Setup { break }
+    }
+    assert_eq!(state(game.server.world()), SimulationState::Setup);
+
+    let (server, clients) = (&mut game.server, &mut game.clients);
+    server.disconnect_client(&mut clients[1]);
+    game.stalled[1] = true;
+    for _ in 0..5 { game.frame() }
+    assert_eq!(state(game.server.world()), SimulationState::None);
+    assert_eq!(state(game.clients[0].world()), SimulationState::None);
+}
+
+/// Runs a practice match of the host alone until it applied tick 10.
+/// Panics if it doesn't.
+fn run_practice_match(settings: SimulationSettings) -> App {
+    let connection = ConnectionSettings { server_mode: ServerMode::Dedicated, ..default() };
+    let mut host = peer_app(&settings, connection, &|app: &mut App| {
+        app.register_lockstep_command::<Marker>();
+    });
+    host.world_mut().trigger(StartPracticeMatch);
+    let mut ready = false;
+    for _ in 0..100 {
+        host.update();
+        let world = host.world_mut();
+        if state(world) == SimulationState::Setup && !ready {
+            world.commands().client_trigger(ClientReadyEvent);
+            world.flush();
+            ready = true;
+        }
+        if state(world) == SimulationState::Running && **world.resource::<SimulationTick>() > 10 {
+            return host;
+        }
+    }
+    panic!("practice match never ran");
+}
+
+#[test]
+fn practice_match_runs_on_the_host_alone() {
+    let host = run_practice_match(settings());
+    assert_eq!(host.world().resource::<SeatLayout>().players().collect::<Vec<_>>(), vec![HOST_CLIENT_ID]);
+}
+
+#[test]
+fn practice_match_restores_the_settings_it_overrode() {
+    let mut host = run_practice_match(SimulationSettings { num_players: 4, ..settings() });
+    host.world_mut().resource_mut::<NextState<SimulationState>>().set(SimulationState::None);
+    host.update();
+
+    let world = host.world();
+    assert!(world.resource::<ConnectionSettings>().server_mode == ServerMode::Dedicated);
+    assert_eq!(world.resource::<SimulationSettings>().num_players, 4);
+    assert!(!world.resource::<RepliconServer>().is_running());
+}
+
+#[test]
+fn host_seat_is_not_waited_on_with_empty_command_suppression() {
+    let settings = SimulationSettings {
+        empty_command_suppression: Some(EmptyCommandSuppression::default()),
+        ..settings()
+    };
+    let host = run_practice_match(settings);
+    assert_eq!(state(host.world()), SimulationState::Running);
+}
+
+/// The previous version of `Marker`, only known to some builds
+#[derive(Reflect, Debug)]
+struct MarkerV1(u16);
+
+impl UpgradeCommand for MarkerV1 {
+    type Next = Marker;
+
+    fn upgrade(self) -> Marker {
+        Marker(self.0 as u32)
+    }
+}
+
+#[test]
+fn clients_with_different_command_versions_play_together() {
+    let mut game = new_match(settings());
+    // The server and the first client still know the previous version, the
+    // second client was built without it
+    game.server.register_command_upgrade::<MarkerV1>();
+    game.clients[0].register_command_upgrade::<MarkerV1>();
+    game.start();
+    {
+        let client = game.clients[0].world();
+        let registry = client.resource::<AppTypeRegistry>().read();
+        let versions = client.resource::<CommandVersions>();
+        assert!(!versions.is_agreed::<MarkerV1>(&registry));
+        assert!(versions.is_agreed::<Marker>(&registry));
+    }
+
+    game.send(0, [Box::new(MarkerV1(3)) as Box<dyn PartialReflect>]);
+    for _ in 0..10 { game.frame() }
+    assert_clients_agree(&game);
+    let markers: Vec<Marker> = game.clients[1]
+        .world()
+        .resource::<LockstepGameCommandBuffer>()
+        .iter()
+        .flat_map(|tick| tick.values().flatten())
+        .filter_map(|command| Marker::from_reflect(command.as_ref()))
+        .collect();
+    assert_eq!(markers, vec![Marker(3)], "the previous version was upgraded");
+}
+
+#[test]
+fn clients_learn_the_command_versions_without_narrowing() {
+    let mut game = new_match(settings());
+    // Only the first client knows the previous version, so the server's own
+    // types are already the common ones and no report narrows them
+    game.clients[0].register_command_upgrade::<MarkerV1>();
+    game.start();
+    let previous = lockstep_command_id(MarkerV1::type_path());
+    for client in game.clients.iter() {
+        let versions = client.world().resource::<CommandVersions>();
+        assert!(!versions.contains(previous), "a client numbers a type the server doesn't know");
+        assert!(versions.contains(lockstep_command_id(Marker::type_path())));
+    }
+
+    send(&mut game, 1, client_tick(&game, 1), 5);
+    for _ in 0..10 { game.frame() }
+    assert_clients_agree(&game);
+}
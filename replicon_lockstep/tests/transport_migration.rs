@@ -0,0 +1,94 @@
+//! Moving a match to a replacement server: the replacement checks the
+//! ticks every reconnecting client received against its own history.  The
+//! in-process server of the match plays the replacement, the clients only
+//! drop their connection for a frame.
+
+use std::{net::SocketAddr, time::Duration};
+use bevy::prelude::*;
+use bevy_replicon::prelude::*;
+use bevy_replicon_lockstep::{prelude::*, test_utils::{state, LockstepTestMatch}};
+
+#[derive(Reflect, Debug)]
+struct Marker(u32);
+
+/// The migration outcomes a peer saw
+#[derive(Resource, Default)]
+struct Migrations {
+    finished: Vec<(ClientId, SimTick)>,
+    failed: Vec<(ClientId, SimTick)>,
+}
+
+fn setup(app: &mut App) {
+    app.register_lockstep_command::<Marker>()
+        .init_resource::<Migrations>()
+        .add_observer(|trigger: Trigger<TransportMigrationFinished>, mut migrations: ResMut<Migrations>| {
+            migrations.finished.push((trigger.client, trigger.resume_tick));
+        })
+        .add_observer(|trigger: Trigger<TransportMigrationFailed>, mut migrations: ResMut<Migrations>| {
+            migrations.failed.push((trigger.client, trigger.tick));
+        });
+}
+
+fn settings() -> SimulationSettings {
+    SimulationSettings {
+        tick_timestep: Duration::from_millis(33),
+        ..default()
+    }
+}
+
+/// Runs a match for a while, then asks the clients to move.  Returns the
+/// tick the replacement resumes after.
+fn start_migration(game: &mut LockstepTestMatch) -> SimTick {
+    game.start();
+    game.send(0, [Box::new(Marker(1)) as Box<dyn PartialReflect>]);
+    game.advance_ticks(10);
+    let resume_tick = game.server_tick();
+    let new_addr: SocketAddr = "127.0.0.1:5001".parse().expect("a valid address");
+    game.server.world_mut().trigger(RequestTransportMigration { new_addr });
+    for _ in 0..3 { game.frame() }
+    resume_tick
+}
+
+/// Drops the connection of a client for one update, then lets it verify
+/// its ticks with the server
+fn reconnect(game: &mut LockstepTestMatch, index: usize) {
+    let client = &mut game.clients[index];
+    client.world_mut().resource_mut::<RepliconClient>().set_status(RepliconClientStatus::Disconnected);
+    client.update();
+    client.world_mut().resource_mut::<RepliconClient>().set_status(RepliconClientStatus::Connected);
+    for _ in 0..3 { game.frame() }
+}
+
+#[test]
+fn replacement_with_the_same_history_resumes() {
+    let mut game = LockstepTestMatch::new(settings(), 2, setup);
+    let resume_tick = start_migration(&mut game);
+    reconnect(&mut game, 0);
+
+    let client = game.clients[0].world().resource::<Migrations>();
+    assert_eq!(client.finished, vec![(2, resume_tick)]);
+    assert!(client.failed.is_empty());
+    let server = game.server.world().resource::<Migrations>();
+    assert_eq!(server.finished, vec![(2, resume_tick)]);
+    assert!(server.failed.is_empty());
+    assert!(!game.server.world().resource::<LockstepSpectators>().contains_key(&2));
+}
+
+#[test]
+fn replacement_with_a_different_history_unseats_the_client() {
+    let mut game = LockstepTestMatch::new(settings(), 2, setup);
+    let resume_tick = start_migration(&mut game);
+    // The replacement's last tick differs from what the clients received
+    let mut history = game.server.world_mut().resource_mut::<LockstepGameCommandBuffer>();
+    history[resume_tick as usize].entry(2).or_default().push(Box::new(Marker(99)));
+    reconnect(&mut game, 0);
+
+    let client = game.clients[0].world().resource::<Migrations>();
+    assert_eq!(client.failed, vec![(2, resume_tick)]);
+    assert!(client.finished.is_empty());
+    assert_eq!(state(game.clients[0].world()), SimulationState::None);
+    let server = game.server.world().resource::<Migrations>();
+    assert_eq!(server.failed, vec![(2, resume_tick)]);
+    // Ticks no longer wait on the client that left
+    assert!(game.server.world().resource::<LockstepSpectators>().contains_key(&2));
+}
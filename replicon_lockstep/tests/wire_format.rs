@@ -0,0 +1,266 @@
+//! Property tests of the network-facing wire format: valid messages round
+//! trip, and malformed, truncated or oversized messages are rejected without
+//! panicking or allocating more than the message could contain
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    sync::OnceLock,
+};
+use bevy::prelude::*;
+use bevy_replicon_lockstep::{fuzzing::{WireMessage, encode, number_command_types, reencode}, prelude::*};
+use proptest::prelude::*;
+
+/// Counts the bytes allocated by the current thread
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.with(|allocated| allocated.set(allocated.get() + layout.size()));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocated_by<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATED.with(Cell::get);
+    let result = f();
+    (result, ALLOCATED.with(Cell::get) - before)
+}
+
+#[derive(Reflect, Debug, Clone)]
+struct MoveTo {
+    unit: u32,
+    x: f32,
+    y: f32,
+}
+
+#[derive(Reflect, Debug, Clone)]
+struct Chat(String);
+
+#[derive(Reflect, Debug, Clone)]
+enum Order {
+    Stop,
+    Attack { target: u64 },
+    Build(u16, i32),
+}
+
+#[derive(Reflect, Debug, Clone)]
+struct Select {
+    units: Vec<u32>,
+}
+
+#[derive(Debug, Clone)]
+enum AnyCommand {
+    MoveTo(MoveTo),
+    Chat(Chat),
+    Order(Order),
+    Select(Select),
+}
+
+impl AnyCommand {
+    fn boxed(self) -> Box<dyn PartialReflect> {
+        match self {
+            Self::MoveTo(command) => Box::new(command),
+            Self::Chat(command) => Box::new(command),
+            Self::Order(command) => Box::new(command),
+            Self::Select(command) => Box::new(command),
+        }
+    }
+}
+
+/// Ticks encoding to more bytes are compressed by [`compressing_registry`]
+const COMPRESS_ABOVE: usize = 64;
+
+fn new_registry() -> AppTypeRegistry {
+    let mut app = App::new();
+    app
+        .register_lockstep_command::<MoveTo>()
+        .register_lockstep_command::<Chat>()
+        .register_lockstep_command::<Order>()
+        .register_lockstep_command::<Select>();
+    app.world().resource::<AppTypeRegistry>().clone()
+}
+
+/// Command types sent by stable id, like before a match starts
+fn registry() -> &'static AppTypeRegistry {
+    static REGISTRY: OnceLock<AppTypeRegistry> = OnceLock::new();
+    REGISTRY.get_or_init(new_registry)
+}
+
+/// Command types sent by the compact ids of a match
+fn compact_registry() -> &'static AppTypeRegistry {
+    static REGISTRY: OnceLock<AppTypeRegistry> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let registry = new_registry();
+        number_command_types(&mut registry.write(), None);
+        registry
+    })
+}
+
+/// Compact ids, and ticks above [`COMPRESS_ABOVE`] bytes compressed with
+/// the `compression` feature
+fn compressing_registry() -> &'static AppTypeRegistry {
+    static REGISTRY: OnceLock<AppTypeRegistry> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let registry = new_registry();
+        number_command_types(&mut registry.write(), Some(COMPRESS_ABOVE));
+        registry
+    })
+}
+
+fn any_command() -> impl Strategy<Value = AnyCommand> {
+    prop_oneof![
+        (any::<u32>(), any::<f32>(), any::<f32>()).prop_map(|(unit, x, y)| AnyCommand::MoveTo(MoveTo { unit, x, y })),
+        ".{0,32}".prop_map(|text| AnyCommand::Chat(Chat(text))),
+        Just(AnyCommand::Order(Order::Stop)),
+        any::<u64>().prop_map(|target| AnyCommand::Order(Order::Attack { target })),
+        (any::<u16>(), any::<i32>()).prop_map(|(kind, cost)| AnyCommand::Order(Order::Build(kind, cost))),
+        prop::collection::vec(any::<u32>(), 0..16).prop_map(|units| AnyCommand::Select(Select { units })),
+    ]
+}
+
+fn any_tick_commands() -> impl Strategy<Value = Vec<(ClientId, Vec<AnyCommand>)>> {
+    prop::collection::vec((any::<ClientId>(), prop::collection::vec(any_command(), 0..8)), 0..4)
+}
+
+fn tick_commands(generated: Vec<(ClientId, Vec<AnyCommand>)>) -> LockstepClientCommands {
+    let mut commands = LockstepClientCommands::default();
+    for (client, client_commands) in generated {
+        commands.insert(client, client_commands.into_iter().map(AnyCommand::boxed).collect());
+    }
+    commands
+}
+
+proptest! {
+    #[test]
+    fn valid_messages_round_trip(tick in 0..1_000_000 as SimTick, generated in any_tick_commands()) {
+        let registry = registry().read();
+        let commands = tick_commands(generated);
+        for kind in WireMessage::ALL {
+            let message = encode(kind, tick, &commands, &registry).unwrap();
+            prop_assert_eq!(reencode(kind, &message, &registry).unwrap(), message, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn compact_messages_round_trip(tick in 0..1_000_000 as SimTick, generated in any_tick_commands()) {
+        let commands = tick_commands(generated);
+        for registry in [compact_registry(), compressing_registry()] {
+            let registry = registry.read();
+            for kind in WireMessage::ALL {
+                let message = encode(kind, tick, &commands, &registry).unwrap();
+                prop_assert_eq!(reencode(kind, &message, &registry).unwrap(), message, "{:?}", kind);
+            }
+        }
+        // Compact ids are never longer than stable ids
+        let stable = encode(WireMessage::TickCommands, tick, &commands, &registry().read()).unwrap();
+        let compact = encode(WireMessage::TickCommands, tick, &commands, &compact_registry().read()).unwrap();
+        prop_assert!(compact.len() <= stable.len());
+    }
+
+    #[test]
+    fn arbitrary_bytes_never_panic(message in prop::collection::vec(any::<u8>(), 0..512)) {
+        let registry = registry().read();
+        for kind in WireMessage::ALL {
+            // Whatever decodes is encoded canonically
+            if let Ok(encoded) = reencode(kind, &message, &registry) {
+                prop_assert_eq!(reencode(kind, &encoded, &registry).unwrap(), encoded, "{:?}", kind);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_messages_are_rejected(tick in 0..1_000_000 as SimTick, generated in any_tick_commands()) {
+        let registry = registry().read();
+        let commands = tick_commands(generated);
+        for kind in WireMessage::ALL {
+            let message = encode(kind, tick, &commands, &registry).unwrap();
+            for len in 0..message.len() {
+                prop_assert!(reencode(kind, &message[..len], &registry).is_err(), "{:?} truncated to {}", kind, len);
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_command_ids_are_rejected(id in 1..u32::MAX, payload in prop::collection::vec(any::<u8>(), 0..64)) {
+        let registry = registry().read();
+        let registered = registry.iter_with_data::<ReflectLockstepCommand>().any(|(_, data)| data.id == id);
+        prop_assume!(!registered);
+        // One command with the id, then the payload
+        let mut message = postcard::to_allocvec(&(1u16, id)).unwrap();
+        message.extend(payload);
+        prop_assert!(reencode(WireMessage::ClientCommands, &message, &registry).is_err());
+    }
+}
+
+/// Decoding a message whose counts claim far more than it contains must fail
+/// before allocating for the claimed size
+fn assert_rejected_cheaply(kind: WireMessage, message: &[u8]) {
+    let registry = registry().read();
+    let (result, allocated) = allocated_by(|| reencode(kind, message, &registry));
+    assert!(result.is_err(), "{:?} accepted a corrupt message", kind);
+    assert!(allocated < 64 * 1024, "{:?} allocated {} bytes for a {} byte message", kind, allocated, message.len());
+}
+
+#[test]
+fn oversized_command_count_is_rejected() {
+    let message = postcard::to_allocvec(&u16::MAX).unwrap();
+    assert_rejected_cheaply(WireMessage::ClientCommands, &message);
+}
+
+#[test]
+fn oversized_client_command_count_is_rejected() {
+    // Command tick tag, session, digest, one client with u16::MAX commands
+    let message = postcard::to_allocvec(&(1u8, 0u32, 0u64, 1u8, 7u64, u16::MAX)).unwrap();
+    assert_rejected_cheaply(WireMessage::TickCommands, &message);
+}
+
+#[test]
+fn oversized_empty_tick_run_is_rejected() {
+    // Every tick up to u32::MAX in one run of empty ticks
+    let history = postcard::to_allocvec(&(0u32, u32::MAX, 1u32, 0u8, u32::MAX)).unwrap();
+    assert_rejected_cheaply(WireMessage::CatchUpHistory, &history);
+    let migration = postcard::to_allocvec(&(0u32, u32::MAX, 1u32, 0u8, u32::MAX)).unwrap();
+    assert_rejected_cheaply(WireMessage::MigrationHistory, &migration);
+}
+
+#[test]
+fn tick_range_past_the_last_tick_is_rejected() {
+    // A single empty tick starting at the last representable tick
+    let history = postcard::to_allocvec(&(u32::MAX, u32::MAX, 1u32, 0u8, 1u32)).unwrap();
+    assert_rejected_cheaply(WireMessage::CatchUpHistory, &history);
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn large_ticks_are_compressed() {
+    let mut commands = LockstepClientCommands::default();
+    for client in 2..6 {
+        let chat = (0..8).map(|_| Box::new(Chat("attack the north gate".into())) as Box<dyn PartialReflect>);
+        commands.insert(client, chat.collect());
+    }
+    let uncompressed = encode(WireMessage::TickCommands, 42, &commands, &compact_registry().read()).unwrap();
+    let registry = compressing_registry().read();
+    let compressed = encode(WireMessage::TickCommands, 42, &commands, &registry).unwrap();
+    assert!(uncompressed.len() > COMPRESS_ABOVE);
+    assert!(compressed.len() < uncompressed.len(), "{} bytes compressed to {}", uncompressed.len(), compressed.len());
+    assert_eq!(reencode(WireMessage::TickCommands, &compressed, &registry).unwrap(), compressed);
+}
+
+#[test]
+fn compact_ids_need_the_numbering_of_the_match() {
+    let commands = tick_commands(vec![(2, vec![AnyCommand::Order(Order::Stop)])]);
+    let message = encode(WireMessage::TickCommands, 1, &commands, &compact_registry().read()).unwrap();
+    assert!(reencode(WireMessage::TickCommands, &message, &registry().read()).is_err());
+}